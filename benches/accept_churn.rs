@@ -0,0 +1,81 @@
+// Connection churn benchmark: many tiny connections against the proxy binary,
+// comparing a single acceptor with one SO_REUSEPORT acceptor per core.
+//
+//     cargo bench --bench accept_churn
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const ADDR: &str = "127.0.0.1:8080";
+const CLIENTS: usize = 64;
+const CONNECTIONS_PER_CLIENT: usize = 500;
+
+fn spawn_proxy(acceptors: usize) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_proxy"))
+        .args(["--reuseport", &acceptors.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to spawn proxy")
+}
+
+async fn wait_until_listening() {
+    for _ in 0..100 {
+        if TcpStream::connect(ADDR).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("proxy did not start listening on {ADDR}");
+}
+
+async fn churn() -> usize {
+    let mut clients = tokio::task::JoinSet::new();
+    for _ in 0..CLIENTS {
+        clients.spawn(async {
+            let mut completed = 0;
+            let mut response = Vec::new();
+            for _ in 0..CONNECTIONS_PER_CLIENT {
+                let Ok(mut stream) = TcpStream::connect(ADDR).await else {
+                    continue;
+                };
+                if stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.is_err() {
+                    continue;
+                }
+                response.clear();
+                if stream.read_to_end(&mut response).await.is_ok() {
+                    completed += 1;
+                }
+            }
+            completed
+        });
+    }
+    let mut completed = 0;
+    while let Some(result) = clients.join_next().await {
+        completed += result.unwrap();
+    }
+    completed
+}
+
+async fn run(acceptors: usize) {
+    let mut proxy = spawn_proxy(acceptors);
+    wait_until_listening().await;
+    let start = Instant::now();
+    let completed = churn().await;
+    let elapsed = start.elapsed();
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+    println!(
+        "acceptors={acceptors:<3} connections={completed:<6} elapsed={:.2}s rate={:.0} conn/s",
+        elapsed.as_secs_f64(),
+        completed as f64 / elapsed.as_secs_f64()
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let cores = std::thread::available_parallelism().map_or(4, |n| n.get());
+    run(1).await;
+    run(cores).await;
+}
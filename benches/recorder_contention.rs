@@ -0,0 +1,57 @@
+// Two-reader stress benchmark for the recorder: one writer task and two reader
+// tasks on a multi-threaded runtime, reporting how often and how long the
+// tasks were blocked on the recorder mutex.
+#[allow(dead_code, unused_imports, unused_macros)]
+#[path = "../src/log.rs"]
+mod log;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/recorder/mod.rs"]
+mod recorder;
+
+use recorder::{Recorder, RecorderReader};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::AsyncReadExt;
+
+const CHUNK: usize = 16 * 1024;
+const TOTAL: usize = 64 * 1024 * 1024;
+
+async fn read_all(mut reader: RecorderReader) -> usize {
+    let mut buf = vec![0; CHUNK];
+    let mut total = 0;
+    while total < TOTAL {
+        total += reader.read(&mut buf).await.unwrap();
+    }
+    total
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
+async fn main() {
+    // Bounded like a tunnel's recorder fed to capture files.
+    let recorder = Arc::new(Recorder::new().with_capacity(1 << 20));
+    let readers = [
+        tokio::spawn(read_all(RecorderReader::new(recorder.clone()))),
+        tokio::spawn(read_all(RecorderReader::new(recorder.clone()))),
+    ];
+    let start = Instant::now();
+    let chunk = vec![0x5a; CHUNK];
+    for _ in 0..TOTAL / CHUNK {
+        recorder.reserve().await.unwrap();
+        recorder.append(&chunk);
+        tokio::task::yield_now().await;
+    }
+    for reader in readers {
+        assert_eq!(reader.await.unwrap(), TOTAL);
+    }
+    let elapsed = start.elapsed();
+    let contention = recorder.contention();
+    eprintln!(
+        "bytes={} elapsed={:.2}s lock_acquisitions={} contended={} ({:.2}%) blocked={:?}",
+        TOTAL,
+        elapsed.as_secs_f64(),
+        contention.acquisitions,
+        contention.contended,
+        contention.contended as f64 * 100.0 / contention.acquisitions as f64,
+        contention.blocked
+    );
+}
@@ -0,0 +1,100 @@
+// Tunnel throughput benchmark: pushes a payload through a CONNECT tunnel of
+// the proxy binary to a discarding loopback target and reports throughput and
+// the proxy's CPU time per GiB (read from /proc on Linux), once with the
+// recorder keeping the bytes and once with `--no-record`, which only counts
+// them.
+//
+//     cargo bench --bench tunnel_throughput
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const ADDR: &str = "127.0.0.1:8080";
+const TOTAL: usize = 100 * 1024 * 1024;
+const CHUNK: usize = 64 * 1024;
+
+fn spawn_proxy(args: &[&str]) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_proxy"))
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to spawn proxy")
+}
+
+async fn connect_proxy() -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(ADDR).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("proxy did not start listening on {ADDR}");
+}
+
+// utime + stime of the process, in clock ticks (normally 100 per second).
+fn cpu_ticks(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    Some(fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?)
+}
+
+async fn run(mode: &str, args: &[&str]) {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let sink = tokio::spawn(async move {
+        let (mut stream, _) = target.accept().await.unwrap();
+        let mut received = 0;
+        let mut buf = vec![0; CHUNK];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                return received;
+            }
+            received += n;
+        }
+    });
+
+    let mut proxy = spawn_proxy(args);
+    let mut client = connect_proxy().await;
+    client
+        .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let mut response = [0; 39];
+    client.read_exact(&mut response).await.unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200"));
+
+    let cpu_before = cpu_ticks(proxy.id());
+    let start = Instant::now();
+    let chunk = vec![0x5a; CHUNK];
+    for _ in 0..TOTAL / CHUNK {
+        client.write_all(&chunk).await.unwrap();
+    }
+    client.shutdown().await.unwrap();
+    assert_eq!(sink.await.unwrap(), TOTAL);
+    let elapsed = start.elapsed();
+    let cpu_after = cpu_ticks(proxy.id());
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+
+    let gib = TOTAL as f64 / (1024.0 * 1024.0 * 1024.0);
+    print!(
+        "mode={mode} bytes={TOTAL} elapsed={:.2}s throughput={:.1} MiB/s",
+        elapsed.as_secs_f64(),
+        TOTAL as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+    );
+    match (cpu_before, cpu_after) {
+        (Some(before), Some(after)) => {
+            println!(" cpu={:.2}s/GiB", (after - before) as f64 / 100.0 / gib)
+        }
+        _ => println!(),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    run("record", &[]).await;
+    run("no-record", &["--no-record"]).await;
+}
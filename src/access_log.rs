@@ -0,0 +1,458 @@
+// One line per connection once it is closed, in the format of an existing
+// proxy's access log or as JSON, so that tools written for those can be
+// reused. Lines go to stdout, or with `--access-log` are appended to a file
+// by a task of their own, so connections never wait on the disk.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::{json, log};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // Squid's native access.log format.
+    Squid,
+    // The Common Log Format of web servers.
+    Clf,
+    // A JSON object per line.
+    Json,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "squid" => Some(Format::Squid),
+            "clf" => Some(Format::Clf),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// What is known about a connection, filled in as it is handled.
+pub struct Entry {
+    started: SystemTime,
+    start: Instant,
+    pub client: IpAddr,
+    pub method: Option<String>,
+    pub uri: Option<String>,
+    pub version: Option<String>,
+    // The host:port connected to for the client, as it asked for it.
+    pub target: Option<String>,
+    pub user: Option<String>,
+    // The status sent to the client, if any response was sent.
+    pub status: Option<u16>,
+    pub denied: bool,
+    // A plain HTTP request was forwarded, rather than a tunnel opened.
+    pub forwarded: bool,
+    // The address the tunnel was connected to.
+    pub peer: Option<IpAddr>,
+    // The peer is the upstream proxy rather than the target.
+    pub upstream: bool,
+    pub bytes_to_client: u64,
+    pub bytes_from_client: u64,
+}
+
+impl Entry {
+    pub fn new(client: IpAddr) -> Self {
+        Self {
+            started: SystemTime::now(),
+            start: Instant::now(),
+            client,
+            method: None,
+            uri: None,
+            version: None,
+            target: None,
+            user: None,
+            status: None,
+            denied: false,
+            forwarded: false,
+            peer: None,
+            upstream: false,
+            bytes_to_client: 0,
+            bytes_from_client: 0,
+        }
+    }
+
+    pub fn format(&self, format: Format) -> String {
+        let elapsed = self.start.elapsed();
+        match format {
+            Format::Squid => self.squid(elapsed),
+            Format::Clf => self.clf(),
+            Format::Json => self.json(elapsed),
+        }
+    }
+
+    // `%h %l %u %t "%r" %>s %b`, the time in UTC.
+    fn clf(&self) -> String {
+        let request = match (&self.method, &self.uri) {
+            (Some(method), Some(uri)) => {
+                let version = self
+                    .version
+                    .as_deref()
+                    .map_or(String::new(), |v| format!(" {v}"));
+                format!("{method} {uri}{version}")
+            }
+            (Some(method), None) => method.clone(),
+            _ => "-".to_string(),
+        };
+        let status = self
+            .status
+            .map_or("-".to_string(), |status| status.to_string());
+        let bytes = match self.bytes_to_client {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        format!(
+            "{} - {} [{}] \"{}\" {status} {bytes}",
+            self.client,
+            self.user.as_deref().unwrap_or("-"),
+            clf_time(self.started),
+            request.replace('\\', "\\\\").replace('"', "\\\""),
+        )
+    }
+
+    fn json(&self, elapsed: Duration) -> String {
+        let time = self.started.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let string =
+            |value: &Option<String>| value.as_deref().map_or("null".to_string(), json::quote);
+        format!(
+            "{{\"time_ms\":{},\"client\":{},\"method\":{},\"target\":{},\"user\":{},\
+             \"status\":{},\"bytes_up\":{},\"bytes_down\":{},\"duration_ms\":{}}}",
+            time.as_millis(),
+            json::quote(&self.client.to_string()),
+            string(&self.method),
+            string(&self.target.clone().or_else(|| self.uri.clone())),
+            string(&self.user),
+            self.status
+                .map_or("null".to_string(), |status| status.to_string()),
+            self.bytes_from_client,
+            self.bytes_to_client,
+            elapsed.as_millis()
+        )
+    }
+
+    // `%ts.%03tu %6tr %>a %Ss/%03>Hs %<st %rm %ru %[un %Sh/%<a %mt`
+    fn squid(&self, elapsed: Duration) -> String {
+        let time = self.started.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let action = match (self.denied, self.peer, self.forwarded) {
+            (true, _, _) => "TCP_DENIED",
+            (false, Some(_), true) => "TCP_MISS",
+            (false, Some(_), false) => "TCP_TUNNEL",
+            (false, None, _) => "NONE",
+        };
+        let hierarchy = match (self.peer, self.upstream) {
+            (Some(peer), false) => format!("HIER_DIRECT/{peer}"),
+            (Some(peer), true) => format!("FIRSTUP_PARENT/{peer}"),
+            (None, _) => "HIER_NONE/-".to_string(),
+        };
+        format!(
+            "{}.{:03} {:>6} {} {}/{:03} {} {} {} {} {} -",
+            time.as_secs(),
+            time.subsec_millis(),
+            elapsed.as_millis(),
+            self.client,
+            action,
+            self.status.unwrap_or(0),
+            self.bytes_to_client,
+            self.method.as_deref().unwrap_or("NONE"),
+            self.uri.as_deref().unwrap_or("error:invalid-request"),
+            self.user.as_deref().unwrap_or("-"),
+            hierarchy
+        )
+    }
+}
+
+// 10/Oct/2000:13:55:36 +0000, after Howard Hinnant's `civil_from_days`.
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, rest) = (secs / 86400, secs % 86400);
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{day:02}/{}/{year:04}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
+
+/// Takes the bytes moved each way from a failed tunnel's error.
+pub fn record_failure(entry: &mut Entry, error: &io::Error) {
+    if let Some((bytes_up, bytes_down)) = crate::connection_error::bytes_of(error) {
+        entry.bytes_from_client = entry.bytes_from_client.max(bytes_up);
+        entry.bytes_to_client = entry.bytes_to_client.max(bytes_down);
+    }
+}
+
+enum Message {
+    Line(String),
+    // Reopen the file, as after it was rotated.
+    Reopen,
+    // Flush and stop.
+    Finish,
+}
+
+/// An access log file, appended to by a writer task.
+pub struct AccessLog {
+    sender: mpsc::UnboundedSender<Message>,
+    writer: Mutex<Option<JoinHandle<io::Result<()>>>>,
+}
+
+impl AccessLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = open(path)?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(write_lines(path.to_path_buf(), file, receiver));
+        Ok(Self {
+            sender,
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    pub fn record(&self, line: String) {
+        let _ = self.sender.send(Message::Line(line));
+    }
+
+    pub fn reopen(&self) {
+        let _ = self.sender.send(Message::Reopen);
+    }
+
+    /// Writes out what was recorded so far and stops the writer.
+    pub async fn finish(&self) -> io::Result<()> {
+        let _ = self.sender.send(Message::Finish);
+        let writer = self.writer.lock().unwrap().take();
+        match writer {
+            Some(writer) => writer.await.map_err(io::Error::other)?,
+            None => Ok(()),
+        }
+    }
+}
+
+fn open(path: &Path) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| {
+            let path = path.display();
+            io::Error::new(e.kind(), format!("cannot open access log {path}: {e}"))
+        })?;
+    Ok(BufWriter::new(File::from_std(file)))
+}
+
+// Flushes whenever the queue runs dry. A line that cannot be written is
+// tried once more on the file opened again, and dropped if that fails too.
+async fn write_lines(
+    path: PathBuf,
+    mut out: BufWriter<File>,
+    mut receiver: mpsc::UnboundedReceiver<Message>,
+) -> io::Result<()> {
+    while let Some(mut next) = receiver.recv().await {
+        loop {
+            match next {
+                Message::Line(line) => {
+                    let line = line + "\n";
+                    if let Err(e) = out.write_all(line.as_bytes()).await {
+                        log::warn!("Access log write failed, reopening {}: {e}", path.display());
+                        out = open(&path)?;
+                        if let Err(e) = out.write_all(line.as_bytes()).await {
+                            log::warn!("Access log line dropped: {e}");
+                        }
+                    }
+                }
+                Message::Reopen => {
+                    let _ = out.flush().await;
+                    out = open(&path)?;
+                }
+                Message::Finish => return out.flush().await,
+            }
+            match receiver.try_recv() {
+                Ok(message) => next = message,
+                Err(_) => break,
+            }
+        }
+        out.flush().await?;
+    }
+    out.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks every field of a native access.log line the way strict log
+    // parsers do.
+    fn assert_squid_line(line: &str) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        assert_eq!(fields.len(), 10, "{line}");
+        let (secs, millis) = fields[0].split_once('.').unwrap();
+        assert!(secs.parse::<u64>().is_ok() && millis.len() == 3, "{line}");
+        assert!(millis.chars().all(|c| c.is_ascii_digit()), "{line}");
+        assert!(fields[1].parse::<u64>().is_ok(), "{line}");
+        assert!(fields[2].parse::<IpAddr>().is_ok(), "{line}");
+        let (action, code) = fields[3].split_once('/').unwrap();
+        assert!(
+            action.chars().all(|c| c.is_ascii_uppercase() || c == '_'),
+            "{line}"
+        );
+        assert!(code.len() == 3 && code.parse::<u16>().is_ok(), "{line}");
+        assert!(fields[4].parse::<u64>().is_ok(), "{line}");
+        assert!(fields[5].chars().all(|c| c.is_ascii_uppercase()), "{line}");
+        let (hierarchy, peer) = fields[8].split_once('/').unwrap();
+        assert!(
+            hierarchy.starts_with("HIER_") || hierarchy == "FIRSTUP_PARENT",
+            "{line}"
+        );
+        assert!(peer == "-" || peer.parse::<IpAddr>().is_ok(), "{line}");
+        assert_eq!(fields[9], "-");
+        // Elapsed is right-aligned in six columns.
+        assert_eq!(&line[15..21], format!("{:>6}", fields[1]), "{line}");
+    }
+
+    fn entry() -> Entry {
+        let mut entry = Entry::new("192.168.0.224".parse().unwrap());
+        entry.started = UNIX_EPOCH + Duration::from_millis(1_286_536_308_779);
+        entry.method = Some("CONNECT".to_string());
+        entry.uri = Some("www.example.com:443".to_string());
+        entry
+    }
+
+    #[test]
+    fn test_squid_lines() {
+        let mut tunnel = entry();
+        tunnel.status = Some(200);
+        tunnel.peer = Some("93.184.216.34".parse().unwrap());
+        tunnel.bytes_to_client = 4462;
+        let mut denied = entry();
+        denied.status = Some(403);
+        denied.denied = true;
+        let failed = entry();
+        let mut parent = entry();
+        parent.status = Some(200);
+        parent.peer = Some("10.0.0.1".parse().unwrap());
+        parent.upstream = true;
+        let mut malformed = Entry::new("::1".parse().unwrap());
+        malformed.status = Some(400);
+        let lines = [
+            (
+                tunnel,
+                "1286536308.779    180 192.168.0.224 TCP_TUNNEL/200 4462 CONNECT \
+                 www.example.com:443 - HIER_DIRECT/93.184.216.34 -",
+            ),
+            (
+                denied,
+                "1286536308.779    180 192.168.0.224 TCP_DENIED/403 0 CONNECT \
+                 www.example.com:443 - HIER_NONE/- -",
+            ),
+            (
+                failed,
+                "1286536308.779    180 192.168.0.224 NONE/000 0 CONNECT \
+                 www.example.com:443 - HIER_NONE/- -",
+            ),
+            (
+                parent,
+                "1286536308.779    180 192.168.0.224 TCP_TUNNEL/200 0 CONNECT \
+                 www.example.com:443 - FIRSTUP_PARENT/10.0.0.1 -",
+            ),
+        ];
+        for (entry, expected) in lines {
+            let line = entry.squid(Duration::from_millis(180));
+            assert_eq!(line, expected);
+            assert_squid_line(&line);
+        }
+        let line = malformed.squid(Duration::from_millis(1));
+        assert!(line.contains(" ::1 NONE/400 0 NONE error:invalid-request - HIER_NONE/- -"));
+    }
+
+    #[test]
+    fn test_clf_and_json_lines() {
+        let mut tunnel = entry();
+        tunnel.status = Some(200);
+        tunnel.user = Some("alice".to_string());
+        tunnel.target = tunnel.uri.clone();
+        tunnel.bytes_from_client = 517;
+        tunnel.bytes_to_client = 4462;
+        assert_eq!(
+            tunnel.clf(),
+            "192.168.0.224 - alice [08/Oct/2010:11:11:48 +0000] \"CONNECT www.example.com:443\" \
+             200 4462"
+        );
+        let line = tunnel.json(Duration::from_millis(180));
+        let value = json::parse(&line).unwrap();
+        assert_eq!(
+            value.get("time_ms").unwrap().as_u64(),
+            Some(1_286_536_308_779)
+        );
+        assert_eq!(value.get("client").unwrap().as_str(), Some("192.168.0.224"));
+        assert_eq!(value.get("method").unwrap().as_str(), Some("CONNECT"));
+        assert_eq!(
+            value.get("target").unwrap().as_str(),
+            Some("www.example.com:443")
+        );
+        assert_eq!(value.get("status").unwrap().as_u64(), Some(200));
+        assert_eq!(value.get("bytes_up").unwrap().as_u64(), Some(517));
+        assert_eq!(value.get("bytes_down").unwrap().as_u64(), Some(4462));
+        assert_eq!(value.get("duration_ms").unwrap().as_u64(), Some(180));
+        let mut forward = Entry::new("::1".parse().unwrap());
+        forward.started = UNIX_EPOCH;
+        forward.method = Some("GET".to_string());
+        forward.uri = Some("http://example.com/a\"b".to_string());
+        forward.version = Some("HTTP/1.1".to_string());
+        assert_eq!(
+            forward.clf(),
+            "::1 - - [01/Jan/1970:00:00:00 +0000] \"GET http://example.com/a\\\"b HTTP/1.1\" - -"
+        );
+        let value = json::parse(&forward.json(Duration::ZERO)).unwrap();
+        assert_eq!(
+            value.get("target").unwrap().as_str(),
+            Some("http://example.com/a\"b")
+        );
+        assert_eq!(value.get("status"), Some(&json::Value::Null));
+        assert_eq!(
+            clf_time(UNIX_EPOCH + Duration::from_secs(951_825_600)),
+            "29/Feb/2000:12:00:00 +0000"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_is_reopened() {
+        let path = std::env::temp_dir().join(format!("proxy-access-log-{}", std::process::id()));
+        let moved = path.with_extension("1");
+        let _ = std::fs::remove_file(&path);
+        let log = AccessLog::create(&path).unwrap();
+        log.record("one".to_string());
+        // Lets the writer write the line before the file is moved.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::rename(&path, &moved).unwrap();
+        log.reopen();
+        log.record("two".to_string());
+        log.finish().await.unwrap();
+        assert_eq!(std::fs::read_to_string(&moved).unwrap(), "one\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "two\n");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&moved).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+// Raw captures of the tunnels: the bytes of each direction, in order, in one
+// file per direction and connection, and an index alongside them with the
+// destination and where each chunk began, which `--replay` plays back from.
+
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::fs::File;
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(1);
+
+pub const INDEX_EXTENSION: &str = "idx";
+const INDEX_HEADER: &str = "# proxy capture index 1";
+
+// Keeps file names to characters that are safe everywhere.
+fn sanitize(host: &str) -> String {
+    host.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn file_names(sequence: u64, host: &str, port: u16) -> [String; 3] {
+    let prefix = format!("{sequence:04}-{}-{port}", sanitize(host));
+    [
+        format!("{prefix}-c2s.bin"),
+        format!("{prefix}-s2c.bin"),
+        format!("{prefix}.{INDEX_EXTENSION}"),
+    ]
+}
+
+/// The server-to-client file of the capture an index belongs to.
+pub fn s2c_path(index: &Path) -> PathBuf {
+    let stem = index.file_stem().unwrap_or_default().to_string_lossy();
+    index.with_file_name(format!("{stem}-s2c.bin"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk {
+    // Client to server.
+    pub up: bool,
+    // Since the capture was created.
+    pub at: Duration,
+    pub len: usize,
+}
+
+/// A capture's index: the destination and every chunk in both directions.
+/// Chunks are kept in memory and the file is written when the tunnel ends.
+#[derive(Debug)]
+pub struct Index {
+    path: PathBuf,
+    host: String,
+    port: u16,
+    started_at: SystemTime,
+    started: Instant,
+    chunks: Mutex<Vec<Chunk>>,
+}
+
+impl Index {
+    pub fn chunk(&self, up: bool, len: usize) {
+        let at = self.started.elapsed();
+        self.chunks.lock().unwrap().push(Chunk { up, at, len });
+    }
+
+    fn render(&self) -> String {
+        let started = self
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut text = format!(
+            "{INDEX_HEADER}\nhost {}\nport {}\nstarted {}\n",
+            self.host,
+            self.port,
+            started.as_millis()
+        );
+        for chunk in self.chunks.lock().unwrap().iter() {
+            let direction = if chunk.up { "c2s" } else { "s2c" };
+            text += &format!("{direction} {} {}\n", chunk.at.as_micros(), chunk.len);
+        }
+        text
+    }
+
+    pub async fn write(&self) -> io::Result<()> {
+        tokio::fs::write(&self.path, self.render())
+            .await
+            .map_err(|e| {
+                let path = self.path.display();
+                io::Error::new(e.kind(), format!("{path}: {e}"))
+            })
+    }
+}
+
+/// An index read back from its file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Recorded {
+    pub host: String,
+    pub port: u16,
+    pub chunks: Vec<Chunk>,
+}
+
+pub fn parse_index(text: &str) -> io::Result<Recorded> {
+    let invalid = |line: &str| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid capture index line: {line}"),
+        )
+    };
+    let mut lines = text.lines();
+    if lines.next() != Some(INDEX_HEADER) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "not a capture index",
+        ));
+    }
+    let (mut host, mut port, mut chunks) = (None, None, vec![]);
+    for line in lines {
+        let fields: Vec<&str> = line.split(' ').collect();
+        match fields[..] {
+            ["host", value] => host = Some(value.to_string()),
+            ["port", value] => port = Some(value.parse().map_err(|_| invalid(line))?),
+            ["started", _] => {}
+            [direction @ ("c2s" | "s2c"), at, len] => chunks.push(Chunk {
+                up: direction == "c2s",
+                at: Duration::from_micros(at.parse().map_err(|_| invalid(line))?),
+                len: len.parse().map_err(|_| invalid(line))?,
+            }),
+            _ => return Err(invalid(line)),
+        }
+    }
+    match (host, port) {
+        (Some(host), Some(port)) => Ok(Recorded { host, port, chunks }),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "capture index without host and port",
+        )),
+    }
+}
+
+pub struct Capture {
+    pub c2s: File,
+    pub s2c: File,
+    pub index: Index,
+}
+
+/// Creates the client-to-server and server-to-client files of a new
+/// connection to `host:port`, and its index.
+pub async fn create(dir: &Path, host: &str, port: u16) -> io::Result<Capture> {
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let [c2s, s2c, index] = file_names(sequence, host, port);
+    let open = |name: String| {
+        let path = dir.join(name);
+        async move {
+            File::create(&path).await.map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("cannot create capture {}: {e}", path.display()),
+                )
+            })
+        }
+    };
+    Ok(Capture {
+        c2s: open(c2s).await?,
+        s2c: open(s2c).await?,
+        index: Index {
+            path: dir.join(index),
+            host: host.to_string(),
+            port,
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+            chunks: Mutex::new(vec![]),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_file_names() {
+        assert_eq!(
+            file_names(1, "client.example.com", 443),
+            [
+                "0001-client.example.com-443-c2s.bin",
+                "0001-client.example.com-443-s2c.bin",
+                "0001-client.example.com-443.idx"
+            ]
+        );
+        assert_eq!(file_names(12345, "::1", 80)[0], "12345-__1-80-c2s.bin");
+        assert_eq!(
+            s2c_path(Path::new("captures/0001-client.example.com-443.idx")),
+            Path::new("captures/0001-client.example.com-443-s2c.bin")
+        );
+    }
+    #[test]
+    fn test_index_round_trip() {
+        let index = Index {
+            path: PathBuf::new(),
+            host: "example.com".to_string(),
+            port: 443,
+            started_at: UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            started: Instant::now(),
+            chunks: Mutex::new(vec![
+                Chunk {
+                    up: true,
+                    at: Duration::from_micros(10),
+                    len: 120,
+                },
+                Chunk {
+                    up: false,
+                    at: Duration::from_millis(25),
+                    len: 4096,
+                },
+            ]),
+        };
+        let text = index.render();
+        assert_eq!(
+            text,
+            "# proxy capture index 1\nhost example.com\nport 443\nstarted 1700000000123\n\
+             c2s 10 120\ns2c 25000 4096\n"
+        );
+        let recorded = parse_index(&text).unwrap();
+        assert_eq!(recorded.host, "example.com");
+        assert_eq!(recorded.port, 443);
+        assert_eq!(recorded.chunks, *index.chunks.lock().unwrap());
+        for bad in [
+            "host example.com\nport 443\n",
+            "# proxy capture index 1\nhost example.com\n",
+            "# proxy capture index 1\nhost example.com\nport 443\ns2c soon 5\n",
+        ] {
+            assert_eq!(parse_index(bad).unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
+}
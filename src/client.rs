@@ -0,0 +1,3295 @@
+// One client connection: the request, the checks and policy decision on it,
+// and the tunnel or forwarded request that follows. A connection whose
+// forwarded requests are answered in full may carry more of them.
+
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+use crate::connection_error::{ConnectionContext, ProxyError, Stage};
+use crate::headers::Headers;
+use crate::http_reader::{HttpReader, Rewound};
+use crate::proxy::ProxyState;
+use crate::stream::Stream;
+use crate::tunnel::{Recording, Taps, forward_streams};
+use crate::{
+    access_log, capture, connection_error, debug_dump, fault, framing, har, http_forward,
+    intercept, json, latency, log, metrics, netlog, policy, proxy_auth, proxy_protocol, recorder,
+    request_id, request_line, resolver, socks5, webhook, websocket,
+};
+
+fn reason_phrase(code: u32) -> &'static str {
+    match code {
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ if code < 500 => "Client Error",
+        _ => "Server Error",
+    }
+}
+
+pub async fn send_error<S: AsyncWrite + Unpin>(
+    client_stream: &mut S,
+    code: u32,
+    body: &str,
+) -> io::Result<()> {
+    send_error_with(client_stream, code, &[], body).await
+}
+
+// `headers` are complete header lines, without the CRLF. The connection is
+// closed after the response, so the body is framed by its length.
+async fn send_error_with<S: AsyncWrite + Unpin>(
+    client_stream: &mut S,
+    code: u32,
+    headers: &[&str],
+    body: &str,
+) -> io::Result<()> {
+    let mut response = format!("HTTP/1.1 {code} {}\r\n", reason_phrase(code));
+    for header in headers {
+        response.push_str(header);
+        response.push_str("\r\n");
+    }
+    response.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    ));
+    response.push_str(body);
+    client_stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+// From the configured source address, within the connect timeout; over a
+// unix socket for a `--unix-target`.
+async fn connect_target(
+    state: &ProxyState,
+    host: &str,
+    port: u16,
+    ctx: &ConnectionContext,
+    netlog: &netlog::Source<'_>,
+) -> io::Result<Stream> {
+    #[cfg(unix)]
+    if let Some(path) = state.config.unix_target(host) {
+        return connect_unix(state, host, path, ctx, netlog).await;
+    }
+    let (resolver, timeout) = (&state.resolver, state.config.connect_timeout);
+    let (retry, source) = (state.config.connect_retry(), &state.config.outbound);
+    netlog.event(
+        netlog::EventType::TcpConnect,
+        netlog::Phase::Begin,
+        &[("address", json::quote(&policy::join_authority(host, port)))],
+    );
+    let deadline = Instant::now() + timeout;
+    let addr = policy::join_authority(host, port);
+    let connect = async {
+        let addrs: Vec<SocketAddr> = match state.config.hosts.lookup(host) {
+            Some(target) => {
+                log::debug!(
+                    "Connecting to {} for {host}, as overridden",
+                    target.addr(port)
+                );
+                vec![target.addr(port)]
+            }
+            None => resolver
+                .resolve(host)
+                .await
+                .map_err(|source| {
+                    let host = host.to_string();
+                    (Stage::Resolve, ProxyError::TargetResolve { host, source })
+                })?
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect(),
+        };
+        resolver::connect(&addrs, resolver::FALLBACK_DELAY, deadline, retry, source)
+            .await
+            .map_err(|source| {
+                let addr = addr.clone();
+                (Stage::Connect, ProxyError::TargetConnect { addr, source })
+            })
+    };
+    let result = match tokio::time::timeout(timeout, connect).await {
+        Ok(result) => result,
+        Err(_) => {
+            let e = format!("no connection within {timeout:?}");
+            let source = io::Error::new(ErrorKind::TimedOut, e);
+            Err((Stage::Connect, ProxyError::TargetConnect { addr, source }))
+        }
+    };
+    let params = match &result {
+        Ok(stream) => match stream.peer_addr() {
+            Ok(peer) => vec![("remote_address", json::quote(&peer.to_string()))],
+            Err(_) => vec![],
+        },
+        Err((_, e)) => vec![("error", json::quote(&e.to_string()))],
+    };
+    netlog.event(netlog::EventType::TcpConnect, netlog::Phase::End, &params);
+    result
+        .map(Stream::Tcp)
+        .map_err(|(stage, e)| ctx.fail(stage, e.into()))
+}
+
+#[cfg(unix)]
+async fn connect_unix(
+    state: &ProxyState,
+    host: &str,
+    path: &std::path::Path,
+    ctx: &ConnectionContext,
+    netlog: &netlog::Source<'_>,
+) -> io::Result<Stream> {
+    let address = format!("unix:{}", path.display());
+    log::debug!("Connecting to {address} for {host}");
+    netlog.event(
+        netlog::EventType::TcpConnect,
+        netlog::Phase::Begin,
+        &[("address", json::quote(&address))],
+    );
+    let timeout = state.config.connect_timeout;
+    let result = match tokio::time::timeout(timeout, UnixStream::connect(path)).await {
+        Ok(result) => result,
+        Err(_) => {
+            let e = format!("no connection within {timeout:?}");
+            Err(io::Error::new(ErrorKind::TimedOut, e))
+        }
+    };
+    let result = result.map_err(|source| {
+        let addr = address.clone();
+        io::Error::from(ProxyError::TargetConnect { addr, source })
+    });
+    let params = match &result {
+        Ok(_) => vec![("remote_address", json::quote(&address))],
+        Err(e) => vec![("error", json::quote(&e.to_string()))],
+    };
+    netlog.event(netlog::EventType::TcpConnect, netlog::Phase::End, &params);
+    result
+        .map(Stream::Unix)
+        .map_err(|e| ctx.fail(Stage::Connect, e))
+}
+
+pub async fn handle_client<S>(
+    mut client_stream: S,
+    socket_addr: SocketAddr,
+    state: Arc<ProxyState>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // The --header-timeout runs from here, over a PROXY header too.
+    let started = Instant::now();
+    if !state.config.accept_proxy_protocol {
+        return handle_client_from(client_stream, socket_addr, state, started).await;
+    }
+    let ctx = ConnectionContext {
+        started,
+        ..ConnectionContext::new(socket_addr)
+    };
+    let header = proxy_protocol::read_header(&mut client_stream);
+    let client_addr = match tokio::time::timeout_at(head_deadline(&state, &ctx), header).await {
+        Ok(Ok(conveyed)) => conveyed.unwrap_or(socket_addr),
+        Ok(Err(e)) => {
+            log::warn!("Closing {socket_addr}: bad PROXY protocol header: {e}");
+            return Err(ctx.fail(Stage::HeaderRead, e));
+        }
+        // Before the header there is no telling how the client would be
+        // answered.
+        Err(_) => return Err(header_timed_out(&state, None, &ctx)),
+    };
+    log::debug!("Client {client_addr} by way of {socket_addr}");
+    let serve = handle_client_from(client_stream, client_addr, state, started);
+    match log::Connection::current() {
+        Some(connection) => {
+            let connection = log::Connection {
+                peer: client_addr,
+                ..connection
+            };
+            connection.scope(serve).await
+        }
+        None => serve.await,
+    }
+}
+
+// The client connection once `client_addr`, the address it is known by, is
+// settled. It was accepted at `started`.
+async fn handle_client_from<S>(
+    client_stream: S,
+    client_addr: SocketAddr,
+    state: Arc<ProxyState>,
+    started: Instant,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut access = access_log::Entry::new(client_addr.ip());
+    let dump = match &state.config.debug_dumps {
+        Some(dir) => debug_dump::Dump::new(dir, client_addr),
+        None => debug_dump::Dump::disabled(),
+    };
+    let result = serve_client(
+        client_stream,
+        client_addr,
+        started,
+        &state,
+        &mut access,
+        &dump,
+    )
+    .await;
+    if let Some(tails) = result.as_ref().err().and_then(connection_error::tails_of) {
+        dump.event(|| {
+            format!(
+                "last bytes from the client:\n{}last bytes from the target:\n{}",
+                recorder::hexdump(&tails.up),
+                recorder::hexdump(&tails.down)
+            )
+        });
+    }
+    dump.event(|| match &result {
+        Ok(()) => "closed".to_string(),
+        Err(e) => format!("closed: {e}"),
+    });
+    record_access(&state, &mut access, &result);
+    result
+}
+
+// One line per request, the last of a connection's once it is closed.
+fn record_access(state: &ProxyState, access: &mut access_log::Entry, result: &io::Result<()>) {
+    if let Some(format) = state.config.access_log_format {
+        if let Err(e) = result {
+            access_log::record_failure(access, e);
+        }
+        let line = access.format(format);
+        match &state.access_log {
+            Some(access_log) => access_log.record(line),
+            // Unlike println!, a stdout closed under the proxy, as by the
+            // end of a pipe, costs only the line.
+            None => {
+                use std::io::Write;
+                let _ = writeln!(std::io::stdout().lock(), "{line}");
+            }
+        }
+    }
+}
+
+// How the client asked for its destination, and so how it is answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    // CONNECT, answered with 200 once connected, in the HTTP version the
+    // client used.
+    Connect(&'static str),
+    // A plain request, sent on to the origin, which answers it.
+    Forward,
+    Socks5,
+}
+
+// A destination asked for, by whichever protocol.
+struct Destination<'a> {
+    host_port: &'a str,
+    user: Option<&'a str>,
+    request_id: &'a str,
+    protocol: Protocol,
+    // Sent to the target before anything else the client sends.
+    first: Vec<u8>,
+    // Set when a HAR file is being written.
+    har: Option<har::Request>,
+    // Set for a forwarded request that is not an upgrade, whose response
+    // is read to its end.
+    exchange: Option<&'a framing::Exchange>,
+}
+
+// Answers a request that is not tunnelled: with an HTTP error, or the SOCKS5
+// reply closest to it.
+async fn refuse<S: AsyncWrite + Unpin>(
+    client_stream: &mut S,
+    protocol: Protocol,
+    status: u32,
+    body: &str,
+) -> io::Result<()> {
+    match protocol {
+        Protocol::Connect(_) | Protocol::Forward => send_error(client_stream, status, body).await,
+        Protocol::Socks5 => {
+            let reply = socks5::Reply::for_status(status);
+            socks5::reply(client_stream, reply, None).await
+        }
+    }
+}
+
+// Answers `error` with its status, as `refuse` does.
+async fn refuse_with<S: AsyncWrite + Unpin>(
+    client_stream: &mut S,
+    protocol: Protocol,
+    error: &ProxyError,
+) -> io::Result<()> {
+    let status = error.status().into();
+    let body = error.body(reason_phrase(status));
+    refuse(client_stream, protocol, status, &body).await
+}
+
+// From the checks on a destination to the end of its tunnel.
+async fn open_tunnel<S>(
+    mut client_stream: S,
+    state: &ProxyState,
+    access: &mut access_log::Entry,
+    dump: &debug_dump::Dump,
+    mut ctx: ConnectionContext,
+    netlog: &netlog::Source<'_>,
+    destination: Destination<'_>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let client_addr = ctx.client;
+    let Destination {
+        host_port,
+        user,
+        request_id,
+        protocol,
+        first,
+        har,
+        exchange,
+    } = destination;
+    access.target = Some(host_port.to_string());
+    let Some((host, port)) = policy::split_authority(host_port) else {
+        dump.event(|| format!("invalid authority: {host_port}"));
+        let error = ProxyError::MalformedRequest {
+            line: host_port.to_string(),
+            reason: "invalid target authority".to_string(),
+        };
+        access.status = Some(error.status());
+        refuse_with(&mut client_stream, protocol, &error)
+            .await
+            .map_err(|e| ctx.fail(Stage::HeaderRead, e))?;
+        return Ok(());
+    };
+    if !state.runtime().host_filter.permits(host, port) {
+        log::info!(
+            "Closing {} from {}: close_reason=host_filter",
+            host_port,
+            client_addr
+        );
+        dump.event(|| "host filter: deny".to_string());
+        let error = ProxyError::PolicyDenied {
+            host: host_port.to_string(),
+            status: 403,
+            reason: "host_filter",
+        };
+        access.status = Some(error.status());
+        access.denied = true;
+        refuse_with(&mut client_stream, protocol, &error)
+            .await
+            .map_err(|e| ctx.fail(Stage::HeaderRead, e))?;
+        return Ok(());
+    }
+    let request = policy::ConnectRequest {
+        host,
+        port,
+        client: client_addr.ip(),
+        user,
+    };
+    let policy = state.policy();
+    let decision = policy.decide(&request, &ctx).await;
+    dump.event(|| {
+        format!(
+            "policy: {} by rule {} (enforced: {})",
+            decision.access,
+            policy.describe(decision.access_rule),
+            decision.enforced
+        )
+    });
+    if let (Some(plugin), Some(latency)) = (&decision.plugin, decision.plugin_latency) {
+        log::info!(
+            "Policy for {} from {}: {} by plugin {} in {:?} (rule {}, enforced: {})",
+            host_port,
+            client_addr,
+            decision.access,
+            plugin,
+            latency,
+            policy.describe(decision.access_rule),
+            decision.enforced
+        );
+    } else if decision.access_rule.is_some() || decision.access != policy::Access::Allow {
+        log::info!(
+            "Policy for {} from {}: {} by rule {} (enforced: {})",
+            host_port,
+            client_addr,
+            decision.access,
+            policy.describe(decision.access_rule),
+            decision.enforced
+        );
+    }
+    if let policy::Access::Deny(status) = decision.access
+        && !decision.enforced
+    {
+        log::info!(
+            "would deny({}) host={} rule={}",
+            status,
+            host_port,
+            policy.describe(decision.access_rule)
+        );
+    } else if let policy::Access::Deny(status) = decision.access {
+        let close_reason = match decision.access_rule {
+            Some(_) => "policy_deny",
+            None => "default_deny",
+        };
+        log::info!(
+            "Closing {} from {}: close_reason={}",
+            host_port,
+            client_addr,
+            close_reason
+        );
+        access.status = Some(status);
+        access.denied = true;
+        if let Some(webhooks) = &state.webhooks {
+            webhooks.notify(webhook::Event::Denial {
+                rule: policy.describe(decision.access_rule),
+                rule_name: decision
+                    .access_rule
+                    .and_then(|rule| policy.rule_name(rule))
+                    .map(str::to_string),
+                target: host_port.to_string(),
+                client: client_addr.to_string(),
+                status,
+            });
+        }
+        let error = ProxyError::PolicyDenied {
+            host: host_port.to_string(),
+            status,
+            reason: close_reason,
+        };
+        refuse_with(&mut client_stream, protocol, &error)
+            .await
+            .map_err(|e| ctx.fail(Stage::HeaderRead, e))?;
+        return Ok(());
+    }
+    let (host, port) = match &decision.rewrite {
+        Some((to_host, to_port)) => {
+            let to = policy::join_authority(to_host, *to_port);
+            log::info!(
+                "Rewriting {} from {} to {} by rule {}",
+                host_port,
+                client_addr,
+                to,
+                policy.describe(decision.rewrite_rule)
+            );
+            dump.event(|| {
+                format!(
+                    "rewrite: {to} by rule {}",
+                    policy.describe(decision.rewrite_rule)
+                )
+            });
+            ctx.rewritten_to = Some(to);
+            (to_host.as_str(), *to_port)
+        }
+        None => (host, port),
+    };
+    let interceptors = intercept::Chain::open(&state.interceptors, host_port);
+    let mut first = first;
+    if let Some(chain) = &interceptors
+        && !first.is_empty()
+    {
+        let reason = match chain.offer_first(&mut first) {
+            Ok(intercept::Action::Forward) => None,
+            Ok(intercept::Action::Drop) => {
+                first.clear();
+                None
+            }
+            Ok(intercept::Action::Abort(reason)) => Some(reason),
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(reason) = reason {
+            log::info!(
+                "Closing {} from {}: close_reason=intercepted ({})",
+                host_port,
+                client_addr,
+                reason
+            );
+            dump.event(|| format!("intercepted: {reason}"));
+            let status = state.config.intercept_status;
+            access.status = Some(status);
+            access.denied = true;
+            refuse(&mut client_stream, protocol, status.into(), "Blocked\n")
+                .await
+                .map_err(|e| ctx.fail(Stage::HeaderRead, e))?;
+            return Ok(());
+        }
+    }
+    // Captures are named for the destination asked for, and so are looked
+    // up by it.
+    if let Some(library) = &state.replay {
+        let replayed = match library.open(request.host, request.port).await {
+            Ok(Some(replayed)) => replayed,
+            Ok(None) => {
+                log::info!(
+                    "Closing {} from {}: close_reason=no_recording",
+                    host_port,
+                    client_addr
+                );
+                dump.event(|| "replay: no recording".to_string());
+                access.status = Some(502);
+                let body = "Bad Gateway: no recording of this destination\n";
+                refuse(&mut client_stream, protocol, 502, body)
+                    .await
+                    .map_err(|e| ctx.fail(Stage::Connect, e))?;
+                return Ok(());
+            }
+            Err(e) => {
+                access.status = Some(502);
+                let _ = refuse(&mut client_stream, protocol, 502, "Bad Gateway\n").await;
+                return Err(ctx.fail(Stage::Connect, e));
+            }
+        };
+        log::info!("Replaying {host_port} (request id {request_id})");
+        dump.event(|| "replaying".to_string());
+        establish(&mut client_stream, protocol, None, access)
+            .await
+            .map_err(|e| ctx.fail(Stage::Connect, e))?;
+        let faults = injector(state, &ctx);
+        let taps = Taps {
+            netlog,
+            pcap: None,
+            metrics: &state.metrics,
+            dump,
+            response_head: None,
+            websocket: None,
+            status: None,
+            interceptors: interceptors.as_ref(),
+            faults: faults.as_ref(),
+            forwarded: protocol == Protocol::Forward,
+        };
+        let recording = match state.config.record {
+            true => Recording::Memory,
+            false => Recording::Counting,
+        };
+        let limits = state.runtime().limits;
+        let stats = forward_streams(
+            client_stream,
+            replayed,
+            &first,
+            &ctx,
+            &taps,
+            recording,
+            limits,
+        )
+        .await?;
+        access.bytes_from_client = stats.bytes_up;
+        access.bytes_to_client = stats.bytes_down;
+        return Ok(());
+    }
+    let connect_start = Instant::now();
+    // A unix socket target is local, and never reached through the parent.
+    let upstream =
+        (state.config.upstream.as_ref()).filter(|_| state.config.unix_target(host).is_none());
+    let connected = match upstream {
+        Some(upstream) => connect_target(state, &upstream.host, upstream.port, &ctx, netlog).await,
+        None => connect_target(state, host, port, &ctx, netlog).await,
+    };
+    let mut target_stream = match connected {
+        Ok(stream) => stream,
+        Err(e) => {
+            dump.event(|| format!("connect: {e}"));
+            let error = connection_error::proxy_error_of(&e);
+            access.status = Some(error.map_or(502, ProxyError::status));
+            let _ = match (protocol, error) {
+                (Protocol::Socks5, _) => {
+                    let reply = socks5::Reply::for_error(&e);
+                    socks5::reply(&mut client_stream, reply, None).await
+                }
+                (_, Some(error)) => refuse_with(&mut client_stream, protocol, error).await,
+                (_, None) => send_error(&mut client_stream, 502, "Bad Gateway\n").await,
+            };
+            return Err(e);
+        }
+    };
+    let effective = policy::join_authority(host, port);
+    if let Some(upstream) = upstream {
+        let answer = upstream.connect(&mut target_stream, &effective);
+        let failure = match tokio::time::timeout(state.config.connect_timeout, answer).await {
+            Ok(Ok(Ok(()))) => None,
+            Ok(Ok(Err(status_line))) => {
+                log::info!("Upstream proxy refused {effective}: {status_line}");
+                dump.event(|| format!("upstream: {status_line}"));
+                access.status = Some(502);
+                let body = format!("Bad Gateway: upstream proxy answered {status_line}\n");
+                refuse(&mut client_stream, protocol, 502, &body)
+                    .await
+                    .map_err(|e| ctx.fail(Stage::Upstream, e))?;
+                return Ok(());
+            }
+            Ok(Err(e)) => Some((502, "Bad Gateway: upstream proxy failed\n", e)),
+            Err(_) => {
+                let e = format!(
+                    "no answer from the upstream proxy within {:?}",
+                    state.config.connect_timeout
+                );
+                let e = io::Error::new(ErrorKind::TimedOut, e);
+                Some((504, "Gateway Timeout\n", e))
+            }
+        };
+        if let Some((status, body, e)) = failure {
+            dump.event(|| format!("upstream: {e}"));
+            access.status = Some(status);
+            let _ = refuse(&mut client_stream, protocol, status.into(), body).await;
+            return Err(ctx.fail(Stage::Upstream, e));
+        }
+    }
+    let connect = connect_start.elapsed();
+    ctx.connect = Some(connect);
+    state.metrics.time(metrics::Timer::Connect, connect);
+    dump.event(|| match target_stream.peer_addr() {
+        Ok(peer) => format!("connected: {effective} ({peer}) in {connect:?}"),
+        Err(_) => format!("connected: {effective} in {connect:?}"),
+    });
+    log::info!(
+        "Connected to target: {}{} (requested {}, request id {}), {}",
+        effective,
+        upstream.map_or(String::new(), |upstream| format!(
+            " via {}:{}",
+            upstream.host, upstream.port
+        )),
+        host_port,
+        request_id,
+        match protocol {
+            Protocol::Connect(_) => "sending 200 OK",
+            Protocol::Forward => "forwarding the request",
+            Protocol::Socks5 => "sending the SOCKS5 reply",
+        }
+    );
+
+    let recording = match &state.config.record_dir {
+        Some(dir) => match capture::create(dir, request.host, request.port).await {
+            Ok(files) => Recording::Files(Box::new(files)),
+            Err(e) => {
+                access.status = Some(500);
+                let _ = refuse(&mut client_stream, protocol, 500, "Internal Server Error").await;
+                return Err(ctx.fail(Stage::Record, e));
+            }
+        },
+        // A tap has nothing to read from a recorder that only counts.
+        None if state.config.record || state.config.tap_addr.is_some() => Recording::Memory,
+        None => Recording::Counting,
+    };
+
+    access.peer = target_stream.peer_addr().ok().map(|addr| addr.ip());
+    access.upstream = upstream.is_some();
+    establish(
+        &mut client_stream,
+        protocol,
+        target_stream.local_addr().ok(),
+        access,
+    )
+    .await
+    .map_err(|e| ctx.fail(Stage::Connect, e))?;
+    netlog.event(
+        netlog::EventType::TunnelEstablished,
+        netlog::Phase::None,
+        &[
+            ("target", json::quote(host_port)),
+            ("effective_target", json::quote(&effective)),
+        ],
+    );
+    let pcap = match (&state.pcap, target_stream.peer_addr()) {
+        (Some(pcap), Ok(target_addr)) if pcap.wants(request.host) => {
+            Some(pcap.flow(client_addr, target_addr))
+        }
+        _ => None,
+    };
+    dump.event(|| "tunnel established".to_string());
+    let faults = injector(state, &ctx);
+    let response_head =
+        (har.is_some() && protocol == Protocol::Forward).then(har::HeadCapture::default);
+    let websocket = state
+        .config
+        .decode_websocket
+        .then(websocket::Decoder::default);
+    let registration = state.status.as_ref().map(|registry| {
+        // Listed under the number its log lines carry, or a fresh one when
+        // served outside an accepted connection's scope.
+        let id = log::Connection::current()
+            .map_or_else(|| log::Connection::next(client_addr).id, |c| c.id);
+        registry.register(id, client_addr, host_port)
+    });
+    let status = registration
+        .as_ref()
+        .map(|registration| &*registration.tunnel);
+    let taps = Taps {
+        netlog,
+        pcap: pcap.as_ref(),
+        metrics: &state.metrics,
+        dump,
+        response_head: response_head.as_ref(),
+        websocket: websocket.as_ref(),
+        status,
+        interceptors: interceptors.as_ref(),
+        faults: faults.as_ref(),
+        forwarded: protocol == Protocol::Forward,
+    };
+    // A close from the admin endpoint ends the tunnel as its peers would.
+    let stats = forward_streams(
+        client_stream,
+        framing::OriginSide::new(target_stream, exchange),
+        &first,
+        &ctx,
+        &taps,
+        recording,
+        state.runtime().limits,
+    )
+    .await?;
+    access.bytes_from_client = stats.bytes_up;
+    access.bytes_to_client = stats.bytes_down;
+    let timings = latency::Timings {
+        connect,
+        ttfb: stats.ttfb,
+        duration: connect_start.elapsed(),
+    };
+    if let (Some(log), Some(request)) = (&state.har, &har) {
+        log.add(&har::Exchange {
+            request,
+            response: response_head.as_ref().and_then(har::HeadCapture::response),
+            server: access.peer,
+            blocked: connect_start.saturating_duration_since(request.start),
+            connect,
+            wait: timings.ttfb,
+            duration: timings.duration,
+            bytes_up: stats.bytes_up,
+            bytes_down: stats.bytes_down,
+        });
+    }
+    state
+        .metrics
+        .time(metrics::Timer::Connection, timings.duration);
+    if let Some(ttfb) = timings.ttfb {
+        state.metrics.time(metrics::Timer::FirstByte, ttfb);
+    }
+    if let Some(report) = state.slow.observe(Instant::now(), &timings) {
+        log::warn!(
+            "slow_connection: client={} target={} connect={:?} (slow={}, threshold={:?}) \
+             ttfb={:?} (slow={}, threshold={:?}) duration={:?}",
+            client_addr,
+            host_port,
+            timings.connect,
+            report.slow_connect,
+            report.connect_threshold,
+            timings.ttfb,
+            report.slow_ttfb,
+            report.ttfb_threshold,
+            timings.duration
+        );
+    }
+    Ok(())
+}
+
+// Tells the client its tunnel is up; `bound` is the address a SOCKS5 client
+// is told the tunnel leaves from. A forwarded request is answered by the
+// target instead.
+async fn establish<S: AsyncWrite + Unpin>(
+    client_stream: &mut S,
+    protocol: Protocol,
+    bound: Option<SocketAddr>,
+    access: &mut access_log::Entry,
+) -> io::Result<()> {
+    match protocol {
+        Protocol::Connect(version) => {
+            access.status = Some(200);
+            let response = format!("{version} 200 Connection Established\r\n\r\n");
+            client_stream.write_all(response.as_bytes()).await
+        }
+        Protocol::Forward => {
+            access.forwarded = true;
+            Ok(())
+        }
+        Protocol::Socks5 => {
+            access.status = Some(200);
+            socks5::reply(client_stream, socks5::Reply::Succeeded, bound).await
+        }
+    }
+}
+
+// A SOCKS5 client, from its greeting to its destination.
+async fn serve_socks5<S>(
+    mut client_stream: S,
+    state: &ProxyState,
+    access: &mut access_log::Entry,
+    dump: &debug_dump::Dump,
+    mut ctx: ConnectionContext,
+    netlog: &netlog::Source<'_>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // The handshake and the request are held to the --header-timeout, as an
+    // HTTP request's head is. SOCKS5 has no reply for it, so the client is
+    // not answered.
+    let deadline = head_deadline(state, &ctx);
+    // Without SOCKS5 username/password support, --auth leaves SOCKS5 clients
+    // no method to pick.
+    let negotiate = socks5::negotiate(&mut client_stream, state.runtime().auth.is_none());
+    let accepted = match tokio::time::timeout_at(deadline, negotiate).await {
+        Ok(accepted) => accepted.map_err(|e| ctx.fail(Stage::HeaderRead, e))?,
+        Err(_) => return Err(header_timed_out(state, Some(access), &ctx)),
+    };
+    if !accepted {
+        dump.event(|| "socks5: no acceptable method".to_string());
+        access.status = Some(407);
+        return Ok(());
+    }
+    let read = socks5::read_request(&mut client_stream);
+    let request = match tokio::time::timeout_at(deadline, read).await {
+        Ok(request) => request.map_err(|e| ctx.fail(Stage::HeaderRead, e))?,
+        Err(_) => return Err(header_timed_out(state, Some(access), &ctx)),
+    };
+    let host_port = match request {
+        socks5::Request::Connect(host_port) => host_port,
+        socks5::Request::Refused(reply) => {
+            dump.event(|| format!("socks5: refused: {reply:?}"));
+            socks5::reply(&mut client_stream, reply, None)
+                .await
+                .map_err(|e| ctx.fail(Stage::HeaderRead, e))?;
+            return Ok(());
+        }
+    };
+    let request_read = ctx.started.elapsed();
+    ctx.request_read = Some(request_read);
+    state
+        .metrics
+        .time(metrics::Timer::RequestRead, request_read);
+    dump.event(|| format!("socks5 connect: {host_port}"));
+    log::debug!("SOCKS5 CONNECT {host_port}");
+    access.method = Some("CONNECT".to_string());
+    access.uri = Some(host_port.clone());
+    ctx.target = Some(host_port.clone());
+    netlog.event(
+        netlog::EventType::ConnectRequest,
+        netlog::Phase::None,
+        &[("target", json::quote(&host_port))],
+    );
+    let request_id = request_id::resolve(None, state.config.trust_request_id);
+    let destination = Destination {
+        host_port: &host_port,
+        user: None,
+        request_id: &request_id,
+        protocol: Protocol::Socks5,
+        first: vec![],
+        har: None,
+        exchange: None,
+    };
+    open_tunnel(client_stream, state, access, dump, ctx, netlog, destination).await
+}
+
+async fn serve_client<S>(
+    mut client_stream: S,
+    client_addr: SocketAddr,
+    started: Instant,
+    state: &ProxyState,
+    access: &mut access_log::Entry,
+    dump: &debug_dump::Dump,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut ctx = ConnectionContext {
+        started,
+        ..ConnectionContext::new(client_addr)
+    };
+    let netlog = match &state.netlog {
+        Some(log) => log.source(&[("source_address", json::quote(&client_addr.to_string()))]),
+        None => netlog::Source::disabled(),
+    };
+    let mut reader = HttpReader::new(state.config.read_buffer_size);
+    let peek = reader.peek(&mut client_stream);
+    let first = match tokio::time::timeout_at(head_deadline(state, &ctx), peek).await {
+        Ok(first) => first.map_err(|e| ctx.fail(Stage::HeaderRead, e))?,
+        Err(_) => return Err(time_out_head(&mut client_stream, state, access, &ctx).await),
+    };
+    if first == Some(socks5::VERSION) {
+        let client_stream = Rewound::new(reader.take_buffered(), client_stream);
+        return serve_socks5(client_stream, state, access, dump, ctx, &netlog).await;
+    }
+    let mut draining = state.draining();
+    let mut request = 0;
+    loop {
+        let kept = serve_request(
+            &mut client_stream,
+            &mut reader,
+            state,
+            access,
+            dump,
+            ctx,
+            &netlog,
+        )
+        .await?;
+        if !kept {
+            return Ok(());
+        }
+        // A proxy shutting down takes no more requests on kept connections.
+        let next = tokio::time::timeout(
+            state.config.keep_alive_timeout,
+            reader.peek(&mut client_stream),
+        );
+        tokio::select! {
+            biased;
+            _ = draining.wait_for(|&draining| draining) => {
+                dump.event(|| "closed for shutdown between requests".to_string());
+                return Ok(());
+            }
+            next = next => {
+                if !matches!(next, Ok(Ok(Some(_)))) {
+                    return Ok(());
+                }
+            }
+        }
+        dump.event(|| "next request".to_string());
+        record_access(state, access, &Ok(()));
+        *access = access_log::Entry::new(client_addr.ip());
+        request += 1;
+        ctx = ConnectionContext {
+            request,
+            ..ConnectionContext::new(client_addr)
+        };
+    }
+}
+
+// The faults for the tunnel of the request `ctx` is for, drawn once it is
+// established, so that a request refused before then draws none.
+fn injector(state: &ProxyState, ctx: &ConnectionContext) -> Option<fault::Injector> {
+    let connection = log::Connection::current().map_or(0, |c| c.id);
+    (state.faults.as_ref()).map(|faults| faults.injector(connection, ctx.request))
+}
+
+// When the request `ctx` is for must have been read by, with its headers.
+fn head_deadline(state: &ProxyState, ctx: &ConnectionContext) -> tokio::time::Instant {
+    tokio::time::Instant::from_std(ctx.started + state.config.header_timeout)
+}
+
+// Answers a client still sending its request head at the `--header-timeout`.
+async fn time_out_head<S: AsyncWrite + Unpin>(
+    client_stream: &mut S,
+    state: &ProxyState,
+    access: &mut access_log::Entry,
+    ctx: &ConnectionContext,
+) -> io::Error {
+    let error = ProxyError::HeaderTimeout(state.config.header_timeout);
+    let _ = refuse_with(client_stream, Protocol::Forward, &error).await;
+    header_timed_out(state, Some(access), ctx)
+}
+
+// The `--header-timeout` error for a client that is not answered, or has
+// been already.
+fn header_timed_out(
+    state: &ProxyState,
+    access: Option<&mut access_log::Entry>,
+    ctx: &ConnectionContext,
+) -> io::Error {
+    let error = ProxyError::HeaderTimeout(state.config.header_timeout);
+    log::info!("Closing {}: close_reason=header_timeout", ctx.client);
+    if let Some(access) = access {
+        access.status = Some(error.status());
+    }
+    ctx.fail(Stage::HeaderRead, error.into())
+}
+
+// One HTTP request, up to the end of its tunnel or of its response. True
+// when the client's connection is kept for another request.
+async fn serve_request<S>(
+    client_stream: &mut S,
+    reader: &mut HttpReader,
+    state: &ProxyState,
+    access: &mut access_log::Entry,
+    dump: &debug_dump::Dump,
+    mut ctx: ConnectionContext,
+    netlog: &netlog::Source<'_>,
+) -> io::Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let client_addr = ctx.client;
+    let read = reader.read_head(client_stream, state.config.head_limits);
+    let head = match tokio::time::timeout_at(head_deadline(state, &ctx), read).await {
+        Ok(Ok(head)) => head,
+        Ok(Err(e)) => {
+            let error = connection_error::proxy_error_of(&e);
+            access.status = Some(error.map_or(400, ProxyError::status));
+            let _ = match error {
+                Some(error) => refuse_with(client_stream, Protocol::Forward, error).await,
+                None => send_error(client_stream, 400, "Bad Request").await,
+            };
+            return Err(ctx.fail(Stage::HeaderRead, e));
+        }
+        Err(_) => return Err(time_out_head(client_stream, state, access, &ctx).await),
+    };
+    let connect_line = head.request_line;
+    let (started, start) = (SystemTime::now(), Instant::now());
+    let request_read = ctx.started.elapsed();
+    ctx.request_read = Some(request_read);
+    state
+        .metrics
+        .time(metrics::Timer::RequestRead, request_read);
+    dump.event(|| format!("request line: {}", request_line::escape(&connect_line)));
+    log::debug!("Request line: {}", request_line::escape(&connect_line));
+    let request_line = match request_line::parse(&connect_line, state.config.lenient_request_line) {
+        Ok(request_line) => request_line,
+        Err(malformed) => {
+            dump.event(|| format!("malformed request line: {malformed}"));
+            log::warn!(
+                "Malformed request line from {}: {}: {}",
+                client_addr,
+                malformed,
+                request_line::escape(&connect_line)
+            );
+            let error = ProxyError::MalformedRequest {
+                line: request_line::escape(&connect_line),
+                reason: malformed.to_string(),
+            };
+            access.status = Some(error.status());
+            refuse_with(client_stream, Protocol::Forward, &error)
+                .await
+                .map_err(|e| ctx.fail(Stage::HeaderRead, e))?;
+            return Ok(false);
+        }
+    };
+    access.method = Some(request_line.method.to_string());
+    access.uri = Some(request_line.target.to_string());
+    access.version = Some(request_line.version.to_string());
+    let plain = match request_line.method {
+        "CONNECT" => None,
+        _ => http_forward::parse_target(request_line.target),
+    };
+
+    if request_line.method == "CONNECT" || plain.is_some() {
+        if !matches!(request_line.version, "HTTP/1.0" | "HTTP/1.1") {
+            access.status = Some(505);
+            send_error(client_stream, 505, "HTTP Version Not Supported\n")
+                .await
+                .map_err(|e| ctx.fail(Stage::HeaderRead, e))?;
+            return Ok(false);
+        }
+        ctx.target = Some(request_line.target.to_string());
+        netlog.event(
+            netlog::EventType::ConnectRequest,
+            netlog::Phase::None,
+            &[("target", json::quote(request_line.target))],
+        );
+        let mut client_request_id = None;
+        let mut proxy_authorization = None;
+        let mut headers = vec![];
+        for line in head.headers {
+            dump.event(|| format!("header: {}", request_line::escape(&line)));
+            if let Some((name, value)) = line.split_once(':')
+                && name.trim().eq_ignore_ascii_case(request_id::HEADER)
+            {
+                client_request_id = Some(value.trim().to_string());
+            }
+            if let Some((name, value)) = line.split_once(':')
+                && name.trim().eq_ignore_ascii_case(proxy_auth::HEADER)
+            {
+                proxy_authorization = Some(value.trim().to_string());
+            }
+            if plain.is_some() || state.har.is_some() {
+                headers.push(line);
+            }
+        }
+        let runtime = state.runtime();
+        let user = match &runtime.auth {
+            Some(credentials) => match credentials.check(proxy_authorization.as_deref()) {
+                Some(user) => Some(user),
+                None => {
+                    dump.event(|| "proxy authentication failed".to_string());
+                    access.status = Some(407);
+                    send_error_with(
+                        client_stream,
+                        407,
+                        &[proxy_auth::CHALLENGE],
+                        "Proxy Authentication Required\n",
+                    )
+                    .await
+                    .map_err(|e| ctx.fail(Stage::HeaderRead, e))?;
+                    return Ok(false);
+                }
+            },
+            None => None,
+        };
+        access.user = user.map(str::to_string);
+        // CONNECT headers are consumed here and never forwarded, so the
+        // client's X-Request-Id cannot reach the target. A forwarded request
+        // carries the resolved id instead.
+        let request_id =
+            request_id::resolve(client_request_id.as_deref(), state.config.trust_request_id);
+        let (mut exchange, mut body) = (None, framing::RequestBody::Length(0));
+        let head = match &plain {
+            Some(target) => {
+                let fields = Headers::parse(&headers).and_then(|fields| {
+                    if !fields.upgrade() {
+                        body = framing::request_body(&fields)?;
+                        let (method, version) = (request_line.method, request_line.version);
+                        exchange = Some(framing::Exchange::new(method, version, &fields));
+                    }
+                    Ok(fields)
+                });
+                let fields = match fields {
+                    Ok(fields) => fields,
+                    Err(reason) => {
+                        access.status = Some(400);
+                        send_error(client_stream, 400, &format!("Bad Request: {reason}\n"))
+                            .await
+                            .map_err(|e| ctx.fail(Stage::HeaderRead, e))?;
+                        return Ok(false);
+                    }
+                };
+                let connection_id = log::Connection::current().map(|c| c.id);
+                let set: Vec<(String, String)> = state
+                    .config
+                    .set_headers
+                    .iter()
+                    .map(|set| (set.name.clone(), set.value(client_addr.ip(), connection_id)))
+                    .collect();
+                http_forward::request_head(
+                    request_line.method,
+                    target,
+                    request_line.version,
+                    fields,
+                    &request_id,
+                    &set,
+                )
+            }
+            None => String::new(),
+        };
+
+        let host_port = plain
+            .as_ref()
+            .map_or(request_line.target, |target| target.host_port.as_str());
+        let mut first = head.into_bytes();
+        let har = state.har.as_ref().map(|_| har::Request {
+            started,
+            start,
+            method: request_line.method.to_string(),
+            url: request_line.target.to_string(),
+            http_version: request_line.version.to_string(),
+            headers,
+            forwarded_head: first.len(),
+        });
+        // The rest of a forwarded body is read as the tunnel goes, and what
+        // comes after it is the client's next request. A chunked body is all
+        // read as the tunnel goes, out of `reader` while it holds any.
+        let body_left = match (&exchange, body) {
+            (Some(_), framing::RequestBody::Length(length)) => {
+                let body = reader.take_up_to(length);
+                let left = length - body.len() as u64;
+                first.extend(body);
+                Some(left)
+            }
+            (Some(_), framing::RequestBody::Chunked) => None,
+            (None, _) => {
+                first.extend(reader.take_buffered());
+                Some(0)
+            }
+        };
+        let destination = Destination {
+            host_port,
+            user,
+            request_id: &request_id,
+            protocol: match plain {
+                Some(_) => Protocol::Forward,
+                None if request_line.version == "HTTP/1.0" => Protocol::Connect("HTTP/1.0"),
+                None => Protocol::Connect("HTTP/1.1"),
+            },
+            first,
+            har,
+            exchange: exchange.as_ref(),
+        };
+        let client_side = match body_left {
+            Some(left) => framing::ClientSide::new(client_stream, exchange.as_ref(), left),
+            None => framing::ClientSide::chunked(client_stream, exchange.as_ref(), reader),
+        };
+        open_tunnel(client_side, state, access, dump, ctx, netlog, destination).await?;
+        Ok(exchange.is_some_and(|exchange| exchange.reusable()))
+    } else {
+        let error = ProxyError::UnsupportedMethod(request_line.method.to_string());
+        access.status = Some(error.status());
+        refuse_with(client_stream, Protocol::Forward, &error)
+            .await
+            .map_err(|e| ctx.fail(Stage::HeaderRead, e))?;
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config, connection_error, headers, host_filter, rules_watch, statsd, test_policy};
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+    async fn serve_one_with(
+        config: config::Config,
+    ) -> (SocketAddr, tokio::task::JoinHandle<io::Result<()>>) {
+        serve_one_with_state(Arc::new(ProxyState::new(config).unwrap())).await
+    }
+
+    async fn serve_one_with_state(
+        state: Arc<ProxyState>,
+    ) -> (SocketAddr, tokio::task::JoinHandle<io::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (socket, peer) = listener.accept().await.unwrap();
+            handle_client(socket, peer, state).await
+        });
+        (addr, handle)
+    }
+
+    async fn serve_one() -> (SocketAddr, tokio::task::JoinHandle<io::Result<()>>) {
+        serve_one_with(config::Config::default()).await
+    }
+
+    #[tokio::test]
+    async fn test_malformed_request_line_gets_diagnostic() {
+        let (proxy_addr, handle) = serve_one().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(b"CONNECT  \x1b[2J:443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 "));
+        assert!(
+            response.ends_with("\r\n\r\nBad Request: multiple spaces between method and target\n")
+        );
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oversized_head_gets_431() {
+        let (proxy_addr, handle) = serve_one().await;
+        let client = TcpStream::connect(proxy_addr).await.unwrap();
+        let (mut reading, mut writing) = client.into_split();
+        // The proxy stops reading at its limit, so this may never finish.
+        tokio::spawn(async move {
+            let header = format!("X-Padding: {}\r\n", "a".repeat(1000));
+            let mut head = b"CONNECT example.com:443 HTTP/1.1\r\n".to_vec();
+            while head.len() < 1 << 20 {
+                head.extend_from_slice(header.as_bytes());
+            }
+            let _ = writing.write_all(&head).await;
+        });
+        let mut response = vec![];
+        let mut buf = [0; 1024];
+        // Until the proxy closes, or resets for the headers it left unread.
+        while let Ok(n @ 1..) = reading.read(&mut buf).await {
+            response.extend_from_slice(&buf[..n]);
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+            "{response}"
+        );
+        let error = handle.await.unwrap().unwrap_err();
+        assert_eq!(connection_error::stage_of(&error), Some(Stage::HeaderRead));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_head_gets_408() {
+        let config = config::Config {
+            header_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let start = Instant::now();
+        client
+            .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: exa")
+            .await
+            .unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(
+            String::from_utf8(response)
+                .unwrap()
+                .starts_with("HTTP/1.1 408 Request Timeout\r\n")
+        );
+        let error = handle.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_proxy_header_times_out() {
+        let config = config::Config {
+            header_timeout: Duration::from_millis(200),
+            accept_proxy_protocol: true,
+            ..Default::default()
+        };
+        let state = Arc::new(ProxyState::new(config).unwrap());
+        // The deadline is not put off by a trickle of bytes, nor set again
+        // for the head after the PROXY header.
+        for sent in [
+            &b"PROXY TCP4 203.0.113.9 "[..],
+            b"PROXY UNKNOWN\r\nCONNECT ex",
+        ] {
+            let (proxy_addr, handle) = serve_one_with_state(state.clone()).await;
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            let start = Instant::now();
+            for piece in sent.chunks(8) {
+                client.write_all(piece).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let mut response = vec![];
+            let _ = client.read_to_end(&mut response).await;
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+            assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+            let error = handle.await.unwrap().unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::TimedOut);
+            assert_eq!(connection_error::stage_of(&error), Some(Stage::HeaderRead));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stalled_socks5_handshake_times_out() {
+        let config = config::Config {
+            header_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let state = Arc::new(ProxyState::new(config).unwrap());
+        // Stalled in the method negotiation, and in the request after it.
+        for (sent, answer) in [
+            (&b"\x05\x02\x00"[..], &b""[..]),
+            (b"\x05\x01\x00\x05\x01\x00\x03\x0bexam", b"\x05\x00"),
+        ] {
+            let (proxy_addr, handle) = serve_one_with_state(state.clone()).await;
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            let start = Instant::now();
+            client.write_all(sent).await.unwrap();
+            let mut response = vec![];
+            client.read_to_end(&mut response).await.unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(200));
+            assert_eq!(response, answer);
+            let error = handle.await.unwrap().unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::TimedOut);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_over_a_stream_other_than_tcp() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let state = Arc::new(ProxyState::new(config::Config::default()).unwrap());
+        let (mut client, server) = io::duplex(1024);
+        let peer = "192.0.2.1:5000".parse().unwrap();
+        let handle = tokio::spawn(handle_client(server, peer, state));
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let (mut upstream, _) = target.accept().await.unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+        client.write_all(b"ping").await.unwrap();
+        let mut ping = [0; 4];
+        upstream.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        upstream.write_all(b"pong").await.unwrap();
+        drop(upstream);
+        let mut pong = vec![];
+        client.read_to_end(&mut pong).await.unwrap();
+        assert_eq!(pong, b"pong");
+        drop(client);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_answers_in_the_client_version() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let (proxy_addr, handle) = serve_one().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.0\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let (upstream, _) = target.accept().await.unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.0 200 Connection Established\r\n\r\n");
+        drop(upstream);
+        drop(client);
+        handle.await.unwrap().unwrap();
+
+        let (proxy_addr, handle) = serve_one().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/2.0\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"));
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_policy_deny_never_dials_target() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let mut config = config::Config::default();
+        config
+            .rules
+            .push(config::RuleInput::Inline(policy::RuleSource {
+                origin: "test".to_string(),
+                text: format!("port={} => deny(403)", target_addr.port()),
+            }));
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 403 "));
+        handle.await.unwrap().unwrap();
+        let accepted = tokio::time::timeout(Duration::from_millis(50), target.accept()).await;
+        assert!(accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_conveys_the_client() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let mut config = config::Config {
+            accept_proxy_protocol: true,
+            ..Default::default()
+        };
+        config
+            .rules
+            .push(config::RuleInput::Inline(policy::RuleSource {
+                origin: "test".to_string(),
+                text: "client=203.0.113.0/24 => deny(403)".to_string(),
+            }));
+        let state = Arc::new(ProxyState::new(config).unwrap());
+        let connect = format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n");
+
+        // Denied as the client the header names, though connecting from
+        // loopback.
+        let (proxy_addr, handle) = serve_one_with_state(state.clone()).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let header = "PROXY TCP4 203.0.113.9 127.0.0.1 40000 8080\r\n";
+        client
+            .write_all(format!("{header}{connect}").as_bytes())
+            .await
+            .unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 403 "));
+        handle.await.unwrap().unwrap();
+
+        // A v2 LOCAL header leaves the socket's address, which is allowed.
+        let (proxy_addr, handle) = serve_one_with_state(state.clone()).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut local = proxy_protocol::V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0x00, 0x00]);
+        client.write_all(&local).await.unwrap();
+        client.write_all(connect.as_bytes()).await.unwrap();
+        let (upstream, _) = target.accept().await.unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200"));
+        drop(upstream);
+        drop(client);
+        handle.await.unwrap().unwrap();
+
+        // Without a header the connection is closed unanswered.
+        let (proxy_addr, handle) = serve_one_with_state(state).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(connect.as_bytes()).await.unwrap();
+        let mut response = vec![];
+        // The unread request may turn the close into a reset.
+        let _ = client.read_to_end(&mut response).await;
+        assert!(response.is_empty());
+        let error = handle.await.unwrap().unwrap_err();
+        assert_eq!(connection_error::stage_of(&error), Some(Stage::HeaderRead));
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_authentication() {
+        // alice:secret, alice:wrong
+        let cases = [
+            (Some("Basic YWxpY2U6c2VjcmV0"), true),
+            (Some("Basic YWxpY2U6d3Jvbmc="), false),
+            (Some("Basic %%garbage%%"), false),
+            (None, false),
+        ];
+        for (authorization, accepted) in cases {
+            let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let target_addr = target.local_addr().unwrap();
+            let config = config::Config {
+                auth: proxy_auth::Credentials::parse("alice:secret"),
+                ..Default::default()
+            };
+            let (proxy_addr, handle) = serve_one_with(config).await;
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            let header = authorization
+                .map(|value| format!("Proxy-Authorization: {value}\r\n"))
+                .unwrap_or_default();
+            client
+                .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n{header}\r\n").as_bytes())
+                .await
+                .unwrap();
+            if accepted {
+                let (mut upstream, _) = target.accept().await.unwrap();
+                let mut response = [0; 39];
+                client.read_exact(&mut response).await.unwrap();
+                assert!(response.starts_with(b"HTTP/1.1 200 "));
+                upstream.write_all(b"pong").await.unwrap();
+                let mut pong = [0; 4];
+                client.read_exact(&mut pong).await.unwrap();
+                drop(upstream);
+                drop(client);
+            } else {
+                let mut response = String::new();
+                client.read_to_string(&mut response).await.unwrap();
+                assert!(
+                    response.starts_with(
+                        "HTTP/1.1 407 Proxy Authentication Required\r\n\
+                         Proxy-Authenticate: Basic realm=\"proxy\"\r\n\
+                         Content-Length: 30\r\nConnection: close\r\n\r\n"
+                    ),
+                    "{authorization:?}: {response}"
+                );
+                let dialed = tokio::time::timeout(Duration::from_millis(50), target.accept()).await;
+                assert!(dialed.is_err());
+            }
+            handle.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_host_filter_denies_before_dialing() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let mut config = config::Config::default();
+        host_filter::HostFilter::extend(&mut config.host_filter.deny, "127.0.0.0/8").unwrap();
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+        handle.await.unwrap().unwrap();
+        let accepted = tokio::time::timeout(Duration::from_millis(50), target.accept()).await;
+        assert!(accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_policy_dry_run_lets_denied_traffic_through() {
+        for dry_run in [false, true] {
+            let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let target_addr = target.local_addr().unwrap();
+            let mut config = config::Config::default();
+            config
+                .rules
+                .push(config::RuleInput::Inline(policy::RuleSource {
+                    origin: "test".to_string(),
+                    text: format!("block: port={} => deny(403)", target_addr.port()),
+                }));
+            config.policy_dry_run = dry_run;
+            let state = Arc::new(ProxyState::new(config).unwrap());
+            let (proxy_addr, handle) = serve_one_with_state(state.clone()).await;
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client
+                .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            if dry_run {
+                let (mut upstream, _) = target.accept().await.unwrap();
+                let mut response = [0; 39];
+                client.read_exact(&mut response).await.unwrap();
+                assert!(response.starts_with(b"HTTP/1.1 200 "));
+                client.write_all(b"ping").await.unwrap();
+                let mut ping = [0; 4];
+                upstream.read_exact(&mut ping).await.unwrap();
+                assert_eq!(&ping, b"ping");
+                drop(upstream);
+                drop(client);
+            } else {
+                let mut response = vec![];
+                client.read_to_end(&mut response).await.unwrap();
+                assert!(response.starts_with(b"HTTP/1.1 403 "));
+            }
+            handle.await.unwrap().unwrap();
+            let stats = &state.policy().rule_stats()[0];
+            let expected = if dry_run { (0, 1) } else { (1, 0) };
+            assert_eq!((stats.denials, stats.would_deny), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_test_policy_agrees_with_live_engine() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let rules = [
+            format!("host=localhost port={port} => deny(451)"),
+            format!("host=127.0.0.1 port={port} => allow"),
+            "=> deny".to_string(),
+        ];
+        let mut config = config::Config::default();
+        for (i, text) in rules.iter().enumerate() {
+            config
+                .rules
+                .push(config::RuleInput::Inline(policy::RuleSource {
+                    origin: format!("--rule #{}", i + 1),
+                    text: text.clone(),
+                }));
+        }
+        let state = Arc::new(ProxyState::new(config).unwrap());
+        let options = test_policy::Options {
+            client: "127.0.0.1".parse().unwrap(),
+            target: None,
+            user: None,
+            json: false,
+        };
+        for authority in [
+            format!("localhost:{port}"),
+            format!("127.0.0.1:{port}"),
+            format!("127.0.0.1:{}", port.wrapping_add(1)),
+        ] {
+            let verdict = test_policy::check(&state.policy(), &options, &authority)
+                .await
+                .unwrap();
+            let (proxy_addr, handle) = serve_one_with_state(state.clone()).await;
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client
+                .write_all(format!("CONNECT {authority} HTTP/1.1\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            let mut status = [0; 12];
+            client.read_exact(&mut status).await.unwrap();
+            let expected = match verdict.decision.access {
+                policy::Access::Allow => "HTTP/1.1 200".to_string(),
+                policy::Access::Deny(code) => format!("HTTP/1.1 {code}"),
+            };
+            assert_eq!(
+                std::str::from_utf8(&status).unwrap(),
+                expected,
+                "{authority}"
+            );
+            drop(client);
+            if verdict.decision.access == policy::Access::Allow {
+                drop(target.accept().await.unwrap());
+            }
+            let _ = handle.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_deny_needs_an_allow_rule() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let connect = |config: config::Config| async move {
+            let (proxy_addr, handle) = serve_one_with(config).await;
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client
+                .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            let mut status = [0; 12];
+            client.read_exact(&mut status).await.unwrap();
+            let mut rest = vec![];
+            if status.starts_with(b"HTTP/1.1 403") {
+                client.read_to_end(&mut rest).await.unwrap();
+            }
+            drop(client);
+            (status, rest, handle)
+        };
+
+        let (status, _, handle) = connect(config::Config::default()).await;
+        assert_eq!(&status, b"HTTP/1.1 200");
+        drop(target.accept().await.unwrap());
+        let _ = handle.await.unwrap();
+
+        let config = config::Config {
+            policy_default_deny: true,
+            ..Default::default()
+        };
+        assert!(ProxyState::new(config).is_err());
+
+        let config = config::Config {
+            policy_default_deny: true,
+            force: true,
+            ..Default::default()
+        };
+        let (status, body, handle) = connect(config).await;
+        assert_eq!(&status, b"HTTP/1.1 403");
+        assert!(body.ends_with(b"No policy rule allows this destination"));
+        handle.await.unwrap().unwrap();
+
+        let config = config::Config {
+            policy_default_deny: true,
+            rules: vec![config::RuleInput::Inline(policy::RuleSource {
+                origin: "test".to_string(),
+                text: format!("port={} => allow", target_addr.port()),
+            })],
+            ..Default::default()
+        };
+        let (status, _, handle) = connect(config).await;
+        assert_eq!(&status, b"HTTP/1.1 200");
+        drop(target.accept().await.unwrap());
+        let _ = handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rules_file_change_applies_to_next_connection() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let path = std::env::temp_dir().join(format!("proxy-reload-{}.txt", std::process::id()));
+        std::fs::write(&path, format!("port={} => deny(451)\n", target_addr.port())).unwrap();
+        let config = config::Config {
+            rules: vec![config::RuleInput::File(path.clone())],
+            watch_rules: true,
+            ..Default::default()
+        };
+        let state = Arc::new(ProxyState::new(config).unwrap());
+        let watched = state.clone();
+        let watcher = tokio::spawn(rules_watch::watch(
+            vec![path.clone()],
+            Duration::from_millis(10),
+            Duration::from_millis(50),
+            move || {
+                let _ = watched.reload();
+            },
+        ));
+        let status = |state: Arc<ProxyState>| async move {
+            let (proxy_addr, handle) = serve_one_with_state(state).await;
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client
+                .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            let mut status = [0; 12];
+            client.read_exact(&mut status).await.unwrap();
+            (status, handle)
+        };
+        let (before, handle) = status(state.clone()).await;
+        assert_eq!(&before, b"HTTP/1.1 451");
+        handle.await.unwrap().unwrap();
+
+        // A broken edit is rejected and the old rules stay in place.
+        std::fs::write(&path, "port=nope => allow\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        std::fs::write(&path, "# allow everything\nhost=* => allow\n").unwrap();
+        let old = state.policy();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while Arc::ptr_eq(&old, &state.policy()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(state.policy().rule_count(), 1);
+        let (after, handle) = status(state.clone()).await;
+        assert_eq!(&after, b"HTTP/1.1 200");
+        drop(target.accept().await.unwrap());
+        let _ = handle.await.unwrap();
+        watcher.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_ipv6_literal() {
+        let target = TcpListener::bind("[::1]:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let (proxy_addr, handle) = serve_one().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let (mut upstream, _) = target.accept().await.unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 "));
+        client.write_all(b"ping").await.unwrap();
+        let mut ping = [0; 4];
+        upstream.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        drop(upstream);
+        drop(client);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_outbound_addr_is_the_source_of_target_connections() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let mut config = config::Config::default();
+        config.outbound.v4 = Some("127.0.0.2".parse().unwrap());
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let (upstream, peer) = target.accept().await.unwrap();
+        assert_eq!(peer.ip().to_string(), "127.0.0.2");
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 "));
+        drop(upstream);
+        drop(client);
+        handle.await.unwrap().unwrap();
+        // An address this host does not have.
+        let mut config = config::Config::default();
+        config.outbound.v4 = Some("192.0.2.10".parse().unwrap());
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+            "{response}"
+        );
+        let e = handle.await.unwrap().unwrap_err();
+        assert!(e.to_string().contains("binding to 192.0.2.10"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn test_malformed_authority_gets_bad_request() {
+        for target in ["]:80", "[::1", "[example.com]:443", "2606:4700::1:443"] {
+            let (proxy_addr, handle) = serve_one().await;
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client
+                .write_all(format!("CONNECT {target} HTTP/1.1\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(
+                response.starts_with("HTTP/1.1 400 "),
+                "{target}: {response}"
+            );
+            handle.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_connects_to_rewritten_target() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let config = config::Config {
+            rules: vec![config::RuleInput::Inline(policy::RuleSource {
+                origin: "test".to_string(),
+                text: format!("host=api.invalid => rewrite({target_addr})"),
+            })],
+            ..Default::default()
+        };
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        // api.invalid never resolves, so connecting at all means the rewrite
+        // was used.
+        client
+            .write_all(b"CONNECT api.invalid:443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let (mut upstream, _) = target.accept().await.unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 "));
+        client.write_all(b"ping").await.unwrap();
+        let mut ping = [0; 4];
+        upstream.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        drop(upstream);
+        drop(client);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_plain_http_request_is_forwarded() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let served = tokio::spawn(async move {
+            let (mut socket, _) = origin.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\nabc") {
+                let n = socket.read(&mut buf).await.unwrap();
+                assert!(n > 0, "{:?}", String::from_utf8_lossy(&request));
+                request.extend_from_slice(&buf[..n]);
+            }
+            socket
+                .write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 5\r\n\r\nhello")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        let (proxy_addr, handle) = serve_one().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(
+                format!(
+                    "POST http://{origin_addr}/upload?x=1 HTTP/1.1\r\nHost: {origin_addr}\r\n\
+                     Proxy-Connection: Keep-Alive\r\nContent-Length: 3\r\n\r\nabc"
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        // The connection is kept for another request.
+        let expected = b"HTTP/1.1 201 Created\r\nContent-Length: 5\r\n\r\nhello";
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, expected);
+        let request = served.await.unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        let lines: Vec<&str> = head.lines().collect();
+        assert_eq!(lines[0], "POST /upload?x=1 HTTP/1.1");
+        assert_eq!(lines[1], format!("Host: {origin_addr}"));
+        assert_eq!(lines[2], "Content-Length: 3");
+        assert!(lines[3].starts_with("X-Request-Id: "));
+        assert_eq!(lines[4..], ["Connection: close"]);
+        assert_eq!(body, "abc");
+        drop(client);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_target_is_dialed_over_its_socket() {
+        let path = std::env::temp_dir().join(format!("proxy-target-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let target = tokio::net::UnixListener::bind(&path).unwrap();
+        // Answers a GET, and echoes anything else back upper-cased.
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = target.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    loop {
+                        let n = socket.read(&mut buf).await.unwrap();
+                        if n == 0 {
+                            return;
+                        }
+                        if buf.starts_with(b"GET / ") {
+                            let response = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nunix";
+                            socket.write_all(response).await.unwrap();
+                            continue;
+                        }
+                        buf[..n].make_ascii_uppercase();
+                        socket.write_all(&buf[..n]).await.unwrap();
+                    }
+                });
+            }
+        });
+        let config = config::Config {
+            unix_targets: vec![("internal.service".to_string(), path.clone())],
+            ..config::Config::default()
+        };
+        let state = Arc::new(ProxyState::new(config).unwrap());
+        let (proxy_addr, handle) = serve_one_with_state(state.clone()).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(b"CONNECT Internal.Service:80 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+        client.write_all(b"ping").await.unwrap();
+        let mut pong = [0; 4];
+        client.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"PING");
+        drop(client);
+        handle.await.unwrap().unwrap();
+        // A plain request, forwarded the same way.
+        let (proxy_addr, handle) = serve_one_with_state(state).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(b"GET http://internal.service/ HTTP/1.1\r\nHost: internal.service\r\n\r\n")
+            .await
+            .unwrap();
+        let expected = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nunix";
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, expected);
+        drop(client);
+        handle.await.unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_is_kept_across_requests() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let responses: [&[u8]; 3] = [
+            b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: keep-alive\r\n\r\none",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\ntwo\r\n0\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nthree",
+        ];
+        let served = tokio::spawn(async move {
+            let mut paths = vec![];
+            for response in responses {
+                // A connection of its own for each request.
+                let (mut socket, _) = origin.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    assert!(n > 0);
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                paths.push(request.split(' ').nth(1).unwrap().to_string());
+                socket.write_all(response).await.unwrap();
+            }
+            paths
+        });
+        let (proxy_addr, handle) = serve_one().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let expected: [&[u8]; 3] = [
+            b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\none",
+            responses[1],
+            responses[2],
+        ];
+        for (path, expected) in ["/1", "/2", "/3"].into_iter().zip(expected) {
+            let request = format!("GET http://{origin_addr}{path} HTTP/1.1\r\nHost: x\r\n\r\n");
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = vec![0; expected.len()];
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(response, expected);
+        }
+        assert_eq!(served.await.unwrap(), ["/1", "/2", "/3"]);
+        drop(client);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chunked_request_body_then_next_request() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let served = tokio::spawn(async move {
+            let mut requests = vec![];
+            for end in [&b"0\r\n\r\n"[..], b"\r\n\r\n"] {
+                let (mut socket, _) = origin.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(end) {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    assert!(n > 0);
+                    request.extend_from_slice(&buf[..n]);
+                }
+                requests.push(String::from_utf8(request).unwrap());
+                socket
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                    .await
+                    .unwrap();
+            }
+            requests
+        });
+        let (proxy_addr, handle) = serve_one().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        // Sent together, so the next request comes in the read with the body.
+        let requests = format!(
+            "POST http://{origin_addr}/upload HTTP/1.1\r\nHost: x\r\n\
+             Transfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n\
+             GET http://{origin_addr}/next HTTP/1.1\r\nHost: x\r\n\r\n"
+        );
+        client.write_all(requests.as_bytes()).await.unwrap();
+        let expected = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        for _ in 0..2 {
+            let mut response = vec![0; expected.len()];
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(response, expected);
+        }
+        let requests = served.await.unwrap();
+        let (head, body) = requests[0].split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("POST /upload HTTP/1.1\r\n"));
+        assert!(head.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert_eq!(body, "3\r\nabc\r\n0\r\n\r\n");
+        assert!(requests[1].starts_with("GET /next HTTP/1.1\r\n"));
+        drop(client);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_access_log_file_gets_a_line_per_connection() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let path = std::env::temp_dir().join(format!("proxy-access-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = config::Config {
+            access_log: Some(path.clone()),
+            access_log_format: Some(access_log::Format::Json),
+            ..Default::default()
+        };
+        let state = Arc::new(ProxyState::new(config).unwrap());
+        for _ in 0..2 {
+            let (proxy_addr, handle) = serve_one_with_state(state.clone()).await;
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            let connect = format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n");
+            client.write_all(connect.as_bytes()).await.unwrap();
+            let (mut upstream, _) = target.accept().await.unwrap();
+            let mut response = [0; 39];
+            client.read_exact(&mut response).await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            let mut ping = [0; 4];
+            upstream.read_exact(&mut ping).await.unwrap();
+            upstream.write_all(b"pong!").await.unwrap();
+            drop(upstream);
+            let mut pong = vec![];
+            client.read_to_end(&mut pong).await.unwrap();
+            drop(client);
+            handle.await.unwrap().unwrap();
+        }
+        state.access_log.as_ref().unwrap().finish().await.unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2, "{text}");
+        for line in lines {
+            let value = json::parse(line).unwrap();
+            assert_eq!(value.get("client").unwrap().as_str(), Some("127.0.0.1"));
+            assert_eq!(value.get("method").unwrap().as_str(), Some("CONNECT"));
+            let target = target_addr.to_string();
+            assert_eq!(value.get("target").unwrap().as_str(), Some(&target[..]));
+            assert_eq!(value.get("status").unwrap().as_u64(), Some(200));
+            assert_eq!(value.get("bytes_up").unwrap().as_u64(), Some(4));
+            assert_eq!(value.get("bytes_down").unwrap().as_u64(), Some(5));
+            assert!(value.get("duration_ms").unwrap().as_u64().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_intercepted_request_is_refused_before_connecting() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let config = config::Config {
+            block_patterns: vec![b"secret".to_vec()],
+            intercept_status: 451,
+            ..Default::default()
+        };
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let request =
+            format!("GET http://{origin_addr}/secret HTTP/1.1\r\nHost: {origin_addr}\r\n\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 451 "), "{response}");
+        handle.await.unwrap().unwrap();
+        let accepted = tokio::time::timeout(Duration::from_millis(50), origin.accept()).await;
+        assert!(accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_set_header_reaches_the_origin() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let served = tokio::spawn(async move {
+            let (mut socket, _) = origin.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                assert!(n > 0, "{:?}", String::from_utf8_lossy(&request));
+                request.extend_from_slice(&buf[..n]);
+            }
+            socket
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        let config = config::Config {
+            set_headers: vec![
+                headers::SetHeader::parse("X-Forwarded-For: {client_ip}").unwrap(),
+                headers::SetHeader::parse("Via: 1.1 proxy").unwrap(),
+            ],
+            ..Default::default()
+        };
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(
+                format!(
+                    "GET http://{origin_addr}/ HTTP/1.1\r\nHost: {origin_addr}\r\n\
+                     X-Forwarded-For: 192.0.2.1\r\nConnection: X-Custom\r\nX-Custom: 1\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = vec![0; 27];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, b"HTTP/1.1 204 No Content\r\n\r\n");
+        let request = served.await.unwrap();
+        let lines: Vec<&str> = request.lines().collect();
+        assert_eq!(
+            lines[2..4],
+            ["X-Forwarded-For: 127.0.0.1", "Via: 1.1 proxy"]
+        );
+        assert!(!request.contains("X-Custom"), "{request}");
+        drop(client);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_over_the_byte_limit_gets_payload_too_large() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        // Takes the request in, and never answers.
+        let served = tokio::spawn(async move {
+            let (mut socket, _) = origin.accept().await.unwrap();
+            let mut request = vec![];
+            let _ = socket.read_to_end(&mut request).await;
+            request.len()
+        });
+        let config = config::Config {
+            max_tunnel_bytes: Some(16 * 1024),
+            ..Default::default()
+        };
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let body = vec![b'x'; 64 * 1024];
+        client
+            .write_all(
+                format!(
+                    "POST http://{origin_addr}/upload HTTP/1.1\r\nHost: {origin_addr}\r\n\
+                     Content-Length: {}\r\n\r\n",
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let (mut reader, mut writer) = client.into_split();
+        let upload = tokio::spawn(async move { writer.write_all(&body).await });
+        let mut response = vec![0; 30];
+        reader.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, b"HTTP/1.1 413 Payload Too Large");
+        let error = handle.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::QuotaExceeded);
+        assert!(served.await.unwrap() <= 16 * 1024);
+        let _ = upload.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_folded_header_gets_bad_request() {
+        let (proxy_addr, handle) = serve_one().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(b"GET http://example.com/ HTTP/1.1\r\nX-Long: a\r\n b\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 "), "{response}");
+        assert!(
+            response.ends_with("obsolete header line folding\n"),
+            "{response}"
+        );
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_switches_to_a_tunnel() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        // Echoes one masked text frame back unmasked, then closes.
+        let served = tokio::spawn(async move {
+            let (mut socket, _) = origin.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                assert!(n > 0, "{:?}", String::from_utf8_lossy(&request));
+                request.extend_from_slice(&buf[..n]);
+            }
+            socket
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n")
+                .await
+                .unwrap();
+            let mut frame = [0; 11];
+            socket.read_exact(&mut frame).await.unwrap();
+            let mask = [frame[2], frame[3], frame[4], frame[5]];
+            let mut echo = vec![0x81, 0x05];
+            echo.extend((0..5).map(|i| frame[6 + i] ^ mask[i % 4]));
+            socket.write_all(&echo).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        let config = config::Config {
+            decode_websocket: true,
+            ..Default::default()
+        };
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(
+                format!(
+                    "GET http://{origin_addr}/chat HTTP/1.1\r\nHost: {origin_addr}\r\n\
+                     Connection: Upgrade\r\nUpgrade: websocket\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await.unwrap());
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101 "));
+        // "Hello", masked as in RFC 6455 section 5.7.
+        let hello = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        client.write_all(&hello).await.unwrap();
+        let mut echo = vec![];
+        client.read_to_end(&mut echo).await.unwrap();
+        assert_eq!(echo, b"\x81\x05Hello");
+        let request = served.await.unwrap();
+        assert!(request.contains("\r\nUpgrade: websocket\r\n"), "{request}");
+        assert!(
+            request.ends_with("\r\nConnection: Upgrade\r\n\r\n"),
+            "{request}"
+        );
+        drop(client);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_small_read_buffer_without_recording() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let config = config::Config {
+            read_buffer_size: 7,
+            record: false,
+            ..Default::default()
+        };
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(
+                format!("CONNECT {target_addr} HTTP/1.1\r\nX-Filler: abc\r\n\r\n").as_bytes(),
+            )
+            .await
+            .unwrap();
+        let (mut upstream, _) = target.accept().await.unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 "));
+        upstream.write_all(b"pong").await.unwrap();
+        let mut pong = [0; 4];
+        client.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+        drop(upstream);
+        drop(client);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_record_dir_captures_both_directions() {
+        let dir = std::env::temp_dir().join(format!("proxy-captures-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let config = config::Config {
+            record_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let (mut upstream, _) = target.accept().await.unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        let request: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        let reply: Vec<u8> = (0..30_000u32).map(|i| (i % 241) as u8).collect();
+        client.write_all(&request).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = vec![];
+        upstream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, request);
+        upstream.write_all(&reply).await.unwrap();
+        drop(upstream);
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, reply);
+        handle.await.unwrap().unwrap();
+
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 3);
+        let prefix = format!("-127.0.0.1-{}", target_addr.port());
+        assert!(
+            names[0].ends_with(&format!("{prefix}-c2s.bin")),
+            "{names:?}"
+        );
+        assert!(
+            names[1].ends_with(&format!("{prefix}-s2c.bin")),
+            "{names:?}"
+        );
+        assert!(names[2].ends_with(&format!("{prefix}.idx")), "{names:?}");
+        assert_eq!(std::fs::read(dir.join(&names[0])).unwrap(), request);
+        assert_eq!(std::fs::read(dir.join(&names[1])).unwrap(), reply);
+        let index = std::fs::read_to_string(dir.join(&names[2])).unwrap();
+        let recorded = capture::parse_index(&index).unwrap();
+        assert_eq!(recorded.port, target_addr.port());
+        let bytes = |up| {
+            let chunks = recorded.chunks.iter().filter(|chunk| chunk.up == up);
+            chunks.map(|chunk| chunk.len).sum::<usize>()
+        };
+        assert_eq!((bytes(true), bytes(false)), (request.len(), reply.len()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_serves_the_recorded_reply() {
+        let dir = std::env::temp_dir().join(format!("proxy-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let connect = format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n");
+        let reply: Vec<u8> = (0..50_000u32).map(|i| (i % 239) as u8).collect();
+
+        let config = config::Config {
+            record_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(connect.as_bytes()).await.unwrap();
+        let (mut upstream, _) = target.accept().await.unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut request = [0; 18];
+        upstream.read_exact(&mut request).await.unwrap();
+        upstream.write_all(&reply[..1000]).await.unwrap();
+        upstream.write_all(&reply[1000..]).await.unwrap();
+        drop(upstream);
+        let mut recorded = vec![];
+        client.read_to_end(&mut recorded).await.unwrap();
+        drop(client);
+        handle.await.unwrap().unwrap();
+        assert_eq!(recorded, reply);
+        drop(target);
+
+        // The target is gone; the reply comes from the capture.
+        let config = config::Config {
+            replay: Some(dir.clone()),
+            ..Default::default()
+        };
+        let state = Arc::new(ProxyState::new(config).unwrap());
+        let (proxy_addr, handle) = serve_one_with_state(state.clone()).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(connect.as_bytes()).await.unwrap();
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+        client.write_all(b"ignored").await.unwrap();
+        let mut replayed = vec![];
+        client.read_to_end(&mut replayed).await.unwrap();
+        drop(client);
+        handle.await.unwrap().unwrap();
+        assert_eq!(replayed, reply);
+
+        let (proxy_addr, handle) = serve_one_with_state(state).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(b"CONNECT unrecorded.test:443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        handle.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_debug_dump_traces_connection() {
+        let dir = std::env::temp_dir().join(format!("proxy-dumps-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let config = config::Config {
+            rules: vec![config::RuleInput::Inline(policy::RuleSource {
+                origin: "test".to_string(),
+                text: format!("host=api.invalid => rewrite({target_addr})"),
+            })],
+            debug_dumps: Some(dir.clone()),
+            ..Default::default()
+        };
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(b"CONNECT api.invalid:443 HTTP/1.1\r\nHost: api.invalid\x07\r\n\r\n")
+            .await
+            .unwrap();
+        let (mut upstream, _) = target.accept().await.unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut ping = [0; 4];
+        upstream.read_exact(&mut ping).await.unwrap();
+        drop(upstream);
+        drop(client);
+        handle.await.unwrap().unwrap();
+
+        let mut dumps: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(dumps.len(), 1);
+        let path = dumps.pop().unwrap().unwrap().path();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let events: Vec<&str> = contents
+            .lines()
+            .map(|line| {
+                assert!(line.starts_with('+'), "{line}");
+                line.split_once("ms ").unwrap().1
+            })
+            .collect();
+        let rewrite = format!("rewrite: {target_addr} by rule #1");
+        assert_eq!(
+            events[..4],
+            [
+                "request line: CONNECT api.invalid:443 HTTP/1.1",
+                "header: Host: api.invalid\\x07",
+                "policy: allow by rule default (enforced: true)",
+                &rewrite,
+            ]
+        );
+        assert!(events[4].starts_with(&format!("connected: {target_addr} ({target_addr}) in ")));
+        assert_eq!(events[5], "tunnel established");
+        assert!(events.contains(&"c2s chunk: 4 bytes, 4 recorded"));
+        assert!(events.contains(&"c2s eof") && events.contains(&"s2c eof"));
+        assert_eq!(events.last(), Some(&"closed"));
+    }
+
+    #[tokio::test]
+    async fn test_netlog_pairs_begin_and_end_events() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let path = std::env::temp_dir().join(format!("proxy-test-{}.netlog", std::process::id()));
+        let config = config::Config {
+            netlog: Some(path.clone()),
+            ..Default::default()
+        };
+        let state = Arc::new(ProxyState::new(config).unwrap());
+        let (proxy_addr, handle) = serve_one_with_state(state.clone()).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let (mut upstream, _) = target.accept().await.unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut ping = [0; 4];
+        upstream.read_exact(&mut ping).await.unwrap();
+        upstream.write_all(b"pong!").await.unwrap();
+        drop(upstream);
+        let mut pong = vec![];
+        client.read_to_end(&mut pong).await.unwrap();
+        drop(client);
+        handle.await.unwrap().unwrap();
+        state.netlog.as_ref().unwrap().finish().await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let log = json::parse(&contents).unwrap();
+        let types = log.get("constants").unwrap().get("logEventTypes").unwrap();
+        let name_of = |id| {
+            let json::Value::Object(types) = types else {
+                panic!("logEventTypes is not an object");
+            };
+            let (name, _) = types.iter().find(|(_, v)| v.as_u64() == Some(id)).unwrap();
+            name.clone()
+        };
+        let json::Value::Array(events) = log.get("events").unwrap() else {
+            panic!("events is not an array");
+        };
+        let field = |event: &json::Value, name| event.get(name).and_then(json::Value::as_u64);
+        let source = field(events[0].get("source").unwrap(), "id");
+        let mut open = vec![];
+        let mut bytes = vec![];
+        for event in events {
+            assert_eq!(field(event.get("source").unwrap(), "id"), source);
+            let name = name_of(field(event, "type").unwrap());
+            match field(event, "phase").unwrap() {
+                1 => open.push(name),
+                2 => assert_eq!(open.pop(), Some(name)),
+                _ if name.starts_with("SOCKET_BYTES_") => {
+                    bytes.push((name, field(event.get("params").unwrap(), "byte_count")))
+                }
+                _ => {}
+            }
+        }
+        assert!(open.is_empty(), "unpaired: {open:?}");
+        assert_eq!(
+            bytes,
+            [
+                ("SOCKET_BYTES_SENT".to_string(), Some(4)),
+                ("SOCKET_BYTES_RECEIVED".to_string(), Some(5)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_har_lists_forwarded_requests() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let served = tokio::spawn(async move {
+            let (mut socket, _) = origin.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            socket
+                .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\n\r\nmissing")
+                .await
+                .unwrap();
+        });
+        let path = std::env::temp_dir().join(format!("proxy-test-{}.har", std::process::id()));
+        let config = config::Config {
+            har: Some(path.clone()),
+            ..Default::default()
+        };
+        let state = Arc::new(ProxyState::new(config).unwrap());
+        let (proxy_addr, handle) = serve_one_with_state(state.clone()).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let url = format!("http://{origin_addr}/missing?page=2");
+        client
+            .write_all(format!("GET {url} HTTP/1.1\r\nHost: {origin_addr}\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        served.await.unwrap();
+        drop(client);
+        handle.await.unwrap().unwrap();
+        state.har.as_ref().unwrap().finish().await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let har = json::parse(&contents).unwrap();
+        let log = har.get("log").unwrap();
+        assert_eq!(log.get("version").unwrap().as_str(), Some("1.2"));
+        let json::Value::Array(entries) = log.get("entries").unwrap() else {
+            panic!("entries is not an array");
+        };
+        assert_eq!(entries.len(), 1);
+        let request = entries[0].get("request").unwrap();
+        assert_eq!(request.get("method").unwrap().as_str(), Some("GET"));
+        assert_eq!(request.get("url").unwrap().as_str(), Some(url.as_str()));
+        assert_eq!(request.get("bodySize").unwrap().as_u64(), Some(0));
+        let response = entries[0].get("response").unwrap();
+        assert_eq!(response.get("status").unwrap().as_u64(), Some(404));
+        assert_eq!(response.get("bodySize").unwrap().as_u64(), Some(7));
+        assert_eq!(
+            entries[0].get("serverIPAddress").unwrap().as_str(),
+            Some("127.0.0.1")
+        );
+        for timing in ["blocked", "connect", "send", "wait", "receive"] {
+            let value = entries[0].get("timings").unwrap().get(timing);
+            assert!(
+                matches!(value, Some(json::Value::Number(n)) if *n >= 0.0),
+                "{timing}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_statsd_reports_traffic() {
+        let collector = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let config = config::Config {
+            statsd: Some(collector.local_addr().unwrap().to_string()),
+            ..Default::default()
+        };
+        let state = Arc::new(ProxyState::new(config).unwrap());
+        let emitter = tokio::spawn(statsd::run(
+            state.metrics.clone(),
+            collector.local_addr().unwrap().to_string(),
+            vec!["instance:test".to_string()],
+            Duration::from_millis(20),
+        ));
+        for connect in [
+            target_addr.to_string(),
+            "nonexistent.invalid:443".to_string(),
+        ] {
+            // serve_one skips accept_loop, which counts connections and errors.
+            let (proxy_addr, handle) = serve_one_with_state(state.clone()).await;
+            state.metrics.connections.fetch_add(1, Ordering::Relaxed);
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client
+                .write_all(format!("CONNECT {connect} HTTP/1.1\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            if connect == target_addr.to_string() {
+                let (mut upstream, _) = target.accept().await.unwrap();
+                let mut response = [0; 39];
+                client.read_exact(&mut response).await.unwrap();
+                client.write_all(b"ping").await.unwrap();
+                let mut ping = [0; 4];
+                upstream.read_exact(&mut ping).await.unwrap();
+                upstream.write_all(b"pong!").await.unwrap();
+                drop(upstream);
+                client.read_to_end(&mut vec![]).await.unwrap();
+                drop(client);
+                handle.await.unwrap().unwrap();
+            } else {
+                let e = handle.await.unwrap().unwrap_err();
+                state.metrics.error(&e);
+            }
+        }
+
+        let mut totals = std::collections::HashMap::new();
+        let mut timers = vec![];
+        let mut datagram = [0; 2048];
+        while totals.get("proxy.bytes_down") != Some(&5) || timers.len() < 2 {
+            let n = tokio::time::timeout(Duration::from_secs(2), collector.recv(&mut datagram))
+                .await
+                .unwrap()
+                .unwrap();
+            for line in std::str::from_utf8(&datagram[..n]).unwrap().lines() {
+                let fields: Vec<&str> = line.split('|').collect();
+                let (name, value) = fields[0].split_once(':').unwrap();
+                let tags = fields[2];
+                assert!(tags.ends_with("instance:test"), "{line}");
+                match fields[1] {
+                    "c" if name == "proxy.errors" => {
+                        *totals.entry(format!("{name} {tags}")).or_insert(0) +=
+                            value.parse::<u64>().unwrap()
+                    }
+                    "c" => {
+                        *totals.entry(name.to_string()).or_insert(0) +=
+                            value.parse::<u64>().unwrap()
+                    }
+                    "ms" => timers.push(name.to_string()),
+                    "g" => {}
+                    kind => panic!("unexpected type {kind}"),
+                }
+            }
+        }
+        emitter.abort();
+        assert_eq!(totals["proxy.connections"], 2);
+        assert_eq!(totals["proxy.bytes_up"], 4);
+        assert!(
+            totals
+                .keys()
+                .any(|name| name.starts_with("proxy.errors #stage:resolve,class:")),
+            "{totals:?}"
+        );
+        assert!(timers.contains(&"proxy.connect_latency".to_string()));
+        assert!(timers.contains(&"proxy.connection_duration".to_string()));
+    }
+
+    // A listener whose accept queue is full: further connection attempts
+    // are never answered.
+    // A SOCKS5 handshake written a byte at a time, and the proxy's answer to
+    // the greeting.
+    async fn socks5_request(proxy_addr: SocketAddr, request: &[u8]) -> TcpStream {
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        for &byte in [5, 1, 0].iter().chain(request) {
+            client.write_all(&[byte]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let mut method = [0; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 0]);
+        client
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect_to_echo_target() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut upstream, _) = target.accept().await.unwrap();
+            let (mut read, mut write) = upstream.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        });
+        let (proxy_addr, handle) = serve_one().await;
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&target_addr.port().to_be_bytes());
+        let mut client = socks5_request(proxy_addr, &request).await;
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..8], [5, 0, 0, 1, 127, 0, 0, 1]);
+        assert_ne!(u16::from_be_bytes([reply[8], reply[9]]), 0);
+        client.write_all(b"ping").await.unwrap();
+        let mut echo = [0; 4];
+        client.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+        client.shutdown().await.unwrap();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_refusals() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+        let mut refused = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        refused.extend_from_slice(&closed_port.to_be_bytes());
+        let mut denied = b"\x05\x01\x00\x03\x0bexample.com".to_vec();
+        denied.extend_from_slice(&443u16.to_be_bytes());
+        let bind = [5, 2, 0, 1, 127, 0, 0, 1, 0, 80];
+        let udp_associate = [5, 3, 0, 1, 0, 0, 0, 0, 0, 0];
+        let cases: [(&[u8], socks5::Reply); 4] = [
+            (&refused, socks5::Reply::ConnectionRefused),
+            (&denied, socks5::Reply::NotAllowed),
+            (&bind, socks5::Reply::CommandNotSupported),
+            (&udp_associate, socks5::Reply::CommandNotSupported),
+        ];
+        for (request, expected) in cases {
+            let mut config = config::Config::default();
+            host_filter::HostFilter::extend(&mut config.host_filter.deny, "example.com").unwrap();
+            let (proxy_addr, handle) = serve_one_with(config).await;
+            let mut client = socks5_request(proxy_addr, request).await;
+            let mut reply = vec![];
+            client.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(reply, [5, expected as u8, 0, 1, 0, 0, 0, 0, 0, 0]);
+            let result = handle.await.unwrap();
+            assert_eq!(
+                result.is_err(),
+                expected == socks5::Reply::ConnectionRefused
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socks5_needs_a_method_when_auth_is_required() {
+        let config = config::Config {
+            auth: proxy_auth::Credentials::parse("alice:secret"),
+            ..Default::default()
+        };
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut answer = vec![];
+        client.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer, [5, 0xff]);
+        handle.await.unwrap().unwrap();
+    }
+
+    fn via_upstream(parent: SocketAddr) -> config::Config {
+        config::Config {
+            upstream: crate::upstream::Upstream::parse(&format!("http://{parent}")),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_chains_through_parent_proxy() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let (parent_addr, parent) = serve_one().await;
+        let (proxy_addr, handle) = serve_one_with(via_upstream(parent_addr)).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+        let (mut upstream, _) = target.accept().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut ping = [0; 4];
+        upstream.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        upstream.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"pong");
+        drop(upstream);
+        drop(client);
+        handle.await.unwrap().unwrap();
+        parent.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upstream_refusal_is_bad_gateway() {
+        let mut config = config::Config::default();
+        host_filter::HostFilter::extend(&mut config.host_filter.deny, "*").unwrap();
+        let (parent_addr, parent) = serve_one_with(config).await;
+        let (proxy_addr, handle) = serve_one_with(via_upstream(parent_addr)).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+            "{response}"
+        );
+        assert!(response.ends_with("upstream proxy answered HTTP/1.1 403 Forbidden\n"));
+        handle.await.unwrap().unwrap();
+        parent.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upstream_closing_is_bad_gateway() {
+        let parent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (proxy_addr, handle) = serve_one_with(via_upstream(parent.local_addr().unwrap())).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        drop(parent.accept().await.unwrap());
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 502 "), "{response}");
+        let error = handle.await.unwrap().unwrap_err();
+        assert_eq!(connection_error::stage_of(&error), Some(Stage::Upstream));
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    async fn unanswered_target() -> (std::net::TcpListener, Vec<TcpStream>) {
+        use socket2::{Domain, Socket, Type};
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        socket
+            .bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into())
+            .unwrap();
+        socket.listen(0).unwrap();
+        let listener: std::net::TcpListener = socket.into();
+        let addr = listener.local_addr().unwrap();
+        let mut queued = vec![];
+        while let Ok(Ok(stream)) =
+            tokio::time::timeout(Duration::from_millis(100), TcpStream::connect(addr)).await
+        {
+            queued.push(stream);
+        }
+        (listener, queued)
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout_gets_gateway_timeout() {
+        let (target, _queued) = unanswered_target().await;
+        let target_addr = target.local_addr().unwrap();
+        let config = config::Config {
+            connect_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 504 Gateway Timeout\r\n"));
+        let error = handle.await.unwrap().unwrap_err();
+        assert_eq!(connection_error::stage_of(&error), Some(Stage::Connect));
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_idle_tunnel_is_torn_down() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let config = config::Config {
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let (mut upstream, _) = target.accept().await.unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        // Traffic keeps the tunnel up past the timeout.
+        let start = Instant::now();
+        for _ in 0..4 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            upstream.write_all(b"tick").await.unwrap();
+            let mut tick = [0; 4];
+            client.read_exact(&mut tick).await.unwrap();
+        }
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(600));
+        let error = handle.await.unwrap().unwrap_err();
+        assert_eq!(connection_error::stage_of(&error), Some(Stage::Idle));
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        let mut rest = vec![];
+        upstream.read_to_end(&mut rest).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_limit_down_slows_the_tunnel() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let config = config::Config {
+            limit_down: Some(64 * 1024),
+            limit_burst: Some(8 * 1024),
+            ..Default::default()
+        };
+        let (proxy_addr, handle) = serve_one_with(config).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let (mut upstream, _) = target.accept().await.unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        let data: Vec<u8> = (0..32 * 1024).map(|i| i as u8).collect();
+        let start = Instant::now();
+        upstream.write_all(&data).await.unwrap();
+        upstream.shutdown().await.unwrap();
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        // 8 KiB at once, then 24 KiB at 64 KiB/s.
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(received, data);
+        // Nothing holds back the other direction.
+        client.write_all(&data).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut sent = vec![];
+        upstream.read_to_end(&mut sent).await.unwrap();
+        assert_eq!(sent, data);
+        handle.await.unwrap().unwrap();
+    }
+
+    // Resolves every host to loopback, counting lookups.
+    struct LoopbackLookup(std::sync::atomic::AtomicUsize);
+
+    impl resolver::Lookup for LoopbackLookup {
+        fn lookup<'a>(&'a self, _host: &'a str) -> resolver::LookupFuture<'a> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async { Ok(vec!["127.0.0.1".parse().unwrap()]) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_back_to_back_connects_share_a_lookup() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let lookup = Arc::new(LoopbackLookup(Default::default()));
+        let mut state = ProxyState::new(config::Config::default()).unwrap();
+        state.resolver = resolver::Resolver::new(
+            lookup.clone(),
+            Duration::from_secs(60),
+            resolver::CACHE_CAPACITY,
+            resolver::Preference::None,
+        );
+        let state = Arc::new(state);
+        for _ in 0..2 {
+            let (proxy_addr, handle) = serve_one_with_state(state.clone()).await;
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client
+                .write_all(format!("CONNECT cached.test:{port} HTTP/1.1\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            let (upstream, _) = target.accept().await.unwrap();
+            let mut response = [0; 39];
+            client.read_exact(&mut response).await.unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 200"));
+            drop(upstream);
+            drop(client);
+            handle.await.unwrap().unwrap();
+        }
+        assert_eq!(lookup.0.load(Ordering::Relaxed), 1);
+    }
+
+    // A dead address ahead of the live one: 127.0.0.2 refuses, as the target
+    // only listens on 127.0.0.1.
+    struct DeadFirstLookup;
+
+    impl resolver::Lookup for DeadFirstLookup {
+        fn lookup<'a>(&'a self, _host: &'a str) -> resolver::LookupFuture<'a> {
+            let addrs = ["127.0.0.2", "127.0.0.1"].map(|ip| ip.parse().unwrap());
+            Box::pin(async move { Ok(addrs.to_vec()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_moves_on_from_a_dead_address() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let mut state = ProxyState::new(config::Config::default()).unwrap();
+        state.resolver = resolver::Resolver::new(
+            Arc::new(DeadFirstLookup),
+            Duration::ZERO,
+            resolver::CACHE_CAPACITY,
+            resolver::Preference::None,
+        );
+        let (proxy_addr, handle) = serve_one_with_state(Arc::new(state)).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT two.test:{port} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let (mut upstream, _) = target.accept().await.unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200"));
+        client.write_all(b"ping").await.unwrap();
+        let mut ping = [0; 4];
+        upstream.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        drop(upstream);
+        drop(client);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resolve_failure_reports_resolve_stage() {
+        let (proxy_addr, handle) = serve_one().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(b"CONNECT does-not-exist.invalid:443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        let error = handle.await.unwrap().unwrap_err();
+        assert_eq!(connection_error::stage_of(&error), Some(Stage::Resolve));
+    }
+
+    #[test]
+    fn test_connect_failure_status() {
+        let status = |kind| {
+            let (addr, source) = ("example.com:443".to_string(), io::Error::from(kind));
+            ProxyError::TargetConnect { addr, source }.status()
+        };
+        assert_eq!(status(ErrorKind::ConnectionRefused), 502);
+        assert_eq!(status(ErrorKind::HostUnreachable), 502);
+        assert_eq!(status(ErrorKind::NotFound), 502);
+        assert_eq!(status(ErrorKind::TimedOut), 504);
+        assert_eq!(status(ErrorKind::PermissionDenied), 403);
+        let (host, source) = ("example.com".to_string(), io::Error::other("no such host"));
+        let resolve = ConnectionContext::new("127.0.0.1:1".parse().unwrap()).fail(
+            Stage::Resolve,
+            ProxyError::TargetResolve { host, source }.into(),
+        );
+        let error = connection_error::proxy_error_of(&resolve).unwrap();
+        assert_eq!(error.status(), 502);
+    }
+
+    #[tokio::test]
+    async fn test_target_reset_reports_tunnel_stage() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = target.accept().await.unwrap();
+            let mut buf = [0; 5];
+            socket.read_exact(&mut buf).await.unwrap();
+            // Dropping with a zero linger time sends an RST.
+            socket.set_linger(Some(Duration::ZERO)).unwrap();
+        });
+        let (proxy_addr, handle) = serve_one().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let error = handle.await.unwrap().unwrap_err();
+        assert_eq!(connection_error::stage_of(&error), Some(Stage::TunnelS2c));
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+    }
+    #[tokio::test]
+    async fn test_target_reset_mid_transfer_closes_client() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = target.accept().await.unwrap();
+            socket.write_all(&[7; 1000]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            socket.set_linger(Some(Duration::ZERO)).unwrap();
+        });
+        let (proxy_addr, handle) = serve_one().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        let mut received = [0; 1000];
+        client.read_exact(&mut received).await.unwrap();
+        // The client never writes, yet its side of the tunnel is closed too.
+        let mut rest = vec![];
+        let closed = tokio::time::timeout(Duration::from_secs(1), client.read_to_end(&mut rest));
+        assert!(closed.await.is_ok());
+        let error = handle.await.unwrap().unwrap_err();
+        let message = error.get_ref().unwrap().to_string();
+        assert_eq!(message.matches("close_reason=").count(), 1);
+        assert!(message.contains(" bytes_down=1000 close_reason=target_reset "));
+    }
+
+    #[tokio::test]
+    async fn test_truncated_response_keeps_the_tail_of_each_direction() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = origin.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = socket.read(&mut buf).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\ncut sh")
+                .await
+                .unwrap();
+        });
+        let (proxy_addr, handle) = serve_one().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("GET http://{origin_addr}/page HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.ends_with(b"\r\n\r\ncut sh"));
+        let error = handle.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        let tails = connection_error::tails_of(&error).unwrap();
+        assert!(tails.up.starts_with(b"GET /page HTTP/1.1\r\n"));
+        assert!(tails.down.ends_with(b"\r\n\r\ncut sh"), "{:?}", tails.down);
+    }
+}
@@ -0,0 +1,1127 @@
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::policy::{self, RuleSource};
+use crate::{
+    access_log, config_file, fault, headers, host_filter, hosts, http_reader, intercept, log,
+    proxy_auth, recorder, resolver, statsd, throttle, tunnel, upstream, webhook,
+};
+use std::sync::Arc;
+
+/// A `--rule` or a `--rules` file, kept in command-line order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleInput {
+    Inline(RuleSource),
+    File(PathBuf),
+}
+
+/// What `ProxyState::reload_config` swaps for a new `--config` file's, read
+/// afresh by each connection.
+#[derive(Debug, Clone)]
+pub struct Runtime {
+    pub host_filter: host_filter::HostFilter,
+    pub auth: Option<proxy_auth::Credentials>,
+    pub limits: tunnel::Limits,
+}
+
+pub struct Config {
+    // Options read ahead of the command line's, and again on a reload.
+    pub config_file: Option<PathBuf>,
+    // The command line, without the program name, to read it again with.
+    pub args: Vec<String>,
+    pub listen: SocketAddr,
+    // `--listen unix:PATH`: a unix socket in place of `listen`, and the
+    // permissions its file is given.
+    pub listen_unix: Option<PathBuf>,
+    pub listen_mode: Option<u32>,
+    pub reuseport: usize,
+    // How much HttpReader reads from the client at a time.
+    pub read_buffer_size: usize,
+    // Record tunnel bytes for subscribers; off only counts them.
+    pub record: bool,
+    pub trust_request_id: bool,
+    pub slow_percentile: f64,
+    pub slow_window: Duration,
+    pub rules: Vec<RuleInput>,
+    // Reload the rules when a rules file changes.
+    pub watch_rules: bool,
+    // Policy plugins by name, each a command line run per check.
+    pub plugins: Vec<(String, String)>,
+    pub plugin_timeout: Duration,
+    pub plugin_fail_open: bool,
+    pub policy_dry_run: bool,
+    // Deny connections no rule allowed, instead of allowing them.
+    pub policy_default_deny: bool,
+    // Start even when the policy is suspicious, e.g. default-deny with no
+    // allow rule.
+    pub force: bool,
+    // Write a Chrome NetLog file of the proxied connections.
+    pub netlog: Option<PathBuf>,
+    // How many payload bytes of each chunk the NetLog includes.
+    pub netlog_bytes: usize,
+    // A named pipe to stream synthesized pcap records of tunnels to.
+    pub pcap_pipe: Option<PathBuf>,
+    // A file to write synthesized pcap records of tunnels to.
+    pub pcap_file: Option<PathBuf>,
+    // Only tunnels to these hosts are captured; empty captures all.
+    pub pcap_hosts: Vec<policy::HostPattern>,
+    // host:port of a statsd server.
+    pub statsd: Option<String>,
+    pub statsd_tags: Vec<String>,
+    pub webhooks: Vec<webhook::Hook>,
+    pub webhook_limits: webhook::Limits,
+    // Print an access log line per connection in this format.
+    pub access_log_format: Option<access_log::Format>,
+    // Append the access log lines to this file instead, as CLF by default.
+    pub access_log: Option<PathBuf>,
+    // Accept runs of whitespace between the request line's tokens.
+    pub lenient_request_line: bool,
+    // Write a debug trace of each connection to a file in this directory.
+    pub debug_dumps: Option<PathBuf>,
+    // Write the bytes of each tunnel direction to a file in this directory.
+    pub record_dir: Option<PathBuf>,
+    // What of each direction its capture file may be behind, held in memory.
+    pub record_buffer: usize,
+    // Require these credentials in Proxy-Authorization.
+    pub auth: Option<proxy_auth::Credentials>,
+    // Resolving and connecting to a target, after which the client gets a 504.
+    pub connect_timeout: Duration,
+    // Rounds over the target's addresses after the first was refused or timed
+    // out, and the pause ahead of the first of them, doubling after each.
+    pub connect_retries: u32,
+    pub connect_retry_delay: Duration,
+    // Tear down tunnels in which neither peer sent anything for this long.
+    pub idle_timeout: Option<Duration>,
+    // The last bytes of each direction a failed tunnel logs, at debug level.
+    pub error_tail_bytes: usize,
+    // `--allow` and `--deny` destinations, checked before the policy.
+    pub host_filter: host_filter::HostFilter,
+    // How long open connections may take to finish once shutting down.
+    pub shutdown_grace: Duration,
+    // None leaves the level to RUST_LOG.
+    pub log_level: Option<log::Level>,
+    // Open every tunnel through this parent proxy.
+    pub upstream: Option<upstream::Upstream>,
+    // Serve Prometheus metrics at /metrics on this address.
+    pub metrics_addr: Option<SocketAddr>,
+    // Serve /status and /connections/<id> on this address.
+    pub admin_addr: Option<SocketAddr>,
+    // Dump the traffic of the tunnel a client on this address names.
+    pub tap_addr: Option<SocketAddr>,
+    // Bytes per second each tunnel may send towards the target and the client.
+    pub limit_up: Option<u64>,
+    pub limit_down: Option<u64>,
+    // How far a limited direction may run ahead after an idle spell; one
+    // second's worth by default.
+    pub limit_burst: Option<u64>,
+    // Bytes a tunnel may carry before it is cut, in both directions together
+    // or, with `max_tunnel_bytes_each`, in each.
+    pub max_tunnel_bytes: Option<u64>,
+    pub max_tunnel_bytes_each: bool,
+    // How long a client has to send a whole request head, from when it is
+    // accepted or, on a kept connection, starts the next request.
+    pub header_timeout: Duration,
+    pub head_limits: http_reader::HeadLimits,
+    // How long a kept client connection may wait for its next request.
+    pub keep_alive_timeout: Duration,
+    // Write a HAR file of the proxied requests here on shutdown.
+    pub har: Option<PathBuf>,
+    // Connections served at once. Beyond it the acceptors wait for one to
+    // close, or with `reject_over_limit` answer 503 straight away.
+    pub max_connections: Option<usize>,
+    pub reject_over_limit: bool,
+    // How long a host's addresses are cached; zero turns the cache off.
+    pub dns_ttl: Duration,
+    // Which address family to try first.
+    pub prefer: resolver::Preference,
+    // Names connected to at these addresses instead of the resolved ones.
+    pub hosts: hosts::Overrides,
+    // Let `hosts` send connections back to the proxy's own listen address.
+    pub allow_self: bool,
+    // Names connected to over these unix sockets, by normalized host and in
+    // the order given.
+    pub unix_targets: Vec<(String, PathBuf)>,
+    // The local addresses, and interface, connections to targets are made
+    // from.
+    pub outbound: resolver::Source,
+    // Read a PROXY protocol header ahead of each client's request, and take
+    // the client's address from it.
+    pub accept_proxy_protocol: bool,
+    // Serve tunnels from the captures in this directory, without dialing
+    // their targets.
+    pub replay: Option<PathBuf>,
+    // Send replayed chunks as far apart as they were recorded.
+    pub replay_realtime: bool,
+    // Fields added to forwarded requests, in place of any of the same name.
+    pub set_headers: Vec<headers::SetHeader>,
+    // Log the messages of tunnels that upgrade to WebSocket.
+    pub decode_websocket: bool,
+    // Offered the bytes of every tunnel, ahead of the `--block-pattern` ones.
+    pub interceptors: Vec<Arc<dyn intercept::Factory>>,
+    pub block_patterns: Vec<Vec<u8>>,
+    // The answer to a request an interceptor aborts before its tunnel.
+    pub intercept_status: u16,
+    // Faults injected into tunnels with the `--fault-*` options.
+    pub faults: fault::Faults,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            config_file: None,
+            args: vec![],
+            listen: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            listen_unix: None,
+            listen_mode: None,
+            reuseport: 1,
+            read_buffer_size: 4096,
+            record: true,
+            trust_request_id: true,
+            slow_percentile: 99.0,
+            slow_window: Duration::from_secs(60),
+            rules: vec![],
+            watch_rules: false,
+            plugins: vec![],
+            plugin_timeout: Duration::from_secs(1),
+            plugin_fail_open: false,
+            policy_dry_run: false,
+            policy_default_deny: false,
+            force: false,
+            netlog: None,
+            netlog_bytes: 0,
+            pcap_pipe: None,
+            pcap_file: None,
+            pcap_hosts: vec![],
+            statsd: None,
+            statsd_tags: vec![],
+            webhooks: vec![],
+            webhook_limits: webhook::Limits::default(),
+            access_log_format: None,
+            access_log: None,
+            lenient_request_line: false,
+            debug_dumps: None,
+            record_dir: None,
+            record_buffer: 1 << 20,
+            auth: None,
+            connect_timeout: Duration::from_secs(10),
+            connect_retries: 0,
+            connect_retry_delay: Duration::from_millis(100),
+            idle_timeout: None,
+            error_tail_bytes: recorder::TAIL_SIZE,
+            host_filter: host_filter::HostFilter::default(),
+            shutdown_grace: Duration::from_secs(30),
+            log_level: None,
+            upstream: None,
+            metrics_addr: None,
+            admin_addr: None,
+            tap_addr: None,
+            limit_up: None,
+            limit_down: None,
+            limit_burst: None,
+            max_tunnel_bytes: None,
+            max_tunnel_bytes_each: false,
+            header_timeout: Duration::from_secs(10),
+            head_limits: http_reader::HeadLimits::default(),
+            keep_alive_timeout: Duration::from_secs(60),
+            har: None,
+            max_connections: None,
+            reject_over_limit: false,
+            dns_ttl: Duration::from_secs(30),
+            prefer: resolver::Preference::None,
+            hosts: hosts::Overrides::default(),
+            allow_self: false,
+            unix_targets: vec![],
+            outbound: resolver::Source::default(),
+            accept_proxy_protocol: false,
+            replay: None,
+            replay_realtime: false,
+            set_headers: vec![],
+            decode_websocket: false,
+            interceptors: vec![],
+            block_patterns: vec![],
+            intercept_status: 403,
+            faults: fault::Faults::default(),
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, msg)
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, name: &str) -> io::Result<String> {
+    args.next()
+        .ok_or_else(|| invalid(format!("{name} requires a value")))
+}
+
+fn parse<T: FromStr>(name: &str, value: &str, valid: impl Fn(&T) -> bool) -> io::Result<T> {
+    match value.parse() {
+        Ok(parsed) if valid(&parsed) => Ok(parsed),
+        _ => Err(invalid(format!("invalid {name} value: {value}"))),
+    }
+}
+
+// A byte count with an optional binary unit: `65536`, `64KiB`, `100MiB`.
+fn parse_size(text: &str) -> Option<u64> {
+    let digits = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(digits);
+    let shift = match unit.trim_start() {
+        "" | "B" => 0,
+        "KiB" => 10,
+        "MiB" => 20,
+        "GiB" => 30,
+        "TiB" => 40,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn probability(p: &f64) -> bool {
+    (0.0..=1.0).contains(p)
+}
+
+// `1MiB=10KiBps`: the bytes after which, and the bytes per second.
+fn parse_throttle_after(text: &str) -> Option<fault::ThrottleAfter> {
+    let (bytes, rate) = text.split_once('=')?;
+    let rate = rate.strip_suffix("ps").unwrap_or(rate);
+    let rate = parse_size(rate).filter(|&rate| rate > 0)?;
+    Some(fault::ThrottleAfter {
+        bytes: parse_size(bytes)?,
+        rate,
+    })
+}
+
+impl Config {
+    pub fn from_args<I: Iterator<Item = String>>(args: I) -> io::Result<Self> {
+        let args: Vec<String> = args.collect();
+        let mut config = Config::default();
+        config.apply(args.iter().cloned())?;
+        // The file's options go first, for the command line's to override.
+        if let Some(path) = config.config_file.take() {
+            let mut file_args = config_file::read(&path)?;
+            file_args.extend(args.iter().cloned());
+            config = Config::default();
+            config.apply(file_args.into_iter())?;
+        }
+        config.args = args;
+        if !config.record && config.record_dir.is_some() {
+            return Err(invalid(
+                "--record-dir cannot be combined with --no-record".to_string(),
+            ));
+        }
+        if config.access_log.is_some() && config.access_log_format.is_none() {
+            config.access_log_format = Some(access_log::Format::Clf);
+        }
+        if config.replay_realtime && config.replay.is_none() {
+            return Err(invalid("--replay-realtime needs --replay".to_string()));
+        }
+        if !config.allow_self
+            && let Some(name) = config.hosts.loop_to(config.listen)
+        {
+            return Err(invalid(format!(
+                "--hosts-override sends {name} to the proxy's own address {}; \
+                 --allow-self permits it",
+                config.listen
+            )));
+        }
+        if config.pcap_pipe.is_some() && config.pcap_file.is_some() {
+            return Err(invalid(
+                "--pcap cannot be combined with --pcap-pipe".to_string(),
+            ));
+        }
+        if config.listen_mode.is_some() && config.listen_unix.is_none() {
+            return Err(invalid(
+                "--listen-mode needs --listen unix:PATH".to_string(),
+            ));
+        }
+        if config.max_tunnel_bytes_each && config.max_tunnel_bytes.is_none() {
+            return Err(invalid(
+                "--max-tunnel-bytes-each needs --max-tunnel-bytes".to_string(),
+            ));
+        }
+        if config.limit_burst.is_some() && config.limit_up.is_none() && config.limit_down.is_none()
+        {
+            return Err(invalid(
+                "--limit-burst needs --limit-up or --limit-down".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+
+    fn apply<I: Iterator<Item = String>>(&mut self, mut args: I) -> io::Result<()> {
+        let config = self;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => config.config_file = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--listen" => {
+                    let value = value(&mut args, &arg)?;
+                    match value.strip_prefix("unix:") {
+                        Some("") => return Err(invalid(format!("{arg} unix: needs a path"))),
+                        Some(path) => config.listen_unix = Some(PathBuf::from(path)),
+                        None => {
+                            config.listen = parse(&arg, &value, |_| true)?;
+                            config.listen_unix = None;
+                        }
+                    }
+                }
+                "--listen-mode" => {
+                    let value = value(&mut args, &arg)?;
+                    let mode = u32::from_str_radix(&value, 8)
+                        .ok()
+                        .filter(|mode| *mode <= 0o777)
+                        .ok_or_else(|| invalid(format!("{arg} expects octal permissions")))?;
+                    config.listen_mode = Some(mode);
+                }
+                "--unix-target" => {
+                    let value = value(&mut args, &arg)?;
+                    let (name, path) = value
+                        .split_once('=')
+                        .filter(|(name, path)| !name.is_empty() && !path.is_empty())
+                        .ok_or_else(|| invalid(format!("{arg} expects name=path")))?;
+                    config
+                        .unix_targets
+                        .push((policy::normalize_host(name), PathBuf::from(path)));
+                }
+                "--read-buffer-size" => {
+                    let value = value(&mut args, &arg)?;
+                    config.read_buffer_size = parse(&arg, &value, |n| *n > 0)?;
+                }
+                "--no-record" => config.record = false,
+                "--record-dir" => config.record_dir = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--record-buffer" => {
+                    let value = value(&mut args, &arg)?;
+                    let bytes = parse_size(&value).filter(|&bytes| bytes > 0);
+                    let bytes = bytes.and_then(|bytes| usize::try_from(bytes).ok());
+                    config.record_buffer =
+                        bytes.ok_or_else(|| invalid(format!("invalid {arg} value: {value}")))?;
+                }
+                "--reuseport" => {
+                    let value = value(&mut args, &arg)?;
+                    config.reuseport = parse(&arg, &value, |n| *n > 0)?;
+                }
+                "--slow-percentile" => {
+                    let value = value(&mut args, &arg)?;
+                    config.slow_percentile = parse(&arg, &value, |p| *p > 0.0 && *p < 100.0)?;
+                }
+                "--slow-window" => {
+                    let value = value(&mut args, &arg)?;
+                    let secs: u64 = parse(&arg, &value, |secs| *secs > 0)?;
+                    config.slow_window = Duration::from_secs(secs);
+                }
+                "--rule" => {
+                    let text = value(&mut args, &arg)?;
+                    let inline = config
+                        .rules
+                        .iter()
+                        .filter(|input| matches!(input, RuleInput::Inline(_)))
+                        .count();
+                    let origin = format!("--rule #{}", inline + 1);
+                    config
+                        .rules
+                        .push(RuleInput::Inline(RuleSource { origin, text }));
+                }
+                "--rules" => {
+                    let path = value(&mut args, &arg)?;
+                    config.rules.push(RuleInput::File(PathBuf::from(path)));
+                }
+                "--watch-rules" => config.watch_rules = true,
+                "--plugin" => {
+                    let value = value(&mut args, &arg)?;
+                    match value.split_once('=') {
+                        Some((name, command)) if !name.is_empty() && !command.trim().is_empty() => {
+                            config.plugins.push((name.to_string(), command.to_string()))
+                        }
+                        _ => return Err(invalid(format!("invalid {arg} value: {value}"))),
+                    }
+                }
+                "--plugin-timeout" => {
+                    let value = value(&mut args, &arg)?;
+                    let millis: u64 = parse(&arg, &value, |millis| *millis > 0)?;
+                    config.plugin_timeout = Duration::from_millis(millis);
+                }
+                "--plugin-fail-open" => config.plugin_fail_open = true,
+                "--connect-timeout" => {
+                    let value = value(&mut args, &arg)?;
+                    let secs: u64 = parse(&arg, &value, |secs| *secs > 0)?;
+                    config.connect_timeout = Duration::from_secs(secs);
+                }
+                "--error-tail-bytes" => {
+                    let value = value(&mut args, &arg)?;
+                    config.error_tail_bytes = parse(&arg, &value, |_| true)?;
+                }
+                "--connect-retries" => {
+                    config.connect_retries = parse(&arg, &value(&mut args, &arg)?, |_| true)?;
+                }
+                "--connect-retry-delay" => {
+                    let value = value(&mut args, &arg)?;
+                    let millis: u64 = parse(&arg, &value, |_| true)?;
+                    config.connect_retry_delay = Duration::from_millis(millis);
+                }
+                "--shutdown-grace" => {
+                    let value = value(&mut args, &arg)?;
+                    let secs: u64 = parse(&arg, &value, |_| true)?;
+                    config.shutdown_grace = Duration::from_secs(secs);
+                }
+                "--idle-timeout" => {
+                    let value = value(&mut args, &arg)?;
+                    let secs: u64 = parse(&arg, &value, |secs| *secs > 0)?;
+                    config.idle_timeout = Some(Duration::from_secs(secs));
+                }
+                "--policy-dry-run" => config.policy_dry_run = true,
+                "--policy-default" => {
+                    let value = value(&mut args, &arg)?;
+                    config.policy_default_deny = match value.as_str() {
+                        "allow" => false,
+                        "deny" => true,
+                        _ => return Err(invalid(format!("invalid {arg} value: {value}"))),
+                    };
+                }
+                "--max-connections" => {
+                    let value = value(&mut args, &arg)?;
+                    let max = parse(&arg, &value, |max| *max > 0)?;
+                    config.max_connections = Some(max);
+                }
+                "--max-connections-policy" => {
+                    let value = value(&mut args, &arg)?;
+                    config.reject_over_limit = match value.as_str() {
+                        "wait" => false,
+                        "reject" => true,
+                        _ => return Err(invalid(format!("invalid {arg} value: {value}"))),
+                    };
+                }
+                "--dns-ttl" => {
+                    let value = value(&mut args, &arg)?;
+                    let secs: u64 = parse(&arg, &value, |_| true)?;
+                    config.dns_ttl = Duration::from_secs(secs);
+                }
+                "--outbound-addr" => {
+                    let value = value(&mut args, &arg)?;
+                    config.outbound.v4 = Some(parse(&arg, &value, |_| true)?);
+                }
+                "--outbound-addr-v6" => {
+                    let value = value(&mut args, &arg)?;
+                    config.outbound.v6 = Some(parse(&arg, &value, |_| true)?);
+                }
+                "--outbound-interface" if cfg!(any(target_os = "linux", target_os = "android")) => {
+                    let value = value(&mut args, &arg)?;
+                    if value.is_empty() {
+                        return Err(invalid(format!("invalid {arg} value: {value}")));
+                    }
+                    config.outbound.interface = Some(value);
+                }
+                "--prefer-ipv4" => config.prefer = resolver::Preference::Ipv4,
+                "--prefer-ipv6" => config.prefer = resolver::Preference::Ipv6,
+                "--accept-proxy-protocol" => config.accept_proxy_protocol = true,
+                "--replay" => config.replay = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--replay-realtime" => config.replay_realtime = true,
+                "--force" => config.force = true,
+                "--netlog" => config.netlog = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--netlog-bytes" => {
+                    let value = value(&mut args, &arg)?;
+                    config.netlog_bytes = parse(&arg, &value, |_| true)?;
+                }
+                "--har" => config.har = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--pcap-pipe" => config.pcap_pipe = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--pcap" => config.pcap_file = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--pcap-host" => {
+                    let value = value(&mut args, &arg)?;
+                    let pattern = policy::HostPattern::parse(&value)
+                        .ok_or_else(|| invalid(format!("invalid {arg} value: {value}")))?;
+                    config.pcap_hosts.push(pattern);
+                }
+                "--statsd" => config.statsd = Some(statsd::parse_url(&value(&mut args, &arg)?)?),
+                "--statsd-tag" => {
+                    let value = value(&mut args, &arg)?;
+                    if value.is_empty() || value.contains([',', '|', '#', '\n']) {
+                        return Err(invalid(format!("invalid {arg} value: {value}")));
+                    }
+                    config.statsd_tags.push(value);
+                }
+                "--webhook" => config
+                    .webhooks
+                    .push(webhook::Hook::parse(&value(&mut args, &arg)?)?),
+                "--webhook-errors" => {
+                    let value = value(&mut args, &arg)?;
+                    let (count, secs) = value
+                        .split_once('/')
+                        .ok_or_else(|| invalid(format!("invalid {arg} value: {value}")))?;
+                    config.webhook_limits.error_threshold = parse(&arg, count, |n| *n > 0)?;
+                    let secs: u64 = parse(&arg, secs, |secs| *secs > 0)?;
+                    config.webhook_limits.error_window = Duration::from_secs(secs);
+                }
+                "--access-log" => config.access_log = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--access-log-format" => {
+                    let value = value(&mut args, &arg)?;
+                    let format = access_log::Format::parse(&value)
+                        .ok_or_else(|| invalid(format!("invalid {arg} value: {value}")))?;
+                    config.access_log_format = Some(format);
+                }
+                "--metrics-addr" => {
+                    let value = value(&mut args, &arg)?;
+                    config.metrics_addr = Some(parse(&arg, &value, |_| true)?);
+                }
+                "--admin-addr" => {
+                    let value = value(&mut args, &arg)?;
+                    config.admin_addr = Some(parse(&arg, &value, |_| true)?);
+                }
+                "--tap-addr" => {
+                    let value = value(&mut args, &arg)?;
+                    config.tap_addr = Some(parse(&arg, &value, |_| true)?);
+                }
+                "--upstream" => {
+                    let value = value(&mut args, &arg)?;
+                    let upstream = upstream::Upstream::parse(&value)
+                        .ok_or_else(|| invalid(format!("invalid {arg} value: {value}")))?;
+                    config.upstream = Some(upstream);
+                }
+                "--limit-up" | "--limit-down" | "--limit-burst" => {
+                    let value = value(&mut args, &arg)?;
+                    let bytes: u64 = parse(&arg, &value, |bytes| *bytes > 0)?;
+                    *match arg.as_str() {
+                        "--limit-up" => &mut config.limit_up,
+                        "--limit-down" => &mut config.limit_down,
+                        _ => &mut config.limit_burst,
+                    } = Some(bytes);
+                }
+                "--log-level" => {
+                    let value = value(&mut args, &arg)?;
+                    let level = log::Level::parse(&value)
+                        .ok_or_else(|| invalid(format!("invalid {arg} value: {value}")))?;
+                    config.log_level = Some(level);
+                }
+                "--debug-dumps" => {
+                    config.debug_dumps = Some(PathBuf::from(value(&mut args, &arg)?))
+                }
+                "--lenient-request-line" => config.lenient_request_line = true,
+                "--decode-websocket" => config.decode_websocket = true,
+                "--set-header" => {
+                    let value = value(&mut args, &arg)?;
+                    let set = headers::SetHeader::parse(&value)
+                        .map_err(|e| invalid(format!("invalid {arg} value: {e}")))?;
+                    config.set_headers.push(set);
+                }
+                "--block-pattern" => {
+                    let value = value(&mut args, &arg)?;
+                    let pattern = intercept::parse_pattern(&value)
+                        .map_err(|e| invalid(format!("invalid {arg} value: {e}")))?;
+                    config.block_patterns.push(pattern);
+                }
+                "--intercept-status" => {
+                    let value = value(&mut args, &arg)?;
+                    config.intercept_status = parse(&arg, &value, |s| (400..600).contains(s))?;
+                }
+                "--allow" | "--deny" => {
+                    let value = value(&mut args, &arg)?;
+                    let list = match arg.as_str() {
+                        "--allow" => &mut config.host_filter.allow,
+                        _ => &mut config.host_filter.deny,
+                    };
+                    host_filter::HostFilter::extend(list, &value)
+                        .map_err(|pattern| invalid(format!("invalid {arg} pattern: {pattern}")))?;
+                }
+                "--max-tunnel-bytes" => {
+                    let value = value(&mut args, &arg)?;
+                    let bytes = parse_size(&value).filter(|&bytes| bytes > 0);
+                    let bytes =
+                        bytes.ok_or_else(|| invalid(format!("invalid {arg} value: {value}")))?;
+                    config.max_tunnel_bytes = Some(bytes);
+                }
+                "--max-tunnel-bytes-each" => config.max_tunnel_bytes_each = true,
+                "--header-timeout" => {
+                    let value = value(&mut args, &arg)?;
+                    let secs: u64 = parse(&arg, &value, |secs| *secs > 0)?;
+                    config.header_timeout = Duration::from_secs(secs);
+                }
+                "--max-head-size" => {
+                    let value = value(&mut args, &arg)?;
+                    let bytes = parse_size(&value).filter(|&bytes| bytes > 0);
+                    let bytes = bytes.and_then(|bytes| usize::try_from(bytes).ok());
+                    config.head_limits.max_bytes =
+                        bytes.ok_or_else(|| invalid(format!("invalid {arg} value: {value}")))?;
+                }
+                "--keep-alive-timeout" => {
+                    let value = value(&mut args, &arg)?;
+                    let secs: u64 = parse(&arg, &value, |secs| *secs > 0)?;
+                    config.keep_alive_timeout = Duration::from_secs(secs);
+                }
+                "--max-headers" => {
+                    let value = value(&mut args, &arg)?;
+                    config.head_limits.max_headers = parse(&arg, &value, |_| true)?;
+                }
+                "--hosts-override" => {
+                    let value = value(&mut args, &arg)?;
+                    config
+                        .hosts
+                        .extend(&value)
+                        .map_err(|e| invalid(format!("invalid {arg} value: {e}")))?;
+                }
+                "--hosts-file" => config
+                    .hosts
+                    .read_file(Path::new(&value(&mut args, &arg)?))?,
+                "--allow-self" => config.allow_self = true,
+                "--fault-seed" => {
+                    config.faults.seed = Some(parse(&arg, &value(&mut args, &arg)?, |_| true)?);
+                }
+                "--fault-close-prob" => {
+                    let value = value(&mut args, &arg)?;
+                    config.faults.close_prob = parse(&arg, &value, probability)?;
+                }
+                "--fault-corrupt-prob" => {
+                    let value = value(&mut args, &arg)?;
+                    config.faults.corrupt_prob = parse(&arg, &value, probability)?;
+                }
+                "--fault-delay" => {
+                    let value = value(&mut args, &arg)?;
+                    let delay = fault::Delay::parse(&value)
+                        .ok_or_else(|| invalid(format!("invalid {arg} value: {value}")))?;
+                    config.faults.delay = Some(delay);
+                }
+                "--fault-throttle-after" => {
+                    let value = value(&mut args, &arg)?;
+                    let throttle = parse_throttle_after(&value)
+                        .ok_or_else(|| invalid(format!("invalid {arg} value: {value}")))?;
+                    config.faults.throttle_after = Some(throttle);
+                }
+                "--auth" => {
+                    let value = value(&mut args, &arg)?;
+                    let credentials = proxy_auth::Credentials::parse(&value)
+                        .ok_or_else(|| invalid(format!("{arg} expects user:password")))?;
+                    config.auth = Some(credentials);
+                }
+                "--replace-request-id" => config.trust_request_id = false,
+                _ => return Err(invalid(format!("unknown argument: {arg}"))),
+            }
+        }
+        Ok(())
+    }
+
+    /// The unix socket `host` is connected to over, if any; of two for the
+    /// same name, the later.
+    pub fn unix_target(&self, host: &str) -> Option<&Path> {
+        let host = policy::normalize_host(host);
+        let mut targets = self.unix_targets.iter().rev();
+        targets
+            .find(|(name, _)| *name == host)
+            .map(|(_, path)| path.as_path())
+    }
+
+    pub fn runtime(&self) -> Runtime {
+        Runtime {
+            host_filter: self.host_filter.clone(),
+            auth: self.auth.clone(),
+            limits: self.tunnel_limits(),
+        }
+    }
+
+    pub fn connect_retry(&self) -> resolver::Retry {
+        resolver::Retry {
+            retries: self.connect_retries,
+            delay: self.connect_retry_delay,
+        }
+    }
+
+    pub fn tunnel_limits(&self) -> tunnel::Limits {
+        let limit = |rate: Option<u64>| {
+            rate.map(|rate| throttle::Limit {
+                rate,
+                burst: self.limit_burst.unwrap_or(rate),
+            })
+        };
+        tunnel::Limits {
+            idle: self.idle_timeout,
+            up: limit(self.limit_up),
+            down: limit(self.limit_down),
+            error_tail: self.error_tail_bytes,
+            max_bytes: self.max_tunnel_bytes.map(|bytes| tunnel::ByteLimit {
+                bytes,
+                each_direction: self.max_tunnel_bytes_each,
+            }),
+            record_buffer: Some(self.record_buffer),
+        }
+    }
+
+    /// The rules in order, reading the rules files as they are now.
+    pub fn load_rules(&self) -> io::Result<Vec<RuleSource>> {
+        let mut sources = vec![];
+        for input in &self.rules {
+            match input {
+                RuleInput::Inline(source) => sources.push(source.clone()),
+                RuleInput::File(path) => sources.extend(policy::read_rules_file(path)?),
+            }
+        }
+        Ok(sources)
+    }
+
+    pub fn rule_files(&self) -> impl Iterator<Item = &Path> {
+        self.rules.iter().filter_map(|input| match input {
+            RuleInput::File(path) => Some(path.as_path()),
+            RuleInput::Inline(_) => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn args(args: &[&str]) -> io::Result<Config> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+    // Each of `bad` is refused as invalid input.
+    fn assert_invalid(bad: &[&[&str]]) {
+        for bad in bad {
+            let error = args(bad).err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput, "{bad:?}");
+        }
+    }
+    #[test]
+    fn test_listen_options() {
+        let config = args(&["--listen", "0.0.0.0:3128", "--read-buffer-size", "512"]).unwrap();
+        assert_eq!(config.listen, "0.0.0.0:3128".parse().unwrap());
+        assert_eq!(config.read_buffer_size, 512);
+        assert_eq!(args(&["--listen", "[::1]:0"]).unwrap().listen.port(), 0);
+        let config = args(&["--log-level", "DEBUG"]).unwrap();
+        assert_eq!(config.log_level, Some(log::Level::Debug));
+        assert_invalid(&[
+            &["--listen", "localhost"],
+            &["--listen", "1.2.3.4:99999"],
+            &["--listen"],
+            &["--read-buffer-size", "0"],
+            &["--log-level", "loud"],
+            &["--metrics-addr", "localhost:9090"],
+            &["--admin-addr", "9091"],
+            &["--tap-addr", "localhost:9099"],
+        ]);
+        assert_eq!(
+            args(&["--listen", "localhost"]).err().unwrap().to_string(),
+            "invalid --listen value: localhost"
+        );
+    }
+    #[test]
+    fn test_unix_socket_options() {
+        let config = args(&[
+            "--listen",
+            "unix:/run/proxy.sock",
+            "--listen-mode",
+            "0660",
+            "--unix-target",
+            "Internal.Service=/run/a.sock",
+            "--unix-target",
+            "internal.service=/run/b.sock",
+        ])
+        .unwrap();
+        assert_eq!(config.listen_unix, Some(PathBuf::from("/run/proxy.sock")));
+        assert_eq!(config.listen_mode, Some(0o660));
+        assert_eq!(
+            config.unix_target("internal.service."),
+            Some(Path::new("/run/b.sock"))
+        );
+        assert_eq!(config.unix_target("other.service"), None);
+        // A later TCP address takes the listener back.
+        let config = args(&[
+            "--listen",
+            "unix:/run/proxy.sock",
+            "--listen",
+            "127.0.0.1:0",
+        ]);
+        assert_eq!(config.unwrap().listen_unix, None);
+        assert_invalid(&[
+            &["--listen", "unix:"],
+            &["--listen-mode", "0660"],
+            &["--listen", "unix:/x.sock", "--listen-mode", "rw"],
+            &["--listen", "unix:/x.sock", "--listen-mode", "1777"],
+            &["--unix-target", "internal.service"],
+            &["--unix-target", "=/run/a.sock"],
+        ]);
+    }
+    #[test]
+    fn test_record_options() {
+        assert!(args(&[]).unwrap().record);
+        assert!(!args(&["--no-record"]).unwrap().record);
+        assert!(args(&["--no-record", "--record-dir", "captures"]).is_err());
+        assert_eq!(
+            args(&[]).unwrap().tunnel_limits().record_buffer,
+            Some(1 << 20)
+        );
+        let config = args(&["--record-dir", "captures", "--record-buffer", "64KiB"]).unwrap();
+        assert_eq!(config.tunnel_limits().record_buffer, Some(64 << 10));
+        assert_invalid(&[
+            &["--record-buffer", "0"],
+            &["--record-buffer", "1MB"],
+            &["--replay-realtime"],
+            &["--pcap", "out.pcap", "--pcap-pipe", "out.pipe"],
+        ]);
+    }
+    #[test]
+    fn test_limit_options() {
+        let limits = args(&[
+            "--limit-up",
+            "1000",
+            "--limit-down",
+            "2000",
+            "--limit-burst",
+            "500",
+        ])
+        .unwrap()
+        .tunnel_limits();
+        assert_eq!(
+            (limits.up, limits.down),
+            (
+                Some(throttle::Limit {
+                    rate: 1000,
+                    burst: 500
+                }),
+                Some(throttle::Limit {
+                    rate: 2000,
+                    burst: 500
+                })
+            )
+        );
+        assert_eq!(args(&[]).unwrap().tunnel_limits().error_tail, 4096);
+        let limits = args(&["--limit-down", "2000", "--error-tail-bytes", "0"])
+            .unwrap()
+            .tunnel_limits();
+        assert_eq!(limits.error_tail, 0);
+        assert_eq!(
+            (limits.up, limits.down.map(|l| l.burst)),
+            (None, Some(2000))
+        );
+        assert_eq!(limits.max_bytes, None);
+        let limits = args(&["--max-tunnel-bytes-each", "--max-tunnel-bytes", "100MiB"])
+            .unwrap()
+            .tunnel_limits();
+        assert_eq!(
+            limits.max_bytes,
+            Some(tunnel::ByteLimit {
+                bytes: 100 << 20,
+                each_direction: true
+            })
+        );
+        assert_invalid(&[
+            &["--error-tail-bytes", "4k"],
+            &["--limit-up", "0"],
+            &["--limit-down", "1M"],
+            &["--limit-burst", "4096"],
+            &["--max-connections", "0"],
+            &["--max-connections-policy", "drop"],
+            &["--max-tunnel-bytes", "0"],
+            &["--max-tunnel-bytes", "1MB"],
+            &["--max-tunnel-bytes-each"],
+        ]);
+    }
+    #[test]
+    fn test_head_options() {
+        let config = args(&["--header-timeout", "3", "--max-head-size", "64KiB"]).unwrap();
+        assert_eq!(config.header_timeout, Duration::from_secs(3));
+        assert_eq!(
+            config.head_limits,
+            http_reader::HeadLimits {
+                max_bytes: 64 << 10,
+                max_headers: 100
+            }
+        );
+        assert_eq!(config.keep_alive_timeout, Duration::from_secs(60));
+        let config = args(&["--keep-alive-timeout", "5"]).unwrap();
+        assert_eq!(config.keep_alive_timeout, Duration::from_secs(5));
+        assert_invalid(&[
+            &["--header-timeout", "0"],
+            &["--keep-alive-timeout", "0"],
+            &["--max-head-size", "0"],
+            &["--max-head-size", "16k"],
+            &["--max-headers", "-1"],
+        ]);
+    }
+    #[test]
+    fn test_fault_options() {
+        assert!(!args(&[]).unwrap().faults.enabled());
+        let faults = args(&[
+            "--fault-seed",
+            "7",
+            "--fault-throttle-after",
+            "1MiB=10KiBps",
+            "--fault-delay",
+            "200ms±100ms",
+        ])
+        .unwrap()
+        .faults;
+        assert!(faults.enabled());
+        assert_eq!(faults.seed, Some(7));
+        assert_eq!(
+            faults.throttle_after,
+            Some(fault::ThrottleAfter {
+                bytes: 1 << 20,
+                rate: 10 << 10
+            })
+        );
+        assert_eq!(faults.delay.unwrap().jitter, Duration::from_millis(100));
+        assert_invalid(&[
+            &["--fault-close-prob", "1.5"],
+            &["--fault-corrupt-prob", "-0.1"],
+            &["--fault-delay", "200"],
+            &["--fault-throttle-after", "1MiB"],
+            &["--fault-throttle-after", "1MiB=0"],
+            &["--fault-seed", "x"],
+        ]);
+    }
+    #[test]
+    fn test_resolver_options() {
+        assert_eq!(args(&[]).unwrap().dns_ttl, Duration::from_secs(30));
+        let config = args(&["--dns-ttl", "0", "--prefer-ipv6"]).unwrap();
+        assert_eq!(config.dns_ttl, Duration::ZERO);
+        assert_eq!(config.prefer, resolver::Preference::Ipv6);
+        let outbound = args(&[
+            "--outbound-addr",
+            "192.0.2.10",
+            "--outbound-addr-v6",
+            "2001:db8::a",
+        ])
+        .unwrap()
+        .outbound;
+        assert_eq!(outbound.v4, Some("192.0.2.10".parse().unwrap()));
+        assert_eq!(outbound.v6, Some("2001:db8::a".parse().unwrap()));
+        assert_eq!(outbound.interface, None);
+        let config = args(&[
+            "--listen",
+            "0.0.0.0:3128",
+            "--hosts-override",
+            "a.test=10.1.2.3",
+            "--hosts-override",
+            "a.test=127.0.0.1:3128",
+            "--allow-self",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.hosts.lookup("a.test").map(|t| t.addr(443)),
+            Some("127.0.0.1:3128".parse().unwrap())
+        );
+        assert_invalid(&[
+            &["--dns-ttl", "-1"],
+            &["--outbound-addr", "2001:db8::a"],
+            &["--outbound-addr-v6", "192.0.2.10"],
+            &["--outbound-interface", ""],
+            &["--hosts-override", "example.com"],
+            &["--hosts-override", "example.com=staging"],
+            &[
+                "--listen",
+                "127.0.0.1:3128",
+                "--hosts-override",
+                "a.test=127.0.0.1:3128",
+            ],
+        ]);
+    }
+    #[test]
+    fn test_connection_options() {
+        assert!(!args(&[]).unwrap().accept_proxy_protocol);
+        assert!(
+            args(&["--accept-proxy-protocol"])
+                .unwrap()
+                .accept_proxy_protocol
+        );
+        let config = args(&[
+            "--set-header",
+            "Via: proxy",
+            "--set-header",
+            "X-Id: {connection_id}",
+        ]);
+        let names: Vec<String> = config
+            .unwrap()
+            .set_headers
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, ["Via", "X-Id"]);
+        let retry = args(&["--connect-retries", "2", "--connect-retry-delay", "50"])
+            .unwrap()
+            .connect_retry();
+        assert_eq!((retry.retries, retry.delay), (2, Duration::from_millis(50)));
+        assert_invalid(&[
+            &["--auth", "alice"],
+            &["--connect-timeout", "0"],
+            &["--idle-timeout", "soon"],
+            &["--connect-retries", "-1"],
+            &["--connect-retry-delay", "1s"],
+            &["--set-header", "Host: example.com"],
+            &["--deny", "*.example.com,*:ssh"],
+            &["--upstream", "parent:3128"],
+        ]);
+    }
+    #[test]
+    fn test_inspection_options() {
+        assert!(!args(&[]).unwrap().decode_websocket);
+        assert!(args(&["--decode-websocket"]).unwrap().decode_websocket);
+        let config = args(&["--block-pattern", "hex:00ff", "--intercept-status", "451"]).unwrap();
+        assert_eq!(config.block_patterns, [vec![0x00, 0xff]]);
+        assert_eq!(config.intercept_status, 451);
+        assert_invalid(&[
+            &["--block-pattern", "deadbeef"],
+            &["--intercept-status", "200"],
+        ]);
+    }
+    #[test]
+    fn test_access_log_options() {
+        let config = args(&["--access-log", "access.log"]).unwrap();
+        assert_eq!(config.access_log, Some(PathBuf::from("access.log")));
+        assert_eq!(config.access_log_format, Some(access_log::Format::Clf));
+        let config = args(&["--access-log-format", "json", "--access-log", "a.log"]).unwrap();
+        assert_eq!(config.access_log_format, Some(access_log::Format::Json));
+        assert_invalid(&[&["--access-log"], &["--access-log-format", "xml"]]);
+    }
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("65536"), Some(65536));
+        assert_eq!(parse_size("512B"), Some(512));
+        assert_eq!(parse_size("64KiB"), Some(64 << 10));
+        assert_eq!(parse_size("100 MiB"), Some(100 << 20));
+        assert_eq!(parse_size("2GiB"), Some(2 << 30));
+        assert_eq!(parse_size("1TiB"), Some(1 << 40));
+        assert_eq!(parse_size("0"), Some(0));
+        for bad in [
+            "",
+            "MiB",
+            "1.5MiB",
+            "64kib",
+            "64K",
+            "1MB",
+            "-1",
+            "20000000TiB",
+        ] {
+            assert_eq!(parse_size(bad), None, "{bad}");
+        }
+    }
+    #[test]
+    fn test_config_file() {
+        let path = std::env::temp_dir().join(format!("proxy-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "listen = \"0.0.0.0:3128\"\nidle_timeout = 30\nno_record = true\ndeny = [\"a.test\"]\n",
+        )
+        .unwrap();
+        let file = path.to_str().unwrap();
+        let config = args(&["--idle-timeout", "5", "--config", file, "--deny", "b.test"]).unwrap();
+        assert_eq!(config.config_file.as_deref(), Some(path.as_path()));
+        assert_eq!(config.listen, "0.0.0.0:3128".parse().unwrap());
+        assert!(!config.record);
+        // The command line wins, wherever it names the file.
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(5)));
+        assert!(!config.host_filter.permits("a.test", 80));
+        assert!(!config.host_filter.permits("b.test", 80));
+        assert_eq!(config.args.len(), 6);
+        std::fs::write(&path, "idle_timeout = \"soon\"\n").unwrap();
+        let error = args(&["--config", file]).err().unwrap();
+        assert_eq!(error.to_string(), "invalid --idle-timeout value: soon");
+        std::fs::write(&path, "[proxy]\n").unwrap();
+        let error = args(&["--config", file]).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!("{file}:1: tables are not supported")
+        );
+        std::fs::remove_file(&path).unwrap();
+        let error = args(&["--config", file]).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert_invalid(&[&["--config"]]);
+    }
+}
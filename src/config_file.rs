@@ -0,0 +1,244 @@
+// `--config proxy.toml`: the command line's options as a TOML file, read
+// into the arguments they stand for, ahead of the command line's own. Each
+// key is an option's name without its dashes, underscores or dashes alike:
+// `idle_timeout = 30` is `--idle-timeout 30`. A string or number is the
+// option's value, `true` a flag given and `false` one left out, and an array
+// the option given once for each element. Options given in both places take
+// the command line's value, and lists such as `deny` get the entries of both.
+//
+// Only this flat subset of TOML is read: no tables, dates or inline tables.
+
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+/// The arguments `path` stands for.
+pub fn read(path: &Path) -> io::Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+    parse(&text).map_err(|(line, e)| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{}:{line}: {e}", path.display()),
+        )
+    })
+}
+
+// Errors come with their line number.
+fn parse(text: &str) -> Result<Vec<String>, (usize, String)> {
+    let mut args = vec![];
+    let mut lines = text.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            let e = match line.starts_with('[') {
+                true => "tables are not supported".to_string(),
+                false => format!("expected key = value, got {line}"),
+            };
+            return Err((i + 1, e));
+        };
+        let key = key.trim();
+        if key.is_empty()
+            || !key
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"_-".contains(&b))
+        {
+            return Err((i + 1, format!("invalid key: {key}")));
+        }
+        let option = format!("--{}", key.replace('_', "-"));
+        if option == "--config" {
+            return Err((i + 1, "a config file cannot name another".to_string()));
+        }
+        let mut text = value.trim().to_string();
+        // An array may go on over the lines after it.
+        let (value, rest) = loop {
+            match parse_value(&text) {
+                Err(e) if e == UNCLOSED => match lines.next() {
+                    Some((_, more)) => {
+                        text.push('\n');
+                        text.push_str(more);
+                    }
+                    None => return Err((i + 1, e)),
+                },
+                parsed => break parsed.map_err(|e| (i + 1, e))?,
+            }
+        };
+        let rest = rest.trim();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err((i + 1, format!("unexpected {rest} after the value")));
+        }
+        match value {
+            Value::Bool(true) => args.push(option),
+            Value::Bool(false) => {}
+            Value::Text(text) => args.extend([option, text]),
+            Value::Array(items) => {
+                for item in items {
+                    args.extend([option.clone(), item]);
+                }
+            }
+        }
+    }
+    Ok(args)
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Bool(bool),
+    // A string or a number, as the option reads it.
+    Text(String),
+    Array(Vec<String>),
+}
+
+const UNCLOSED: &str = "unclosed array";
+
+// The value at the start of `text`, and what follows it.
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    let Some(mut rest) = text.strip_prefix('[') else {
+        return match scalar(text)? {
+            (Scalar::Bool(b), rest) => Ok((Value::Bool(b), rest)),
+            (Scalar::Text(text), rest) => Ok((Value::Text(text), rest)),
+        };
+    };
+    let mut items = vec![];
+    loop {
+        rest = skip_space(rest);
+        if let Some(after) = rest.strip_prefix(']') {
+            return Ok((Value::Array(items), after));
+        }
+        if rest.is_empty() {
+            return Err(UNCLOSED.to_string());
+        }
+        match scalar(rest)? {
+            (Scalar::Text(text), after) => {
+                items.push(text);
+                rest = skip_space(after);
+            }
+            (Scalar::Bool(_), _) => return Err("arrays hold strings and numbers".to_string()),
+        }
+        match rest.strip_prefix(',') {
+            Some(after) => rest = after,
+            None if rest.starts_with(']') => {}
+            None if rest.is_empty() => return Err(UNCLOSED.to_string()),
+            None => return Err("expected , or ] in the array".to_string()),
+        }
+    }
+}
+
+// Past whitespace, line breaks and comments, in an array.
+fn skip_space(mut text: &str) -> &str {
+    loop {
+        text = text.trim_start();
+        match text.strip_prefix('#') {
+            Some(comment) => text = comment.find('\n').map_or("", |end| &comment[end..]),
+            None => return text,
+        }
+    }
+}
+
+enum Scalar {
+    Bool(bool),
+    Text(String),
+}
+
+// A string, number or boolean at the start of `text`.
+fn scalar(text: &str) -> Result<(Scalar, &str), String> {
+    if let Some(body) = text.strip_prefix('\'') {
+        let end = body.find('\'').ok_or("unclosed string")?;
+        return Ok((Scalar::Text(body[..end].to_string()), &body[end + 1..]));
+    }
+    if let Some(body) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = body.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Scalar::Text(value), &body[i + 1..])),
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    _ => return Err("unsupported escape in string".to_string()),
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("unclosed string".to_string());
+    }
+    let end = text
+        .find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '#')
+        .unwrap_or(text.len());
+    let (token, rest) = text.split_at(end);
+    match token {
+        "true" => Ok((Scalar::Bool(true), rest)),
+        "false" => Ok((Scalar::Bool(false), rest)),
+        // Numbers as TOML writes them, digit separators aside.
+        _ if !token.is_empty()
+            && token
+                .bytes()
+                .all(|b| b.is_ascii_digit() || b"+-._".contains(&b)) =>
+        {
+            Ok((Scalar::Text(token.replace('_', "")), rest))
+        }
+        _ => Err(format!("invalid value: {token}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse() {
+        let text = r#"
+            # The listener.
+            listen = "127.0.0.1:3128"
+            idle-timeout = 30  # seconds
+            limit_up = 1_000_000
+            no_record = true
+            decode_websocket = false
+            deny = ["*.example.com", '10.0.0.0/8']
+            allow = [
+                "a.test",  # first
+                "b.test",
+            ]
+            set_header = "Via: 1.1 \"proxy\""
+        "#;
+        assert_eq!(
+            parse(text).unwrap(),
+            [
+                "--listen",
+                "127.0.0.1:3128",
+                "--idle-timeout",
+                "30",
+                "--limit-up",
+                "1000000",
+                "--no-record",
+                "--deny",
+                "*.example.com",
+                "--deny",
+                "10.0.0.0/8",
+                "--allow",
+                "a.test",
+                "--allow",
+                "b.test",
+                "--set-header",
+                "Via: 1.1 \"proxy\"",
+            ]
+        );
+        for (text, line) in [
+            ("[proxy]", 1),
+            ("listen 127.0.0.1:3128", 1),
+            ("\nlisten = localhost", 2),
+            ("listen = \"127.0.0.1", 1),
+            ("deny = [\"a\" \"b\"]", 1),
+            ("deny = [\"a\",\n", 1),
+            ("deny = [true]", 1),
+            ("config = \"other.toml\"", 1),
+            ("listen = \"a\" \"b\"", 1),
+            ("bad key = 1", 1),
+        ] {
+            assert_eq!(parse(text).map_err(|(line, _)| line), Err(line), "{text}");
+        }
+    }
+}
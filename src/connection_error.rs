@@ -0,0 +1,557 @@
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::log;
+
+/// Where in the pipeline a connection failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    HeaderRead,
+    Resolve,
+    Connect,
+    TunnelC2s,
+    TunnelS2c,
+    Shutdown,
+    Record,
+    Idle,
+    Upstream,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::HeaderRead => "header_read",
+            Stage::Resolve => "resolve",
+            Stage::Connect => "connect",
+            Stage::TunnelC2s => "tunnel_c2s",
+            Stage::TunnelS2c => "tunnel_s2c",
+            Stage::Shutdown => "shutdown",
+            Stage::Record => "record",
+            Stage::Idle => "idle",
+            Stage::Upstream => "upstream",
+        }
+    }
+}
+
+/// One side of a connection: the client, or the target it connects to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    Client,
+    Target,
+}
+
+/// Why a tunnel was torn down, given whose socket failed and how.
+pub fn close_reason(peer: Peer, kind: ErrorKind) -> &'static str {
+    let reset = matches!(kind, ErrorKind::ConnectionReset | ErrorKind::BrokenPipe);
+    match (peer, reset, kind == ErrorKind::TimedOut) {
+        (Peer::Client, true, _) => "client_reset",
+        (Peer::Target, true, _) => "target_reset",
+        (Peer::Client, _, true) => "client_timeout",
+        (Peer::Target, _, true) => "target_timeout",
+        (Peer::Client, _, _) => "client_error",
+        (Peer::Target, _, _) => "target_error",
+    }
+}
+
+pub fn classify(kind: ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::NotFound => "not_found",
+        ErrorKind::ConnectionRefused => "connection_refused",
+        ErrorKind::ConnectionReset => "connection_reset",
+        ErrorKind::ConnectionAborted => "connection_aborted",
+        ErrorKind::NotConnected => "not_connected",
+        ErrorKind::BrokenPipe => "broken_pipe",
+        ErrorKind::TimedOut => "timed_out",
+        ErrorKind::UnexpectedEof => "unexpected_eof",
+        ErrorKind::InvalidInput | ErrorKind::InvalidData => "invalid_request",
+        ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => "unreachable",
+        ErrorKind::AddrNotAvailable => "addr_not_available",
+        _ => "other",
+    }
+}
+
+/// Why a connection was refused or could not reach its target, with what
+/// the client is answered and the logs say. It travels inside an io::Error,
+/// as ConnectionError does, and `proxy_error_of` finds it again. Failures
+/// inside a tunnel have nothing left to answer and stay plain io::Errors.
+#[derive(Debug)]
+pub enum ProxyError {
+    MalformedRequest {
+        line: String,
+        reason: String,
+    },
+    UnsupportedMethod(String),
+    // A request head beyond the `--max-head-size` or `--max-headers` limit.
+    HeadTooLarge {
+        max_bytes: usize,
+        max_headers: usize,
+    },
+    // No whole request head within the `--header-timeout`.
+    HeaderTimeout(Duration),
+    TargetResolve {
+        host: String,
+        source: io::Error,
+    },
+    // `addr` is the authority dialed, or `unix:` and a socket's path.
+    TargetConnect {
+        addr: String,
+        source: io::Error,
+    },
+    // `reason` is the close reason logged: the host filter or the policy.
+    PolicyDenied {
+        host: String,
+        status: u16,
+        reason: &'static str,
+    },
+}
+
+impl ProxyError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProxyError::MalformedRequest { .. } => "malformed_request",
+            ProxyError::UnsupportedMethod(_) => "unsupported_method",
+            ProxyError::HeadTooLarge { .. } => "head_too_large",
+            ProxyError::HeaderTimeout(_) => "header_timeout",
+            ProxyError::TargetResolve { .. } => "target_resolve",
+            ProxyError::TargetConnect { .. } => "target_connect",
+            ProxyError::PolicyDenied { .. } => "policy_denied",
+        }
+    }
+
+    /// The HTTP status the client is answered with.
+    pub fn status(&self) -> u16 {
+        match self {
+            ProxyError::MalformedRequest { .. } => 400,
+            ProxyError::UnsupportedMethod(_) => 405,
+            ProxyError::HeadTooLarge { .. } => 431,
+            ProxyError::HeaderTimeout(_) => 408,
+            ProxyError::PolicyDenied { status, .. } => *status,
+            ProxyError::TargetResolve { source, .. } | ProxyError::TargetConnect { source, .. } => {
+                match source.kind() {
+                    ErrorKind::TimedOut => 504,
+                    // Refused locally, such as by a firewall rule.
+                    ErrorKind::PermissionDenied => 403,
+                    _ => 502,
+                }
+            }
+        }
+    }
+
+    /// The body of the answer, which `reason` is the reason phrase of.
+    pub fn body(&self, reason: &str) -> String {
+        match self {
+            ProxyError::MalformedRequest { reason, .. } => format!("Bad Request: {reason}\n"),
+            ProxyError::UnsupportedMethod(_) => "Method Not Allowed".to_string(),
+            ProxyError::HeadTooLarge { .. } | ProxyError::HeaderTimeout(_) => format!("{self}\n"),
+            ProxyError::PolicyDenied { reason, .. } => match *reason {
+                "host_filter" => "Destination not allowed\n",
+                "policy_deny" => "Blocked by policy",
+                _ => "No policy rule allows this destination",
+            }
+            .to_string(),
+            ProxyError::TargetResolve { .. } | ProxyError::TargetConnect { .. } => {
+                format!("{reason}\n")
+            }
+        }
+    }
+
+    fn kind(&self) -> ErrorKind {
+        match self {
+            ProxyError::MalformedRequest { .. } => ErrorKind::InvalidData,
+            ProxyError::UnsupportedMethod(_) => ErrorKind::InvalidInput,
+            ProxyError::HeadTooLarge { .. } => ErrorKind::InvalidData,
+            ProxyError::HeaderTimeout(_) => ErrorKind::TimedOut,
+            ProxyError::PolicyDenied { .. } => ErrorKind::PermissionDenied,
+            ProxyError::TargetResolve { source, .. } | ProxyError::TargetConnect { source, .. } => {
+                source.kind()
+            }
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::MalformedRequest { line, reason } => {
+                write!(f, "malformed request line: {reason}: {line}")
+            }
+            ProxyError::UnsupportedMethod(method) => write!(f, "unsupported method {method}"),
+            ProxyError::HeadTooLarge {
+                max_bytes,
+                max_headers,
+            } => write!(
+                f,
+                "request head over {max_bytes} bytes or {max_headers} headers"
+            ),
+            ProxyError::HeaderTimeout(timeout) => {
+                write!(f, "no complete request head within {timeout:?}")
+            }
+            ProxyError::TargetResolve { host, source } => write!(f, "resolving {host}: {source}"),
+            ProxyError::TargetConnect { addr, source } => {
+                write!(f, "connecting to {addr}: {source}")
+            }
+            ProxyError::PolicyDenied { host, reason, .. } => {
+                write!(f, "{host} denied: close_reason={reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProxyError::TargetResolve { source, .. } | ProxyError::TargetConnect { source, .. } => {
+                Some(source)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<ProxyError> for io::Error {
+    fn from(error: ProxyError) -> Self {
+        io::Error::new(error.kind(), error)
+    }
+}
+
+/// The ProxyError in `error`, whether it is one or a ConnectionError's.
+pub fn proxy_error_of(error: &io::Error) -> Option<&ProxyError> {
+    let inner = error.get_ref()?;
+    if let Some(error) = inner.downcast_ref::<ProxyError>() {
+        return Some(error);
+    }
+    let source = &inner.downcast_ref::<ConnectionError>()?.source;
+    source.get_ref()?.downcast_ref::<ProxyError>()
+}
+
+/// The last bytes each direction of a failed tunnel carried, up to the
+/// configured tail size.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tails {
+    // Client to target.
+    pub up: Vec<u8>,
+    pub down: Vec<u8>,
+}
+
+/// The error of a failed connection together with where in the pipeline it
+/// failed and what was known about the connection at that point.
+#[derive(Debug)]
+pub struct ConnectionError {
+    pub stage: Stage,
+    pub client: SocketAddr,
+    pub target: Option<String>,
+    // Where a rewrite rule sent the connection instead of `target`.
+    pub rewritten_to: Option<String>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    // Set when a failing tunnel was torn down.
+    pub close_reason: Option<&'static str>,
+    // Empty unless a tunnel was torn down.
+    pub tails: Tails,
+    // How long the request took to read and the target to connect to, as
+    // far as the connection got.
+    pub request_read: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub source: io::Error,
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "stage={} client={} target={}{} bytes_up={} bytes_down={}{}{} kind={} error=\"{}\"",
+            self.stage.as_str(),
+            self.client,
+            self.target.as_deref().unwrap_or("-"),
+            self.rewritten_to
+                .as_ref()
+                .map_or(String::new(), |to| format!(" rewritten_to={to}")),
+            self.bytes_up,
+            self.bytes_down,
+            self.close_reason
+                .map_or(String::new(), |reason| format!(" close_reason={reason}")),
+            [
+                ("request_read", self.request_read),
+                ("connect", self.connect)
+            ]
+            .iter()
+            .filter_map(|(name, took)| Some(format!(" {name}={}", millis((*took)?))))
+            .collect::<String>(),
+            classify(self.source.kind()),
+            self.source
+        )
+    }
+}
+
+/// `12.3ms`, as the logs write durations.
+pub fn millis(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
+
+impl ConnectionError {
+    /// The connection error inside an error the proxy returned, if it is one.
+    pub fn of(error: &io::Error) -> Option<&ConnectionError> {
+        error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<ConnectionError>())
+    }
+}
+
+impl std::error::Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+pub struct ConnectionContext {
+    pub client: SocketAddr,
+    pub target: Option<String>,
+    pub rewritten_to: Option<String>,
+    // From the accept, or from the next request's arrival on a kept
+    // connection.
+    pub started: Instant,
+    // Which request of the connection this is, from 0.
+    pub request: u64,
+    // Set once the request is read and once the target is connected to.
+    pub request_read: Option<Duration>,
+    pub connect: Option<Duration>,
+}
+
+impl ConnectionContext {
+    pub fn new(client: SocketAddr) -> Self {
+        Self {
+            client,
+            target: None,
+            rewritten_to: None,
+            started: Instant::now(),
+            request: 0,
+            request_read: None,
+            connect: None,
+        }
+    }
+
+    pub fn fail(&self, stage: Stage, error: io::Error) -> io::Error {
+        self.fail_after(stage, error, 0, 0)
+    }
+
+    /// Emits the error event for this connection and returns an io::Error
+    /// wrapping the `ConnectionError`, so callers can still inspect the stage.
+    pub fn fail_after(
+        &self,
+        stage: Stage,
+        error: io::Error,
+        bytes_up: u64,
+        bytes_down: u64,
+    ) -> io::Error {
+        self.emit(ConnectionError {
+            stage,
+            client: self.client,
+            target: self.target.clone(),
+            rewritten_to: self.rewritten_to.clone(),
+            bytes_up,
+            bytes_down,
+            close_reason: None,
+            tails: Tails::default(),
+            request_read: self.request_read,
+            connect: self.connect,
+            source: error,
+        })
+    }
+
+    /// Like `fail_after`, for a tunnel torn down because `peer`'s socket
+    /// failed, with what it carried last.
+    pub fn fail_tunnel(
+        &self,
+        stage: Stage,
+        peer: Peer,
+        error: io::Error,
+        bytes_up: u64,
+        bytes_down: u64,
+        tails: Tails,
+    ) -> io::Error {
+        self.emit(ConnectionError {
+            stage,
+            client: self.client,
+            target: self.target.clone(),
+            rewritten_to: self.rewritten_to.clone(),
+            bytes_up,
+            bytes_down,
+            close_reason: Some(close_reason(peer, error.kind())),
+            tails,
+            request_read: self.request_read,
+            connect: self.connect,
+            source: error,
+        })
+    }
+
+    /// For a tunnel torn down because neither peer sent anything for too
+    /// long.
+    pub fn fail_idle(
+        &self,
+        error: io::Error,
+        bytes_up: u64,
+        bytes_down: u64,
+        tails: Tails,
+    ) -> io::Error {
+        self.emit(ConnectionError {
+            stage: Stage::Idle,
+            client: self.client,
+            target: self.target.clone(),
+            rewritten_to: self.rewritten_to.clone(),
+            bytes_up,
+            bytes_down,
+            close_reason: Some("idle_timeout"),
+            tails,
+            request_read: self.request_read,
+            connect: self.connect,
+            source: error,
+        })
+    }
+
+    fn emit(&self, error: ConnectionError) -> io::Error {
+        log::error!("connection error: {}", error);
+        io::Error::new(error.source.kind(), error)
+    }
+}
+
+pub fn stage_of(error: &io::Error) -> Option<Stage> {
+    ConnectionError::of(error).map(|error| error.stage)
+}
+
+/// The bytes forwarded up and down before the connection failed.
+pub fn bytes_of(error: &io::Error) -> Option<(u64, u64)> {
+    ConnectionError::of(error).map(|error| (error.bytes_up, error.bytes_down))
+}
+
+/// What a failed tunnel carried last in each direction.
+pub fn tails_of(error: &io::Error) -> Option<&Tails> {
+    ConnectionError::of(error).map(|error| &error.tails)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_display_contains_context() {
+        let mut ctx = ConnectionContext::new("127.0.0.1:5000".parse().unwrap());
+        ctx.target = Some("example.com:443".to_string());
+        let error = ctx.fail_after(
+            Stage::TunnelS2c,
+            io::Error::new(ErrorKind::ConnectionReset, "reset by peer"),
+            10,
+            20,
+        );
+        assert_eq!(
+            error.get_ref().unwrap().to_string(),
+            "stage=tunnel_s2c client=127.0.0.1:5000 target=example.com:443 bytes_up=10 \
+             bytes_down=20 kind=connection_reset error=\"reset by peer\""
+        );
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+        assert_eq!(stage_of(&error), Some(Stage::TunnelS2c));
+        ctx.rewritten_to = Some("staging.example.com:8443".to_string());
+        let error = ctx.fail(
+            Stage::Connect,
+            io::Error::from(ErrorKind::ConnectionRefused),
+        );
+        assert!(
+            error
+                .get_ref()
+                .unwrap()
+                .to_string()
+                .contains("target=example.com:443 rewritten_to=staging.example.com:8443 ")
+        );
+        // A connect that fails says how long the request took to read.
+        ctx.request_read = Some(Duration::from_micros(2500));
+        let error = ctx.fail(Stage::Connect, io::Error::from(ErrorKind::TimedOut));
+        assert!(
+            error
+                .get_ref()
+                .unwrap()
+                .to_string()
+                .contains(" bytes_down=0 request_read=2.5ms kind=timed_out ")
+        );
+    }
+    #[test]
+    fn test_proxy_error() {
+        let source = io::Error::new(ErrorKind::TimedOut, "no connection within 10s");
+        let error = ProxyError::TargetConnect {
+            addr: "[::1]:8443".to_string(),
+            source,
+        };
+        assert_eq!(
+            error.to_string(),
+            "connecting to [::1]:8443: no connection within 10s"
+        );
+        assert_eq!(error.status(), 504);
+        let ctx = ConnectionContext::new("127.0.0.1:5000".parse().unwrap());
+        let error = ctx.fail(Stage::Connect, error.into());
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(error.get_ref().unwrap().to_string().contains("[::1]:8443"));
+        assert_eq!(proxy_error_of(&error).unwrap().as_str(), "target_connect");
+        for (error, status) in [
+            (
+                ProxyError::MalformedRequest {
+                    line: "GET".to_string(),
+                    reason: "missing target".to_string(),
+                },
+                400,
+            ),
+            (ProxyError::UnsupportedMethod("BREW".to_string()), 405),
+            (
+                ProxyError::PolicyDenied {
+                    host: "example.com:443".to_string(),
+                    status: 451,
+                    reason: "policy_deny",
+                },
+                451,
+            ),
+            (
+                ProxyError::TargetResolve {
+                    host: "example.com".to_string(),
+                    source: io::Error::other("no such host"),
+                },
+                502,
+            ),
+        ] {
+            assert_eq!(error.status(), status, "{error}");
+        }
+        assert_eq!(proxy_error_of(&io::Error::other("plain")).map(|_| ()), None);
+    }
+    #[test]
+    fn test_close_reason() {
+        let ctx = ConnectionContext::new("127.0.0.1:5000".parse().unwrap());
+        let error = ctx.fail_tunnel(
+            Stage::TunnelS2c,
+            Peer::Target,
+            io::Error::from(ErrorKind::ConnectionReset),
+            1,
+            2,
+            Tails {
+                up: b"GET".to_vec(),
+                down: vec![],
+            },
+        );
+        assert!(
+            error
+                .get_ref()
+                .unwrap()
+                .to_string()
+                .contains(" bytes_down=2 close_reason=target_reset kind=connection_reset ")
+        );
+        assert_eq!(tails_of(&error).unwrap().up, b"GET");
+        assert_eq!(
+            close_reason(Peer::Client, ErrorKind::BrokenPipe),
+            "client_reset"
+        );
+        assert_eq!(
+            close_reason(Peer::Target, ErrorKind::TimedOut),
+            "target_timeout"
+        );
+        assert_eq!(
+            close_reason(Peer::Client, ErrorKind::UnexpectedEof),
+            "client_error"
+        );
+    }
+}
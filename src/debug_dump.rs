@@ -0,0 +1,107 @@
+// Opt-in per-connection debug traces: one text file per connection with what
+// was parsed, decided and forwarded, each line stamped with the time since
+// the connection was accepted.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::log;
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+enum State {
+    // Not created until the first event.
+    Pending(PathBuf),
+    Open(BufWriter<File>),
+    Failed,
+}
+
+pub struct Dump {
+    start: Instant,
+    // None when dumps are off.
+    state: Option<Mutex<State>>,
+}
+
+impl Dump {
+    pub fn disabled() -> Self {
+        Self {
+            start: Instant::now(),
+            state: None,
+        }
+    }
+
+    pub fn new(dir: &Path, client: SocketAddr) -> Self {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        let client = client.to_string().replace([':', '[', ']'], "_");
+        let path = dir.join(format!("{millis}-{sequence}-{client}.txt"));
+        Self {
+            start: Instant::now(),
+            state: Some(Mutex::new(State::Pending(path))),
+        }
+    }
+
+    /// Appends a line to the dump. `line` is only called when dumps are on.
+    pub fn event(&self, line: impl FnOnce() -> String) {
+        let Some(state) = &self.state else {
+            return;
+        };
+        let elapsed = self.start.elapsed();
+        let mut state = state.lock().unwrap();
+        if let State::Pending(path) = &*state {
+            *state = match File::create(path) {
+                Ok(file) => State::Open(BufWriter::new(file)),
+                Err(e) => {
+                    log::warn!("Debug dump {} failed: {e}", path.display());
+                    State::Failed
+                }
+            };
+        }
+        if let State::Open(file) = &mut *state {
+            let millis = elapsed.as_secs_f64() * 1000.0;
+            if let Err(e) = writeln!(file, "+{millis:.3}ms {}", line()) {
+                log::warn!("Debug dump write failed: {e}");
+                *state = State::Failed;
+            }
+        }
+    }
+}
+
+impl Drop for Dump {
+    fn drop(&mut self) {
+        if let Some(state) = &mut self.state
+            && let State::Open(file) = state.get_mut().unwrap()
+            && let Err(e) = file.flush()
+        {
+            log::warn!("Debug dump write failed: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_file_is_created_lazily() {
+        let dir = std::env::temp_dir().join(format!("proxy-dump-lazy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dump = Dump::new(&dir, "[::1]:5000".parse().unwrap());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        dump.event(|| "first".to_string());
+        drop(dump);
+        let entry = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap();
+        let contents = std::fs::read_to_string(entry.path()).unwrap();
+        assert!(contents.starts_with('+') && contents.ends_with("ms first\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        Dump::disabled().event(|| unreachable!("formatted with dumps off"));
+    }
+}
@@ -0,0 +1,405 @@
+// `--fault-*`: faults injected into tunnels on purpose, to see how clients
+// cope with a bad network. A tunnel may be closed partway through, have each
+// chunk held back for a while, slow down once it has carried a given amount,
+// or have bytes flipped on their way; all of it is off unless asked for.
+//
+// Each request draws its faults from a generator seeded with the
+// `--fault-seed`, the connection's id and the request's index on the
+// connection (0 for the first), and each direction from one of its own, so
+// the same seed gives the same requests the same faults at the same byte
+// offsets however their reads are split. Every fault is logged with the
+// connection's id and the request's index, the seed once at startup, and
+// each request's seed as its tunnel is established.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::log;
+
+// A tunnel picked for `--fault-close-prob` is closed within this many bytes
+// of one of its directions.
+pub const CLOSE_WITHIN: u64 = 64 * 1024;
+
+/// `--fault-delay`: how long each chunk is held back, give or take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delay {
+    pub base: Duration,
+    pub jitter: Duration,
+}
+
+impl Delay {
+    /// `200ms`, `1.5s`, or either with a jitter: `200ms±100ms`, `200ms+-100ms`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let (base, jitter) = match text.split_once('±').or_else(|| text.split_once("+-")) {
+            Some((base, jitter)) => (base, Some(jitter)),
+            None => (text, None),
+        };
+        let base = parse_duration(base.trim())?;
+        let jitter = match jitter {
+            Some(jitter) => parse_duration(jitter.trim())?,
+            None => Duration::ZERO,
+        };
+        (jitter <= base).then_some(Self { base, jitter })
+    }
+}
+
+// `250ms`, `2s` or `0.5s`.
+fn parse_duration(text: &str) -> Option<Duration> {
+    let (number, scale) = match text.strip_suffix("ms") {
+        Some(number) => (number, 1e-3),
+        None => (text.strip_suffix('s')?, 1.0),
+    };
+    let secs = number.parse::<f64>().ok()? * scale;
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// `--fault-throttle-after`: a bandwidth each direction drops to once it
+/// has carried `bytes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThrottleAfter {
+    pub bytes: u64,
+    // Bytes per second.
+    pub rate: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Faults {
+    // None picks one at startup, which is logged.
+    pub seed: Option<u64>,
+    // The share of connections closed partway through.
+    pub close_prob: f64,
+    pub delay: Option<Delay>,
+    pub throttle_after: Option<ThrottleAfter>,
+    // The chance of each byte being flipped.
+    pub corrupt_prob: f64,
+}
+
+impl Faults {
+    pub fn enabled(&self) -> bool {
+        self.close_prob > 0.0
+            || self.delay.is_some()
+            || self.throttle_after.is_some()
+            || self.corrupt_prob > 0.0
+    }
+
+    /// These faults with a seed, picking and logging one if none was given.
+    pub fn seeded(&self) -> Self {
+        let seed = self
+            .seed
+            .unwrap_or_else(|| RandomState::new().build_hasher().finish());
+        log::info!("Injecting faults with --fault-seed {seed}");
+        Self {
+            seed: Some(seed),
+            ..self.clone()
+        }
+    }
+
+    /// The injector for the tunnel of request `request` on connection `id`,
+    /// which logs the faults drawn for it.
+    pub fn injector(&self, id: u64, request: u64) -> Injector {
+        let seed = self.seed.unwrap_or_default();
+        // The connection's seed, then the request's: the same requests on
+        // different connections draw apart, as do a connection's requests.
+        let connection_seed = Rng(seed ^ id.wrapping_mul(GOLDEN)).next();
+        let request_seed = connection_seed ^ request.wrapping_mul(GOLDEN);
+        let mut rng = Rng(request_seed);
+        let close = rng
+            .chance(self.close_prob)
+            .then(|| (rng.below(2) == 0, rng.below(CLOSE_WITHIN)));
+        let mut direction = |up: bool| {
+            let mut rng = Rng(rng.next());
+            let next_corrupt = (self.corrupt_prob > 0.0).then(|| rng.gap(self.corrupt_prob));
+            Mutex::new(Direction {
+                jitter: Rng(rng.next()),
+                rng,
+                offset: 0,
+                close_at: close
+                    .filter(|(close_up, _)| *close_up == up)
+                    .map(|(_, at)| at),
+                next_corrupt,
+                throttled: false,
+            })
+        };
+        let directions = [direction(true), direction(false)];
+        let injector = Injector {
+            id,
+            request,
+            faults: self.clone(),
+            directions,
+        };
+        log::info!(
+            "Faults for connection {id} request {request}: {injector} \
+             (seed {request_seed:#x} from --fault-seed {seed})"
+        );
+        injector
+    }
+}
+
+/// What the tunnel does with a chunk the injector has seen.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Effect {
+    // How long to hold the chunk back before writing it.
+    pub delay: Duration,
+    // The bytes of the chunk to write before the tunnel is closed, and the
+    // direction's offset it is closed at.
+    pub close: Option<(usize, u64)>,
+}
+
+struct Direction {
+    // By byte offset, for the corruption; and by chunk, for the jitter,
+    // which then leaves the other alone.
+    rng: Rng,
+    jitter: Rng,
+    // Bytes offered so far.
+    offset: u64,
+    close_at: Option<u64>,
+    next_corrupt: Option<u64>,
+    throttled: bool,
+}
+
+/// One tunnel's faults.
+pub struct Injector {
+    id: u64,
+    request: u64,
+    faults: Faults,
+    // Client to target, then target to client.
+    directions: [Mutex<Direction>; 2],
+}
+
+impl Injector {
+    /// Applies the faults due in `chunk`, `up` being client to target,
+    /// flipping its bytes in place.
+    pub fn apply(&self, up: bool, chunk: &mut [u8]) -> Effect {
+        let (id, request) = (self.id, self.request);
+        let name = if up { "c2s" } else { "s2c" };
+        let mut direction = self.directions[usize::from(!up)].lock().unwrap();
+        let Direction {
+            rng,
+            jitter,
+            offset,
+            close_at,
+            next_corrupt,
+            throttled,
+        } = &mut *direction;
+        let start = *offset;
+        let mut effect = Effect::default();
+        if let Some(at) = *close_at
+            && at < start + chunk.len() as u64
+        {
+            log::info!("Fault on connection {id} request {request}: closing {name} at byte {at}");
+            effect.close = Some(((at - start) as usize, at));
+        }
+        let chunk = match effect.close {
+            Some((keep, _)) => &mut chunk[..keep],
+            None => chunk,
+        };
+        let end = start + chunk.len() as u64;
+        while let Some(at) = next_corrupt.filter(|at| *at < end) {
+            chunk[(at - start) as usize] ^= 0xff;
+            log::info!("Fault on connection {id} request {request}: flipped {name} byte {at}");
+            *next_corrupt = Some(at + 1 + rng.gap(self.faults.corrupt_prob));
+        }
+        if let Some(delay) = self.faults.delay {
+            let jitter = delay.jitter.as_secs_f64() * (2.0 * jitter.unit() - 1.0);
+            effect.delay = Duration::from_secs_f64(delay.base.as_secs_f64() + jitter);
+            log::trace!(
+                "Fault on connection {id} request {request}: {name} chunk held back {effect:?}"
+            );
+        }
+        if let Some(throttle) = self.faults.throttle_after
+            && end > throttle.bytes
+        {
+            if !*throttled {
+                *throttled = true;
+                log::info!(
+                    "Fault on connection {id} request {request}: throttling {name} to {} bytes/s after byte {}",
+                    throttle.rate,
+                    throttle.bytes
+                );
+            }
+            let over = end - start.max(throttle.bytes);
+            effect.delay += Duration::from_secs_f64(over as f64 / throttle.rate as f64);
+        }
+        *offset = end;
+        effect
+    }
+}
+
+// "close=c2s@1234 delay=200ms±100ms throttle_after=1048576@10240/s
+// corrupt=0.001", or "none".
+impl fmt::Display for Injector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        for (name, direction) in ["c2s", "s2c"].iter().zip(&self.directions) {
+            if let Some(at) = direction.lock().unwrap().close_at {
+                parts.push(format!("close={name}@{at}"));
+            }
+        }
+        if let Some(delay) = self.faults.delay {
+            parts.push(format!("delay={:?}±{:?}", delay.base, delay.jitter));
+        }
+        if let Some(throttle) = self.faults.throttle_after {
+            parts.push(format!(
+                "throttle_after={}@{}/s",
+                throttle.bytes, throttle.rate
+            ));
+        }
+        if self.faults.corrupt_prob > 0.0 {
+            parts.push(format!("corrupt={}", self.faults.corrupt_prob));
+        }
+        match parts.is_empty() {
+            true => f.write_str("none"),
+            false => f.write_str(&parts.join(" ")),
+        }
+    }
+}
+
+const GOLDEN: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// SplitMix64: small, fast, and the same on every platform.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(GOLDEN);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // In [0, 1).
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        self.unit() < p
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    // How many bytes pass before the next one hit with chance `p` each.
+    fn gap(&mut self, p: f64) -> u64 {
+        if p >= 1.0 {
+            return 0;
+        }
+        ((1.0 - self.unit()).ln() / (1.0 - p).ln()) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn faults(close_prob: f64, corrupt_prob: f64) -> Faults {
+        Faults {
+            seed: Some(42),
+            close_prob,
+            corrupt_prob,
+            ..Faults::default()
+        }
+    }
+    // Whether client to target, and the byte.
+    type Offsets = Vec<(bool, u64)>;
+    // Offers `total` bytes of each direction in chunks of `size`, and gives
+    // back where a direction was closed and which bytes were flipped.
+    fn run(injector: &Injector, total: usize, size: usize) -> (Offsets, Offsets) {
+        let (mut closes, mut flips) = (vec![], vec![]);
+        for up in [true, false] {
+            let mut offset = 0;
+            while offset < total {
+                let mut chunk = vec![0; size.min(total - offset)];
+                let effect = injector.apply(up, &mut chunk);
+                for (i, _) in chunk.iter().enumerate().filter(|(_, b)| **b == 0xff) {
+                    flips.push((up, (offset + i) as u64));
+                }
+                if let Some((keep, at)) = effect.close {
+                    assert_eq!(at, (offset + keep) as u64);
+                    closes.push((up, at));
+                    break;
+                }
+                offset += chunk.len();
+            }
+        }
+        (closes, flips)
+    }
+    #[test]
+    fn test_same_seed_same_faults_however_split() {
+        let total = 2 * CLOSE_WITHIN as usize;
+        let faults = faults(1.0, 0.0005);
+        let events = run(&faults.injector(7, 0), total, 1000);
+        assert_eq!(events.0.len(), 1, "{events:?}");
+        assert!(events.0[0].1 < CLOSE_WITHIN);
+        assert!(!events.1.is_empty());
+        for size in [1, 333, 64 * 1024] {
+            assert_eq!(run(&faults.injector(7, 0), total, size), events);
+        }
+        // Another connection, request on it, or seed draws other faults.
+        assert_ne!(run(&faults.injector(8, 0), total, 1000), events);
+        let next = run(&faults.injector(7, 1), total, 1000);
+        assert_ne!(next, events);
+        assert_ne!(run(&faults.injector(6, 1), total, 1000), next);
+        let other = Faults {
+            seed: Some(43),
+            ..faults.clone()
+        };
+        assert_ne!(run(&other.injector(7, 0), total, 1000), events);
+        // Nothing is cut or flipped that was not asked for.
+        assert_eq!(
+            run(&self::faults(0.0, 0.0).injector(7, 0), total, 4096),
+            (vec![], vec![])
+        );
+        let (closes, _) = run(&self::faults(0.0, 0.5).injector(7, 0), total, 4096);
+        assert_eq!(closes, []);
+    }
+    #[test]
+    fn test_delay_and_throttle() {
+        let faults = Faults {
+            seed: Some(1),
+            delay: Delay::parse("200ms±100ms"),
+            throttle_after: Some(ThrottleAfter {
+                bytes: 1000,
+                rate: 100,
+            }),
+            ..Faults::default()
+        };
+        let injector = faults.injector(1, 0);
+        for _ in 0..100 {
+            let delay = injector.apply(true, &mut [0; 10]).delay;
+            assert!((100..=300).contains(&delay.as_millis()), "{delay:?}");
+        }
+        // Past byte 1000, 500 bytes more at 100 bytes/s.
+        let delay = injector.apply(true, &mut [0; 500]).delay;
+        assert!((5100..=5300).contains(&delay.as_millis()), "{delay:?}");
+        // The other direction has carried nothing yet.
+        let delay = injector.apply(false, &mut [0; 10]).delay;
+        assert!(delay < Duration::from_millis(300), "{delay:?}");
+    }
+    #[test]
+    fn test_parse_delay() {
+        let ms = Duration::from_millis;
+        assert_eq!(
+            Delay::parse("200ms±100ms"),
+            Some(Delay {
+                base: ms(200),
+                jitter: ms(100)
+            })
+        );
+        assert_eq!(
+            Delay::parse("1.5s +- 0.5s"),
+            Some(Delay {
+                base: ms(1500),
+                jitter: ms(500)
+            })
+        );
+        assert_eq!(Delay::parse("20ms").unwrap().jitter, Duration::ZERO);
+        for bad in ["", "200", "ms", "-1s", "100ms±200ms", "1m"] {
+            assert_eq!(Delay::parse(bad), None, "{bad}");
+        }
+    }
+}
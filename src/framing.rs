@@ -0,0 +1,756 @@
+// Where a forwarded request and its response end (RFC 9112 section 6), so
+// that the client's connection can carry another request after them. Each
+// request still gets a connection to the origin of its own, which is asked
+// to close; it is the client's connection that is kept. The client side of
+// the tunnel reads only the request's body, and the origin side ends with
+// the response, whose head says whether the client may send another.
+
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::headers::Headers;
+use crate::http_reader::HttpReader;
+
+// The longest response head accepted.
+const MAX_HEAD: usize = 64 * 1024;
+const READ_SIZE: usize = 8 * 1024;
+
+/// How a request's body is framed on the client's connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestBody {
+    // From its Content-Length, 0 without one.
+    Length(u64),
+    Chunked,
+}
+
+/// The framing of a request's body: chunked when its Transfer-Encoding ends
+/// in chunked, by its Content-Length otherwise.
+pub fn request_body(headers: &Headers) -> Result<RequestBody, &'static str> {
+    let length = content_length(headers)?;
+    match chunked(headers) {
+        None => Ok(RequestBody::Length(length.unwrap_or(0))),
+        // The two could end the body in different places, and an origin
+        // going by the other one would read the rest as a request.
+        Some(_) if length.is_some() => Err("both Transfer-Encoding and Content-Length"),
+        Some(true) => Ok(RequestBody::Chunked),
+        // Only the close of the connection could end it.
+        Some(false) => Err("request body not chunked"),
+    }
+}
+
+// Whether the last transfer coding is chunked, None without one.
+fn chunked(headers: &Headers) -> Option<bool> {
+    headers
+        .get_all("transfer-encoding")
+        .flat_map(|value| value.split(','))
+        .last()
+        .map(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+// Every Content-Length value, of one field or several, has to agree.
+fn content_length(headers: &Headers) -> Result<Option<u64>, &'static str> {
+    let mut length = None;
+    for value in headers.get_all("content-length").flat_map(|v| v.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err("invalid Content-Length");
+        }
+        let value = value.parse().map_err(|_| "invalid Content-Length")?;
+        if length.is_some_and(|length| length != value) {
+            return Err("conflicting Content-Length values");
+        }
+        length = Some(value);
+    }
+    Ok(length)
+}
+
+/// One forwarded request and its response.
+#[derive(Debug)]
+pub struct Exchange {
+    // The response to HEAD has no body, whatever its head says.
+    head_request: bool,
+    // The client asked to keep its connection: HTTP/1.1 without
+    // `Connection: close`, or HTTP/1.0 with `Connection: keep-alive`.
+    keep_alive: bool,
+    http10: bool,
+    // The whole response went to the client, and it may send another request.
+    reusable: AtomicBool,
+}
+
+impl Exchange {
+    pub fn new(method: &str, version: &str, headers: &Headers) -> Self {
+        let options = headers.connection_options();
+        let http10 = version == "HTTP/1.0";
+        let keep_alive = match http10 {
+            true => options.iter().any(|option| option == "keep-alive"),
+            false => !options.iter().any(|option| option == "close"),
+        };
+        Self {
+            head_request: method == "HEAD",
+            keep_alive,
+            http10,
+            reusable: AtomicBool::new(false),
+        }
+    }
+
+    pub fn reusable(&self) -> bool {
+        self.reusable.load(Ordering::Relaxed)
+    }
+}
+
+/// The client's side of a forwarded request's tunnel: reads end with the
+/// request body, and the connection is only shut down when it is not kept.
+pub struct ClientSide<'a, S> {
+    inner: &'a mut S,
+    // None reads on to EOF, as for CONNECT.
+    exchange: Option<&'a Exchange>,
+    // What is left of the body: a length, or chunked framing.
+    phase: Phase,
+    // A chunked body is read out of the reader its head came from, so that
+    // what follows it stays there for the next request.
+    reader: Option<&'a mut HttpReader>,
+    // Looked at and not yet read from here.
+    output: Vec<u8>,
+    position: usize,
+}
+
+impl<'a, S> ClientSide<'a, S> {
+    pub fn new(inner: &'a mut S, exchange: Option<&'a Exchange>, body_left: u64) -> Self {
+        Self {
+            inner,
+            exchange,
+            phase: Phase::Length(body_left),
+            reader: None,
+            output: vec![],
+            position: 0,
+        }
+    }
+
+    /// For a chunked body, which is passed on as it is, up to the end of
+    /// its trailers.
+    pub fn chunked(
+        inner: &'a mut S,
+        exchange: Option<&'a Exchange>,
+        reader: &'a mut HttpReader,
+    ) -> Self {
+        Self {
+            phase: Phase::Chunked(Chunked::Size),
+            reader: Some(reader),
+            ..Self::new(inner, exchange, 0)
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ClientSide<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.exchange.is_none() {
+            return Pin::new(&mut *this.inner).poll_read(cx, buf);
+        }
+        let body_left = match this.phase {
+            Phase::Length(0) => return Poll::Ready(Ok(())),
+            Phase::Length(left) => left,
+            _ => return this.poll_read_chunked(cx, buf),
+        };
+        let limit = body_left.min(buf.remaining() as u64) as usize;
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(limit));
+        ready!(Pin::new(&mut *this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        if n == 0 {
+            return Poll::Ready(Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "client closed before the end of the request body",
+            )));
+        }
+        buf.advance(n);
+        this.phase = Phase::Length(body_left - n as u64);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> ClientSide<'_, S> {
+    fn poll_read_chunked(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.position < self.output.len() {
+                let n = (self.output.len() - self.position).min(buf.remaining());
+                buf.put_slice(&self.output[self.position..self.position + n]);
+                self.position += n;
+                return Poll::Ready(Ok(()));
+            }
+            self.output.clear();
+            self.position = 0;
+            let (Phase::Chunked(chunked), Some(reader)) = (self.phase, self.reader.as_deref_mut())
+            else {
+                return Poll::Ready(Ok(()));
+            };
+            let input = reader.buffered_mut();
+            match advance_chunked(chunked, input, &mut self.output).map_err(malformed)? {
+                Some(phase) => self.phase = phase,
+                None => {
+                    if ready!(reader.poll_fill(cx, &mut *self.inner))? == 0 {
+                        return Poll::Ready(Err(io::Error::new(
+                            ErrorKind::UnexpectedEof,
+                            "client closed before the end of the request body",
+                        )));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ClientSide<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.exchange {
+            Some(exchange) if exchange.reusable() => Pin::new(&mut *this.inner).poll_flush(cx),
+            _ => Pin::new(&mut *this.inner).poll_shutdown(cx),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chunked {
+    // The chunk size line.
+    Size,
+    Data(u64),
+    // The CRLF after a chunk's data.
+    DataEnd,
+    // Trailer fields, passed on as they are, up to the empty line.
+    Trailers,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Head,
+    Length(u64),
+    Chunked(Chunked),
+    // Without framing the response ends when the origin closes, and the
+    // client's connection with it.
+    UntilClose,
+    Done,
+}
+
+/// The origin's side of a forwarded request's tunnel: what it sends is read
+/// up to the end of the response, with a head that tells the client whether
+/// its connection is kept. Writes go straight through, and a shutdown only
+/// flushes, as the connection is closed with the tunnel.
+pub struct OriginSide<'a, T> {
+    inner: T,
+    // None passes everything through, as for CONNECT.
+    exchange: Option<&'a Exchange>,
+    phase: Phase,
+    // Read from the origin and not looked at yet.
+    input: Vec<u8>,
+    // Looked at and not yet read from here.
+    output: Vec<u8>,
+    position: usize,
+    keep: bool,
+}
+
+impl<'a, T> OriginSide<'a, T> {
+    pub fn new(inner: T, exchange: Option<&'a Exchange>) -> Self {
+        Self {
+            inner,
+            exchange,
+            phase: Phase::Head,
+            input: vec![],
+            output: vec![],
+            position: 0,
+            keep: false,
+        }
+    }
+
+    // Moves what it can from `input` to `output`. False when it needs more
+    // input to go on.
+    fn advance(&mut self, exchange: &Exchange) -> io::Result<bool> {
+        match self.phase {
+            Phase::Head => {
+                let Some(end) = find(&self.input, b"\r\n\r\n") else {
+                    if self.input.len() > MAX_HEAD {
+                        return Err(invalid("response head too long"));
+                    }
+                    return Ok(false);
+                };
+                let head: Vec<u8> = self.input.drain(..end + 4).collect();
+                self.response_head(&head, exchange)?;
+            }
+            Phase::Length(0) => self.done(exchange),
+            Phase::Length(left) => {
+                if self.input.is_empty() {
+                    return Ok(false);
+                }
+                let n = left.min(self.input.len() as u64) as usize;
+                self.output.extend(self.input.drain(..n));
+                self.phase = Phase::Length(left - n as u64);
+            }
+            Phase::Chunked(chunked) => {
+                match advance_chunked(chunked, &mut self.input, &mut self.output)
+                    .map_err(invalid)?
+                {
+                    None => return Ok(false),
+                    Some(Phase::Done) => self.done(exchange),
+                    Some(phase) => self.phase = phase,
+                }
+            }
+            Phase::UntilClose => {
+                if self.input.is_empty() {
+                    return Ok(false);
+                }
+                self.output.append(&mut self.input);
+            }
+            // Anything after the response is not the client's.
+            Phase::Done => self.input.clear(),
+        }
+        Ok(true)
+    }
+
+    fn done(&mut self, exchange: &Exchange) {
+        self.phase = Phase::Done;
+        exchange.reusable.store(self.keep, Ordering::Relaxed);
+    }
+
+    // Picks the framing of the response and writes out its head, with the
+    // origin's connection fields replaced by the proxy's own.
+    fn response_head(&mut self, head: &[u8], exchange: &Exchange) -> io::Result<()> {
+        let text = std::str::from_utf8(head).map_err(|_| invalid("response head"))?;
+        let mut lines = text.trim_end_matches("\r\n").split("\r\n");
+        let status_line = lines.next().unwrap_or_default();
+        let status: u16 = status_line
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| invalid("response status line"))?;
+        let lines: Vec<String> = lines.map(str::to_string).collect();
+        let mut headers = Headers::parse(&lines).map_err(invalid)?;
+        // An interim response comes before the real one.
+        if (100..200).contains(&status) && status != 101 {
+            self.output.extend_from_slice(head);
+            return Ok(());
+        }
+        self.phase = if exchange.head_request || status == 204 || status == 304 {
+            Phase::Length(0)
+        } else if status == 101 {
+            // Not asked for, as upgrades are not exchanges.
+            Phase::UntilClose
+        } else {
+            match chunked(&headers) {
+                Some(true) => Phase::Chunked(Chunked::Size),
+                Some(false) => Phase::UntilClose,
+                None => match content_length(&headers).map_err(invalid)? {
+                    Some(length) => Phase::Length(length),
+                    None => Phase::UntilClose,
+                },
+            }
+        };
+        self.keep = exchange.keep_alive && self.phase != Phase::UntilClose;
+        for option in headers.connection_options() {
+            headers.remove(&option);
+        }
+        for name in ["connection", "keep-alive", "proxy-connection"] {
+            headers.remove(name);
+        }
+        match (self.keep, exchange.http10) {
+            (true, true) => headers.set("Connection", "keep-alive"),
+            (true, false) => {}
+            (false, _) => headers.set("Connection", "close"),
+        }
+        self.output
+            .extend_from_slice(format!("{status_line}\r\n{}\r\n", headers.render()).as_bytes());
+        Ok(())
+    }
+}
+
+// Moves the next piece of chunked framing from `input` to `output`, as it
+// is, and gives the phase after it: Done past the trailers, None while more
+// input is needed.
+fn advance_chunked(
+    chunked: Chunked,
+    input: &mut Vec<u8>,
+    output: &mut Vec<u8>,
+) -> Result<Option<Phase>, &'static str> {
+    let line_pending = |input: &[u8]| match input.len() > crate::http_reader::MAX_LINE {
+        true => Err("chunked framing line too long"),
+        false => Ok(None),
+    };
+    let next = match chunked {
+        Chunked::Size => {
+            let Some(end) = find(input, b"\r\n") else {
+                return line_pending(input);
+            };
+            let line = std::str::from_utf8(&input[..end]).map_err(|_| "chunk")?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = u64::from_str_radix(size, 16).map_err(|_| "chunk size")?;
+            output.extend(input.drain(..end + 2));
+            match size {
+                0 => Chunked::Trailers,
+                size => Chunked::Data(size),
+            }
+        }
+        Chunked::Data(left) => {
+            if input.is_empty() {
+                return Ok(None);
+            }
+            let n = left.min(input.len() as u64) as usize;
+            output.extend(input.drain(..n));
+            match left - n as u64 {
+                0 => Chunked::DataEnd,
+                left => Chunked::Data(left),
+            }
+        }
+        Chunked::DataEnd => {
+            if input.len() < 2 {
+                return Ok(None);
+            }
+            if !input.starts_with(b"\r\n") {
+                return Err("chunk data");
+            }
+            output.extend(input.drain(..2));
+            Chunked::Size
+        }
+        Chunked::Trailers => {
+            let Some(end) = find(input, b"\r\n") else {
+                return line_pending(input);
+            };
+            output.extend(input.drain(..end + 2));
+            if end == 0 {
+                return Ok(Some(Phase::Done));
+            }
+            Chunked::Trailers
+        }
+    };
+    Ok(Some(Phase::Chunked(next)))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("malformed {what} from the origin"),
+    )
+}
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("malformed {what} from the client"),
+    )
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for OriginSide<'_, T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(exchange) = this.exchange else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        loop {
+            if this.position < this.output.len() {
+                let n = (this.output.len() - this.position).min(buf.remaining());
+                buf.put_slice(&this.output[this.position..this.position + n]);
+                this.position += n;
+                return Poll::Ready(Ok(()));
+            }
+            this.output.clear();
+            this.position = 0;
+            if this.phase == Phase::Done {
+                return Poll::Ready(Ok(()));
+            }
+            if this.advance(exchange)? {
+                continue;
+            }
+            let begin = this.input.len();
+            this.input.resize(begin + READ_SIZE, 0);
+            let mut read = ReadBuf::new(&mut this.input[begin..]);
+            let polled = Pin::new(&mut this.inner).poll_read(cx, &mut read);
+            let n = match &polled {
+                Poll::Ready(Ok(())) => read.filled().len(),
+                _ => 0,
+            };
+            this.input.truncate(begin + n);
+            ready!(polled)?;
+            if n == 0 {
+                if this.phase != Phase::UntilClose {
+                    return Poll::Ready(Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "origin closed before the end of the response",
+                    )));
+                }
+                this.phase = Phase::Done;
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for OriginSide<'_, T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    // A half-closed connection can read as an aborted request to an origin,
+    // so a tunnel for an exchange never sends its FIN early.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.exchange {
+            Some(_) => Pin::new(&mut this.inner).poll_flush(cx),
+            None => Pin::new(&mut this.inner).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use tokio::io::AsyncReadExt;
+    // Hands out its chunks one read at a time.
+    struct Chunks(VecDeque<&'static [u8]>);
+    impl AsyncRead for Chunks {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if let Some(chunk) = self.0.pop_front() {
+                buf.put_slice(chunk);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+    fn exchange(method: &str, version: &str, lines: &[&str]) -> Exchange {
+        let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        Exchange::new(method, version, &Headers::parse(&lines).unwrap())
+    }
+    async fn respond(exchange: &Exchange, chunks: &[&'static [u8]]) -> io::Result<String> {
+        let origin = Chunks(chunks.iter().copied().collect());
+        let mut response = vec![];
+        OriginSide::new(origin, Some(exchange))
+            .read_to_end(&mut response)
+            .await?;
+        Ok(String::from_utf8(response).unwrap())
+    }
+    #[test]
+    fn test_request_body() {
+        let fields = |lines: &[&str]| {
+            let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+            Headers::parse(&lines).unwrap()
+        };
+        assert_eq!(request_body(&fields(&[])), Ok(RequestBody::Length(0)));
+        let length = fields(&["Content-Length: 12", "content-length: 12, 12"]);
+        assert_eq!(request_body(&length), Ok(RequestBody::Length(12)));
+        let chunked = fields(&["Transfer-Encoding: gzip, Chunked"]);
+        assert_eq!(request_body(&chunked), Ok(RequestBody::Chunked));
+        for bad in [
+            &["Content-Length: 1", "Content-Length: 2"][..],
+            &["Content-Length: +1"],
+            &["Transfer-Encoding: chunked", "Content-Length: 3"],
+            &["Transfer-Encoding: chunked, gzip"],
+        ] {
+            assert!(request_body(&fields(bad)).is_err(), "{bad:?}");
+        }
+    }
+    #[tokio::test]
+    async fn test_response_framed_by_length() {
+        let get = exchange("GET", "HTTP/1.1", &[]);
+        let response = respond(
+            &get,
+            &[
+                b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: keep-alive, X-Hop\r\n",
+                b"X-Hop: 1\r\n\r\nhel",
+                b"lo and more",
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"
+        );
+        assert!(get.reusable());
+        // Without a body, whatever the head says.
+        let head = exchange("HEAD", "HTTP/1.1", &[]);
+        let response = respond(&head, &[b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n"]);
+        assert_eq!(
+            response.await.unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n"
+        );
+        assert!(head.reusable());
+        let get = exchange("GET", "HTTP/1.1", &[]);
+        let response = respond(&get, &[b"HTTP/1.1 204 No Content\r\n\r\n"]);
+        assert_eq!(response.await.unwrap(), "HTTP/1.1 204 No Content\r\n\r\n");
+        assert!(get.reusable());
+        // Cut short.
+        let get = exchange("GET", "HTTP/1.1", &[]);
+        let response = respond(&get, &[b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhel"]);
+        assert_eq!(response.await.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert!(!get.reusable());
+    }
+    #[tokio::test]
+    async fn test_chunked_response_with_trailers() {
+        let get = exchange("GET", "HTTP/1.1", &[]);
+        let response = respond(
+            &get,
+            &[
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r",
+                b"\nWi",
+                b"ki\r\n5;ext=1\r\npedia\r\n0\r\nExpires: never\r",
+                b"\n\r\nnext",
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5;ext=1\r\n\
+             pedia\r\n0\r\nExpires: never\r\n\r\n"
+        );
+        assert!(get.reusable());
+        let get = exchange("GET", "HTTP/1.1", &[]);
+        let response = respond(
+            &get,
+            &[b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"],
+        );
+        assert_eq!(response.await.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+    #[tokio::test]
+    async fn test_connection_kept_as_the_client_asked() {
+        // Read to the close, and so not kept.
+        let get = exchange("GET", "HTTP/1.1", &[]);
+        let response = respond(&get, &[b"HTTP/1.1 200 OK\r\n\r\nuntil ", b"closed"]);
+        assert_eq!(
+            response.await.unwrap(),
+            "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nuntil closed"
+        );
+        assert!(!get.reusable());
+        let get = exchange("GET", "HTTP/1.1", &["Connection: close"]);
+        let response = respond(&get, &[b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"]);
+        assert_eq!(
+            response.await.unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+        assert!(!get.reusable());
+        let get = exchange("GET", "HTTP/1.0", &["Connection: keep-alive"]);
+        let response = respond(&get, &[b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"]);
+        assert_eq!(
+            response.await.unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: keep-alive\r\n\r\n"
+        );
+        assert!(get.reusable());
+        let get = exchange("GET", "HTTP/1.0", &[]);
+        let response = respond(&get, &[b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"]);
+        assert!(response.await.unwrap().contains("Connection: close\r\n"));
+        assert!(!get.reusable());
+    }
+    #[tokio::test]
+    async fn test_interim_response_comes_first() {
+        let post = exchange("POST", "HTTP/1.1", &["Expect: 100-continue"]);
+        let response = respond(
+            &post,
+            &[b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"],
+        );
+        assert_eq!(
+            response.await.unwrap(),
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"
+        );
+        assert!(post.reusable());
+    }
+    #[tokio::test]
+    async fn test_client_side_reads_only_the_body() {
+        let post = exchange("POST", "HTTP/1.1", &[]);
+        let mut client: &[u8] = b"abcGET http://example.com/ HTTP/1.1\r\n";
+        let mut body = vec![];
+        ClientSide::new(&mut client, Some(&post), 3)
+            .read_to_end(&mut body)
+            .await
+            .unwrap();
+        assert_eq!(body, b"abc");
+        assert!(client.starts_with(b"GET "));
+        let mut client: &[u8] = b"ab";
+        let mut body = vec![];
+        let mut side = ClientSide::new(&mut client, Some(&post), 3);
+        let read = side.read_to_end(&mut body).await;
+        assert_eq!(read.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+    #[tokio::test]
+    async fn test_client_side_reads_a_chunked_body() {
+        let post = exchange("POST", "HTTP/1.1", &["Transfer-Encoding: chunked"]);
+        // Part of the body came with the head, and the rest is read in pieces.
+        let mut reader = HttpReader::new(1024);
+        let mut head: &[u8] = b"3\r\nab";
+        reader.peek(&mut head).await.unwrap();
+        let mut client = Chunks(
+            [
+                &b"c\r"[..],
+                b"\n10;ext=1\r\n0123456789abcdef\r\n0\r\nX-Trailer: 1\r",
+                b"\n\r\nGET / HTTP/1.1\r\n",
+            ]
+            .into(),
+        );
+        let mut body = vec![];
+        ClientSide::chunked(&mut client, Some(&post), &mut reader)
+            .read_to_end(&mut body)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "3\r\nabc\r\n10;ext=1\r\n0123456789abcdef\r\n0\r\nX-Trailer: 1\r\n\r\n"
+        );
+        // What follows the body is left for the next request.
+        assert_eq!(
+            reader.read_line(&mut client).await.unwrap(),
+            "GET / HTTP/1.1"
+        );
+        for bad in [&b"zz\r\n"[..], b"3\r\nabcde"] {
+            let mut reader = HttpReader::new(1024);
+            let mut body = vec![];
+            let mut client = Chunks([bad].into());
+            let read = ClientSide::chunked(&mut client, Some(&post), &mut reader)
+                .read_to_end(&mut body)
+                .await;
+            assert_eq!(read.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+        let mut reader = HttpReader::new(1024);
+        let mut client = Chunks([&b"3\r\nab"[..]].into());
+        let read = ClientSide::chunked(&mut client, Some(&post), &mut reader)
+            .read_to_end(&mut vec![])
+            .await;
+        assert_eq!(read.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,363 @@
+// A HAR 1.2 session log (`--har out.har`), written out when the proxy shuts
+// down. A forwarded plain HTTP request gets its request and response heads
+// and body sizes. A CONNECT tunnel's payload is opaque, so its entry only has
+// the CONNECT request, how long the tunnel was open and the bytes each way.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+
+use crate::json;
+
+// Response heads longer than this are not parsed.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// The client's request, as it arrived.
+pub struct Request {
+    pub started: SystemTime,
+    pub start: Instant,
+    pub method: String,
+    pub url: String,
+    pub http_version: String,
+    // Header lines, without their CRLF.
+    pub headers: Vec<String>,
+    // The head sent on to the origin in its place; 0 for a tunnel.
+    pub forwarded_head: usize,
+}
+
+impl Request {
+    fn headers_size(&self) -> usize {
+        let line = self.method.len() + self.url.len() + self.http_version.len() + 2;
+        line + 2 + self.headers.iter().map(|h| h.len() + 2).sum::<usize>() + 2
+    }
+}
+
+/// A response head, parsed off the start of what the target sent.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Response {
+    pub http_version: String,
+    pub status: u16,
+    pub status_text: String,
+    pub headers: Vec<String>,
+    // With the blank line ending it.
+    pub head_size: usize,
+}
+
+/// Collects the start of the server-to-client stream until the end of the
+/// response head.
+#[derive(Default)]
+pub struct HeadCapture(Mutex<Vec<u8>>);
+
+impl HeadCapture {
+    pub fn observe(&self, chunk: &[u8]) {
+        let mut head = self.0.lock().unwrap();
+        if head.len() < MAX_HEAD && !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            let room = MAX_HEAD - head.len();
+            head.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        }
+    }
+
+    pub fn response(&self) -> Option<Response> {
+        let head = self.0.lock().unwrap();
+        let end = head.windows(4).position(|w| w == b"\r\n\r\n")?;
+        let text = std::str::from_utf8(&head[..end]).ok()?;
+        let mut lines = text.split("\r\n");
+        let mut status_line = lines.next()?.splitn(3, ' ');
+        let http_version = status_line.next()?;
+        if !http_version.starts_with("HTTP/") {
+            return None;
+        }
+        let status = status_line.next()?.parse().ok()?;
+        Some(Response {
+            http_version: http_version.to_string(),
+            status,
+            status_text: status_line.next().unwrap_or("").to_string(),
+            headers: lines.map(str::to_string).collect(),
+            head_size: end + 4,
+        })
+    }
+}
+
+/// How a request went, from reading it to the end of its tunnel.
+pub struct Exchange<'a> {
+    pub request: &'a Request,
+    // None for a tunnel, answered by the proxy itself.
+    pub response: Option<Response>,
+    pub server: Option<IpAddr>,
+    // From the request to starting to connect: headers and policy.
+    pub blocked: Duration,
+    pub connect: Duration,
+    // From the tunnel opening to the target's first byte.
+    pub wait: Option<Duration>,
+    pub duration: Duration,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+/// The entries so far, each already rendered.
+pub struct SessionLog {
+    file: Mutex<Option<File>>,
+    entries: Mutex<Vec<String>>,
+}
+
+impl SessionLog {
+    /// Creates the file now, so that a bad path fails at startup rather
+    /// than on shutdown.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: Mutex::new(Some(File::create(path)?)),
+            entries: Mutex::new(vec![]),
+        })
+    }
+
+    pub fn add(&self, exchange: &Exchange<'_>) {
+        let entry = entry(exchange);
+        self.entries.lock().unwrap().push(entry);
+    }
+
+    /// Writes the HAR document; later calls do nothing.
+    pub async fn finish(&self) -> io::Result<()> {
+        let Some(file) = self.file.lock().unwrap().take() else {
+            return Ok(());
+        };
+        let document = document(&self.entries.lock().unwrap());
+        let mut file = tokio::fs::File::from_std(file);
+        file.write_all(document.as_bytes()).await?;
+        file.flush().await
+    }
+}
+
+fn document(entries: &[String]) -> String {
+    format!(
+        "{{\"log\":{{\"version\":\"1.2\",\
+         \"creator\":{{\"name\":\"proxy\",\"version\":{}}},\
+         \"entries\":[\n{}\n]}}}}\n",
+        json::quote(env!("CARGO_PKG_VERSION")),
+        entries.join(",\n")
+    )
+}
+
+// `{"name":…,"value":…}` objects from `name: value` lines.
+fn name_values<'a>(pairs: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+    let pairs: Vec<String> = pairs
+        .map(|(name, value)| {
+            format!(
+                "{{\"name\":{},\"value\":{}}}",
+                json::quote(name.trim()),
+                json::quote(value.trim())
+            )
+        })
+        .collect();
+    format!("[{}]", pairs.join(","))
+}
+
+fn headers(lines: &[String]) -> String {
+    name_values(lines.iter().filter_map(|line| line.split_once(':')))
+}
+
+fn query_string(url: &str) -> String {
+    let query = url.split_once('?').map_or("", |(_, query)| query);
+    let pairs = query.split('&').filter(|pair| !pair.is_empty());
+    name_values(pairs.map(|pair| pair.split_once('=').unwrap_or((pair, ""))))
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn entry(exchange: &Exchange<'_>) -> String {
+    let request = exchange.request;
+    let wait = exchange.wait.unwrap_or(Duration::ZERO);
+    let receive = exchange
+        .duration
+        .saturating_sub(exchange.connect)
+        .saturating_sub(wait);
+    let mut out = format!(
+        "{{\"startedDateTime\":{},\"time\":{:.3},",
+        json::quote(&iso8601(request.started)),
+        millis(exchange.blocked + exchange.duration)
+    );
+    let _ = write!(
+        out,
+        "\"request\":{{\"method\":{},\"url\":{},\"httpVersion\":{},\"cookies\":[],\
+         \"headers\":{},\"queryString\":{},\"headersSize\":{},\"bodySize\":{}}},",
+        json::quote(&request.method),
+        json::quote(&request.url),
+        json::quote(&request.http_version),
+        headers(&request.headers),
+        query_string(&request.url),
+        request.headers_size(),
+        exchange
+            .bytes_up
+            .saturating_sub(request.forwarded_head as u64)
+    );
+    let (version, status, status_text, response_headers, head_size, body_size) =
+        match &exchange.response {
+            Some(response) => (
+                response.http_version.as_str(),
+                response.status,
+                response.status_text.as_str(),
+                headers(&response.headers),
+                response.head_size as i64,
+                exchange.bytes_down as i64 - response.head_size as i64,
+            ),
+            None if request.method == "CONNECT" => (
+                request.http_version.as_str(),
+                200,
+                "Connection Established",
+                "[]".to_string(),
+                -1,
+                exchange.bytes_down as i64,
+            ),
+            // Whatever the target sent was not an HTTP response.
+            None => ("", 0, "", "[]".to_string(), -1, exchange.bytes_down as i64),
+        };
+    let mime_type = exchange
+        .response
+        .as_ref()
+        .and_then(|response| {
+            response
+                .headers
+                .iter()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-type"))
+                .map(|(_, value)| value.trim())
+        })
+        .unwrap_or("");
+    let _ = write!(
+        out,
+        "\"response\":{{\"status\":{status},\"statusText\":{},\"httpVersion\":{},\
+         \"cookies\":[],\"headers\":{response_headers},\
+         \"content\":{{\"size\":{},\"mimeType\":{}}},\"redirectURL\":\"\",\
+         \"headersSize\":{head_size},\"bodySize\":{body_size}}},",
+        json::quote(status_text),
+        json::quote(version),
+        body_size.max(0),
+        json::quote(mime_type)
+    );
+    // Resolving is part of connecting, and TLS is the client's business.
+    let _ = write!(
+        out,
+        "\"cache\":{{}},\"timings\":{{\"blocked\":{:.3},\"dns\":-1,\"connect\":{:.3},\
+         \"send\":0,\"wait\":{:.3},\"receive\":{:.3},\"ssl\":-1}}",
+        millis(exchange.blocked),
+        millis(exchange.connect),
+        millis(wait),
+        millis(receive)
+    );
+    if let Some(server) = exchange.server {
+        let _ = write!(
+            out,
+            ",\"serverIPAddress\":{}",
+            json::quote(&server.to_string())
+        );
+    }
+    out.push('}');
+    out
+}
+
+// UTC, with milliseconds: 2024-01-31T12:00:00.000Z.
+pub fn iso8601(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rest) = (secs / 86400, secs % 86400);
+    // Days since the epoch to a civil date, after Howard Hinnant's
+    // `civil_from_days`.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60,
+        since.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_iso8601() {
+        assert_eq!(iso8601(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(iso8601(time), "2024-02-29T12:34:56.789Z");
+    }
+    #[test]
+    fn test_head_capture() {
+        let capture = HeadCapture::default();
+        capture.observe(b"HTTP/1.1 404 Not");
+        assert_eq!(capture.response(), None);
+        capture.observe(b" Found\r\nContent-Type: text/plain\r\n\r\nmissing");
+        capture.observe(b"more body");
+        assert_eq!(
+            capture.response(),
+            Some(Response {
+                http_version: "HTTP/1.1".to_string(),
+                status: 404,
+                status_text: "Not Found".to_string(),
+                headers: vec!["Content-Type: text/plain".to_string()],
+                head_size: 52,
+            })
+        );
+        let capture = HeadCapture::default();
+        capture.observe(b"SSH-2.0-OpenSSH\r\n\r\n");
+        assert_eq!(capture.response(), None);
+    }
+    #[test]
+    fn test_entry_is_valid_json() {
+        let request = Request {
+            started: UNIX_EPOCH,
+            start: Instant::now(),
+            method: "GET".to_string(),
+            url: "http://example.com/a?b=1&c".to_string(),
+            http_version: "HTTP/1.1".to_string(),
+            headers: vec!["Host: example.com".to_string()],
+            forwarded_head: 40,
+        };
+        let capture = HeadCapture::default();
+        capture.observe(b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n<p>");
+        let exchange = Exchange {
+            request: &request,
+            response: capture.response(),
+            server: Some("127.0.0.1".parse().unwrap()),
+            blocked: Duration::from_millis(1),
+            connect: Duration::from_millis(2),
+            wait: Some(Duration::from_millis(3)),
+            duration: Duration::from_millis(10),
+            bytes_up: 50,
+            bytes_down: 47,
+        };
+        let har = json::parse(&document(&[entry(&exchange)])).unwrap();
+        let entries = har.get("log").unwrap().get("entries").unwrap();
+        let json::Value::Array(entries) = entries else {
+            panic!("entries is not an array");
+        };
+        let response = entries[0].get("response").unwrap();
+        assert_eq!(response.get("status").unwrap().as_u64(), Some(200));
+        assert_eq!(response.get("bodySize").unwrap().as_u64(), Some(3));
+        let content = response.get("content").unwrap();
+        assert_eq!(content.get("mimeType").unwrap().as_str(), Some("text/html"));
+        let query = entries[0].get("request").unwrap().get("queryString");
+        let json::Value::Array(query) = query.unwrap() else {
+            panic!("queryString is not an array");
+        };
+        assert_eq!(query[1].get("name").unwrap().as_str(), Some("c"));
+        let request = entries[0].get("request").unwrap();
+        assert_eq!(request.get("bodySize").unwrap().as_u64(), Some(10));
+        let timings = entries[0].get("timings").unwrap();
+        assert_eq!(timings.get("receive").unwrap().as_u64(), Some(5));
+    }
+}
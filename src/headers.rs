@@ -0,0 +1,297 @@
+// Request header fields, as the lines HttpReader reads them, for requests
+// that are sent on: looked up by case-insensitive name, duplicates kept in
+// the order they came in, and written back out in that order. Also the
+// `--set-header` fields added to them.
+
+use std::net::IpAddr;
+
+/// The header fields of a request, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    // Names as written, values without surrounding whitespace.
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    /// Reads header lines, without their CRLF. Obsolete line folding is
+    /// rejected (RFC 9112 section 5.2), as is a line that is not a field.
+    pub fn parse(lines: &[String]) -> Result<Self, &'static str> {
+        let mut fields = vec![];
+        for line in lines {
+            if line.starts_with([' ', '\t']) {
+                return Err("obsolete header line folding");
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                return Err("invalid header line");
+            };
+            // No whitespace is allowed between the name and the colon.
+            if name.is_empty() || !name.bytes().all(is_token) {
+                return Err("invalid header name");
+            }
+            let value = value.trim_matches([' ', '\t']);
+            fields.push((name.to_string(), value.to_string()));
+        }
+        Ok(Self { fields })
+    }
+
+    /// The first field named `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        let (_, value) = self
+            .fields
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))?;
+        Some(value)
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    /// Replaces the fields named `name` with one in place of the first of
+    /// them, or at the end without one.
+    pub fn set(&mut self, name: &str, value: &str) {
+        match self
+            .fields
+            .iter()
+            .position(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            Some(first) => {
+                self.fields[first].1 = value.to_string();
+                let mut i = 0;
+                self.fields.retain(|(n, _)| {
+                    i += 1;
+                    i - 1 == first || !n.eq_ignore_ascii_case(name)
+                });
+            }
+            None => self.fields.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    /// The options of every Connection field, lowercased.
+    pub fn connection_options(&self) -> Vec<String> {
+        self.get_all("connection")
+            .flat_map(|value| value.split(','))
+            .map(|option| option.trim().to_ascii_lowercase())
+            .filter(|option| !option.is_empty())
+            .collect()
+    }
+
+    /// Whether the request asks to upgrade the connection: it has an Upgrade
+    /// field and Connection names it.
+    pub fn upgrade(&self) -> bool {
+        self.contains("upgrade") && self.connection_options().iter().any(|o| o == "upgrade")
+    }
+
+    /// Drops the fields that apply to one hop only: the fixed set, and the
+    /// ones Connection names. Upgrade stays when `upgrading`.
+    pub fn strip_hop_by_hop(&mut self, upgrading: bool) {
+        let named = self.connection_options();
+        self.fields.retain(|(name, _)| {
+            let name = name.to_ascii_lowercase();
+            if upgrading && name == "upgrade" {
+                return true;
+            }
+            !HOP_BY_HOP.contains(&name.as_str()) && !named.contains(&name)
+        });
+    }
+
+    /// The fields as header lines, each ending in CRLF.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for (name, value) in &self.fields {
+            text.push_str(&format!("{name}: {value}\r\n"));
+        }
+        text
+    }
+}
+
+// Headers that apply to one hop only and are never sent on.
+const HOP_BY_HOP: [&str; 7] = [
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+// RFC 9110 section 5.6.2.
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+// Set by the proxy itself, so not by `--set-header`.
+const RESERVED: [&str; 5] = [
+    "host",
+    "connection",
+    "content-length",
+    "transfer-encoding",
+    "x-request-id",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    ClientIp,
+    ConnectionId,
+}
+
+/// A `--set-header "Name: value"` field. The value may use `{client_ip}`
+/// and `{connection_id}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetHeader {
+    pub name: String,
+    value: Vec<Part>,
+}
+
+impl SetHeader {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (name, value) = text
+            .split_once(':')
+            .ok_or_else(|| format!("expected Name: value, got {text}"))?;
+        let name = name.trim();
+        if name.is_empty() || !name.bytes().all(is_token) {
+            return Err(format!("invalid header name: {name}"));
+        }
+        if RESERVED.contains(&name.to_ascii_lowercase().as_str()) {
+            return Err(format!("{name} cannot be set"));
+        }
+        let mut parts = vec![];
+        let mut rest = value.trim();
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed variable in {text}"))?;
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            parts.push(match &rest[start + 1..start + end] {
+                "client_ip" => Part::ClientIp,
+                "connection_id" => Part::ConnectionId,
+                variable => return Err(format!("unknown variable {{{variable}}}")),
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            value: parts,
+        })
+    }
+
+    /// The value for one connection. Without a connection id, as outside a
+    /// connection's log scope, `{connection_id}` is `-`.
+    pub fn value(&self, client_ip: IpAddr, connection_id: Option<u64>) -> String {
+        let mut value = String::new();
+        for part in &self.value {
+            match part {
+                Part::Text(text) => value.push_str(text),
+                Part::ClientIp => value.push_str(&client_ip.to_string()),
+                Part::ConnectionId => match connection_id {
+                    Some(id) => value.push_str(&id.to_string()),
+                    None => value.push('-'),
+                },
+            }
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn headers(lines: &[&str]) -> Headers {
+        let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        Headers::parse(&lines).unwrap()
+    }
+    #[test]
+    fn test_lookup_and_set() {
+        let mut fields = headers(&[
+            "Accept: text/html",
+            "x-trace:  a ",
+            "Cookie: a=1",
+            "X-Trace: b",
+        ]);
+        assert_eq!(fields.get("ACCEPT"), Some("text/html"));
+        assert_eq!(fields.get_all("X-Trace").collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(fields.get("missing"), None);
+        fields.set("X-TRACE", "c");
+        fields.set("X-Added", "d");
+        assert_eq!(
+            fields.render(),
+            "Accept: text/html\r\nx-trace: c\r\nCookie: a=1\r\nX-Added: d\r\n"
+        );
+        fields.remove("cookie");
+        assert!(!fields.contains("Cookie"));
+        for (line, reason) in [
+            (" continued", "obsolete header line folding"),
+            ("\tcontinued", "obsolete header line folding"),
+            ("no colon", "invalid header line"),
+            ("Name : value", "invalid header name"),
+            (": value", "invalid header name"),
+        ] {
+            assert_eq!(Headers::parse(&[line.to_string()]), Err(reason), "{line}");
+        }
+    }
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut fields = headers(&[
+            "Host: example.com",
+            "Connection: keep-alive, X-Custom",
+            "Keep-Alive: timeout=5",
+            "X-Custom: dropped",
+            "Proxy-Connection: keep-alive",
+            "Proxy-Authorization: Basic YTpi",
+            "TE: trailers",
+            "Upgrade: websocket",
+            "X-Kept: 1",
+        ]);
+        assert!(!fields.upgrade());
+        fields.strip_hop_by_hop(false);
+        assert_eq!(fields.render(), "Host: example.com\r\nX-Kept: 1\r\n");
+        let mut fields = headers(&[
+            "Connection: Upgrade",
+            "connection: x-custom",
+            "Upgrade: websocket",
+            "X-Custom: dropped",
+        ]);
+        assert!(fields.upgrade());
+        fields.strip_hop_by_hop(true);
+        assert_eq!(fields.render(), "Upgrade: websocket\r\n");
+    }
+    #[test]
+    fn test_set_header() {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let set = SetHeader::parse("X-Forwarded-For: {client_ip}").unwrap();
+        assert_eq!(set.name, "X-Forwarded-For");
+        assert_eq!(set.value(ip, Some(3)), "203.0.113.7");
+        let set = SetHeader::parse("Via:1.1 proxy (conn {connection_id})").unwrap();
+        assert_eq!(set.value(ip, Some(3)), "1.1 proxy (conn 3)");
+        assert_eq!(set.value(ip, None), "1.1 proxy (conn -)");
+        assert_eq!(SetHeader::parse("X-Empty:").unwrap().value(ip, None), "");
+        for bad in [
+            "X-Forwarded-For",
+            "X Forwarded: 1",
+            "Host: example.com",
+            "content-length: 0",
+            "X-Id: {id}",
+            "X-Id: {client_ip",
+        ] {
+            assert!(SetHeader::parse(bad).is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,126 @@
+// `--allow` and `--deny` lists of destinations, checked against the CONNECT
+// target before anything else. A deny match always wins; with an allow list,
+// only the destinations on it are permitted.
+
+use crate::policy::{HostPattern, PortRange, normalize_host};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    host: HostPattern,
+    // None matches every port.
+    ports: Option<PortRange>,
+}
+
+impl Pattern {
+    /// `host`, `host:port` or `host:first-last`, where the host is anything
+    /// a policy `host=` matcher takes, e.g. `*.example.com`, `10.0.0.0/8`,
+    /// `[::1]` or `*`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (host, ports) = match s.rsplit_once(':') {
+            // A bare IPv6 address or network, without brackets.
+            Some((host, _)) if host.contains(':') && !host.ends_with(']') => (s, None),
+            Some((host, ports)) => (host, Some(PortRange::parse(ports)?)),
+            None => (s, None),
+        };
+        Some(Self {
+            host: HostPattern::parse(host)?,
+            ports,
+        })
+    }
+
+    fn matches(&self, host: &str, port: u16) -> bool {
+        self.host.matches(host) && self.ports.is_none_or(|ports| ports.contains(port))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostFilter {
+    pub allow: Vec<Pattern>,
+    pub deny: Vec<Pattern>,
+}
+
+impl HostFilter {
+    /// Adds the comma-separated `patterns`, or returns the first invalid one.
+    pub fn extend(list: &mut Vec<Pattern>, patterns: &str) -> Result<(), String> {
+        for pattern in patterns.split(',').map(str::trim) {
+            list.push(Pattern::parse(pattern).ok_or_else(|| pattern.to_string())?);
+        }
+        Ok(())
+    }
+
+    pub fn permits(&self, host: &str, port: u16) -> bool {
+        let host = normalize_host(host);
+        let any = |list: &[Pattern]| list.iter().any(|pattern| pattern.matches(&host, port));
+        !any(&self.deny) && (self.allow.is_empty() || any(&self.allow))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn filter(allow: &str, deny: &str) -> HostFilter {
+        let mut filter = HostFilter::default();
+        if !allow.is_empty() {
+            HostFilter::extend(&mut filter.allow, allow).unwrap();
+        }
+        if !deny.is_empty() {
+            HostFilter::extend(&mut filter.deny, deny).unwrap();
+        }
+        filter
+    }
+    #[test]
+    fn test_parse() {
+        for pattern in [
+            "example.org",
+            "*.example.com",
+            "10.0.0.0/8",
+            "*:22",
+            "example.org:8000-8080",
+            "::1",
+            "[::1]:443",
+            "fd00::/8",
+        ] {
+            assert!(Pattern::parse(pattern).is_some(), "{pattern}");
+        }
+        for pattern in ["", "*:ssh", "example.org:", "a*b.example.com"] {
+            assert_eq!(Pattern::parse(pattern), None, "{pattern}");
+        }
+        let mut list = vec![];
+        assert_eq!(
+            HostFilter::extend(&mut list, "example.org, *:http"),
+            Err("*:http".to_string())
+        );
+    }
+    #[test]
+    fn test_deny_only_allows_by_default() {
+        let filter = filter("", "*.example.com,10.0.0.0/8,*:22");
+        assert!(!filter.permits("api.EXAMPLE.com", 443));
+        assert!(filter.permits("example.com", 443));
+        assert!(!filter.permits("10.1.2.3", 443));
+        assert!(filter.permits("11.1.2.3", 443));
+        assert!(!filter.permits("github.com", 22));
+        assert!(filter.permits("github.com", 443));
+    }
+    #[test]
+    fn test_allow_only_denies_by_default() {
+        let filter = filter("example.org,[::1]:443", "");
+        assert!(filter.permits("Example.Org.", 443));
+        assert!(filter.permits("example.org", 80));
+        assert!(!filter.permits("example.com", 443));
+        assert!(filter.permits("[::1]", 443));
+        assert!(!filter.permits("::1", 22));
+    }
+    #[test]
+    fn test_deny_wins_over_allow() {
+        let filter = filter(
+            "*.example.com,10.0.0.0/8",
+            "secret.example.com,10.0.0.0/24,*:22",
+        );
+        assert!(filter.permits("www.example.com", 443));
+        assert!(!filter.permits("secret.example.com", 443));
+        assert!(!filter.permits("www.example.com", 22));
+        assert!(filter.permits("10.1.0.1", 443));
+        assert!(!filter.permits("10.0.0.1", 443));
+        assert!(!filter.permits("example.org", 443));
+    }
+}
@@ -0,0 +1,226 @@
+// `--hosts-override` and `--hosts-file`: names that connect to a given
+// address instead of what DNS says, such as a staging server standing in for
+// the real one. A name is exact or a `*.` wildcard for every name below it.
+// An exact name beats a wildcard, and of two entries for the same name, or
+// two wildcards that match, the one given later wins. The client's name is
+// still the one logged and checked against the filters and the policy.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use crate::policy::normalize_host;
+
+/// Where an overridden name connects to; without a port, the one the client
+/// asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+}
+
+impl Target {
+    // `10.1.2.3`, `10.1.2.3:8443`, `::1` or `[::1]:8443`.
+    fn parse(text: &str) -> Option<Self> {
+        if let Ok(ip) = text.parse() {
+            return Some(Self { ip, port: None });
+        }
+        let addr: SocketAddr = text.parse().ok()?;
+        Some(Self {
+            ip: addr.ip(),
+            port: Some(addr.port()),
+        })
+    }
+
+    pub fn addr(&self, port: u16) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port.unwrap_or(port))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    // Lowercased; a wildcard's keeps its leading dot, `.staging.local`.
+    name: String,
+    wildcard: bool,
+    target: Target,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Overrides {
+    // In the order given.
+    entries: Vec<Entry>,
+}
+
+impl Overrides {
+    fn push(&mut self, name: &str, target: Target) -> Result<(), String> {
+        let (name, wildcard) = match name.strip_prefix("*.") {
+            Some(suffix) => (format!(".{}", normalize_host(suffix)), true),
+            None => (normalize_host(name), false),
+        };
+        if name.trim_start_matches('.').is_empty() || name.contains('*') {
+            return Err(format!("invalid host name: {name}"));
+        }
+        self.entries.push(Entry {
+            name,
+            wildcard,
+            target,
+        });
+        Ok(())
+    }
+
+    /// Adds the comma-separated `name=address` pairs of a `--hosts-override`.
+    pub fn extend(&mut self, pairs: &str) -> Result<(), String> {
+        for pair in pairs.split(',').map(str::trim) {
+            let (name, target) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=address, got {pair}"))?;
+            let target = Target::parse(target.trim())
+                .ok_or_else(|| format!("invalid address for {name}: {target}"))?;
+            self.push(name.trim(), target)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the entries of a hosts file: an address, then the names that
+    /// map to it, with `#` comments, as in `/etc/hosts`.
+    pub fn read_file(&mut self, path: &Path) -> io::Result<()> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        let invalid = |line: usize, e: String| {
+            let e = format!("{}:{line}: {e}", path.display());
+            io::Error::new(io::ErrorKind::InvalidInput, e)
+        };
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            let Some(address) = words.next() else {
+                continue;
+            };
+            let target = Target::parse(address)
+                .ok_or_else(|| invalid(i + 1, format!("invalid address: {address}")))?;
+            let mut names = words.peekable();
+            if names.peek().is_none() {
+                return Err(invalid(i + 1, format!("no names for {address}")));
+            }
+            for name in names {
+                self.push(name, target).map_err(|e| invalid(i + 1, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Where `host` connects to instead, if anywhere.
+    pub fn lookup(&self, host: &str) -> Option<Target> {
+        let host = normalize_host(host);
+        let exact = self
+            .entries
+            .iter()
+            .rev()
+            .find(|e| !e.wildcard && e.name == host);
+        exact
+            .or_else(|| {
+                let mut wildcards = self.entries.iter().rev().filter(|e| e.wildcard);
+                wildcards.find(|e| host.ends_with(&e.name))
+            })
+            .map(|entry| entry.target)
+    }
+
+    /// A name that would have the proxy connect to its own `listen` address.
+    /// Only overrides that name a port are told: without one, only the
+    /// clients that ask for the listen port loop.
+    pub fn loop_to(&self, listen: SocketAddr) -> Option<&str> {
+        let own = |ip: IpAddr| {
+            ip == listen.ip()
+                || listen.ip().is_unspecified() && (ip.is_loopback() || ip.is_unspecified())
+        };
+        self.entries
+            .iter()
+            .find(|e| e.target.port == Some(listen.port()) && own(e.target.ip))
+            .map(|e| e.name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn target(text: &str) -> Option<Target> {
+        Target::parse(text)
+    }
+    #[test]
+    fn test_lookup() {
+        let mut overrides = Overrides::default();
+        overrides
+            .extend("example.com=10.1.2.3, *.staging.local=10.1.2.4,api.example.com=[::1]:8443")
+            .unwrap();
+        overrides.extend("*.local=10.9.9.9").unwrap();
+        assert_eq!(overrides.lookup("Example.COM."), target("10.1.2.3"));
+        assert_eq!(overrides.lookup("api.example.com"), target("[::1]:8443"));
+        assert_eq!(overrides.lookup("www.example.com"), None);
+        // The later wildcard wins, and an exact name beats both.
+        assert_eq!(overrides.lookup("a.staging.local"), target("10.9.9.9"));
+        assert_eq!(overrides.lookup("staging.local"), target("10.9.9.9"));
+        assert_eq!(overrides.lookup("local"), None);
+        overrides.extend("a.staging.local=10.0.0.1").unwrap();
+        assert_eq!(overrides.lookup("a.staging.local"), target("10.0.0.1"));
+        // A later entry for the same name replaces the earlier.
+        overrides.extend("example.com=10.3.3.3:80").unwrap();
+        assert_eq!(overrides.lookup("example.com"), target("10.3.3.3:80"));
+        assert_eq!(
+            target("10.3.3.3:80").unwrap().addr(443),
+            "10.3.3.3:80".parse().unwrap()
+        );
+        assert_eq!(
+            target("10.3.3.3").unwrap().addr(443),
+            "10.3.3.3:443".parse().unwrap()
+        );
+        for bad in [
+            "example.com",
+            "example.com=",
+            "example.com=localhost",
+            "example.com=10.1.2.3:99999",
+            "=10.1.2.3",
+            "*.=10.1.2.3",
+            "a.*.com=10.1.2.3",
+        ] {
+            assert!(Overrides::default().extend(bad).is_err(), "{bad}");
+        }
+    }
+    #[test]
+    fn test_read_file() {
+        let path = std::env::temp_dir().join(format!("proxy-hosts-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# staging\n10.1.2.3 example.com www.example.com  # both\n\n::1 *.test\n",
+        )
+        .unwrap();
+        let mut overrides = Overrides::default();
+        overrides.read_file(&path).unwrap();
+        assert_eq!(overrides.lookup("www.example.com"), target("10.1.2.3"));
+        assert_eq!(overrides.lookup("a.test"), target("::1"));
+        std::fs::write(&path, "10.1.2.3 a.test\n10.1.2.4\n").unwrap();
+        let error = Overrides::default().read_file(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(
+            error.to_string().ends_with(":2: no names for 10.1.2.4"),
+            "{error}"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_loop_to() {
+        let mut overrides = Overrides::default();
+        overrides
+            .extend("a.test=127.0.0.1,b.test=127.0.0.1:9000")
+            .unwrap();
+        assert_eq!(overrides.loop_to("127.0.0.1:8080".parse().unwrap()), None);
+        assert_eq!(
+            overrides.loop_to("127.0.0.1:9000".parse().unwrap()),
+            Some("b.test")
+        );
+        assert_eq!(
+            overrides.loop_to("0.0.0.0:9000".parse().unwrap()),
+            Some("b.test")
+        );
+        assert_eq!(overrides.loop_to("10.0.0.1:9000".parse().unwrap()), None);
+    }
+}
@@ -0,0 +1,165 @@
+// Plain HTTP requests to a proxy (RFC 9112 section 3.2.2): the target is in
+// absolute form, and is sent on to the origin in origin form. One request
+// per origin connection: the origin is asked to close after its response
+// (the client's connection may be kept, see framing.rs), unless
+// the request asks to upgrade the connection, e.g. to WebSocket. An upgrade
+// the origin switches to with a 101 carries on as an opaque tunnel.
+
+use crate::headers::Headers;
+use crate::request_id;
+
+/// Where an absolute-form `http://` target points.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Target {
+    // host:port, with the default port filled in.
+    pub host_port: String,
+    // The URI's authority as written, for the Host header.
+    pub authority: String,
+    // Path and query; `/` when the URI has neither.
+    pub path: String,
+}
+
+pub fn parse_target(uri: &str) -> Option<Target> {
+    let scheme_end = uri.find("://")?;
+    if !uri[..scheme_end].eq_ignore_ascii_case("http") {
+        return None;
+    }
+    let rest = &uri[scheme_end + 3..];
+    let rest = rest.split_once('#').map_or(rest, |(rest, _)| rest);
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(end);
+    if authority.is_empty() || authority.contains('@') {
+        return None;
+    }
+    let host_port = match authority.rsplit_once(':') {
+        _ if authority.ends_with(']') => format!("{authority}:80"),
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            authority.to_string()
+        }
+        Some(_) => return None,
+        None => format!("{authority}:80"),
+    };
+    let path = match path.chars().next() {
+        None => "/".to_string(),
+        Some('?') => format!("/{path}"),
+        Some(_) => path.to_string(),
+    };
+    Some(Target {
+        host_port,
+        authority: authority.to_string(),
+        path,
+    })
+}
+
+/// The request head to send to the origin. Hop-by-hop fields are dropped,
+/// the client's X-Request-Id is replaced by `request_id`, and `set` fields
+/// take the place of any of the same name.
+pub fn request_head(
+    method: &str,
+    target: &Target,
+    version: &str,
+    mut headers: Headers,
+    request_id: &str,
+    set: &[(String, String)],
+) -> String {
+    let upgrade = headers.upgrade();
+    headers.strip_hop_by_hop(upgrade);
+    headers.remove("host");
+    headers.remove(request_id::HEADER);
+    for (name, value) in set {
+        headers.set(name, value);
+    }
+    let mut head = format!("{method} {} {version}\r\n", target.path);
+    // The Host header must match the absolute-form target.
+    head.push_str(&format!("Host: {}\r\n", target.authority));
+    head.push_str(&headers.render());
+    head.push_str(&format!("X-Request-Id: {request_id}\r\n"));
+    match upgrade {
+        true => head.push_str("Connection: Upgrade\r\n\r\n"),
+        false => head.push_str("Connection: close\r\n\r\n"),
+    }
+    head
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_target() {
+        let target = parse_target("http://example.com/a/b?c=d#frag").unwrap();
+        assert_eq!(target.host_port, "example.com:80");
+        assert_eq!(target.authority, "example.com");
+        assert_eq!(target.path, "/a/b?c=d");
+        let target = parse_target("HTTP://example.com:8080?q").unwrap();
+        assert_eq!(target.host_port, "example.com:8080");
+        assert_eq!(target.path, "/?q");
+        assert_eq!(parse_target("http://example.com").unwrap().path, "/");
+        for uri in [
+            "/index.html",
+            "https://example.com/",
+            "http:///path",
+            "http://user@example.com/",
+            "http://example.com:http/",
+        ] {
+            assert_eq!(parse_target(uri), None, "{uri}");
+        }
+    }
+    fn fields(lines: &[&str]) -> Headers {
+        let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        Headers::parse(&lines).unwrap()
+    }
+    #[test]
+    fn test_request_head() {
+        let target = parse_target("http://example.com:8080/x").unwrap();
+        let headers = fields(&[
+            "Host: elsewhere",
+            "User-Agent: curl/8.0",
+            "Proxy-Connection: Keep-Alive",
+            "Connection: keep-alive, X-Private",
+            "X-Private: 1",
+            "X-Request-Id: from-client",
+            "Content-Length: 3",
+        ]);
+        assert_eq!(
+            request_head("POST", &target, "HTTP/1.1", headers.clone(), "id-1", &[]),
+            "POST /x HTTP/1.1\r\nHost: example.com:8080\r\nUser-Agent: curl/8.0\r\n\
+             Content-Length: 3\r\nX-Request-Id: id-1\r\nConnection: close\r\n\r\n"
+        );
+        // Set fields replace the client's in place, or come last.
+        let set = [
+            ("user-agent".to_string(), "proxy".to_string()),
+            ("X-Forwarded-For".to_string(), "192.0.2.1".to_string()),
+        ];
+        assert_eq!(
+            request_head("POST", &target, "HTTP/1.1", headers, "id-1", &set),
+            "POST /x HTTP/1.1\r\nHost: example.com:8080\r\nUser-Agent: proxy\r\n\
+             Content-Length: 3\r\nX-Forwarded-For: 192.0.2.1\r\nX-Request-Id: id-1\r\n\
+             Connection: close\r\n\r\n"
+        );
+        // A chunked body goes on as it came.
+        let chunked = fields(&["Transfer-Encoding: chunked"]);
+        assert_eq!(
+            request_head("POST", &target, "HTTP/1.1", chunked, "id-1", &[]),
+            "POST /x HTTP/1.1\r\nHost: example.com:8080\r\nTransfer-Encoding: chunked\r\n\
+             X-Request-Id: id-1\r\nConnection: close\r\n\r\n"
+        );
+        // The upgrade headers are kept for a request to upgrade.
+        let target = parse_target("http://example.com/chat").unwrap();
+        let mut lines = [
+            "Connection: keep-alive, Upgrade",
+            "Upgrade: websocket",
+            "Sec-WebSocket-Version: 13",
+        ];
+        assert_eq!(
+            request_head("GET", &target, "HTTP/1.1", fields(&lines), "id-1", &[]),
+            "GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\nX-Request-Id: id-1\r\nConnection: Upgrade\r\n\r\n"
+        );
+        // Not without the Connection header naming it.
+        lines[0] = "Connection: keep-alive";
+        let head = request_head("GET", &target, "HTTP/1.1", fields(&lines), "id-1", &[]);
+        assert!(!head.contains("Upgrade"));
+        let target = parse_target("http://[::1]/").unwrap();
+        assert_eq!(target.host_port, "[::1]:80");
+    }
+}
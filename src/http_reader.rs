@@ -0,0 +1,408 @@
+// Reads the request line and headers off the client connection, one CRLF
+// terminated line at a time.
+
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::connection_error::ProxyError;
+
+// The longest line accepted, without its CRLF.
+pub const MAX_LINE: usize = 8 * 1024;
+
+/// What a request head may hold: bytes, CRLFs included, and header lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeadLimits {
+    pub max_bytes: usize,
+    pub max_headers: usize,
+}
+
+impl Default for HeadLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 16 * 1024,
+            max_headers: 100,
+        }
+    }
+}
+
+/// A request line and its header lines, without their CRLFs.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Head {
+    pub request_line: String,
+    pub headers: Vec<String>,
+}
+
+fn line_too_long(max: usize) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("line longer than {max} bytes"),
+    )
+}
+
+fn head_too_large(limits: HeadLimits) -> io::Error {
+    ProxyError::HeadTooLarge {
+        max_bytes: limits.max_bytes,
+        max_headers: limits.max_headers,
+    }
+    .into()
+}
+
+pub struct HttpReader {
+    buf: Vec<u8>,
+    read_size: usize,
+    // Where in `buf` the search for the next CRLF picks up.
+    searched: usize,
+}
+
+impl HttpReader {
+    pub fn new(read_size: usize) -> Self {
+        Self {
+            buf: vec![],
+            read_size,
+            searched: 0,
+        }
+    }
+
+    // What was read past the last line, such as the start of a body.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        self.searched = 0;
+        std::mem::take(&mut self.buf)
+    }
+
+    // At most `n` of the bytes read past the last line, such as the part of
+    // a body that came with its head; the rest stays to be read.
+    pub fn take_up_to(&mut self, n: u64) -> Vec<u8> {
+        self.searched = 0;
+        let n = n.min(self.buf.len() as u64) as usize;
+        self.buf.drain(..n).collect()
+    }
+
+    // The bytes read past the last line, for framing to be taken out of in
+    // place, with what follows it left for the next line.
+    pub fn buffered_mut(&mut self) -> &mut Vec<u8> {
+        self.searched = 0;
+        &mut self.buf
+    }
+
+    // Reads more of `stream` onto the end of the buffered bytes: how many,
+    // 0 once the peer has closed.
+    pub fn poll_fill<S: AsyncRead + Unpin>(
+        &mut self,
+        cx: &mut Context<'_>,
+        stream: &mut S,
+    ) -> Poll<io::Result<usize>> {
+        let begin = self.buf.len();
+        self.buf.resize(begin + self.read_size, 0);
+        let mut read = ReadBuf::new(&mut self.buf[begin..]);
+        let polled = Pin::new(stream).poll_read(cx, &mut read);
+        let n = match &polled {
+            Poll::Ready(Ok(())) => read.filled().len(),
+            _ => 0,
+        };
+        self.buf.truncate(begin + n);
+        std::task::ready!(polled)?;
+        Poll::Ready(Ok(n))
+    }
+
+    /// The first byte the peer sends, which is left to be read, or None if
+    /// it closes without sending any.
+    pub async fn peek<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> io::Result<Option<u8>> {
+        if self.buf.is_empty() {
+            self.buf.resize(self.read_size, 0);
+            let read = stream.read(&mut self.buf).await;
+            self.buf.truncate(*read.as_ref().unwrap_or(&0));
+            read?;
+        }
+        Ok(self.buf.first().copied())
+    }
+
+    /// The next line, without its CRLF. However the peer's writes are split
+    /// up, a line only comes back once all of it has arrived.
+    pub async fn read_line<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> io::Result<String> {
+        self.next_line(stream, MAX_LINE, line_too_long).await
+    }
+
+    /// The request line and the header lines up to the blank one, which is
+    /// consumed. A head beyond `limits` is a HeadTooLarge error, once as
+    /// much of it as was allowed has been read.
+    pub async fn read_head<S: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut S,
+        limits: HeadLimits,
+    ) -> io::Result<Head> {
+        let request_line = self.read_line(stream).await?;
+        let mut used = request_line.len() + 2;
+        let mut headers = vec![];
+        loop {
+            // Room for the next line, past its CRLF.
+            let Some(left) = limits.max_bytes.checked_sub(used + 2) else {
+                return Err(head_too_large(limits));
+            };
+            let line = self
+                .next_line(stream, left.min(MAX_LINE), |_| head_too_large(limits))
+                .await?;
+            if line.is_empty() {
+                return Ok(Head {
+                    request_line,
+                    headers,
+                });
+            }
+            if headers.len() == limits.max_headers {
+                return Err(head_too_large(limits));
+            }
+            used += line.len() + 2;
+            headers.push(line);
+        }
+    }
+
+    // A line of at most `max` bytes, or the error `too_long` makes of `max`.
+    async fn next_line<S: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut S,
+        max: usize,
+        too_long: impl Fn(usize) -> io::Error,
+    ) -> io::Result<String> {
+        loop {
+            let found = self.find_crlf();
+            let pending = self.buf.len() - usize::from(self.buf.last() == Some(&b'\r'));
+            if found.unwrap_or(pending) > max {
+                return Err(too_long(max));
+            }
+            if let Some(end) = found {
+                let line = self.buf.drain(..end + 2).take(end).collect();
+                self.searched = 0;
+                return String::from_utf8(line).map_err(|e| {
+                    io::Error::new(ErrorKind::InvalidData, format!("Invalid UTF-8: {e}"))
+                });
+            }
+            let begin = self.buf.len();
+            self.buf.resize(begin + self.read_size, 0);
+            let read = stream.read(&mut self.buf[begin..]).await;
+            self.buf.truncate(begin + read.as_ref().map_or(0, |&n| n));
+            if read? == 0 {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "client closed before the end of the line",
+                ));
+            }
+        }
+    }
+
+    fn find_crlf(&mut self) -> Option<usize> {
+        let found = self.buf[self.searched..]
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .map(|i| self.searched + i);
+        // The last byte may be the CR of a CRLF split across reads.
+        self.searched = self.buf.len().saturating_sub(1);
+        found
+    }
+}
+
+/// A stream whose reads start with bytes already read off it, such as what
+/// an HttpReader buffered.
+pub struct Rewound<S> {
+    buffered: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> Rewound<S> {
+    pub fn new(buffered: Vec<u8>, inner: S) -> Self {
+        Self {
+            buffered,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewound<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let rest = &this.buffered[this.position..];
+        if rest.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let n = rest.len().min(buf.remaining());
+        buf.put_slice(&rest[..n]);
+        this.position += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewound<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out its data one byte per read, then reports end of file.
+    struct Trickle(Vec<u8>);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if !self.0.is_empty() {
+                let byte = self.0.remove(0);
+                buf.put_slice(&[byte]);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lines_split_across_reads() {
+        let mut stream = Trickle(b"CONNECT a:443 HTTP/1.1\r\nHost: a\r\n\r\nbody".to_vec());
+        let mut reader = HttpReader::new(4096);
+        assert_eq!(
+            reader.read_line(&mut stream).await.unwrap(),
+            "CONNECT a:443 HTTP/1.1"
+        );
+        assert_eq!(reader.read_line(&mut stream).await.unwrap(), "Host: a");
+        assert_eq!(reader.read_line(&mut stream).await.unwrap(), "");
+        assert_eq!(reader.take_buffered(), b"");
+        // A lone CR or LF is part of the line.
+        let mut stream = Trickle(b"a\rb\nc\r\n".to_vec());
+        assert_eq!(reader.read_line(&mut stream).await.unwrap(), "a\rb\nc");
+    }
+
+    #[tokio::test]
+    async fn test_several_lines_in_one_read() {
+        let mut stream: &[u8] = b"one\r\ntwo\r\n\r\nbody";
+        let mut reader = HttpReader::new(4096);
+        assert_eq!(reader.read_line(&mut stream).await.unwrap(), "one");
+        assert_eq!(reader.read_line(&mut stream).await.unwrap(), "two");
+        assert_eq!(reader.read_line(&mut stream).await.unwrap(), "");
+        assert_eq!(reader.take_buffered(), b"body");
+    }
+
+    #[tokio::test]
+    async fn test_line_length_limit() {
+        let mut longest = vec![b'a'; MAX_LINE];
+        longest.extend_from_slice(b"\r\n");
+        let mut reader = HttpReader::new(3);
+        let line = reader.read_line(&mut &longest[..]).await.unwrap();
+        assert_eq!(line.len(), MAX_LINE);
+
+        let mut too_long = vec![b'a'; MAX_LINE + 1];
+        too_long.extend_from_slice(b"\r\n");
+        for read_size in [3, 16 * 1024] {
+            let mut reader = HttpReader::new(read_size);
+            let e = reader.read_line(&mut &too_long[..]).await.unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+        }
+        // Without a CRLF ever arriving, too.
+        let e = HttpReader::new(4096)
+            .read_line(&mut tokio::io::repeat(b'a'))
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_read_head() {
+        let mut stream = Trickle(b"CONNECT a:443 HTTP/1.1\r\nHost: a\r\nX: 1\r\n\r\nbody".to_vec());
+        let mut reader = HttpReader::new(4096);
+        let head = reader
+            .read_head(&mut stream, HeadLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(head.request_line, "CONNECT a:443 HTTP/1.1");
+        assert_eq!(head.headers, ["Host: a", "X: 1"]);
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"body");
+
+        // 24 bytes of request line, 6 of each header and 2 for the blank line.
+        let text = b"CONNECT a:443 HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n";
+        let too_large = |max_bytes, max_headers| async move {
+            let limits = HeadLimits {
+                max_bytes,
+                max_headers,
+            };
+            let head = HttpReader::new(4096)
+                .read_head(&mut &text[..], limits)
+                .await;
+            let status = head.map_err(|e| {
+                crate::connection_error::proxy_error_of(&e)
+                    .unwrap()
+                    .status()
+            });
+            status.err()
+        };
+        assert_eq!(too_large(38, 2).await, None);
+        assert_eq!(too_large(37, 2).await, Some(431));
+        assert_eq!(too_large(38, 1).await, Some(431));
+        // A head that never ends is cut off at the limit.
+        let limits = HeadLimits::default();
+        let mut endless = b"CONNECT a:443 HTTP/1.1\r\n".chain(tokio::io::repeat(b'a'));
+        let e = HttpReader::new(4096)
+            .read_head(&mut endless, limits)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_peer_closing_mid_line() {
+        for data in [&b""[..], b"CONNECT a:443", b"Host: a\r"] {
+            let mut reader = HttpReader::new(4096);
+            let e = reader
+                .read_line(&mut Trickle(data.to_vec()))
+                .await
+                .unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peek_leaves_the_byte_to_read() {
+        let mut stream: &[u8] = b"\x05\x01\x00rest";
+        let mut reader = HttpReader::new(2);
+        assert_eq!(reader.peek(&mut stream).await.unwrap(), Some(5));
+        assert_eq!(reader.peek(&mut stream).await.unwrap(), Some(5));
+        let mut rewound = Rewound::new(reader.take_buffered(), stream);
+        let mut all = vec![];
+        rewound.read_to_end(&mut all).await.unwrap();
+        assert_eq!(all, b"\x05\x01\x00rest");
+        let mut reader = HttpReader::new(4096);
+        assert_eq!(reader.peek(&mut &b""[..]).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8() {
+        let mut reader = HttpReader::new(4096);
+        let e = reader
+            .read_line(&mut &b"\xff\r\nok\r\n"[..])
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(reader.read_line(&mut &b""[..]).await.unwrap(), "ok");
+    }
+}
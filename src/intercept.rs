@@ -0,0 +1,265 @@
+// Hooks that see, and may change, the bytes of a tunnel as they go through.
+// Each chunk read from one side is offered to the tunnel's interceptors
+// before it is recorded or written to the other side. The bytes the client
+// sent before the tunnel was established, such as a forwarded request's
+// head, are offered before the target is connected to, so an abort there is
+// answered with an error rather than a closed connection.
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// What becomes of a chunk once an interceptor has seen it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Sends the chunk on, as the interceptor left it.
+    Forward,
+    /// Leaves the chunk out; nothing is sent for it.
+    Drop,
+    /// Tears the tunnel down.
+    Abort(String),
+}
+
+/// One tunnel's interceptor. A chunk may be changed in place, grown or
+/// emptied before it is forwarded.
+pub trait Interceptor: Send {
+    fn on_client_data(&mut self, chunk: &mut Vec<u8>) -> io::Result<Action>;
+    fn on_server_data(&mut self, chunk: &mut Vec<u8>) -> io::Result<Action>;
+    /// Called once the tunnel is done, however it ended.
+    fn on_close(&mut self) {}
+}
+
+/// Makes the interceptor for each new tunnel to `target`, `host:port` as the
+/// client asked for it. None leaves that tunnel alone.
+pub trait Factory: Send + Sync {
+    fn intercept(&self, target: &str) -> Option<Box<dyn Interceptor>>;
+}
+
+/// A tunnel's interceptors, offered each chunk in order until one does not
+/// forward it.
+pub struct Chain {
+    interceptors: Mutex<Vec<Box<dyn Interceptor>>>,
+    // Client bytes offered by `offer_first`, which the tunnel reads again.
+    seen: AtomicUsize,
+}
+
+impl Chain {
+    /// None without any interceptor for `target`.
+    pub fn open(factories: &[Arc<dyn Factory>], target: &str) -> Option<Self> {
+        let interceptors: Vec<_> = factories
+            .iter()
+            .filter_map(|factory| factory.intercept(target))
+            .collect();
+        (!interceptors.is_empty()).then(|| Self {
+            interceptors: Mutex::new(interceptors),
+            seen: AtomicUsize::new(0),
+        })
+    }
+
+    /// Offers what the client sent before the tunnel. What it becomes is
+    /// what the tunnel sends first, and is not offered again.
+    pub fn offer_first(&self, first: &mut Vec<u8>) -> io::Result<Action> {
+        let action = self.offer(true, first)?;
+        if action == Action::Forward {
+            self.seen.store(first.len(), Ordering::Relaxed);
+        }
+        Ok(action)
+    }
+
+    /// `up` is client to target.
+    pub fn offer(&self, up: bool, chunk: &mut Vec<u8>) -> io::Result<Action> {
+        let seen = match up {
+            true => self.seen.load(Ordering::Relaxed).min(chunk.len()),
+            false => 0,
+        };
+        if seen > 0 {
+            self.seen.fetch_sub(seen, Ordering::Relaxed);
+            let mut rest = chunk.split_off(seen);
+            if rest.is_empty() {
+                return Ok(Action::Forward);
+            }
+            let action = self.offer(up, &mut rest)?;
+            if action == Action::Forward {
+                chunk.append(&mut rest);
+            }
+            return Ok(action);
+        }
+        for interceptor in self.interceptors.lock().unwrap().iter_mut() {
+            let action = match up {
+                true => interceptor.on_client_data(chunk)?,
+                false => interceptor.on_server_data(chunk)?,
+            };
+            if action != Action::Forward {
+                return Ok(action);
+            }
+        }
+        Ok(Action::Forward)
+    }
+}
+
+impl Drop for Chain {
+    fn drop(&mut self) {
+        for interceptor in self.interceptors.get_mut().unwrap().iter_mut() {
+            interceptor.on_close();
+        }
+    }
+}
+
+/// Reads a `--block-pattern` value, `hex:` and an even number of hex digits.
+pub fn parse_pattern(text: &str) -> Result<Vec<u8>, String> {
+    let invalid = || format!("expected hex: and hex digits, got {text}");
+    let digits = text.strip_prefix("hex:").ok_or_else(invalid)?;
+    if digits.is_empty() || digits.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok())
+        .collect::<Option<_>>()
+        .ok_or_else(invalid)
+}
+
+/// `--block-pattern`: aborts the tunnels that carry the pattern in either
+/// direction, including where it is split across chunks.
+pub struct BlockPattern(pub Vec<u8>);
+
+impl Factory for BlockPattern {
+    fn intercept(&self, _: &str) -> Option<Box<dyn Interceptor>> {
+        Some(Box::new(PatternBlocker {
+            pattern: self.0.clone(),
+            tails: [vec![], vec![]],
+        }))
+    }
+}
+
+struct PatternBlocker {
+    pattern: Vec<u8>,
+    // The end of what each direction carried so far, one byte short of the
+    // pattern, for a match that started in an earlier chunk.
+    tails: [Vec<u8>; 2],
+}
+
+impl PatternBlocker {
+    fn check(&mut self, up: bool, chunk: &[u8]) -> Action {
+        let tail = &mut self.tails[usize::from(up)];
+        tail.extend_from_slice(chunk);
+        if tail.windows(self.pattern.len()).any(|w| w == self.pattern) {
+            let hex: String = self.pattern.iter().map(|b| format!("{b:02x}")).collect();
+            return Action::Abort(format!("blocked pattern hex:{hex}"));
+        }
+        let keep = self.pattern.len() - 1;
+        tail.drain(..tail.len().saturating_sub(keep));
+        Action::Forward
+    }
+}
+
+impl Interceptor for PatternBlocker {
+    fn on_client_data(&mut self, chunk: &mut Vec<u8>) -> io::Result<Action> {
+        Ok(self.check(true, chunk))
+    }
+
+    fn on_server_data(&mut self, chunk: &mut Vec<u8>) -> io::Result<Action> {
+        Ok(self.check(false, chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_pattern() {
+        assert_eq!(
+            parse_pattern("hex:DEADbeef"),
+            Ok(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+        for bad in ["deadbeef", "hex:", "hex:abc", "hex:zz", "hex:é0"] {
+            assert!(parse_pattern(bad).is_err(), "{bad}");
+        }
+    }
+    #[test]
+    fn test_pattern_split_across_chunks() {
+        let factories: Vec<Arc<dyn Factory>> = vec![Arc::new(BlockPattern(b"EVIL".to_vec()))];
+        let chain = Chain::open(&factories, "example.com:443").unwrap();
+        for chunk in ["harmless E", "V", "xIL"] {
+            assert_eq!(
+                chain.offer(true, &mut chunk.into()).unwrap(),
+                Action::Forward
+            );
+        }
+        // Each direction is matched on its own.
+        assert_eq!(
+            chain.offer(false, &mut b"EV".to_vec()).unwrap(),
+            Action::Forward
+        );
+        assert_eq!(
+            chain.offer(true, &mut b"IL".to_vec()).unwrap(),
+            Action::Forward
+        );
+        let action = chain.offer(false, &mut b"IL and more".to_vec()).unwrap();
+        assert_eq!(
+            action,
+            Action::Abort("blocked pattern hex:4556494c".to_string())
+        );
+    }
+    struct Counting(Arc<Mutex<Vec<&'static str>>>);
+    impl Interceptor for Counting {
+        fn on_client_data(&mut self, chunk: &mut Vec<u8>) -> io::Result<Action> {
+            self.0.lock().unwrap().push("client");
+            Ok(match chunk.as_slice() {
+                b"drop" => Action::Drop,
+                _ => Action::Forward,
+            })
+        }
+        fn on_server_data(&mut self, _: &mut Vec<u8>) -> io::Result<Action> {
+            self.0.lock().unwrap().push("server");
+            Ok(Action::Forward)
+        }
+        fn on_close(&mut self) {
+            self.0.lock().unwrap().push("close");
+        }
+    }
+    struct Factories(Arc<Mutex<Vec<&'static str>>>);
+    impl Factory for Factories {
+        fn intercept(&self, target: &str) -> Option<Box<dyn Interceptor>> {
+            (target != "skipped:80").then(|| Box::new(Counting(self.0.clone())) as _)
+        }
+    }
+    #[test]
+    fn test_chain_stops_at_the_first_interceptor_that_does_not_forward() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let factories: Vec<Arc<dyn Factory>> = vec![
+            Arc::new(Factories(seen.clone())),
+            Arc::new(Factories(seen.clone())),
+        ];
+        assert!(Chain::open(&factories, "skipped:80").is_none());
+        let chain = Chain::open(&factories, "example.com:80").unwrap();
+        assert_eq!(
+            chain.offer(true, &mut b"drop".to_vec()).unwrap(),
+            Action::Drop
+        );
+        assert_eq!(chain.offer(false, &mut vec![]).unwrap(), Action::Forward);
+        drop(chain);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["client", "server", "server", "close", "close"]
+        );
+    }
+    #[test]
+    fn test_first_bytes_are_offered_once() {
+        let factories: Vec<Arc<dyn Factory>> = vec![Arc::new(BlockPattern(b"EVIL".to_vec()))];
+        let chain = Chain::open(&factories, "example.com:80").unwrap();
+        let mut first = b"GET /EVI".to_vec();
+        assert_eq!(chain.offer_first(&mut first).unwrap(), Action::Forward);
+        // The tunnel reads the first bytes again, in chunks of its own.
+        assert_eq!(
+            chain.offer(true, &mut b"GET ".to_vec()).unwrap(),
+            Action::Forward
+        );
+        let mut chunk = b"/EVIL".to_vec();
+        let action = chain.offer(true, &mut chunk).unwrap();
+        assert_eq!(
+            action,
+            Action::Abort("blocked pattern hex:4556494c".to_string())
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Log-linear buckets in the style of HdrHistogram: values below 2^SUB_BUCKET_BITS
+// are exact, above that every power of two is split into 2^(SUB_BUCKET_BITS-1)
+// linear buckets, which bounds the relative error to about 3%.
+const SUB_BUCKET_BITS: u32 = 6;
+const HALF_SUB_BUCKETS: u64 = 1 << (SUB_BUCKET_BITS - 1);
+const BUCKETS: usize = ((64 - SUB_BUCKET_BITS as usize) + 2) * HALF_SUB_BUCKETS as usize;
+
+fn bucket_index(value: u64) -> usize {
+    let shift = (64 - value.leading_zeros()).saturating_sub(SUB_BUCKET_BITS);
+    ((shift as u64) * HALF_SUB_BUCKETS + (value >> shift)) as usize
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    let index = index as u64;
+    if index < 2 * HALF_SUB_BUCKETS {
+        return index;
+    }
+    let shift = index / HALF_SUB_BUCKETS - 1;
+    let top = index - shift * HALF_SUB_BUCKETS;
+    ((top + 1) << shift).wrapping_sub(1)
+}
+
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; BUCKETS],
+            total: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.counts[bucket_index(value)] += 1;
+        self.total += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    fn clear(&mut self) {
+        self.counts.iter_mut().for_each(|count| *count = 0);
+        self.total = 0;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.total += other.total;
+    }
+
+    /// The highest value equivalent to the given percentile (0-100], or None
+    /// when empty.
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        if self.total == 0 {
+            return None;
+        }
+        let rank = ((percentile / 100.0) * self.total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(bucket_upper_bound(index));
+            }
+        }
+        Some(bucket_upper_bound(BUCKETS - 1))
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Slot {
+    number: u64,
+    histogram: Histogram,
+}
+
+/// A histogram over a trailing time window, kept as a ring of fixed-width
+/// slots that are recycled as time moves on.
+pub struct WindowedHistogram {
+    origin: Instant,
+    slot_width: Duration,
+    slots: Vec<Slot>,
+}
+
+impl WindowedHistogram {
+    pub fn new(origin: Instant, window: Duration, slots: usize) -> Self {
+        Self {
+            origin,
+            slot_width: window / slots as u32,
+            slots: (0..slots)
+                .map(|_| Slot {
+                    number: u64::MAX,
+                    histogram: Histogram::new(),
+                })
+                .collect(),
+        }
+    }
+
+    fn slot_number(&self, now: Instant) -> u64 {
+        (now.saturating_duration_since(self.origin).as_nanos() / self.slot_width.as_nanos()) as u64
+    }
+
+    pub fn record(&mut self, now: Instant, value: u64) {
+        let number = self.slot_number(now);
+        let len = self.slots.len() as u64;
+        let slot = &mut self.slots[(number % len) as usize];
+        if slot.number != number {
+            slot.number = number;
+            slot.histogram.clear();
+        }
+        slot.histogram.record(value);
+    }
+
+    pub fn snapshot(&self, now: Instant) -> Histogram {
+        let current = self.slot_number(now);
+        let oldest = current.saturating_sub(self.slots.len() as u64 - 1);
+        let mut merged = Histogram::new();
+        for slot in &self.slots {
+            if slot.number >= oldest && slot.number <= current {
+                merged.merge(&slot.histogram);
+            }
+        }
+        merged
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timings {
+    pub connect: Duration,
+    pub ttfb: Option<Duration>,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowReport {
+    pub connect_threshold: Option<Duration>,
+    pub ttfb_threshold: Option<Duration>,
+    pub slow_connect: bool,
+    pub slow_ttfb: bool,
+}
+
+struct Windows {
+    connect: WindowedHistogram,
+    ttfb: WindowedHistogram,
+}
+
+/// Flags connections whose connect latency or time-to-first-byte is above the
+/// configured percentile of the trailing window. Nothing is flagged until the
+/// window holds `min_samples` values, so startup noise is not reported.
+pub struct SlowConnectionDetector {
+    percentile: f64,
+    min_samples: u64,
+    windows: Mutex<Windows>,
+}
+
+const WINDOW_SLOTS: usize = 6;
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros().min(u64::MAX as u128) as u64
+}
+
+fn exceeds(
+    histogram: &Histogram,
+    min_samples: u64,
+    percentile: f64,
+    value: u64,
+) -> (Option<Duration>, bool) {
+    if histogram.count() < min_samples {
+        return (None, false);
+    }
+    let threshold = histogram.percentile(percentile).unwrap();
+    (Some(Duration::from_micros(threshold)), value > threshold)
+}
+
+impl SlowConnectionDetector {
+    pub fn new(origin: Instant, window: Duration, percentile: f64, min_samples: u64) -> Self {
+        Self {
+            percentile,
+            min_samples,
+            windows: Mutex::new(Windows {
+                connect: WindowedHistogram::new(origin, window, WINDOW_SLOTS),
+                ttfb: WindowedHistogram::new(origin, window, WINDOW_SLOTS),
+            }),
+        }
+    }
+
+    /// Compares the connection against the window (excluding itself), then adds
+    /// it. Returns a report when any of its timings is an outlier.
+    pub fn observe(&self, now: Instant, timings: &Timings) -> Option<SlowReport> {
+        let mut windows = self.windows.lock().unwrap();
+        let connect = micros(timings.connect);
+        let (connect_threshold, slow_connect) = exceeds(
+            &windows.connect.snapshot(now),
+            self.min_samples,
+            self.percentile,
+            connect,
+        );
+        windows.connect.record(now, connect);
+        let (ttfb_threshold, slow_ttfb) = match timings.ttfb {
+            Some(ttfb) => {
+                let ttfb = micros(ttfb);
+                let result = exceeds(
+                    &windows.ttfb.snapshot(now),
+                    self.min_samples,
+                    self.percentile,
+                    ttfb,
+                );
+                windows.ttfb.record(now, ttfb);
+                result
+            }
+            None => (None, false),
+        };
+        if !slow_connect && !slow_ttfb {
+            return None;
+        }
+        Some(SlowReport {
+            connect_threshold,
+            ttfb_threshold,
+            slow_connect,
+            slow_ttfb,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_bucket_bounds_are_contiguous() {
+        for index in 1..BUCKETS - 1 {
+            assert_eq!(
+                bucket_index(bucket_upper_bound(index - 1) + 1),
+                index,
+                "index {index}"
+            );
+        }
+        assert_eq!(bucket_index(u64::MAX), BUCKETS - 1);
+    }
+    #[test]
+    fn test_bucket_relative_error() {
+        for value in [1, 63, 64, 65, 1000, 123_456, 10_000_000_000] {
+            let upper = bucket_upper_bound(bucket_index(value));
+            assert!(upper >= value);
+            assert!(
+                (upper - value) as f64 <= value as f64 * 0.032,
+                "value {value}"
+            );
+        }
+    }
+    #[test]
+    fn test_percentiles_of_uniform_distribution() {
+        let mut histogram = Histogram::new();
+        for value in 1..=10_000 {
+            histogram.record(value);
+        }
+        for (percentile, expected) in [(50.0, 5_000.0), (90.0, 9_000.0), (99.0, 9_900.0)] {
+            let actual = histogram.percentile(percentile).unwrap() as f64;
+            assert!(
+                (actual - expected).abs() / expected < 0.04,
+                "p{percentile}: {actual}"
+            );
+        }
+        assert_eq!(
+            histogram.percentile(100.0),
+            Some(bucket_upper_bound(bucket_index(10_000)))
+        );
+        assert_eq!(Histogram::new().percentile(50.0), None);
+    }
+    #[test]
+    fn test_window_forgets_old_slots() {
+        let origin = Instant::now();
+        let mut window = WindowedHistogram::new(origin, Duration::from_secs(60), 6);
+        window.record(origin, 1_000_000);
+        window.record(origin + Duration::from_secs(30), 10);
+        assert_eq!(window.snapshot(origin + Duration::from_secs(55)).count(), 2);
+        let snapshot = window.snapshot(origin + Duration::from_secs(65));
+        assert_eq!(snapshot.count(), 1);
+        assert_eq!(snapshot.percentile(100.0), Some(10));
+        assert_eq!(
+            window.snapshot(origin + Duration::from_secs(120)).count(),
+            0
+        );
+    }
+    fn timings(connect_ms: u64, ttfb_ms: Option<u64>) -> Timings {
+        Timings {
+            connect: Duration::from_millis(connect_ms),
+            ttfb: ttfb_ms.map(Duration::from_millis),
+            duration: Duration::from_secs(1),
+        }
+    }
+    #[test]
+    fn test_detector_flags_outliers() {
+        let origin = Instant::now();
+        let detector = SlowConnectionDetector::new(origin, Duration::from_secs(60), 99.0, 100);
+        // 10-19ms connects, 50-59ms ttfb.
+        for i in 0..1000 {
+            let now = origin + Duration::from_millis(i * 10);
+            assert_eq!(
+                detector.observe(now, &timings(10 + i % 10, Some(50 + i % 10))),
+                None
+            );
+        }
+        let now = origin + Duration::from_secs(11);
+        let report = detector.observe(now, &timings(500, Some(55))).unwrap();
+        assert!(report.slow_connect);
+        assert!(!report.slow_ttfb);
+        let threshold = report.connect_threshold.unwrap();
+        assert!(threshold >= Duration::from_millis(19) && threshold < Duration::from_millis(21));
+        let report = detector.observe(now, &timings(12, Some(900))).unwrap();
+        assert!(!report.slow_connect && report.slow_ttfb);
+        assert_eq!(detector.observe(now, &timings(15, None)), None);
+    }
+    #[test]
+    fn test_detector_waits_for_min_samples() {
+        let origin = Instant::now();
+        let detector = SlowConnectionDetector::new(origin, Duration::from_secs(60), 99.0, 100);
+        for _ in 0..99 {
+            assert_eq!(detector.observe(origin, &timings(10, Some(10))), None);
+        }
+        assert_eq!(detector.observe(origin, &timings(10_000, Some(10))), None);
+        assert!(
+            detector
+                .observe(origin, &timings(10_000, Some(10)))
+                .is_some()
+        );
+    }
+    #[test]
+    fn test_detector_threshold_follows_window() {
+        let origin = Instant::now();
+        let detector = SlowConnectionDetector::new(origin, Duration::from_secs(60), 99.0, 10);
+        for _ in 0..100 {
+            detector.observe(origin, &timings(1000, None));
+        }
+        // Once the slow period has left the window, the fast baseline applies.
+        let later = origin + Duration::from_secs(120);
+        for _ in 0..100 {
+            assert_eq!(detector.observe(later, &timings(10, None)), None);
+        }
+        assert!(
+            detector
+                .observe(later, &timings(100, None))
+                .unwrap()
+                .slow_connect
+        );
+    }
+}
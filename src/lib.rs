@@ -0,0 +1,66 @@
+// A proxy for HTTP CONNECT tunnels and plain HTTP requests, with a rule
+// based policy on where clients may connect to. `Proxy` runs it in-process;
+// the binary is a thin wrapper around it.
+
+use std::error::Error;
+
+mod access_log;
+mod capture;
+mod client;
+pub mod config;
+mod config_file;
+mod connection_error;
+mod debug_dump;
+mod fault;
+mod framing;
+mod har;
+mod headers;
+mod host_filter;
+mod hosts;
+mod http_forward;
+mod http_reader;
+pub mod intercept;
+mod json;
+mod latency;
+mod listener;
+mod log;
+mod metrics;
+mod netlog;
+mod pcap;
+mod policy;
+mod prometheus;
+mod proxy;
+mod proxy_auth;
+mod proxy_protocol;
+mod recorder;
+mod replay;
+mod request_id;
+mod request_line;
+mod resolver;
+mod rules_watch;
+mod sd_notify;
+mod socks5;
+mod statsd;
+mod status;
+mod stream;
+mod tap;
+mod test_policy;
+mod throttle;
+mod tunnel;
+mod upstream;
+mod webhook;
+mod websocket;
+
+pub use connection_error::{ConnectionError, Peer, Stage, Tails, tails_of};
+pub use proxy::{Proxy, Shutdown};
+pub use tunnel::{TunnelStats, forward};
+
+/// The `test-policy` subcommand, given the arguments after it.
+pub async fn run_test_policy(args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let (options, rest) = test_policy::Options::from_args(args)?;
+    let config = config::Config::from_args(rest.into_iter())?;
+    let policy = proxy::build_policy(&config, &config.load_rules()?)?;
+    let stdin = tokio::io::BufReader::new(tokio::io::stdin());
+    test_policy::run(&policy, &options, stdin, tokio::io::stdout()).await?;
+    Ok(())
+}
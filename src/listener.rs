@@ -0,0 +1,260 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use crate::stream::{self, Stream};
+
+const BACKLOG: i32 = 1024;
+
+const HAS_REUSEPORT: bool = cfg!(all(
+    unix,
+    not(any(
+        target_os = "solaris",
+        target_os = "illumos",
+        target_os = "cygwin"
+    ))
+));
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+))]
+fn enable_reuseport(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+)))]
+fn enable_reuseport(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported",
+    ))
+}
+
+fn bind_socket(addr: SocketAddr, reuseport: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if reuseport {
+        enable_reuseport(&socket)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Returns one listener per acceptor. With SO_REUSEPORT every acceptor owns its
+/// own socket and the kernel balances connections between them; without it the
+/// acceptors share a single socket.
+pub fn bind(addr: SocketAddr, acceptors: usize) -> io::Result<Vec<Arc<TcpListener>>> {
+    if acceptors <= 1 || !HAS_REUSEPORT {
+        let listener = Arc::new(bind_socket(addr, false)?);
+        return Ok(vec![listener; acceptors.max(1)]);
+    }
+    let first = bind_socket(addr, true)?;
+    // Binding port 0 picks a port for the first socket; the rest must join it.
+    let addr = first.local_addr()?;
+    let mut listeners = vec![Arc::new(first)];
+    for _ in 1..acceptors {
+        listeners.push(Arc::new(bind_socket(addr, true)?));
+    }
+    Ok(listeners)
+}
+
+/// What clients are accepted on; acceptors sharing a socket hold it each.
+#[derive(Clone)]
+pub enum Listener {
+    Tcp(Arc<TcpListener>),
+    #[cfg(unix)]
+    Unix(Arc<UnixSocket>),
+}
+
+/// A listening unix socket, whose file is removed once it is closed.
+#[cfg(unix)]
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Listener {
+    pub async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(socket) => {
+                let (stream, _) = socket.listener.accept().await?;
+                Ok((Stream::Unix(stream), stream::UNIX_PEER))
+            }
+        }
+    }
+
+    /// Fails for a unix socket, which has no address.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr(),
+            #[cfg(unix)]
+            Listener::Unix(socket) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("listening on unix:{}", socket.path.display()),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => write!(f, "-"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(socket) => write!(f, "unix:{}", socket.path.display()),
+        }
+    }
+}
+
+/// A unix socket at `path`, given `mode` as its permissions, shared by the
+/// acceptors. A socket file left behind by an earlier run is replaced, but
+/// not one that a running process still accepts on.
+#[cfg(unix)]
+pub fn bind_unix(path: &Path, mode: Option<u32>, acceptors: usize) -> io::Result<Vec<Listener>> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    let in_path = |e: io::Error| io::Error::new(e.kind(), format!("{}: {e}", path.display()));
+    if let Ok(metadata) = std::fs::symlink_metadata(path)
+        && metadata.file_type().is_socket()
+    {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(in_path(io::ErrorKind::AddrInUse.into()));
+        }
+        std::fs::remove_file(path).map_err(in_path)?;
+    }
+    let listener = UnixListener::bind(path).map_err(in_path)?;
+    let socket = Arc::new(UnixSocket {
+        listener,
+        path: path.to_path_buf(),
+    });
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).map_err(in_path)?;
+    }
+    Ok(vec![Listener::Unix(socket); acceptors.max(1)])
+}
+
+#[cfg(not(unix))]
+pub fn bind_unix(_: &Path, _: Option<u32>, _: usize) -> io::Result<Vec<Listener>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix sockets are not supported on this platform",
+    ))
+}
+
+pub struct AcceptStats {
+    counts: Vec<AtomicU64>,
+    // When each acceptor last ran, in milliseconds since `start`.
+    beats: Vec<AtomicU64>,
+    start: Instant,
+}
+
+impl AcceptStats {
+    pub fn new(acceptors: usize) -> Self {
+        Self {
+            counts: (0..acceptors).map(|_| AtomicU64::new(0)).collect(),
+            beats: (0..acceptors).map(|_| AtomicU64::new(0)).collect(),
+            start: Instant::now(),
+        }
+    }
+    pub fn record(&self, acceptor: usize) {
+        self.counts[acceptor].fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn beat(&self, acceptor: usize) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.beats[acceptor].store(now, Ordering::Relaxed);
+    }
+    /// Whether every acceptor has run within `max_age`.
+    pub fn alive(&self, max_age: Duration) -> bool {
+        let now = self.start.elapsed().as_millis() as u64;
+        let max_age = max_age.as_millis() as u64;
+        self.beats
+            .iter()
+            .all(|beat| now.saturating_sub(beat.load(Ordering::Relaxed)) <= max_age)
+    }
+    pub fn counts(&self) -> Vec<u64> {
+        self.counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[tokio::test]
+    async fn test_bind_shares_port() {
+        let listeners = bind("127.0.0.1:0".parse().unwrap(), 4).unwrap();
+        assert_eq!(listeners.len(), 4);
+        let addr = listeners[0].local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        for listener in &listeners {
+            assert_eq!(listener.local_addr().unwrap(), addr);
+        }
+    }
+    #[tokio::test]
+    async fn test_bind_single() {
+        let listeners = bind("127.0.0.1:0".parse().unwrap(), 1).unwrap();
+        assert_eq!(listeners.len(), 1);
+    }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_unix_replaces_a_stale_socket() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("proxy-listen-{}.sock", std::process::id()));
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listeners = bind_unix(&path, Some(0o660), 2).unwrap();
+        assert_eq!(listeners.len(), 2);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        assert_eq!(listeners[0].to_string(), format!("unix:{}", path.display()));
+        // Taken while it is open, and removed once closed.
+        let e = bind_unix(&path, None, 1).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        drop(listeners);
+        assert!(!path.exists());
+    }
+    #[test]
+    fn test_accept_stats() {
+        let stats = AcceptStats::new(3);
+        stats.record(0);
+        stats.record(2);
+        stats.record(2);
+        assert_eq!(stats.counts(), vec![1, 0, 2]);
+        std::thread::sleep(Duration::from_millis(20));
+        stats.beat(0);
+        stats.beat(1);
+        assert!(!stats.alive(Duration::from_millis(10)));
+        stats.beat(2);
+        assert!(stats.alive(Duration::from_millis(10)));
+    }
+}
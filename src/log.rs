@@ -0,0 +1,204 @@
+// Leveled log lines on stderr, leaving stdout to the access log. Lines logged
+// while serving a connection carry its ID and peer, so one connection can be
+// followed among many by grepping for `conn=<id>`.
+
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// This crate's level in a `RUST_LOG` style filter: comma-separated
+/// directives, each a bare level or `target=level`. A directive for this
+/// crate wins over a bare level; those for other targets are ignored.
+pub fn parse_filter(filter: &str) -> Result<Option<Level>, String> {
+    let mut default = None;
+    let mut ours = None;
+    for directive in filter.split(',').map(str::trim) {
+        if directive.is_empty() {
+            continue;
+        }
+        let (target, level) = match directive.split_once('=') {
+            Some((target, level)) => (Some(target), level),
+            None => (None, directive),
+        };
+        let level = Level::parse(level).ok_or_else(|| directive.to_string())?;
+        match target {
+            None => default = Some(level),
+            Some(target) if target.split("::").next() == Some(env!("CARGO_CRATE_NAME")) => {
+                ours = Some(level)
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(ours.or(default))
+}
+
+/// The level when `--log-level` is not given: from `RUST_LOG`, or info.
+pub fn level_from_env() -> Level {
+    let Ok(filter) = std::env::var("RUST_LOG") else {
+        return Level::Info;
+    };
+    match parse_filter(&filter) {
+        Ok(level) => level.unwrap_or(Level::Info),
+        Err(directive) => {
+            write(
+                Level::Warn,
+                format_args!("Ignoring RUST_LOG, invalid directive {directive}"),
+            );
+            Level::Info
+        }
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Connection {
+    pub id: u64,
+    pub peer: SocketAddr,
+}
+
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+impl Connection {
+    /// The next accepted connection, numbered from 1 in accept order.
+    pub fn next(peer: SocketAddr) -> Self {
+        Self {
+            id: NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed),
+            peer,
+        }
+    }
+
+    /// The connection the current task serves, if any.
+    pub fn current() -> Option<Self> {
+        CONNECTION.try_with(|connection| *connection).ok()
+    }
+
+    /// Runs `f` with every line it logs tagged with this connection. Tasks
+    /// it spawns are not covered.
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CONNECTION.scope(self, f).await
+    }
+}
+
+tokio::task_local! {
+    static CONNECTION: Connection;
+}
+
+pub fn write(level: Level, args: fmt::Arguments<'_>) {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let time = format!("{}.{:03}", time.as_secs(), time.subsec_millis());
+    match CONNECTION.try_with(|connection| *connection) {
+        Ok(connection) => eprintln!(
+            "{time} {:<5} conn={} peer={}: {args}",
+            level.as_str(),
+            connection.id,
+            connection.peer
+        ),
+        Err(_) => eprintln!("{time} {:<5} {args}", level.as_str()),
+    }
+}
+
+macro_rules! event {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write($level, format_args!($($arg)+));
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log::event!($crate::log::Level::Error, $($arg)+) };
+}
+
+// Named so as not to clash with the `warn` attribute, and exported as `warn`.
+macro_rules! warn_ {
+    ($($arg:tt)+) => { $crate::log::event!($crate::log::Level::Warn, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log::event!($crate::log::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log::event!($crate::log::Level::Debug, $($arg)+) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log::event!($crate::log::Level::Trace, $($arg)+) };
+}
+
+pub(crate) use {debug, error, event, info, trace, warn_ as warn};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(parse_filter("debug"), Ok(Some(Level::Debug)));
+        assert_eq!(parse_filter("proxy=trace"), Ok(Some(Level::Trace)));
+        assert_eq!(
+            parse_filter("warn,hyper=debug,proxy::client=debug"),
+            Ok(Some(Level::Debug))
+        );
+        assert_eq!(parse_filter("proxy=error,info"), Ok(Some(Level::Error)));
+        assert_eq!(parse_filter("tokio=trace"), Ok(None));
+        assert_eq!(parse_filter(""), Ok(None));
+        assert_eq!(parse_filter("proxy=loud"), Err("proxy=loud".to_string()));
+    }
+    #[tokio::test]
+    async fn test_connection_ids_increase() {
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let first = Connection::next(peer);
+        let second = Connection::next(peer);
+        assert!(second.id > first.id);
+        let id = second
+            .scope(async { CONNECTION.with(|connection| connection.id) })
+            .await;
+        assert_eq!(id, second.id);
+        assert!(CONNECTION.try_with(|_| ()).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+// Process-wide counters and gauges, read by the metrics exporters.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::connection_error::{self, classify};
+
+// Timing samples beyond this, not yet taken by an exporter, are dropped.
+const MAX_PENDING_TIMINGS: usize = 10_000;
+
+/// The upper bounds, in seconds, of each timer's histogram buckets, past
+/// which a sample only counts towards `+Inf`.
+pub const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0, 60.0,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    // Resolving and connecting to the target.
+    Connect,
+    // From connecting to the target to the tunnel's end.
+    Connection,
+    // From the accept to the request read.
+    RequestRead,
+    // From the tunnel's start to the target's first byte.
+    FirstByte,
+}
+
+impl Timer {
+    pub const ALL: [Timer; 4] = [
+        Timer::Connect,
+        Timer::Connection,
+        Timer::RequestRead,
+        Timer::FirstByte,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Timer::Connect => "connect_latency",
+            Timer::Connection => "connection_duration",
+            Timer::RequestRead => "request_read_latency",
+            Timer::FirstByte => "first_byte_latency",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    // Samples in each bucket alone; the last is past every bound.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+/// A timer's samples so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    // Samples at or under each of `BUCKETS`, as Prometheus counts them.
+    pub cumulative: Vec<u64>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub connections: AtomicU64,
+    pub active: AtomicU64,
+    // Turned away with a 503 at `--max-connections`.
+    pub rejected: AtomicU64,
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    // Read from one side of a tunnel and not yet written to the other.
+    pub buffered: AtomicU64,
+    // Live tails detached from a tunnel for falling too far behind it.
+    pub evictions: AtomicU64,
+    // Failed connections by stage and error class.
+    errors: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    // None unless an exporter takes the samples.
+    timings: Option<Mutex<Vec<(Timer, Duration)>>>,
+    // One for each of `Timer::ALL`.
+    histograms: [Histogram; 4],
+}
+
+impl Metrics {
+    pub fn with_timings() -> Self {
+        Self {
+            timings: Some(Mutex::new(vec![])),
+            ..Self::default()
+        }
+    }
+
+    /// Counts a connection as active until the guard is dropped.
+    pub fn active(&self) -> ActiveGuard<'_> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard(self)
+    }
+
+    pub fn error(&self, error: &io::Error) {
+        let stage = connection_error::stage_of(error).map_or("other", |stage| stage.as_str());
+        let key = (stage, classify(error.kind()));
+        *self.errors.lock().unwrap().entry(key).or_default() += 1;
+    }
+
+    pub fn errors(&self) -> Vec<((&'static str, &'static str), u64)> {
+        let errors = self.errors.lock().unwrap();
+        errors.iter().map(|(key, count)| (*key, *count)).collect()
+    }
+
+    pub fn time(&self, timer: Timer, duration: Duration) {
+        let histogram = &self.histograms[timer as usize];
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| duration.as_secs_f64() <= bound)
+            .unwrap_or(BUCKETS.len());
+        histogram.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let micros = duration.as_micros().min(u64::MAX.into()) as u64;
+        histogram.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        if let Some(timings) = &self.timings {
+            let mut timings = timings.lock().unwrap();
+            if timings.len() < MAX_PENDING_TIMINGS {
+                timings.push((timer, duration));
+            }
+        }
+    }
+
+    pub fn histogram(&self, timer: Timer) -> HistogramSnapshot {
+        let histogram = &self.histograms[timer as usize];
+        let mut count = 0;
+        let mut cumulative = vec![];
+        for bucket in &histogram.buckets {
+            count += bucket.load(Ordering::Relaxed);
+            cumulative.push(count);
+        }
+        cumulative.pop();
+        HistogramSnapshot {
+            cumulative,
+            count,
+            sum: Duration::from_micros(histogram.sum_micros.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub fn take_timings(&self) -> Vec<(Timer, Duration)> {
+        match &self.timings {
+            Some(timings) => std::mem::take(&mut *timings.lock().unwrap()),
+            None => vec![],
+        }
+    }
+}
+
+pub struct ActiveGuard<'a>(&'a Metrics);
+
+impl Drop for ActiveGuard<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
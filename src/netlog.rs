@@ -0,0 +1,352 @@
+// Writes proxy activity as a Chrome NetLog file, for the netlog viewer
+// (https://netlog-viewer.appspot.com). Each connection is one SOCKET source.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::json;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventType {
+    // Begins on accept, ends when the connection is closed.
+    SocketAlive,
+    ConnectRequest,
+    TcpConnect,
+    TunnelEstablished,
+    SocketBytesSent,
+    SocketBytesReceived,
+}
+
+const EVENT_TYPES: [EventType; 6] = [
+    EventType::SocketAlive,
+    EventType::ConnectRequest,
+    EventType::TcpConnect,
+    EventType::TunnelEstablished,
+    EventType::SocketBytesSent,
+    EventType::SocketBytesReceived,
+];
+
+impl EventType {
+    fn name(self) -> &'static str {
+        match self {
+            EventType::SocketAlive => "SOCKET_ALIVE",
+            EventType::ConnectRequest => "HTTP_TUNNEL_CONNECT_REQUEST",
+            EventType::TcpConnect => "TCP_CONNECT",
+            EventType::TunnelEstablished => "HTTP_TUNNEL_ESTABLISHED",
+            EventType::SocketBytesSent => "SOCKET_BYTES_SENT",
+            EventType::SocketBytesReceived => "SOCKET_BYTES_RECEIVED",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    None = 0,
+    Begin = 1,
+    End = 2,
+}
+
+const SOURCE_TYPE_SOCKET: u32 = 1;
+// Events waiting for the writer; more are dropped and counted, rather than
+// held in memory for as long as the disk is slow.
+const QUEUE_EVENTS: usize = 4096;
+
+fn constants(time_tick_offset: u128) -> String {
+    let mut event_types = String::new();
+    for (id, event_type) in EVENT_TYPES.iter().enumerate() {
+        if id > 0 {
+            event_types.push(',');
+        }
+        let _ = write!(event_types, "{}:{id}", json::quote(event_type.name()));
+    }
+    format!(
+        "{{\"constants\":{{\"logFormatVersion\":1,\"logEventTypes\":{{{event_types}}},\
+         \"logEventPhase\":{{\"PHASE_NONE\":0,\"PHASE_BEGIN\":1,\"PHASE_END\":2}},\
+         \"logSourceType\":{{\"NONE\":0,\"SOCKET\":{SOURCE_TYPE_SOCKET}}},\
+         \"netError\":{{}},\"loadFlag\":{{}},\"addressFamily\":{{}},\
+         \"timeTickOffset\":\"{time_tick_offset}\",\
+         \"clientInfo\":{{\"name\":\"proxy\",\"version\":{}}}}},\n\"events\":[\n",
+        json::quote(env!("CARGO_PKG_VERSION"))
+    )
+}
+
+/// A NetLog file being written. Events are queued to a writer task, so
+/// logging never waits for the disk.
+pub struct NetLog {
+    // None marks the end of the log.
+    sender: mpsc::Sender<Option<String>>,
+    writer: Mutex<Option<JoinHandle<io::Result<()>>>>,
+    start: Instant,
+    next_source: AtomicU64,
+    // How much of each chunk's payload to include; 0 logs byte counts only.
+    capture_bytes: usize,
+    dropped: AtomicU64,
+}
+
+impl NetLog {
+    pub fn create(path: &Path, capture_bytes: usize) -> io::Result<Self> {
+        let file = File::create(path)?;
+        let time_tick_offset = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?
+            .as_millis();
+        let (sender, receiver) = mpsc::channel(QUEUE_EVENTS);
+        let writer = tokio::spawn(write_events(
+            tokio::fs::File::from_std(file),
+            constants(time_tick_offset),
+            receiver,
+        ));
+        Ok(Self {
+            sender,
+            writer: Mutex::new(Some(writer)),
+            start: Instant::now(),
+            next_source: AtomicU64::new(1),
+            capture_bytes,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Events dropped because the writer fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Starts the SOCKET source for a new connection.
+    pub fn source(&self, params: &[(&str, String)]) -> Source<'_> {
+        let source = Source {
+            log: Some(self),
+            id: self.next_source.fetch_add(1, Ordering::Relaxed),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+        };
+        source.event(EventType::SocketAlive, Phase::Begin, params);
+        source
+    }
+
+    fn record(&self, source: u64, event_type: EventType, phase: Phase, params: &[(&str, String)]) {
+        let mut event = format!(
+            "{{\"time\":\"{}\",\"type\":{},\
+             \"source\":{{\"id\":{source},\"type\":{SOURCE_TYPE_SOCKET}}},\"phase\":{}",
+            self.start.elapsed().as_millis(),
+            EVENT_TYPES.iter().position(|t| *t == event_type).unwrap(),
+            phase as u32
+        );
+        if !params.is_empty() {
+            event.push_str(",\"params\":{");
+            for (i, (name, value)) in params.iter().enumerate() {
+                if i > 0 {
+                    event.push(',');
+                }
+                let _ = write!(event, "{}:{value}", json::quote(name));
+            }
+            event.push('}');
+        }
+        event.push('}');
+        // Once finished, later events are dropped, and not counted.
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.sender.try_send(Some(event)) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes the events logged so far and closes the JSON document.
+    pub async fn finish(&self) -> io::Result<()> {
+        let _ = self.sender.send(None).await;
+        let writer = self.writer.lock().unwrap().take();
+        match writer {
+            Some(writer) => writer.await.map_err(io::Error::other)?,
+            None => Ok(()),
+        }
+    }
+}
+
+async fn write_events(
+    file: tokio::fs::File,
+    header: String,
+    mut receiver: mpsc::Receiver<Option<String>>,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(file);
+    out.write_all(header.as_bytes()).await?;
+    let mut first = true;
+    // Flushes whenever the queue runs dry, so the file is readable while the
+    // proxy runs.
+    while let Some(mut next) = receiver.recv().await {
+        loop {
+            let Some(event) = next else {
+                out.write_all(b"\n]}\n").await?;
+                return out.flush().await;
+            };
+            if !first {
+                out.write_all(b",\n").await?;
+            }
+            first = false;
+            out.write_all(event.as_bytes()).await?;
+            match receiver.try_recv() {
+                Ok(event) => next = event,
+                Err(_) => break,
+            }
+        }
+        out.flush().await?;
+    }
+    Ok(())
+}
+
+/// One connection's events. Does nothing when NetLog output is off; the
+/// SOCKET_ALIVE end event is logged when it is dropped.
+pub struct Source<'a> {
+    log: Option<&'a NetLog>,
+    id: u64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl Source<'_> {
+    pub fn disabled() -> Self {
+        Self {
+            log: None,
+            id: 0,
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+        }
+    }
+
+    pub fn event(&self, event_type: EventType, phase: Phase, params: &[(&str, String)]) {
+        if let Some(log) = self.log {
+            log.record(self.id, event_type, phase, params);
+        }
+    }
+
+    /// Logs a chunk forwarded to the target (`sent`) or to the client.
+    pub fn bytes(&self, sent: bool, chunk: &[u8]) {
+        let Some(log) = self.log else {
+            return;
+        };
+        let (event_type, total) = if sent {
+            (EventType::SocketBytesSent, &self.bytes_sent)
+        } else {
+            (EventType::SocketBytesReceived, &self.bytes_received)
+        };
+        total.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        let mut params = vec![("byte_count", chunk.len().to_string())];
+        if log.capture_bytes > 0 {
+            let captured = &chunk[..chunk.len().min(log.capture_bytes)];
+            let mut hex = String::with_capacity(captured.len() * 2);
+            for byte in captured {
+                let _ = write!(hex, "{byte:02X}");
+            }
+            params.push(("hex_encoded_bytes", json::quote(&hex)));
+        }
+        self.event(event_type, Phase::None, &params);
+    }
+}
+
+impl Drop for Source<'_> {
+    fn drop(&mut self) {
+        let params = [
+            (
+                "bytes_sent",
+                self.bytes_sent.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "bytes_received",
+                self.bytes_received.load(Ordering::Relaxed).to_string(),
+            ),
+        ];
+        self.event(EventType::SocketAlive, Phase::End, &params);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[tokio::test]
+    async fn test_events_and_payload_capture() {
+        let path = std::env::temp_dir().join(format!("proxy-netlog-{}.json", std::process::id()));
+        let log = NetLog::create(&path, 2).unwrap();
+        {
+            let source = log.source(&[("source_address", json::quote("127.0.0.1:5000"))]);
+            source.bytes(true, b"abc");
+            source.bytes(false, b"\x00");
+        }
+        log.finish().await.unwrap();
+        // Too late to be written.
+        drop(log.source(&[]));
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let netlog = json::parse(&contents).unwrap();
+        let constants = netlog.get("constants").unwrap();
+        let type_id = |name| {
+            constants
+                .get("logEventTypes")
+                .and_then(|types| types.get(name))
+                .and_then(json::Value::as_u64)
+                .unwrap()
+        };
+        let json::Value::Array(events) = netlog.get("events").unwrap() else {
+            panic!("events is not an array");
+        };
+        let summary: Vec<_> = events
+            .iter()
+            .map(|event| {
+                let field = |name| event.get(name).and_then(json::Value::as_u64).unwrap();
+                let params = event.get("params");
+                let param = |name| params.and_then(|p| p.get(name)).cloned();
+                (
+                    field("type"),
+                    field("phase"),
+                    param("byte_count").and_then(|n| n.as_u64()),
+                    param("hex_encoded_bytes").map(|h| h.as_str().unwrap().to_string()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (type_id("SOCKET_ALIVE"), 1, None, None),
+                (
+                    type_id("SOCKET_BYTES_SENT"),
+                    0,
+                    Some(3),
+                    Some("6162".to_string())
+                ),
+                (
+                    type_id("SOCKET_BYTES_RECEIVED"),
+                    0,
+                    Some(1),
+                    Some("00".to_string())
+                ),
+                (type_id("SOCKET_ALIVE"), 2, None, None),
+            ]
+        );
+        let end = events[3].get("params").unwrap();
+        assert_eq!(end.get("bytes_sent").and_then(json::Value::as_u64), Some(3));
+    }
+    #[tokio::test]
+    async fn test_events_beyond_the_queue_are_dropped() {
+        let path = std::env::temp_dir().join(format!("proxy-netlog-drops-{}", std::process::id()));
+        let log = NetLog::create(&path, 0).unwrap();
+        // The writer does not get to run before the queue is full.
+        let source = log.source(&[]);
+        for _ in 0..QUEUE_EVENTS {
+            source.bytes(true, b"x");
+        }
+        drop(source);
+        assert_eq!(log.dropped(), 2);
+        log.finish().await.unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let netlog = json::parse(&contents).unwrap();
+        let json::Value::Array(events) = netlog.get("events").unwrap() else {
+            panic!("events is not an array");
+        };
+        assert_eq!(events.len(), QUEUE_EVENTS);
+    }
+}
@@ -0,0 +1,468 @@
+// Synthesizes a pcap stream of the tunnelled bytes, as plain TCP between the
+// client and the target, and writes it as it happens to a named pipe,
+// `wireshark -k -i PIPE`, or to a `--pcap` file to be opened afterwards.
+// Each direction's sequence numbers count its bytes, so Wireshark's "Follow
+// TCP Stream" gives back what the tunnel carried.
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::log;
+use crate::policy::{HostPattern, normalize_host};
+
+// Raw IPv4 or IPv6 packets, no link-layer header.
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+// Records waiting to be written; more are dropped rather than slowing
+// tunnels, which for a file is warned about at the first.
+const QUEUE_RECORDS: usize = 4096;
+const MAX_SEGMENT: usize = 65_000;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+fn global_header() -> [u8; 24] {
+    let mut header = [0; 24];
+    header[0..4].copy_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    header[4..6].copy_from_slice(&2u16.to_le_bytes());
+    header[6..8].copy_from_slice(&4u16.to_le_bytes());
+    header[16..20].copy_from_slice(&SNAPLEN.to_le_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    header
+}
+
+fn make_fifo(path: &Path) -> io::Result<()> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_fifo() => return Ok(()),
+        Ok(_) => {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a named pipe", path.display()),
+            ));
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    // SAFETY: `c_path` is a valid NUL-terminated string.
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Runs on its own thread: opening a pipe blocks until a reader attaches, and
+// a reader that goes away is waited for again, starting over with the header.
+fn write_records(path: PathBuf, records: Receiver<Vec<u8>>) {
+    loop {
+        let mut pipe = match OpenOptions::new().write(true).open(&path) {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                log::warn!("pcap pipe {} cannot be opened: {e}", path.display());
+                return;
+            }
+        };
+        log::info!("pcap reader attached to {}", path.display());
+        if pipe.write_all(&global_header()).is_err() {
+            continue;
+        }
+        loop {
+            let Ok(record) = records.recv() else {
+                return;
+            };
+            if let Err(e) = pipe.write_all(&record) {
+                if e.kind() != ErrorKind::BrokenPipe {
+                    log::warn!("pcap pipe {} write failed: {e}", path.display());
+                }
+                log::info!("pcap reader detached from {}", path.display());
+                break;
+            }
+        }
+    }
+}
+
+// Runs on its own thread, flushing whenever it has caught up, so that the
+// file is whole up to the last record but one written.
+fn write_file(path: PathBuf, file: File, records: Receiver<Vec<u8>>) {
+    let mut file = BufWriter::new(file);
+    loop {
+        let record = match records.try_recv() {
+            Ok(record) => record,
+            Err(TryRecvError::Empty) => {
+                if let Err(e) = file.flush() {
+                    log::warn!("pcap file {} write failed: {e}", path.display());
+                    return;
+                }
+                match records.recv() {
+                    Ok(record) => record,
+                    Err(_) => return,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        if let Err(e) = file.write_all(&record) {
+            log::warn!("pcap file {} write failed: {e}", path.display());
+            return;
+        }
+    }
+    let _ = file.flush();
+}
+
+pub struct Pcap {
+    records: SyncSender<Vec<u8>>,
+    // Only connections to these hosts are written; empty means all.
+    hosts: Vec<HostPattern>,
+    dropped: AtomicU64,
+    // Set when writing a file, which is read afterwards as the whole capture,
+    // where a pipe's reader may well come and go.
+    file: Option<PathBuf>,
+}
+
+impl Pcap {
+    /// Creates the named pipe at `path` unless one is already there.
+    pub fn create_pipe(path: &Path, hosts: Vec<HostPattern>) -> io::Result<Self> {
+        make_fifo(path)?;
+        let (records, receiver) = mpsc::sync_channel(QUEUE_RECORDS);
+        let path = path.to_path_buf();
+        std::thread::Builder::new()
+            .name("pcap-pipe".to_string())
+            .spawn(move || write_records(path, receiver))?;
+        Ok(Self::new(records, hosts, None))
+    }
+
+    /// Creates, or truncates, the capture file at `path`.
+    pub fn create_file(path: &Path, hosts: Vec<HostPattern>) -> io::Result<Self> {
+        let mut file = File::create(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        file.write_all(&global_header())?;
+        let (records, receiver) = mpsc::sync_channel(QUEUE_RECORDS);
+        let file_path = path.to_path_buf();
+        std::thread::Builder::new()
+            .name("pcap-file".to_string())
+            .spawn(move || write_file(file_path, file, receiver))?;
+        Ok(Self::new(records, hosts, Some(path.to_path_buf())))
+    }
+
+    fn new(records: SyncSender<Vec<u8>>, hosts: Vec<HostPattern>, file: Option<PathBuf>) -> Self {
+        Self {
+            records,
+            hosts,
+            dropped: AtomicU64::new(0),
+            file,
+        }
+    }
+
+    pub fn wants(&self, host: &str) -> bool {
+        let host = normalize_host(host);
+        self.hosts.is_empty() || self.hosts.iter().any(|pattern| pattern.matches(&host))
+    }
+
+    /// Records dropped because the pipe's reader, or the file, fell behind,
+    /// or the pipe had no reader.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Starts a flow, writing a TCP handshake for it.
+    pub fn flow(&self, client: SocketAddr, target: SocketAddr) -> Flow<'_> {
+        let flow = Flow {
+            pipe: self,
+            client,
+            target,
+            seq: Mutex::new((0, 0)),
+        };
+        flow.segment(true, SYN, &[]);
+        flow.segment(false, SYN | ACK, &[]);
+        flow.segment(true, ACK, &[]);
+        flow
+    }
+
+    fn send(&self, record: Vec<u8>) {
+        if let Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) =
+            self.records.try_send(record)
+        {
+            let first = self.dropped.fetch_add(1, Ordering::Relaxed) == 0;
+            if first && let Some(path) = &self.file {
+                log::warn!(
+                    "pcap file {} is missing records, the writer having fallen behind",
+                    path.display()
+                );
+            }
+        }
+    }
+}
+
+/// One tunnel as a synthetic TCP connection from the client to the target.
+pub struct Flow<'a> {
+    pipe: &'a Pcap,
+    client: SocketAddr,
+    target: SocketAddr,
+    // Next sequence number from the client and from the target.
+    seq: Mutex<(u32, u32)>,
+}
+
+impl Flow<'_> {
+    /// Bytes forwarded to the target (`to_target`) or to the client.
+    pub fn data(&self, to_target: bool, bytes: &[u8]) {
+        for chunk in bytes.chunks(MAX_SEGMENT) {
+            self.segment(to_target, PSH | ACK, chunk);
+        }
+    }
+
+    pub fn fin(&self, to_target: bool) {
+        self.segment(to_target, FIN | ACK, &[]);
+    }
+
+    fn segment(&self, to_target: bool, flags: u8, payload: &[u8]) {
+        let (seq, ack) = {
+            let mut seq = self.seq.lock().unwrap();
+            let (client, target) = &mut *seq;
+            let (ours, theirs) = if to_target {
+                (client, *target)
+            } else {
+                (target, *client)
+            };
+            let current = *ours;
+            let consumed = payload.len() as u32 + u32::from(flags & (SYN | FIN) != 0);
+            *ours = ours.wrapping_add(consumed);
+            (current, theirs)
+        };
+        let (src, dst) = if to_target {
+            (self.client, self.target)
+        } else {
+            (self.target, self.client)
+        };
+        let ack = if flags & ACK != 0 { ack } else { 0 };
+        let packet = packet(src, dst, seq, ack, flags, payload);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet);
+        self.pipe.send(record);
+    }
+}
+
+fn checksum(data: &[u8], mut sum: u32) -> u16 {
+    for pair in data.chunks(2) {
+        let word = u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]);
+        sum += u32::from(word);
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    tcp.extend_from_slice(&[5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    tcp.extend_from_slice(payload);
+    let tcp_len = tcp.len() as u32;
+    // A v4 client of a v6 target, or the reverse, is shown as IPv6.
+    let (src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut pseudo = [0; 12];
+            pseudo[0..4].copy_from_slice(&src.octets());
+            pseudo[4..8].copy_from_slice(&dst.octets());
+            pseudo[9] = 6;
+            pseudo[10..12].copy_from_slice(&(tcp_len as u16).to_be_bytes());
+            let tcp_sum = checksum(&tcp, pseudo_sum(&pseudo));
+            tcp[16..18].copy_from_slice(&tcp_sum.to_be_bytes());
+            let mut ip = vec![0x45, 0];
+            ip.extend_from_slice(&((20 + tcp_len) as u16).to_be_bytes());
+            ip.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            ip.extend_from_slice(&src.octets());
+            ip.extend_from_slice(&dst.octets());
+            let ip_sum = checksum(&ip, 0);
+            ip[10..12].copy_from_slice(&ip_sum.to_be_bytes());
+            ip.extend_from_slice(&tcp);
+            return ip;
+        }
+        (src, dst) => (to_v6(src), to_v6(dst)),
+    };
+    let mut pseudo = [0; 40];
+    pseudo[0..16].copy_from_slice(&src_ip.octets());
+    pseudo[16..32].copy_from_slice(&dst_ip.octets());
+    pseudo[32..36].copy_from_slice(&tcp_len.to_be_bytes());
+    pseudo[39] = 6;
+    let tcp_sum = checksum(&tcp, pseudo_sum(&pseudo));
+    tcp[16..18].copy_from_slice(&tcp_sum.to_be_bytes());
+    let mut ip = vec![0x60, 0, 0, 0];
+    ip.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+    ip.extend_from_slice(&[6, 64]);
+    ip.extend_from_slice(&src_ip.octets());
+    ip.extend_from_slice(&dst_ip.octets());
+    ip.extend_from_slice(&tcp);
+    ip
+}
+
+fn pseudo_sum(pseudo: &[u8]) -> u32 {
+    u32::from(!checksum(pseudo, 0))
+}
+
+fn to_v6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Read;
+    use std::time::Duration;
+
+    // Reads the pcap stream until `payload` is seen in a TCP segment.
+    fn read_until(mut pipe: File, payload: &'static [u8]) -> mpsc::Receiver<File> {
+        let (found, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut header = [0; 24];
+            pipe.read_exact(&mut header).unwrap();
+            assert_eq!(header, global_header());
+            loop {
+                let mut record = [0; 16];
+                pipe.read_exact(&mut record).unwrap();
+                let len = u32::from_le_bytes(record[8..12].try_into().unwrap()) as usize;
+                let mut packet = vec![0; len];
+                pipe.read_exact(&mut packet).unwrap();
+                assert_eq!(packet[0], 0x45);
+                assert_eq!(checksum(&packet[..20], 0), 0);
+                if &packet[40..] == payload {
+                    found.send(pipe).unwrap();
+                    return;
+                }
+            }
+        });
+        receiver
+    }
+
+    #[test]
+    fn test_live_reader_sees_frames_and_can_reattach() {
+        let path = std::env::temp_dir().join(format!("proxy-pcap-{}.pipe", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pcap = Pcap::create_pipe(&path, vec![]).unwrap();
+        // Nobody is reading yet; nothing blocks.
+        let flow = pcap.flow(
+            "10.0.0.1:5000".parse().unwrap(),
+            "93.184.216.34:443".parse().unwrap(),
+        );
+        let reader = read_until(File::open(&path).unwrap(), b"hello");
+        flow.data(true, b"hello");
+        let pipe = reader.recv_timeout(Duration::from_secs(2)).unwrap();
+
+        drop(pipe);
+        // Noticed by the writer, which waits for the next reader.
+        flow.data(false, b"lost");
+        std::thread::sleep(Duration::from_millis(100));
+        let reader = read_until(File::open(&path).unwrap(), b"again");
+        flow.data(false, b"again");
+        reader.recv_timeout(Duration::from_secs(2)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // The TCP payloads of a capture file from the client and from the target,
+    // checking that each direction's sequence numbers follow on.
+    fn payloads(file: &[u8]) -> [Vec<u8>; 2] {
+        assert_eq!(file[..24], global_header());
+        let mut segments = [vec![], vec![]];
+        let mut rest = &file[24..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+            assert_eq!(rest[12..16], rest[8..12]);
+            let packet = &rest[16..16 + len];
+            rest = &rest[16 + len..];
+            assert_eq!(usize::from(u16::from_be_bytes([packet[2], packet[3]])), len);
+            let from_client = packet[12..16] == [10, 0, 0, 1];
+            let seq = u32::from_be_bytes(packet[24..28].try_into().unwrap());
+            if len > 40 {
+                segments[usize::from(!from_client)].push((seq, packet[40..].to_vec()));
+            }
+        }
+        segments.map(|segments| {
+            // After the SYN, which takes a sequence number of its own.
+            let mut next = 1;
+            let mut bytes = vec![];
+            for (seq, payload) in segments {
+                assert_eq!(seq, next);
+                next += payload.len() as u32;
+                bytes.extend(payload);
+            }
+            bytes
+        })
+    }
+
+    #[test]
+    fn test_file_reproduces_each_direction() {
+        let path = std::env::temp_dir().join(format!("proxy-pcap-{}.pcap", std::process::id()));
+        let pcap = Pcap::create_file(&path, vec![]).unwrap();
+        let flow = pcap.flow(
+            "10.0.0.1:5000".parse().unwrap(),
+            "93.184.216.34:443".parse().unwrap(),
+        );
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec();
+        let response: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        flow.data(true, &request[..10]);
+        flow.data(false, &response[..100]);
+        flow.data(true, &request[10..]);
+        // Larger than a segment, so split.
+        flow.data(false, &response[100..]);
+        flow.fin(true);
+        flow.fin(false);
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        let file = loop {
+            let file = std::fs::read(&path).unwrap();
+            match payloads(&file) {
+                [up, down] if up.len() == request.len() && down.len() == response.len() => {
+                    break file;
+                }
+                _ => assert!(std::time::Instant::now() < deadline, "capture incomplete"),
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(payloads(&file), [request, response]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_host_filter() {
+        let pcap = Pcap::new(
+            mpsc::sync_channel(1).0,
+            vec![HostPattern::parse("*.example.com").unwrap()],
+            None,
+        );
+        assert!(pcap.wants("WWW.example.com."));
+        assert!(!pcap.wants("example.org"));
+        pcap.send(vec![]);
+        assert_eq!(pcap.dropped(), 1);
+    }
+}
@@ -0,0 +1,204 @@
+use std::net::IpAddr;
+
+/// An IPv4 or IPv6 network in CIDR notation; a bare address is a /32 or /128.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+fn mask_v4(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn mask_v6(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr.parse().ok()?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|p| *p <= max)?,
+            None => max,
+        };
+        Some(Self { network, prefix })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            IpAddr::V4(_) => addr,
+        };
+        match (self.network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = mask_v4(self.prefix);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = mask_v6(self.prefix);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Lowercases a host and strips a trailing dot and IPv6 brackets, so that
+/// patterns are compared against one canonical spelling.
+pub fn normalize_host(host: &str) -> String {
+    let host = host.trim_end_matches('.');
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    host.to_ascii_lowercase()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    Any,
+    Exact(String),
+    // `*.example.com`: any subdomain of example.com, but not example.com itself.
+    Suffix(String),
+    Network(Cidr),
+}
+
+impl HostPattern {
+    pub fn parse(s: &str) -> Option<Self> {
+        if s.is_empty() {
+            return None;
+        }
+        if s == "*" {
+            return Some(HostPattern::Any);
+        }
+        if let Some(cidr) = Cidr::parse(s) {
+            return Some(HostPattern::Network(cidr));
+        }
+        if let Some(suffix) = s.strip_prefix("*.") {
+            if suffix.is_empty() || suffix.contains('*') {
+                return None;
+            }
+            return Some(HostPattern::Suffix(format!(".{}", normalize_host(suffix))));
+        }
+        if s.contains('*') {
+            return None;
+        }
+        Some(HostPattern::Exact(normalize_host(s)))
+    }
+
+    /// `host` must already be normalized.
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Any => true,
+            HostPattern::Exact(exact) => host == exact,
+            HostPattern::Suffix(suffix) => host.ends_with(suffix.as_str()),
+            HostPattern::Network(cidr) => host.parse().is_ok_and(|addr| cidr.contains(addr)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (first, last) = match s.split_once('-') {
+            Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+            None => {
+                let port = s.parse().ok()?;
+                (port, port)
+            }
+        };
+        (first <= last).then_some(Self { first, last })
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.first <= port && port <= self.last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+    #[test]
+    fn test_cidr_contains() {
+        let net = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        assert!(net.contains(ip("::ffff:10.9.9.9")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(
+            Cidr::parse("192.168.1.7")
+                .unwrap()
+                .contains(ip("192.168.1.7"))
+        );
+        assert!(
+            !Cidr::parse("192.168.1.7")
+                .unwrap()
+                .contains(ip("192.168.1.8"))
+        );
+        let v6 = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(v6.contains(ip("2001:db8::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+    }
+    #[test]
+    fn test_cidr_rejects_malformed() {
+        for s in ["10.0.0.0/33", "::/129", "10.0.0/8", "host/8", "10.0.0.0/x"] {
+            assert_eq!(Cidr::parse(s), None, "{s}");
+        }
+    }
+    #[test]
+    fn test_host_patterns() {
+        let cases = [
+            ("example.com", "example.com", true),
+            ("Example.COM", "example.com", true),
+            ("example.com", "www.example.com", false),
+            ("*.example.com", "www.example.com", true),
+            ("*.example.com", "a.b.example.com", true),
+            ("*.example.com", "example.com", false),
+            ("*.example.com", "badexample.com", false),
+            ("*", "anything.test", true),
+            ("10.0.0.0/8", "10.2.3.4", true),
+            ("10.0.0.0/8", "example.com", false),
+            ("::1", "::1", true),
+        ];
+        for (pattern, host, expected) in cases {
+            let parsed = HostPattern::parse(pattern).unwrap();
+            assert_eq!(
+                parsed.matches(&normalize_host(host)),
+                expected,
+                "{pattern} vs {host}"
+            );
+        }
+        for pattern in ["", "*.", "a*.example.com", "*.*.example.com"] {
+            assert_eq!(HostPattern::parse(pattern), None, "{pattern}");
+        }
+    }
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("WWW.Example.com."), "www.example.com");
+        assert_eq!(normalize_host("[::1]"), "::1");
+    }
+    #[test]
+    fn test_port_range() {
+        assert!(PortRange::parse("22").unwrap().contains(22));
+        assert!(!PortRange::parse("22").unwrap().contains(23));
+        let range = PortRange::parse("8000-8999").unwrap();
+        assert!(range.contains(8000) && range.contains(8999) && !range.contains(9000));
+        assert_eq!(PortRange::parse("9-1"), None);
+        assert_eq!(PortRange::parse("70000"), None);
+    }
+}
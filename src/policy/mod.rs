@@ -0,0 +1,1025 @@
+mod matcher;
+mod plugin;
+mod regex;
+
+pub use matcher::{Cidr, HostPattern, PortRange, normalize_host};
+pub use plugin::{ExecPlugin, PluginDecision, Plugins, PolicyPlugin};
+use regex::RegexSet;
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::connection_error::ConnectionContext;
+use crate::log;
+
+/// What a rule is evaluated against.
+pub struct ConnectRequest<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub client: IpAddr,
+    pub user: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Matcher {
+    Host(HostPattern),
+    // Index into the policy's RegexSet.
+    HostRegex(usize),
+    Port(PortRange),
+    Client(Cidr),
+    // Never matches unauthenticated connections.
+    User(String),
+}
+
+impl Matcher {
+    fn parse(s: &str, regexes: &mut Vec<String>) -> Result<Self, String> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected key=value matcher, got `{s}`"))?;
+        let invalid = || format!("invalid {key} value `{value}`");
+        match key {
+            "host" => HostPattern::parse(value)
+                .map(Matcher::Host)
+                .ok_or_else(invalid),
+            "host_regex" => {
+                RegexSet::new(&[value]).map_err(|(_, e)| format!("{}: {e}", invalid()))?;
+                regexes.push(value.to_string());
+                Ok(Matcher::HostRegex(regexes.len() - 1))
+            }
+            "port" => PortRange::parse(value)
+                .map(Matcher::Port)
+                .ok_or_else(invalid),
+            "client" => Cidr::parse(value).map(Matcher::Client).ok_or_else(invalid),
+            "user" if !value.is_empty() => Ok(Matcher::User(value.to_string())),
+            "user" => Err(invalid()),
+            _ => Err(format!("unknown matcher `{key}`")),
+        }
+    }
+
+    fn matches(&self, request: &ConnectRequest, host: &str, regexes: &[bool]) -> bool {
+        match self {
+            Matcher::Host(pattern) => pattern.matches(host),
+            Matcher::HostRegex(index) => regexes[*index],
+            Matcher::Port(range) => range.contains(request.port),
+            Matcher::Client(cidr) => cidr.contains(request.client),
+            Matcher::User(user) => request.user == Some(user.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Allow,
+    Deny(u16),
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Allow => write!(f, "allow"),
+            Access::Deny(status) => write!(f, "deny({status})"),
+        }
+    }
+}
+
+// Every action belongs to one category; the first matching rule that has an
+// action of a category decides that category.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    Access(Access),
+    // Access decided by the named plugin.
+    External(String),
+    // Connect somewhere else; without a port the requested one is kept.
+    Rewrite(String, Option<u16>),
+}
+
+const DEFAULT_DENY_STATUS: u16 = 403;
+
+impl Action {
+    fn parse(s: &str) -> Result<Self, String> {
+        let (name, argument) = match s.split_once('(') {
+            Some((name, rest)) => {
+                let argument = rest
+                    .strip_suffix(')')
+                    .ok_or_else(|| format!("unterminated action `{s}`"))?;
+                (name, Some(argument))
+            }
+            None => (s, None),
+        };
+        match (name, argument) {
+            ("allow", None) => Ok(Action::Access(Access::Allow)),
+            ("deny", None) => Ok(Action::Access(Access::Deny(DEFAULT_DENY_STATUS))),
+            ("deny", Some(status)) => match status.parse() {
+                Ok(status @ 400..=599) => Ok(Action::Access(Access::Deny(status))),
+                _ => Err(format!("invalid deny status `{status}`")),
+            },
+            ("external", Some(plugin)) => {
+                let plugin = plugin.trim_matches('"');
+                if plugin.is_empty() {
+                    return Err("external plugin name is empty".to_string());
+                }
+                Ok(Action::External(plugin.to_string()))
+            }
+            ("rewrite", Some(target)) => {
+                let (host, port) = parse_authority(target)
+                    .ok_or_else(|| format!("invalid rewrite target `{target}`"))?;
+                Ok(Action::Rewrite(normalize_host(host), port))
+            }
+            ("route" | "throttle" | "record" | "mitm", _) => {
+                Err(format!("action `{name}` is not supported yet"))
+            }
+            _ => Err(format!("unknown action `{s}`")),
+        }
+    }
+}
+
+/// Where a rule came from, used to point at it in errors.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RuleSource {
+    pub origin: String,
+    pub text: String,
+}
+
+#[derive(Debug, Default)]
+struct RuleCounters {
+    evaluations: AtomicU64,
+    matches: AtomicU64,
+    denials: AtomicU64,
+    would_deny: AtomicU64,
+    rewrites: AtomicU64,
+}
+
+/// How often a rule was reached, matched and denied a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleStats {
+    pub index: usize,
+    pub name: Option<String>,
+    pub evaluations: u64,
+    pub matches: u64,
+    pub denials: u64,
+    // Denials a dry run logged instead of enforcing.
+    pub would_deny: u64,
+    pub rewrites: u64,
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: Option<String>,
+    matchers: Vec<Matcher>,
+    actions: Vec<Action>,
+    // Denials are logged but not enforced.
+    dry_run: bool,
+    counters: RuleCounters,
+}
+
+impl Rule {
+    // [name:] [matcher ...] => action[, action ...][, dry_run]
+    fn parse(s: &str, regexes: &mut Vec<String>) -> Result<Self, String> {
+        let (lhs, rhs) = s
+            .split_once("=>")
+            .ok_or_else(|| "missing `=>` between matchers and actions".to_string())?;
+        let mut tokens = lhs.split_whitespace().peekable();
+        let name = match tokens.peek() {
+            Some(token) if token.ends_with(':') && !token.contains('=') => {
+                let name = token.trim_end_matches(':').to_string();
+                tokens.next();
+                Some(name)
+            }
+            _ => None,
+        };
+        let matchers = tokens
+            .map(|token| Matcher::parse(token, regexes))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut dry_run = false;
+        let actions = rhs
+            .split(',')
+            .map(str::trim)
+            .filter(|action| {
+                let flag = *action == "dry_run";
+                dry_run |= flag;
+                !flag
+            })
+            .map(Action::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Rule {
+            name,
+            matchers,
+            actions,
+            dry_run,
+            counters: RuleCounters::default(),
+        })
+    }
+
+    fn matches(&self, request: &ConnectRequest, host: &str, regexes: &[bool]) -> bool {
+        self.matchers
+            .iter()
+            .all(|m| m.matches(request, host, regexes))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyDecision {
+    pub access: Access,
+    // Index of the rule that decided `access`, None for the default.
+    pub access_rule: Option<usize>,
+    // The plugin that decided `access`, and how long it took.
+    pub plugin: Option<String>,
+    pub plugin_latency: Option<Duration>,
+    // False for a denial made in dry-run mode, which lets the connection
+    // proceed.
+    pub enforced: bool,
+    // Where to connect instead of the requested target, and the rule that
+    // said so. Rewritten targets are not evaluated again.
+    pub rewrite: Option<(String, u16)>,
+    pub rewrite_rule: Option<usize>,
+}
+
+pub struct Policy {
+    rules: Vec<Rule>,
+    // Every host_regex of every rule, matched in one pass per connection.
+    host_regexes: RegexSet,
+    plugins: Plugins,
+    // The last rule with a rewrite action; rules after it and the access
+    // decision are not evaluated.
+    last_rewrite: Option<usize>,
+    default_access: Access,
+    dry_run: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            rules: vec![],
+            host_regexes: RegexSet::new::<&str>(&[]).unwrap(),
+            plugins: Plugins::default(),
+            last_rewrite: None,
+            default_access: Access::Allow,
+            dry_run: false,
+        }
+    }
+}
+
+impl Policy {
+    /// Parses and compiles every rule. Nothing is returned unless all of them
+    /// are valid, so a failed reload leaves the running policy untouched.
+    pub fn parse(sources: &[RuleSource], plugins: Plugins) -> io::Result<Self> {
+        let mut regexes = vec![];
+        let rules = sources
+            .iter()
+            .map(|source| {
+                Rule::parse(&source.text, &mut regexes)
+                    .and_then(|rule| {
+                        for action in &rule.actions {
+                            if let Action::External(name) = action
+                                && !plugins.contains(name)
+                            {
+                                return Err(format!("unknown plugin `{name}`"));
+                            }
+                        }
+                        Ok(rule)
+                    })
+                    .map_err(|e| {
+                        io::Error::new(ErrorKind::InvalidInput, format!("{}: {e}", source.origin))
+                    })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        // Every pattern was already validated on its own while parsing.
+        let host_regexes = RegexSet::new(&regexes).unwrap();
+        let last_rewrite = rules.iter().rposition(|rule| {
+            rule.actions
+                .iter()
+                .any(|action| matches!(action, Action::Rewrite(..)))
+        });
+        Ok(Self {
+            rules,
+            host_regexes,
+            plugins,
+            last_rewrite,
+            ..Self::default()
+        })
+    }
+
+    fn regex_matches(&self, host: &str) -> Vec<bool> {
+        if self.host_regexes.is_empty() {
+            vec![]
+        } else {
+            self.host_regexes.matches(host)
+        }
+    }
+
+    /// Every rule whose matchers match the request, including those after
+    /// the rule that decided it. Not counted in the rule stats.
+    pub fn matching_rules(&self, request: &ConnectRequest) -> Vec<usize> {
+        let host = normalize_host(request.host);
+        let regexes = self.regex_matches(&host);
+        (0..self.rules.len())
+            .filter(|index| self.rules[*index].matches(request, &host, &regexes))
+            .collect()
+    }
+
+    /// What applies when no rule decides access.
+    pub fn with_default_access(mut self, access: Access) -> Self {
+        self.default_access = access;
+        self
+    }
+
+    /// Whether some rule can allow a connection, directly or via a plugin.
+    pub fn has_allow_rule(&self) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.actions
+                .iter()
+                .any(|action| matches!(action, Action::Access(Access::Allow) | Action::External(_)))
+        })
+    }
+
+    /// In dry-run mode no denial is enforced, by any rule.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Evaluates the rules alone. When an `external` action decides access,
+    /// `plugin` names it and `access` is the plugins' fallback; use `decide`
+    /// to actually ask the plugin.
+    pub fn evaluate(&self, request: &ConnectRequest) -> PolicyDecision {
+        let host = normalize_host(request.host);
+        let regexes = self.regex_matches(&host);
+        let mut access = None;
+        let mut rewrite = None;
+        for (index, rule) in self.rules.iter().enumerate() {
+            if access.is_some() && (rewrite.is_some() || self.last_rewrite < Some(index)) {
+                break;
+            }
+            rule.counters.evaluations.fetch_add(1, Ordering::Relaxed);
+            if !rule.matches(request, &host, &regexes) {
+                continue;
+            }
+            rule.counters.matches.fetch_add(1, Ordering::Relaxed);
+            for action in &rule.actions {
+                match action {
+                    Action::Access(_) | Action::External(_) => {
+                        access.get_or_insert((action, index));
+                    }
+                    Action::Rewrite(host, port) => {
+                        rewrite.get_or_insert(((host, port.unwrap_or(request.port)), index));
+                    }
+                }
+            }
+        }
+        let access_rule = access.map(|(_, index)| index);
+        let (access, plugin) = match access {
+            None => (self.default_access, None),
+            Some((Action::Access(access), _)) => (*access, None),
+            Some((Action::External(name), _)) => (
+                self.plugin_access(self.plugins.fallback()),
+                Some(name.clone()),
+            ),
+            Some((Action::Rewrite(..), _)) => unreachable!("rewrites do not decide access"),
+        };
+        let mut decision = PolicyDecision {
+            access,
+            access_rule,
+            plugin,
+            plugin_latency: None,
+            enforced: true,
+            rewrite: rewrite.map(|((host, port), _)| (host.clone(), port)),
+            rewrite_rule: rewrite.map(|(_, index)| index),
+        };
+        if let Some(index) = decision.rewrite_rule {
+            self.rules[index]
+                .counters
+                .rewrites
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        // A plugin's answer is only settled once `decide` has it.
+        if decision.plugin.is_none() {
+            self.settle(&mut decision);
+        }
+        decision
+    }
+
+    /// Evaluates the rules and, when an `external` action decides access,
+    /// asks that plugin.
+    pub async fn decide(
+        &self,
+        request: &ConnectRequest<'_>,
+        ctx: &ConnectionContext,
+    ) -> PolicyDecision {
+        let mut decision = self.evaluate(request);
+        if let Some(name) = &decision.plugin {
+            let (answer, latency) = self.plugins.check(name, request, ctx).await;
+            decision.access = self.plugin_access(answer);
+            decision.plugin_latency = Some(latency);
+            self.settle(&mut decision);
+        }
+        decision
+    }
+
+    // Applies dry-run mode to a final decision and counts it.
+    fn settle(&self, decision: &mut PolicyDecision) {
+        if !matches!(decision.access, Access::Deny(_)) {
+            return;
+        }
+        let rule = decision.access_rule.map(|index| &self.rules[index]);
+        decision.enforced = !(self.dry_run || rule.is_some_and(|rule| rule.dry_run));
+        if let Some(rule) = rule {
+            let counter = if decision.enforced {
+                &rule.counters.denials
+            } else {
+                &rule.counters.would_deny
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn rule_stats(&self) -> Vec<RuleStats> {
+        self.rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| RuleStats {
+                index,
+                name: rule.name.clone(),
+                evaluations: rule.counters.evaluations.load(Ordering::Relaxed),
+                matches: rule.counters.matches.load(Ordering::Relaxed),
+                denials: rule.counters.denials.load(Ordering::Relaxed),
+                would_deny: rule.counters.would_deny.load(Ordering::Relaxed),
+                rewrites: rule.counters.rewrites.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+    fn plugin_access(&self, decision: PluginDecision) -> Access {
+        match decision {
+            PluginDecision::Allow => Access::Allow,
+            PluginDecision::Deny(status) => Access::Deny(status),
+            // There is no upstream routing to honour this with, so it is
+            // treated like any other answer the proxy cannot act on.
+            PluginDecision::Route(upstream) => {
+                log::warn!("plugin asked to route via {upstream}, which is not supported");
+                self.plugin_access(self.plugins.fallback())
+            }
+        }
+    }
+
+    pub fn rule_name(&self, rule: usize) -> Option<&str> {
+        self.rules[rule].name.as_deref()
+    }
+
+    /// A short label for a rule in log lines, e.g. `#2 (block-ads)`.
+    pub fn describe(&self, rule: Option<usize>) -> String {
+        match rule.map(|index| (index, &self.rules[index].name)) {
+            None => "default".to_string(),
+            Some((index, None)) => format!("#{}", index + 1),
+            Some((index, Some(name))) => format!("#{} ({name})", index + 1),
+        }
+    }
+}
+
+/// `host`, `host:port`, `[ipv6]` or `[ipv6]:port`. The brackets are not part
+/// of the host returned, so it can be connected to as it is.
+pub fn parse_authority(authority: &str) -> Option<(&str, Option<u16>)> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest.split_once(']')?;
+            host.parse::<Ipv6Addr>().ok()?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':')?)),
+            }
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    if host.is_empty() || host.contains(|c: char| c.is_whitespace() || "[]/@".contains(c)) {
+        return None;
+    }
+    // Only digits: `+443` parses as a u16 too.
+    let port = match port {
+        Some(port) if port.is_empty() || !port.bytes().all(|b| b.is_ascii_digit()) => {
+            return None;
+        }
+        Some(port) => Some(port.parse().ok()?),
+        None => None,
+    };
+    Some((host, port))
+}
+
+/// Splits a CONNECT authority into host and port, defaulting to 443.
+pub fn split_authority(authority: &str) -> Option<(&str, u16)> {
+    let (host, port) = parse_authority(authority)?;
+    Some((host, port.unwrap_or(443)))
+}
+
+/// `host:port`, with an IPv6 host in brackets.
+pub fn join_authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+/// Reads one rule per line; blank lines and lines starting with `#` are skipped.
+pub fn read_rules_file(path: &Path) -> io::Result<Vec<RuleSource>> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("cannot read rules file {}: {e}", path.display()),
+        )
+    })?;
+    Ok(contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(number, line)| RuleSource {
+            origin: format!("{}:{}", path.display(), number + 1),
+            text: line.to_string(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn policy(rules: &[&str]) -> Policy {
+        let sources: Vec<RuleSource> = rules
+            .iter()
+            .enumerate()
+            .map(|(i, text)| RuleSource {
+                origin: format!("rule {}", i + 1),
+                text: text.to_string(),
+            })
+            .collect();
+        Policy::parse(&sources, Plugins::default()).unwrap()
+    }
+    fn request<'a>(
+        host: &'a str,
+        port: u16,
+        client: &str,
+        user: Option<&'a str>,
+    ) -> ConnectRequest<'a> {
+        ConnectRequest {
+            host,
+            port,
+            client: client.parse().unwrap(),
+            user,
+        }
+    }
+    #[test]
+    fn test_precedence_table() {
+        let rules = policy(&[
+            "ssh-internal: port=22 client=10.0.0.0/8 => allow",
+            "no-ssh: port=22 => deny",
+            "alice: user=alice host=*.internal.example.com => allow",
+            "internal: host=*.internal.example.com => deny(451)",
+            "host=ads.example.com => deny(404), allow",
+            "high-ports: port=10000-65535 client=192.168.0.0/16 => deny",
+        ]);
+        let cases: &[(ConnectRequest, Access, Option<usize>)] = &[
+            (
+                request("example.com", 443, "1.2.3.4", None),
+                Access::Allow,
+                None,
+            ),
+            (
+                request("example.com", 22, "10.1.1.1", None),
+                Access::Allow,
+                Some(0),
+            ),
+            (
+                request("example.com", 22, "11.1.1.1", None),
+                Access::Deny(403),
+                Some(1),
+            ),
+            (
+                request("db.internal.example.com", 443, "1.2.3.4", Some("alice")),
+                Access::Allow,
+                Some(2),
+            ),
+            (
+                request("db.internal.example.com", 443, "1.2.3.4", Some("bob")),
+                Access::Deny(451),
+                Some(3),
+            ),
+            (
+                request("db.internal.example.com", 443, "1.2.3.4", None),
+                Access::Deny(451),
+                Some(3),
+            ),
+            (
+                request("DB.Internal.Example.com.", 443, "1.2.3.4", None),
+                Access::Deny(451),
+                Some(3),
+            ),
+            (
+                request("ads.example.com", 443, "1.2.3.4", None),
+                Access::Deny(404),
+                Some(4),
+            ),
+            (
+                request("example.com", 10000, "192.168.3.4", None),
+                Access::Deny(403),
+                Some(5),
+            ),
+            (
+                request("example.com", 9999, "192.168.3.4", None),
+                Access::Allow,
+                None,
+            ),
+            // The earlier ssh rule wins over the later high-ports rule.
+            (
+                request("example.com", 22, "192.168.3.4", None),
+                Access::Deny(403),
+                Some(1),
+            ),
+        ];
+        for (request, access, rule) in cases {
+            let decision = rules.evaluate(request);
+            assert_eq!(
+                (decision.access, decision.access_rule),
+                (*access, *rule),
+                "{}:{} from {} as {:?}",
+                request.host,
+                request.port,
+                request.client,
+                request.user
+            );
+        }
+    }
+    #[test]
+    fn test_catch_all_rule() {
+        let rules = policy(&["host=example.com => allow", "=> deny"]);
+        assert_eq!(
+            rules
+                .evaluate(&request("example.com", 443, "1.2.3.4", None))
+                .access,
+            Access::Allow
+        );
+        assert_eq!(
+            rules
+                .evaluate(&request("other.com", 443, "1.2.3.4", None))
+                .access,
+            Access::Deny(403)
+        );
+    }
+    #[test]
+    fn test_describe() {
+        let rules = policy(&["named: port=1 => deny", "port=2 => deny"]);
+        assert_eq!(rules.describe(None), "default");
+        assert_eq!(rules.describe(Some(0)), "#1 (named)");
+        assert_eq!(rules.describe(Some(1)), "#2");
+    }
+    #[test]
+    fn test_parse_errors_name_the_rule() {
+        let cases = [
+            ("host=example.com deny", "missing `=>`"),
+            ("colour=red => deny", "unknown matcher `colour`"),
+            ("port=99999 => deny", "invalid port value `99999`"),
+            ("client=10.0.0.0/40 => deny", "invalid client value"),
+            ("host=example.com => deny(200)", "invalid deny status `200`"),
+            (
+                "host=example.com => route(parent)",
+                "action `route` is not supported yet",
+            ),
+            ("host=example.com => explode", "unknown action `explode`"),
+            (
+                "host=example.com => rewrite(other:99999)",
+                "invalid rewrite target `other:99999`",
+            ),
+            ("host=example.com => rewrite(:80)", "invalid rewrite target"),
+            ("host_regex=(ads => deny", "invalid host_regex value `(ads`"),
+            (
+                "host=example.com => external(\"entitlements\")",
+                "unknown plugin `entitlements`",
+            ),
+        ];
+        for (text, expected) in cases {
+            let sources = [
+                RuleSource {
+                    origin: "--rule #1".to_string(),
+                    text: "=> allow".to_string(),
+                },
+                RuleSource {
+                    origin: "rules.txt:7".to_string(),
+                    text: text.to_string(),
+                },
+            ];
+            let error = Policy::parse(&sources, Plugins::default())
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(error.starts_with("rules.txt:7: "), "{error}");
+            assert!(error.contains(expected), "{error}");
+        }
+    }
+    #[test]
+    fn test_host_regex_rules() {
+        let rules = policy(&[
+            r"ads: host_regex=^ads[0-9]+\. => deny",
+            "host_regex=tracking port=443 => deny(451)",
+            r"host_regex=(?i)^CDN\d => deny(404)",
+        ]);
+        let cases = [
+            ("ads1.example.com", 443, Some(0)),
+            ("ADS22.Example.com", 443, Some(0)),
+            ("myads1.example.com", 443, None),
+            ("eu.tracking.example.com", 443, Some(1)),
+            ("eu.tracking.example.com", 80, None),
+            ("cdn7.example.com", 443, Some(2)),
+            ("example.com", 443, None),
+        ];
+        for (host, port, rule) in cases {
+            assert_eq!(
+                rules
+                    .evaluate(&request(host, port, "1.2.3.4", None))
+                    .access_rule,
+                rule,
+                "{host}:{port}"
+            );
+        }
+    }
+    #[test]
+    fn test_invalid_host_regex_keeps_old_policy() {
+        let mut current = policy(&["host_regex=^old => deny"]);
+        let sources = [
+            RuleSource {
+                origin: "rules.txt:1".to_string(),
+                text: "host_regex=^new => deny".to_string(),
+            },
+            RuleSource {
+                origin: "rules.txt:2".to_string(),
+                text: "host_regex=(a{100}){100} => deny".to_string(),
+            },
+        ];
+        match Policy::parse(&sources, Plugins::default()) {
+            Ok(policy) => current = policy,
+            Err(e) => {
+                let error = e.to_string();
+                assert!(
+                    error.starts_with("rules.txt:2: invalid host_regex"),
+                    "{error}"
+                );
+                assert!(error.contains("too large"), "{error}");
+            }
+        }
+        let old = request("old.example.com", 443, "1.2.3.4", None);
+        let new = request("new.example.com", 443, "1.2.3.4", None);
+        assert_eq!(current.evaluate(&old).access, Access::Deny(403));
+        assert_eq!(current.evaluate(&new).access, Access::Allow);
+    }
+    struct FixedPlugin(PluginDecision);
+    impl PolicyPlugin for FixedPlugin {
+        fn check<'a>(
+            &'a self,
+            _: &'a ConnectRequest<'a>,
+            _: &'a ConnectionContext,
+        ) -> plugin::PluginFuture<'a> {
+            Box::pin(async move { Ok(self.0.clone()) })
+        }
+    }
+    #[tokio::test]
+    async fn test_external_action_asks_plugin() {
+        let mut plugins = Plugins::new(Duration::from_secs(1), false);
+        for (name, decision) in [
+            ("yes", PluginDecision::Allow),
+            ("no", PluginDecision::Deny(451)),
+            ("route", PluginDecision::Route("parent:3128".to_string())),
+        ] {
+            plugins.insert(name.to_string(), std::sync::Arc::new(FixedPlugin(decision)));
+        }
+        let sources: Vec<RuleSource> = [
+            "port=1 => external(\"yes\")",
+            "port=2 => external(no)",
+            "port=3 => external(\"route\")",
+        ]
+        .iter()
+        .map(|text| RuleSource {
+            origin: "test".to_string(),
+            text: text.to_string(),
+        })
+        .collect();
+        let rules = Policy::parse(&sources, plugins).unwrap();
+        let ctx = ConnectionContext::new("1.2.3.4:5000".parse().unwrap());
+        let cases = [
+            (1, Some("yes"), Access::Allow),
+            (2, Some("no"), Access::Deny(451)),
+            // Routing is not supported, so the fail-closed fallback applies.
+            (3, Some("route"), Access::Deny(503)),
+            (4, None, Access::Allow),
+        ];
+        for (port, plugin, access) in cases {
+            let request = request("example.com", port, "1.2.3.4", None);
+            let pending = rules.evaluate(&request);
+            assert_eq!(pending.plugin.as_deref(), plugin);
+            assert_eq!(pending.plugin_latency, None);
+            let decision = rules.decide(&request, &ctx).await;
+            assert_eq!(decision.access, access, "port {port}");
+            assert_eq!(decision.plugin.as_deref(), plugin);
+            assert_eq!(decision.plugin_latency.is_some(), plugin.is_some());
+        }
+    }
+    #[tokio::test]
+    async fn test_rule_counters() {
+        let mut plugins = Plugins::new(Duration::from_secs(1), false);
+        plugins.insert(
+            "no".to_string(),
+            std::sync::Arc::new(FixedPlugin(PluginDecision::Deny(451))),
+        );
+        let sources: Vec<RuleSource> = [
+            "ssh: port=22 client=10.0.0.0/8 => allow",
+            "port=22 => deny",
+            "entitlements: port=8443 => external(no)",
+            "host=*.example.com => allow",
+        ]
+        .iter()
+        .map(|text| RuleSource {
+            origin: "test".to_string(),
+            text: text.to_string(),
+        })
+        .collect();
+        let rules = Policy::parse(&sources, plugins).unwrap();
+        let ctx = ConnectionContext::new("1.2.3.4:5000".parse().unwrap());
+        for (host, port, client) in [
+            ("example.com", 22, "10.0.0.1"),
+            ("example.com", 22, "1.2.3.4"),
+            ("example.com", 22, "1.2.3.4"),
+            ("example.com", 8443, "1.2.3.4"),
+            ("www.example.com", 443, "1.2.3.4"),
+            ("example.org", 443, "1.2.3.4"),
+        ] {
+            rules.decide(&request(host, port, client, None), &ctx).await;
+        }
+        let counts: Vec<_> = rules
+            .rule_stats()
+            .into_iter()
+            .map(|s| (s.name, s.evaluations, s.matches, s.denials, s.would_deny))
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                (Some("ssh".to_string()), 6, 1, 0, 0),
+                (None, 5, 2, 2, 0),
+                (Some("entitlements".to_string()), 3, 1, 1, 0),
+                (None, 2, 1, 0, 0),
+            ]
+        );
+    }
+    #[test]
+    fn test_dry_run() {
+        let sources: Vec<RuleSource> = [
+            "block-ads: host=ads.example.com => deny, dry_run",
+            "port=22 => deny(451)",
+        ]
+        .iter()
+        .map(|text| RuleSource {
+            origin: "test".to_string(),
+            text: text.to_string(),
+        })
+        .collect();
+        for global in [false, true] {
+            let rules = Policy::parse(&sources, Plugins::default())
+                .unwrap()
+                .with_dry_run(global);
+            let ads = rules.evaluate(&request("ads.example.com", 443, "1.2.3.4", None));
+            assert_eq!((ads.access, ads.enforced), (Access::Deny(403), false));
+            let ssh = rules.evaluate(&request("example.com", 22, "1.2.3.4", None));
+            assert_eq!((ssh.access, ssh.enforced), (Access::Deny(451), !global));
+            let other = rules.evaluate(&request("example.com", 443, "1.2.3.4", None));
+            assert_eq!((other.access, other.enforced), (Access::Allow, true));
+            let counts: Vec<_> = rules
+                .rule_stats()
+                .iter()
+                .map(|s| (s.denials, s.would_deny))
+                .collect();
+            let ssh_counts = if global { (0, 1) } else { (1, 0) };
+            assert_eq!(counts, [(0, 1), ssh_counts]);
+        }
+    }
+    #[test]
+    fn test_default_deny() {
+        let allow_internal = policy(&["host=*.internal.example.com => allow"]);
+        assert!(allow_internal.has_allow_rule());
+        let rules = allow_internal.with_default_access(Access::Deny(403));
+        let internal = rules.evaluate(&request("db.internal.example.com", 443, "1.2.3.4", None));
+        assert_eq!(
+            (internal.access, internal.access_rule),
+            (Access::Allow, Some(0))
+        );
+        let other = rules.evaluate(&request("example.com", 443, "1.2.3.4", None));
+        assert_eq!((other.access, other.access_rule), (Access::Deny(403), None));
+        assert!(other.enforced);
+        let dry_run = rules.with_dry_run(true);
+        assert!(
+            !dry_run
+                .evaluate(&request("example.com", 443, "1.2.3.4", None))
+                .enforced
+        );
+        assert!(!policy(&["port=22 => deny"]).has_allow_rule());
+        assert!(!Policy::default().has_allow_rule());
+    }
+    #[test]
+    fn test_split_authority() {
+        assert_eq!(split_authority("[::1]:8443"), Some(("::1", 8443)));
+        assert_eq!(split_authority("[::1]"), Some(("::1", 443)));
+        assert_eq!(
+            split_authority("[2606:4700::6810:84e5]:443"),
+            Some(("2606:4700::6810:84e5", 443))
+        );
+        assert_eq!(
+            split_authority("example.com:443"),
+            Some(("example.com", 443))
+        );
+        assert_eq!(split_authority("example.com"), Some(("example.com", 443)));
+        assert_eq!(split_authority("10.0.0.1:80"), Some(("10.0.0.1", 80)));
+        for malformed in [
+            "]:80",
+            "",
+            ":443",
+            "[::1",
+            "[::1]443",
+            "[example.com]:443",
+            "::1:443",
+            "example.com:",
+            "example.com:+443",
+            "example.com:65536",
+            "user@example.com:443",
+        ] {
+            assert_eq!(split_authority(malformed), None, "{malformed}");
+        }
+        assert_eq!(join_authority("::1", 443), "[::1]:443");
+        assert_eq!(join_authority("example.com", 443), "example.com:443");
+    }
+    #[test]
+    fn test_rewrite() {
+        let rules = policy(&[
+            "no-ssh: port=22 => deny",
+            "staging: host=api.example.com => rewrite(api-staging.example.com:8443)",
+            "host=*.example.com => rewrite(Mirror.Example.com.)",
+            "port=22 => rewrite(bastion.example.com)",
+        ]);
+        let rewrite = |host, port| {
+            let decision = rules.evaluate(&request(host, port, "1.2.3.4", None));
+            (decision.access, decision.rewrite, decision.rewrite_rule)
+        };
+        assert_eq!(
+            rewrite("API.example.com", 443),
+            (
+                Access::Allow,
+                Some(("api-staging.example.com".to_string(), 8443)),
+                Some(1)
+            )
+        );
+        assert_eq!(
+            rewrite("www.example.com", 80),
+            (
+                Access::Allow,
+                Some(("mirror.example.com".to_string(), 80)),
+                Some(2)
+            )
+        );
+        // Access is decided against the requested target.
+        assert_eq!(
+            rewrite("api.example.com", 22),
+            (
+                Access::Deny(403),
+                Some(("api-staging.example.com".to_string(), 8443)),
+                Some(1)
+            )
+        );
+        // The rewritten target is not matched against the rules again.
+        assert_eq!(
+            rewrite("api-staging.example.com", 443),
+            (
+                Access::Allow,
+                Some(("mirror.example.com".to_string(), 443)),
+                Some(2)
+            )
+        );
+        assert_eq!(rewrite("example.org", 443), (Access::Allow, None, None));
+        let rewrites: Vec<_> = rules.rule_stats().iter().map(|s| s.rewrites).collect();
+        assert_eq!(rewrites, [0, 2, 2, 0]);
+    }
+    #[test]
+    fn test_read_rules_file() {
+        let path = std::env::temp_dir().join(format!("proxy-rules-{}.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            "# comment\n\nport=22 => deny\n  # indented comment\nhost=* => allow\n",
+        )
+        .unwrap();
+        let sources = read_rules_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources[0].origin, format!("{}:3", path.display()));
+        assert_eq!(sources[1].text, "host=* => allow");
+    }
+}
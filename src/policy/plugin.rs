@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+
+use super::ConnectRequest;
+use crate::connection_error::ConnectionContext;
+use crate::{json, log};
+
+// Answers longer than this are malformed.
+const MAX_ANSWER: u64 = 64 * 1024;
+const FAIL_CLOSED_STATUS: u16 = 503;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginDecision {
+    Allow,
+    Deny(u16),
+    Route(String),
+}
+
+pub type PluginFuture<'a> = Pin<Box<dyn Future<Output = io::Result<PluginDecision>> + Send + 'a>>;
+
+/// A decision made outside the proxy, consulted by rules whose action is
+/// `external("name")`.
+pub trait PolicyPlugin: Send + Sync {
+    fn check<'a>(
+        &'a self,
+        request: &'a ConnectRequest<'a>,
+        ctx: &'a ConnectionContext,
+    ) -> PluginFuture<'a>;
+}
+
+/// Runs a program per check, writing the request as JSON to its stdin and
+/// reading one JSON answer from its stdout:
+/// `{"decision": "allow"}`, `{"decision": "deny", "status": 451}` or
+/// `{"decision": "route", "upstream": "parent:3128"}`.
+pub struct ExecPlugin {
+    program: PathBuf,
+    args: Vec<String>,
+}
+
+impl ExecPlugin {
+    pub fn new(program: impl Into<PathBuf>, args: Vec<String>) -> Self {
+        Self {
+            program: program.into(),
+            args,
+        }
+    }
+
+    async fn run(&self, input: String) -> io::Result<PluginDecision> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let write = async move { stdin.write_all(input.as_bytes()).await };
+        let mut stdout = child.stdout.take().unwrap().take(MAX_ANSWER);
+        let mut output = String::new();
+        let read = stdout.read_to_string(&mut output);
+        let (written, read) = tokio::join!(write, read);
+        // The program may answer without reading its input.
+        if let Err(e) = written
+            && e.kind() != ErrorKind::BrokenPipe
+        {
+            return Err(e);
+        }
+        read?;
+        let status = child.wait().await?;
+        if !status.success() {
+            return Err(io::Error::other(format!("exited with {status}")));
+        }
+        parse_answer(&output)
+    }
+}
+
+impl PolicyPlugin for ExecPlugin {
+    fn check<'a>(
+        &'a self,
+        request: &'a ConnectRequest<'a>,
+        ctx: &'a ConnectionContext,
+    ) -> PluginFuture<'a> {
+        Box::pin(self.run(request_json(request, ctx)))
+    }
+}
+
+fn request_json(request: &ConnectRequest, ctx: &ConnectionContext) -> String {
+    format!(
+        "{{\"host\":{},\"port\":{},\"client\":{},\"user\":{},\"target\":{}}}\n",
+        json::quote(request.host),
+        request.port,
+        json::quote(&ctx.client.to_string()),
+        request.user.map_or("null".to_string(), json::quote),
+        ctx.target
+            .as_deref()
+            .map_or("null".to_string(), json::quote),
+    )
+}
+
+fn parse_answer(output: &str) -> io::Result<PluginDecision> {
+    let malformed = |msg: String| io::Error::new(ErrorKind::InvalidData, msg);
+    let answer =
+        json::parse(output.trim()).map_err(|e| malformed(format!("invalid answer: {e}")))?;
+    let field = |name| answer.get(name);
+    match field("decision").and_then(json::Value::as_str) {
+        Some("allow") => Ok(PluginDecision::Allow),
+        Some("deny") => match field("status") {
+            None => Ok(PluginDecision::Deny(super::DEFAULT_DENY_STATUS)),
+            Some(status) => match status.as_u64() {
+                Some(status @ 400..=599) => Ok(PluginDecision::Deny(status as u16)),
+                _ => Err(malformed(format!("invalid deny status {status:?}"))),
+            },
+        },
+        Some("route") => match field("upstream").and_then(json::Value::as_str) {
+            Some(upstream) if !upstream.is_empty() => {
+                Ok(PluginDecision::Route(upstream.to_string()))
+            }
+            _ => Err(malformed("route without upstream".to_string())),
+        },
+        _ => Err(malformed(format!(
+            "invalid decision in `{}`",
+            output.trim()
+        ))),
+    }
+}
+
+/// The configured plugins, and how long and how hard to rely on them.
+pub struct Plugins {
+    plugins: HashMap<String, Arc<dyn PolicyPlugin>>,
+    timeout: Duration,
+    // Whether a plugin that fails or times out allows the connection.
+    fail_open: bool,
+}
+
+impl Default for Plugins {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), false)
+    }
+}
+
+impl Plugins {
+    pub fn new(timeout: Duration, fail_open: bool) -> Self {
+        Self {
+            plugins: HashMap::new(),
+            timeout,
+            fail_open,
+        }
+    }
+
+    pub fn insert(&mut self, name: String, plugin: Arc<dyn PolicyPlugin>) {
+        self.plugins.insert(name, plugin);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.plugins.contains_key(name)
+    }
+
+    pub fn fallback(&self) -> PluginDecision {
+        if self.fail_open {
+            PluginDecision::Allow
+        } else {
+            PluginDecision::Deny(FAIL_CLOSED_STATUS)
+        }
+    }
+
+    /// Asks the named plugin, bounded by the timeout; failures are logged and
+    /// answered with the fail-open/closed fallback. Also returns how long the
+    /// plugin took.
+    pub async fn check(
+        &self,
+        name: &str,
+        request: &ConnectRequest<'_>,
+        ctx: &ConnectionContext,
+    ) -> (PluginDecision, Duration) {
+        let start = Instant::now();
+        let result = match tokio::time::timeout(
+            self.timeout,
+            self.plugins[name].check(request, ctx),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(ErrorKind::TimedOut, "timed out")),
+        };
+        let latency = start.elapsed();
+        let decision = result.unwrap_or_else(|e| {
+            let fallback = self.fallback();
+            log::warn!(
+                "plugin {name} failed for {}:{} after {latency:?}: {e}; using {fallback:?}",
+                request.host,
+                request.port
+            );
+            fallback
+        });
+        (decision, latency)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn script(body: &str) -> Arc<dyn PolicyPlugin> {
+        Arc::new(ExecPlugin::new(
+            "/bin/sh",
+            vec!["-c".to_string(), body.to_string()],
+        ))
+    }
+    async fn check(plugins: &Plugins, name: &str) -> (PluginDecision, Duration) {
+        let request = ConnectRequest {
+            host: "example.com",
+            port: 443,
+            client: "10.0.0.1".parse().unwrap(),
+            user: Some("alice"),
+        };
+        let mut ctx = ConnectionContext::new("10.0.0.1:5000".parse().unwrap());
+        ctx.target = Some("example.com:443".to_string());
+        plugins.check(name, &request, &ctx).await
+    }
+    #[tokio::test]
+    async fn test_decision_types() {
+        let mut plugins = Plugins::default();
+        for (name, body) in [
+            ("allow", r#"cat >/dev/null; echo '{"decision": "allow"}'"#),
+            ("deny", r#"echo '{"decision": "deny", "status": 451}'"#),
+            ("deny-default", r#"echo '{"decision": "deny"}'"#),
+            (
+                "route",
+                r#"echo '{"decision": "route", "upstream": "parent:3128"}'"#,
+            ),
+            // Sees the request it was given.
+            (
+                "input",
+                r#"grep -q '"host":"example.com","port":443,"client":"10.0.0.1:5000","user":"alice"' \
+                   && echo '{"decision": "allow"}' || echo '{"decision": "deny"}'"#,
+            ),
+        ] {
+            plugins.insert(name.to_string(), script(body));
+        }
+        let cases = [
+            ("allow", PluginDecision::Allow),
+            ("deny", PluginDecision::Deny(451)),
+            ("deny-default", PluginDecision::Deny(403)),
+            ("route", PluginDecision::Route("parent:3128".to_string())),
+            ("input", PluginDecision::Allow),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(check(&plugins, name).await.0, expected, "{name}");
+        }
+    }
+    #[tokio::test]
+    async fn test_timeout_uses_fallback() {
+        let mut plugins = Plugins::new(Duration::from_millis(100), false);
+        plugins.insert("slow".to_string(), script("sleep 5"));
+        let (decision, latency) = check(&plugins, "slow").await;
+        assert_eq!(decision, PluginDecision::Deny(503));
+        assert!(latency < Duration::from_secs(2), "{latency:?}");
+        let mut plugins = Plugins::new(Duration::from_millis(100), true);
+        plugins.insert("slow".to_string(), script("sleep 5"));
+        assert_eq!(check(&plugins, "slow").await.0, PluginDecision::Allow);
+    }
+    #[tokio::test]
+    async fn test_malformed_output_uses_fallback() {
+        let mut plugins = Plugins::default();
+        for (name, body) in [
+            ("garbage", "echo not json"),
+            ("empty", "true"),
+            ("unknown", r#"echo '{"decision": "maybe"}'"#),
+            ("status", r#"echo '{"decision": "deny", "status": 200}'"#),
+            ("route", r#"echo '{"decision": "route"}'"#),
+            ("exit", r#"echo '{"decision": "allow"}'; exit 1"#),
+        ] {
+            plugins.insert(name.to_string(), script(body));
+        }
+        for name in ["garbage", "empty", "unknown", "status", "route", "exit"] {
+            assert_eq!(
+                check(&plugins, name).await.0,
+                PluginDecision::Deny(503),
+                "{name}"
+            );
+        }
+        let mut plugins = Plugins::new(Duration::from_secs(1), true);
+        plugins.insert(
+            "missing-program".to_string(),
+            Arc::new(ExecPlugin::new("/nonexistent/plugin", vec![])),
+        );
+        assert_eq!(
+            check(&plugins, "missing-program").await.0,
+            PluginDecision::Allow
+        );
+    }
+}
@@ -0,0 +1,541 @@
+// A small regular expression engine for host matching. Patterns compile to a
+// Pike VM program, so matching is linear in the input for any pattern, and a
+// whole set of patterns runs as one program in a single pass.
+//
+// Supported syntax: literals, `.`, classes (`[a-z0-9]`, `[^.]`), escapes
+// (`\.`, `\d`, `\w`, `\s` and their negations), anchors `^` and `$`, groups
+// `(...)` / `(?:...)`, alternation `|`, greedy quantifiers `* + ? {n} {n,}
+// {n,m}`, and a leading `(?i)` for case-insensitive matching. Matching is
+// unanchored unless the pattern uses `^` / `$`.
+
+const MAX_INSTRUCTIONS: usize = 10_000;
+const MAX_REPEAT: u32 = 1_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Class {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl Class {
+    fn single(c: char) -> Self {
+        Self {
+            ranges: vec![(c, c)],
+            negated: false,
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != self.negated
+    }
+
+    fn ignore_case(mut self) -> Self {
+        let mut extra = vec![];
+        for &(lo, hi) in &self.ranges {
+            for (from, to, offset) in [('a', 'z', -32i32), ('A', 'Z', 32)] {
+                let lo = lo.max(from);
+                let hi = hi.min(to);
+                if lo <= hi {
+                    let shift = |c: char| char::from_u32((c as i32 + offset) as u32).unwrap();
+                    extra.push((shift(lo), shift(hi)));
+                }
+            }
+        }
+        self.ranges.extend(extra);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Empty,
+    Class(Class),
+    Start,
+    End,
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+    },
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    ignore_case: bool,
+}
+
+impl Parser<'_> {
+    fn parse(pattern: &str) -> Result<Node, String> {
+        let (ignore_case, pattern) = match pattern.strip_prefix("(?i)") {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let mut parser = Parser {
+            chars: pattern.chars().peekable(),
+            ignore_case,
+        };
+        let node = parser.alternation()?;
+        match parser.chars.next() {
+            None => Ok(node),
+            Some(')') => Err("unmatched `)`".to_string()),
+            Some(c) => Err(format!("unexpected `{c}`")),
+        }
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.concatenation()?];
+        while self.chars.next_if_eq(&'|').is_some() {
+            branches.push(self.concatenation()?);
+        }
+        Ok(if branches.len() == 1 {
+            branches.pop().unwrap()
+        } else {
+            Node::Alternate(branches)
+        })
+    }
+
+    fn concatenation(&mut self) -> Result<Node, String> {
+        let mut nodes = vec![];
+        while let Some(&c) = self.chars.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.pop().unwrap(),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    fn class(&self, class: Class) -> Node {
+        Node::Class(if self.ignore_case {
+            class.ignore_case()
+        } else {
+            class
+        })
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.chars.next().unwrap() {
+            '^' => Ok(Node::Start),
+            '$' => Ok(Node::End),
+            '.' => Ok(Node::Class(Class {
+                ranges: vec![],
+                negated: true,
+            })),
+            '(' => {
+                if self.chars.next_if_eq(&'?').is_some() && self.chars.next() != Some(':') {
+                    return Err("unsupported group flag".to_string());
+                }
+                let node = self.alternation()?;
+                if self.chars.next() != Some(')') {
+                    return Err("unclosed `(`".to_string());
+                }
+                Ok(node)
+            }
+            '[' => {
+                let class = self.bracket()?;
+                Ok(self.class(class))
+            }
+            '\\' => {
+                let class = self.escape()?;
+                Ok(self.class(class))
+            }
+            c @ ('*' | '+' | '?' | '{') => Err(format!("`{c}` has nothing to repeat")),
+            c => Ok(self.class(Class::single(c))),
+        }
+    }
+
+    fn escape(&mut self) -> Result<Class, String> {
+        let perl = |ranges: &[(char, char)], negated| Class {
+            ranges: ranges.to_vec(),
+            negated,
+        };
+        const DIGIT: &[(char, char)] = &[('0', '9')];
+        const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+        const SPACE: &[(char, char)] = &[('\t', '\r'), (' ', ' ')];
+        match self.chars.next() {
+            Some('d') => Ok(perl(DIGIT, false)),
+            Some('D') => Ok(perl(DIGIT, true)),
+            Some('w') => Ok(perl(WORD, false)),
+            Some('W') => Ok(perl(WORD, true)),
+            Some('s') => Ok(perl(SPACE, false)),
+            Some('S') => Ok(perl(SPACE, true)),
+            Some(c) if c.is_ascii_punctuation() => Ok(Class::single(c)),
+            Some(c) => Err(format!("unsupported escape `\\{c}`")),
+            None => Err("trailing `\\`".to_string()),
+        }
+    }
+
+    fn bracket(&mut self) -> Result<Class, String> {
+        let negated = self.chars.next_if_eq(&'^').is_some();
+        let mut ranges = vec![];
+        let mut first = true;
+        loop {
+            let c = match self.chars.next() {
+                None => return Err("unclosed `[`".to_string()),
+                Some(']') if !first => break,
+                Some('\\') => {
+                    let class = self.escape()?;
+                    if class.negated {
+                        return Err("negated escape inside a class".to_string());
+                    }
+                    if let [(lo, hi)] = class.ranges[..]
+                        && lo == hi
+                    {
+                        lo
+                    } else {
+                        ranges.extend(class.ranges);
+                        first = false;
+                        continue;
+                    }
+                }
+                Some(c) => c,
+            };
+            first = false;
+            if self.chars.peek() == Some(&'-') {
+                let mut lookahead = self.chars.clone();
+                lookahead.next();
+                match lookahead.peek() {
+                    Some(&hi) if hi != ']' => {
+                        self.chars.next();
+                        self.chars.next();
+                        if hi < c {
+                            return Err(format!("invalid range `{c}-{hi}`"));
+                        }
+                        ranges.push((c, hi));
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+            ranges.push((c, c));
+        }
+        Ok(Class { ranges, negated })
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        let mut digits = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit()) {
+            digits.push(c);
+        }
+        digits.parse().ok()
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.chars.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.chars.next();
+                let min = self.number().ok_or("invalid repetition")?;
+                let max = if self.chars.next_if_eq(&',').is_some() {
+                    if self.chars.peek() == Some(&'}') {
+                        None
+                    } else {
+                        Some(self.number().ok_or("invalid repetition")?)
+                    }
+                } else {
+                    Some(min)
+                };
+                if self.chars.next() != Some('}') {
+                    return Err("unclosed `{`".to_string());
+                }
+                if max.is_some_and(|max| max < min) || min.max(max.unwrap_or(0)) > MAX_REPEAT {
+                    return Err("invalid repetition".to_string());
+                }
+                return self.quantified_again(atom, min, max);
+            }
+            _ => return Ok(atom),
+        };
+        self.chars.next();
+        self.quantified_again(atom, min, max)
+    }
+
+    fn quantified_again(&mut self, atom: Node, min: u32, max: Option<u32>) -> Result<Node, String> {
+        if matches!(atom, Node::Start | Node::End) {
+            return Err("anchors cannot be repeated".to_string());
+        }
+        if matches!(self.chars.peek(), Some('*' | '+' | '?' | '{')) {
+            return Err("nested quantifier".to_string());
+        }
+        Ok(Node::Repeat {
+            node: Box::new(atom),
+            min,
+            max,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Inst {
+    Class(Class),
+    Split(usize, usize),
+    Jump(usize),
+    Start,
+    End,
+    Match(usize),
+}
+
+struct Compiler {
+    program: Vec<Inst>,
+    // Where the current pattern starts; the size limit is per pattern.
+    pattern_start: usize,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> Result<usize, String> {
+        if self.program.len() - self.pattern_start >= MAX_INSTRUCTIONS {
+            return Err("pattern is too large".to_string());
+        }
+        self.program.push(inst);
+        Ok(self.program.len() - 1)
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Empty => {}
+            Node::Class(class) => {
+                self.push(Inst::Class(class.clone()))?;
+            }
+            Node::Start => {
+                self.push(Inst::Start)?;
+            }
+            Node::End => {
+                self.push(Inst::End)?;
+            }
+            Node::Concat(nodes) => {
+                for node in nodes {
+                    self.compile(node)?;
+                }
+            }
+            Node::Alternate(branches) => {
+                let mut jumps = vec![];
+                for (i, branch) in branches.iter().enumerate() {
+                    if i + 1 < branches.len() {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.compile(branch)?;
+                        jumps.push(self.push(Inst::Jump(0))?);
+                        let next = self.program.len();
+                        self.program[split] = Inst::Split(split + 1, next);
+                    } else {
+                        self.compile(branch)?;
+                    }
+                }
+                let end = self.program.len();
+                for jump in jumps {
+                    self.program[jump] = Inst::Jump(end);
+                }
+            }
+            Node::Repeat { node, min, max } => {
+                for _ in 0..*min {
+                    self.compile(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.compile(node)?;
+                        self.push(Inst::Jump(split))?;
+                        let end = self.program.len();
+                        self.program[split] = Inst::Split(split + 1, end);
+                    }
+                    Some(max) => {
+                        let mut splits = vec![];
+                        for _ in *min..*max {
+                            splits.push(self.push(Inst::Split(0, 0))?);
+                            self.compile(node)?;
+                        }
+                        let end = self.program.len();
+                        for split in splits {
+                            self.program[split] = Inst::Split(split + 1, end);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A set of patterns matched together; `matches` reports which of them match.
+#[derive(Debug, Clone)]
+pub struct RegexSet {
+    program: Vec<Inst>,
+    len: usize,
+}
+
+impl RegexSet {
+    /// Compiles the patterns; on error returns the index of the offending
+    /// pattern and a description.
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self, (usize, String)> {
+        let mut compiler = Compiler {
+            program: vec![],
+            pattern_start: 0,
+        };
+        for (index, pattern) in patterns.iter().enumerate() {
+            let node = Parser::parse(pattern.as_ref()).map_err(|e| (index, e))?;
+            compiler.pattern_start = compiler.program.len();
+            // Patterns are alternatives of one program: split to this one or
+            // to the rest.
+            let split = if index + 1 < patterns.len() {
+                Some(compiler.push(Inst::Split(0, 0)).map_err(|e| (index, e))?)
+            } else {
+                None
+            };
+            compiler.compile(&node).map_err(|e| (index, e))?;
+            compiler.push(Inst::Match(index)).map_err(|e| (index, e))?;
+            if let Some(split) = split {
+                compiler.program[split] = Inst::Split(split + 1, compiler.program.len());
+            }
+        }
+        Ok(Self {
+            program: compiler.program,
+            len: patterns.len(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn add_thread(
+        &self,
+        list: &mut Vec<usize>,
+        seen: &mut [bool],
+        pc: usize,
+        at: usize,
+        len: usize,
+    ) {
+        if seen[pc] {
+            return;
+        }
+        seen[pc] = true;
+        match self.program[pc] {
+            Inst::Split(a, b) => {
+                self.add_thread(list, seen, a, at, len);
+                self.add_thread(list, seen, b, at, len);
+            }
+            Inst::Jump(target) => self.add_thread(list, seen, target, at, len),
+            Inst::Start if at == 0 => self.add_thread(list, seen, pc + 1, at, len),
+            Inst::End if at == len => self.add_thread(list, seen, pc + 1, at, len),
+            Inst::Start | Inst::End => {}
+            Inst::Class(_) | Inst::Match(_) => list.push(pc),
+        }
+    }
+
+    pub fn matches(&self, haystack: &str) -> Vec<bool> {
+        let mut matched = vec![false; self.len];
+        if self.program.is_empty() {
+            return matched;
+        }
+        let chars: Vec<char> = haystack.chars().collect();
+        let mut current = vec![];
+        let mut next = vec![];
+        let mut seen = vec![false; self.program.len()];
+        for at in 0..=chars.len() {
+            // Starting a thread at every position makes the search unanchored.
+            self.add_thread(&mut current, &mut seen, 0, at, chars.len());
+            seen.iter_mut().for_each(|s| *s = false);
+            for &pc in &current {
+                match &self.program[pc] {
+                    Inst::Match(index) => matched[*index] = true,
+                    Inst::Class(class) if at < chars.len() && class.matches(chars[at]) => {
+                        self.add_thread(&mut next, &mut seen, pc + 1, at + 1, chars.len());
+                    }
+                    _ => {}
+                }
+            }
+            std::mem::swap(&mut current, &mut next);
+            next.clear();
+        }
+        matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn is_match(pattern: &str, haystack: &str) -> bool {
+        RegexSet::new(&[pattern]).unwrap().matches(haystack)[0]
+    }
+    #[test]
+    fn test_unanchored_search() {
+        assert!(is_match("tracking", "eu.tracking.example.com"));
+        assert!(is_match("tracking", "tracking"));
+        assert!(!is_match("tracking", "track.example.com"));
+    }
+    #[test]
+    fn test_anchors() {
+        assert!(is_match(r"^ads[0-9]+\.", "ads12.example.com"));
+        assert!(!is_match(r"^ads[0-9]+\.", "myads12.example.com"));
+        assert!(!is_match(r"^ads[0-9]+\.", "ads.example.com"));
+        assert!(is_match(r"\.example\.com$", "www.example.com"));
+        assert!(!is_match(r"\.example\.com$", "www.example.com.evil"));
+        assert!(is_match("^$", ""));
+        assert!(!is_match("^$", "a"));
+    }
+    #[test]
+    fn test_case_insensitive_flag() {
+        assert!(!is_match("Tracking", "tracking.example.com"));
+        assert!(is_match("(?i)Tracking", "tracking.example.com"));
+        assert!(is_match("(?i)[A-C]x", "bx"));
+        assert!(is_match("(?i)^ads", "ADS.example.com"));
+    }
+    #[test]
+    fn test_syntax() {
+        let cases = [
+            ("a|b", "b", true),
+            ("^(?:foo|bar)baz$", "barbaz", true),
+            ("^(foo|bar)baz$", "foobar", false),
+            ("^a{2,3}$", "aa", true),
+            ("^a{2,3}$", "aaaa", false),
+            ("^a{2}$", "aa", true),
+            ("^a{2,}$", "aaaaa", true),
+            ("^a?b+c*$", "bbb", true),
+            ("^[^.]+\\.com$", "example.com", true),
+            ("^[^.]+\\.com$", "www.example.com", false),
+            ("^\\d+$", "123", true),
+            ("^\\w+$", "a_b", true),
+            ("^[a-]+$", "a-a", true),
+            ("^[\\d.]+$", "10.0.0.1", true),
+            ("^.$", "x", true),
+        ];
+        for (pattern, haystack, expected) in cases {
+            assert_eq!(
+                is_match(pattern, haystack),
+                expected,
+                "{pattern} vs {haystack}"
+            );
+        }
+    }
+    #[test]
+    fn test_invalid_patterns() {
+        for pattern in [
+            "(", ")", "a)", "[a", "*a", "a**", "a{3,1}", "a{2000}", "\\q", "[z-a]", "^*", "(?x)a",
+        ] {
+            assert!(RegexSet::new(&[pattern]).is_err(), "{pattern}");
+        }
+        assert!(RegexSet::new(&["(a{100}){100}"]).is_err());
+        assert_eq!(RegexSet::new(&["ok", "(bad"]).unwrap_err().0, 1);
+    }
+    #[test]
+    fn test_set_reports_every_match() {
+        let set = RegexSet::new(&["^ads", "tracking", "\\.com$", "^nothing$"]).unwrap();
+        assert_eq!(
+            set.matches("ads.tracking.com"),
+            vec![true, true, true, false]
+        );
+        assert_eq!(set.matches("example.org"), vec![false; 4]);
+    }
+    #[test]
+    fn test_pathological_pattern_is_linear() {
+        let haystack = "a".repeat(5_000);
+        assert!(!is_match("^(a|a)*(a|a)*(a|a)*b$", &haystack));
+    }
+}
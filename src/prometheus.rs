@@ -0,0 +1,219 @@
+// The metrics in Prometheus text format, served at `/metrics` on the
+// `--metrics-addr` listener. One request per connection, answered and closed.
+
+use std::fmt::Write;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::http_reader::HttpReader;
+use crate::log;
+use crate::metrics::{self, Metrics, Timer};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+// A scraper that takes longer than this to send its request is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Failed connects by what went wrong, from the error counts.
+fn connect_failure_class(stage: &str, class: &str) -> Option<&'static str> {
+    match (stage, class) {
+        ("resolve", _) => Some("dns"),
+        ("connect", "connection_refused") => Some("refused"),
+        ("connect", "timed_out") => Some("timeout"),
+        ("connect", _) => Some("other"),
+        _ => None,
+    }
+}
+
+pub fn render(metrics: &Metrics) -> String {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+        let _ = writeln!(out, "# HELP proxy_{name} {help}");
+        let _ = writeln!(out, "# TYPE proxy_{name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "proxy_{name}{labels} {value}");
+        }
+    };
+    metric(
+        "connections_total",
+        "counter",
+        "Connections accepted.",
+        &[(String::new(), load(&metrics.connections))],
+    );
+    metric(
+        "active_connections",
+        "gauge",
+        "Connections being served.",
+        &[(String::new(), load(&metrics.active))],
+    );
+    metric(
+        "rejected_connections_total",
+        "counter",
+        "Connections turned away at the connection limit.",
+        &[(String::new(), load(&metrics.rejected))],
+    );
+    metric(
+        "bytes_total",
+        "counter",
+        "Bytes tunnelled, by direction.",
+        &[
+            ("{direction=\"up\"}".to_string(), load(&metrics.bytes_up)),
+            (
+                "{direction=\"down\"}".to_string(),
+                load(&metrics.bytes_down),
+            ),
+        ],
+    );
+    let errors = metrics.errors();
+    let mut failures = [("dns", 0), ("refused", 0), ("timeout", 0), ("other", 0)];
+    for ((stage, class), count) in &errors {
+        if let Some(failure) = connect_failure_class(stage, class) {
+            let entry = failures.iter_mut().find(|(name, _)| *name == failure);
+            entry.unwrap().1 += count;
+        }
+    }
+    let failures: Vec<_> = failures
+        .iter()
+        .map(|(class, count)| (format!("{{class=\"{class}\"}}"), *count))
+        .collect();
+    metric(
+        "connect_failures_total",
+        "counter",
+        "Targets that could not be connected to, by cause.",
+        &failures,
+    );
+    let errors: Vec<_> = errors
+        .iter()
+        .map(|((stage, class), count)| {
+            let labels = format!("{{stage=\"{stage}\",class=\"{class}\"}}");
+            (labels, *count)
+        })
+        .collect();
+    metric(
+        "errors_total",
+        "counter",
+        "Failed connections, by stage and error class.",
+        &errors,
+    );
+    metric(
+        "buffered_bytes",
+        "gauge",
+        "Bytes read from one side of a tunnel and not yet written to the other.",
+        &[(String::new(), load(&metrics.buffered))],
+    );
+    metric(
+        "recorder_evictions_total",
+        "counter",
+        "Live tails evicted for lagging too far behind their tunnel.",
+        &[(String::new(), load(&metrics.evictions))],
+    );
+    for timer in Timer::ALL {
+        let name = format!("proxy_{}_seconds", timer.as_str());
+        let histogram = metrics.histogram(timer);
+        let _ = writeln!(out, "# HELP {name} {}", timer_help(timer));
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bound, count) in metrics::BUCKETS.iter().zip(&histogram.cumulative) {
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", histogram.count);
+        let _ = writeln!(out, "{name}_sum {}", histogram.sum.as_secs_f64());
+        let _ = writeln!(out, "{name}_count {}", histogram.count);
+    }
+    out
+}
+
+fn timer_help(timer: Timer) -> &'static str {
+    match timer {
+        Timer::Connect => "Time to resolve and connect to targets.",
+        Timer::Connection => "Time from connecting to a target to the tunnel's end.",
+        Timer::RequestRead => "Time from the accept to the request read.",
+        Timer::FirstByte => "Time from a tunnel's start to the target's first byte.",
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    let mut reader = HttpReader::new(1024);
+    let request_line = reader.read_line(&mut stream).await?;
+    while !reader.read_line(&mut stream).await?.is_empty() {}
+    let mut parts = request_line.split(' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(metrics)),
+        (Some("GET"), _) => ("404 Not Found", "Not Found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Answers scrapes until the task is dropped.
+pub async fn serve(listener: Arc<TcpListener>, metrics: Arc<Metrics>) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, respond(stream, &metrics)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log::debug!("Metrics request from {peer} failed: {e}"),
+                Err(_) => log::debug!("Metrics request from {peer} timed out"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_error::{ConnectionContext, Stage};
+    use std::io::ErrorKind;
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.connections.store(3, Ordering::Relaxed);
+        metrics.bytes_up.store(10, Ordering::Relaxed);
+        metrics.bytes_down.store(20, Ordering::Relaxed);
+        metrics.evictions.store(2, Ordering::Relaxed);
+        let ctx = ConnectionContext::new("127.0.0.1:1".parse().unwrap());
+        let refused = io::Error::from(ErrorKind::ConnectionRefused);
+        metrics.error(&ctx.fail(Stage::Connect, refused));
+        metrics.error(&ctx.fail(Stage::Resolve, io::Error::other("no such host")));
+        metrics.time(Timer::FirstByte, Duration::from_millis(200));
+        metrics.time(Timer::FirstByte, Duration::from_secs(100));
+        let text = render(&metrics);
+        for line in [
+            "# TYPE proxy_connections_total counter",
+            "proxy_connections_total 3",
+            "proxy_active_connections 0",
+            "proxy_bytes_total{direction=\"up\"} 10",
+            "proxy_bytes_total{direction=\"down\"} 20",
+            "proxy_connect_failures_total{class=\"dns\"} 1",
+            "proxy_connect_failures_total{class=\"refused\"} 1",
+            "proxy_connect_failures_total{class=\"timeout\"} 0",
+            "proxy_errors_total{stage=\"connect\",class=\"connection_refused\"} 1",
+            "# TYPE proxy_recorder_evictions_total counter",
+            "proxy_recorder_evictions_total 2",
+            "# TYPE proxy_first_byte_latency_seconds histogram",
+            "proxy_first_byte_latency_seconds_bucket{le=\"0.1\"} 0",
+            "proxy_first_byte_latency_seconds_bucket{le=\"0.25\"} 1",
+            "proxy_first_byte_latency_seconds_bucket{le=\"60\"} 1",
+            "proxy_first_byte_latency_seconds_bucket{le=\"+Inf\"} 2",
+            "proxy_first_byte_latency_seconds_sum 100.2",
+            "proxy_first_byte_latency_seconds_count 2",
+            "proxy_connect_latency_seconds_count 0",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{line} missing from:\n{text}"
+            );
+        }
+        assert!(text.ends_with('\n'));
+    }
+}
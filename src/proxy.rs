@@ -0,0 +1,897 @@
+// The proxy as a whole: its listeners, the state its connections share, and
+// the tasks that run beside them.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc, watch};
+use tokio::task::JoinSet;
+
+use crate::client::handle_client;
+use crate::{
+    access_log, config, connection_error, fault, har, intercept, latency, listener, log, metrics,
+    netlog, pcap, policy, prometheus, replay, resolver, rules_watch, sd_notify, statsd, status,
+    tap, webhook,
+};
+
+const SLOW_MIN_SAMPLES: u64 = 100;
+const DEFAULT_DENY_STATUS: u16 = 403;
+// The answer at `--max-connections` with `--max-connections-policy reject`.
+const OVER_LIMIT_RESPONSE: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\n\
+    Content-Length: 20\r\nConnection: close\r\n\r\nToo many connections";
+// How long a rejected client has to read the answer.
+const REJECT_LINGER: Duration = Duration::from_secs(1);
+// How often an idle acceptor wakes up to show it is still running.
+const ACCEPT_HEARTBEAT: Duration = Duration::from_secs(1);
+// How long an acceptor waits after a failed accept, as when the process is
+// out of file descriptors, before it tries again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// Everything a connection needs that outlives it.
+pub struct ProxyState {
+    pub config: config::Config,
+    // Replaced as a whole on reload; connections keep the one they started with.
+    policy: RwLock<Arc<policy::Policy>>,
+    // The `--allow`/`--deny` lists, credentials and limits, replaced the same
+    // way when the `--config` file is reloaded.
+    runtime: RwLock<Arc<config::Runtime>>,
+    pub slow: latency::SlowConnectionDetector,
+    pub netlog: Option<netlog::NetLog>,
+    pub har: Option<har::SessionLog>,
+    pub access_log: Option<access_log::AccessLog>,
+    // The open tunnels, kept with `--admin-addr`.
+    pub status: Option<status::Registry>,
+    // The embedder's interceptors, then one per `--block-pattern`.
+    pub interceptors: Vec<Arc<dyn intercept::Factory>>,
+    pub pcap: Option<pcap::Pcap>,
+    // With any `--fault-*` option, and a seed picked if none was given.
+    pub faults: Option<fault::Faults>,
+    pub metrics: Arc<metrics::Metrics>,
+    pub webhooks: Option<webhook::Webhooks>,
+    pub resolver: resolver::Resolver,
+    // With `--replay`, where tunnels are served from instead of targets.
+    pub replay: Option<replay::Library>,
+    // One permit per connection allowed by `--max-connections`.
+    connection_limit: Option<Arc<Semaphore>>,
+    // The connection tasks; dropping the set aborts whichever still run.
+    connections: Mutex<JoinSet<()>>,
+    // Set once shutting down, for kept connections to close between requests.
+    draining: watch::Sender<bool>,
+}
+
+pub fn build_policy(
+    config: &config::Config,
+    sources: &[policy::RuleSource],
+) -> io::Result<policy::Policy> {
+    let mut plugins = policy::Plugins::new(config.plugin_timeout, config.plugin_fail_open);
+    for (name, command) in &config.plugins {
+        let mut words = command.split_whitespace().map(str::to_string);
+        let program = words.next().unwrap();
+        let plugin = policy::ExecPlugin::new(program, words.collect());
+        plugins.insert(name.clone(), Arc::new(plugin));
+    }
+    let mut policy = policy::Policy::parse(sources, plugins)?.with_dry_run(config.policy_dry_run);
+    if config.policy_default_deny {
+        if !policy.has_allow_rule() && !config.force {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--policy-default deny without any allow rule would reject every connection; \
+                 add an allow rule or pass --force",
+            ));
+        }
+        policy = policy.with_default_access(policy::Access::Deny(DEFAULT_DENY_STATUS));
+    }
+    Ok(policy)
+}
+
+impl ProxyState {
+    pub fn new(config: config::Config) -> io::Result<Self> {
+        // A tunnel's recorders could never take a byte.
+        if config.record_buffer == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--record-buffer must be at least one byte",
+            ));
+        }
+        let policy = build_policy(&config, &config.load_rules()?)?;
+        let slow = latency::SlowConnectionDetector::new(
+            Instant::now(),
+            config.slow_window,
+            config.slow_percentile,
+            SLOW_MIN_SAMPLES,
+        );
+        let netlog = match &config.netlog {
+            Some(path) => Some(netlog::NetLog::create(path, config.netlog_bytes)?),
+            None => None,
+        };
+        let har = match &config.har {
+            Some(path) => Some(har::SessionLog::create(path)?),
+            None => None,
+        };
+        let access_log = match &config.access_log {
+            Some(path) => Some(access_log::AccessLog::create(path)?),
+            None => None,
+        };
+        let pcap = match (&config.pcap_pipe, &config.pcap_file) {
+            (Some(path), _) => Some(pcap::Pcap::create_pipe(path, config.pcap_hosts.clone())?),
+            (_, Some(path)) => Some(pcap::Pcap::create_file(path, config.pcap_hosts.clone())?),
+            (None, None) => None,
+        };
+        let metrics = if config.statsd.is_some() {
+            metrics::Metrics::with_timings()
+        } else {
+            metrics::Metrics::default()
+        };
+        let webhooks = (!config.webhooks.is_empty()).then(|| {
+            webhook::Webhooks::start(config.webhooks.clone(), config.webhook_limits.clone())
+        });
+        let resolver = resolver::Resolver::new(
+            Arc::new(resolver::SystemLookup),
+            config.dns_ttl,
+            resolver::CACHE_CAPACITY,
+            config.prefer,
+        );
+        let replay = match &config.replay {
+            Some(dir) => Some(replay::Library::load(dir, config.replay_realtime)?),
+            None => None,
+        };
+        // Taps find their tunnels among those listed for `/status`.
+        let status = (config.admin_addr.is_some() || config.tap_addr.is_some())
+            .then(status::Registry::default);
+        let mut interceptors = config.interceptors.clone();
+        for pattern in &config.block_patterns {
+            interceptors.push(Arc::new(intercept::BlockPattern(pattern.clone())));
+        }
+        let faults = config.faults.enabled().then(|| config.faults.seeded());
+        let connection_limit = config
+            .max_connections
+            .map(|max| Arc::new(Semaphore::new(max)));
+        Ok(Self {
+            runtime: RwLock::new(Arc::new(config.runtime())),
+            config,
+            policy: RwLock::new(Arc::new(policy)),
+            slow,
+            netlog,
+            har,
+            access_log,
+            status,
+            interceptors,
+            pcap,
+            faults,
+            metrics: Arc::new(metrics),
+            webhooks,
+            resolver,
+            replay,
+            connection_limit,
+            connections: Mutex::new(JoinSet::new()),
+            draining: watch::Sender::new(false),
+        })
+    }
+
+    pub fn policy(&self) -> Arc<policy::Policy> {
+        self.policy.read().unwrap().clone()
+    }
+
+    /// Re-reads and validates the rules, and only then replaces the policy.
+    pub fn reload(&self) -> io::Result<()> {
+        let sources = self.config.load_rules()?;
+        let policy = build_policy(&self.config, &sources)?;
+        let mut hasher = DefaultHasher::new();
+        sources.hash(&mut hasher);
+        let old = std::mem::replace(&mut *self.policy.write().unwrap(), Arc::new(policy));
+        for rule in old.rule_stats() {
+            log::info!(
+                "Rule {} before reload: evaluations={} matches={} denials={} would_deny={} rewrites={}",
+                old.describe(Some(rule.index)),
+                rule.evaluations,
+                rule.matches,
+                rule.denials,
+                rule.would_deny,
+                rule.rewrites
+            );
+        }
+        log::info!(
+            "Reloaded rules: {} -> {} rules (content hash {:016x})",
+            old.rule_count(),
+            self.policy().rule_count(),
+            hasher.finish()
+        );
+        Ok(())
+    }
+
+    /// Turns true once the proxy starts shutting down.
+    pub fn draining(&self) -> watch::Receiver<bool> {
+        self.draining.subscribe()
+    }
+
+    pub fn runtime(&self) -> Arc<config::Runtime> {
+        self.runtime.read().unwrap().clone()
+    }
+
+    /// Reads the `--config` file and command line again, and only once they
+    /// are valid replaces the filters, credentials and limits with theirs.
+    /// Open tunnels keep the limits they started with.
+    pub fn reload_config(&self) -> io::Result<()> {
+        let Some(path) = &self.config.config_file else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no --config file to reload",
+            ));
+        };
+        let config = config::Config::from_args(self.config.args.iter().cloned())?;
+        *self.runtime.write().unwrap() = Arc::new(config.runtime());
+        log::info!("Reloaded {}", path.display());
+        Ok(())
+    }
+}
+
+/// Asks a running proxy to shut down, like SIGINT does the binary: the first
+/// request stops accepting and drains the open connections, the next one
+/// aborts them.
+#[derive(Clone)]
+pub struct Shutdown(mpsc::Sender<()>);
+
+impl Shutdown {
+    pub fn request(&self) {
+        // A full channel already holds both requests that matter.
+        let _ = self.0.try_send(());
+    }
+}
+
+/// A proxy bound to its listening sockets.
+pub struct Proxy {
+    state: Arc<ProxyState>,
+    listeners: Vec<listener::Listener>,
+    metrics_listener: Option<Arc<TcpListener>>,
+    admin_listener: Option<Arc<TcpListener>>,
+    tap_listener: Option<Arc<TcpListener>>,
+    shutdown: Shutdown,
+    requests: mpsc::Receiver<()>,
+}
+
+impl Proxy {
+    /// A proxy with the default configuration, listening on `addr`.
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Self::with_config(config::Config {
+            listen: addr,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_config(config: config::Config) -> io::Result<Self> {
+        if config.watch_rules && config.rule_files().next().is_none() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--watch-rules needs at least one --rules file",
+            ));
+        }
+        log::set_max_level(config.log_level.unwrap_or_else(log::level_from_env));
+        let state = Arc::new(ProxyState::new(config)?);
+        // Connecting would fail from these, but the address may be assigned
+        // later, as when an interface comes up.
+        for ip in state.config.outbound.unassigned() {
+            log::warn!("Outbound address {ip} is not assigned to this host");
+        }
+        let listeners = match &state.config.listen_unix {
+            Some(path) => {
+                listener::bind_unix(path, state.config.listen_mode, state.config.reuseport)?
+            }
+            None => listener::bind(state.config.listen, state.config.reuseport)?
+                .into_iter()
+                .map(listener::Listener::Tcp)
+                .collect(),
+        };
+        let metrics_listener = match state.config.metrics_addr {
+            Some(addr) => Some(listener::bind(addr, 1)?.remove(0)),
+            None => None,
+        };
+        let admin_listener = match state.config.admin_addr {
+            Some(addr) => Some(listener::bind(addr, 1)?.remove(0)),
+            None => None,
+        };
+        let tap_listener = match state.config.tap_addr {
+            Some(addr) => Some(listener::bind(addr, 1)?.remove(0)),
+            None => None,
+        };
+        let (requests_tx, requests) = mpsc::channel(2);
+        Ok(Self {
+            state,
+            listeners,
+            metrics_listener,
+            admin_listener,
+            tap_listener,
+            shutdown: Shutdown(requests_tx),
+            requests,
+        })
+    }
+
+    /// The actual address, with the port picked when binding port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    /// Where `/metrics` is served, with `--metrics-addr`.
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        let listener = self.metrics_listener.as_ref()?;
+        listener.local_addr().ok()
+    }
+
+    /// Where `/status` is served, with `--admin-addr`.
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        let listener = self.admin_listener.as_ref()?;
+        listener.local_addr().ok()
+    }
+
+    /// Where tunnels are tapped, with `--tap-addr`.
+    pub fn tap_addr(&self) -> Option<SocketAddr> {
+        let listener = self.tap_listener.as_ref()?;
+        listener.local_addr().ok()
+    }
+
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Serves until shut down. Fails if connections had to be aborted.
+    pub async fn run(self) -> io::Result<()> {
+        let Proxy {
+            state,
+            listeners,
+            metrics_listener,
+            admin_listener,
+            tap_listener,
+            shutdown: _shutdown,
+            mut requests,
+        } = self;
+        log::info!(
+            "Server listening on {} with {} acceptor(s)",
+            listeners[0],
+            listeners.len()
+        );
+        // Stopped with the proxy.
+        let mut background = JoinSet::new();
+        let stats = Arc::new(listener::AcceptStats::new(listeners.len()));
+        if listeners.len() > 1 {
+            background.spawn(report_accept_stats(stats.clone()));
+        }
+        if state.config.watch_rules {
+            let paths: Vec<_> = state.config.rule_files().map(|p| p.to_path_buf()).collect();
+            let watched = state.clone();
+            background.spawn(rules_watch::watch(
+                paths,
+                rules_watch::POLL_INTERVAL,
+                rules_watch::DEBOUNCE,
+                move || {
+                    if let Err(e) = watched.reload() {
+                        log::warn!("Rules reload failed, keeping the current rules: {e}");
+                    }
+                },
+            ));
+        }
+        if state.config.watch_rules || !state.policy().rule_stats().is_empty() {
+            background.spawn(report_rule_stats(state.clone()));
+        }
+        if state.access_log.is_some() || state.config.config_file.is_some() {
+            // Log rotation renames the file and sends SIGHUP, as does whoever
+            // changed the config file.
+            let mut hangup = signal(SignalKind::hangup())?;
+            let state = state.clone();
+            background.spawn(async move {
+                while hangup.recv().await.is_some() {
+                    if let Some(access_log) = &state.access_log {
+                        log::info!("Reopening the access log");
+                        access_log.reopen();
+                    }
+                    if state.config.config_file.is_some()
+                        && let Err(e) = state.reload_config()
+                    {
+                        log::error!("Config reload failed, keeping the current config: {e}");
+                    }
+                }
+            });
+        }
+        if let Some(statsd) = &state.config.statsd {
+            let mut tags = state.config.statsd_tags.clone();
+            tags.push(format!("listener:{}", listeners[0]));
+            let emitter = statsd::run(
+                state.metrics.clone(),
+                statsd.clone(),
+                tags,
+                statsd::FLUSH_INTERVAL,
+            );
+            background.spawn(async move {
+                if let Err(e) = emitter.await {
+                    log::warn!("statsd emitter stopped: {e}");
+                }
+            });
+        }
+        if let Some(listener) = metrics_listener {
+            log::info!("Serving metrics on {}", listener.local_addr()?);
+            let metrics = state.metrics.clone();
+            background.spawn(async move {
+                if let Err(e) = prometheus::serve(listener, metrics).await {
+                    log::warn!("Metrics listener stopped: {e}");
+                }
+            });
+        }
+        if let Some(listener) = admin_listener {
+            log::info!("Serving status on {}", listener.local_addr()?);
+            let state = state.clone();
+            background.spawn(async move {
+                if let Err(e) = status::serve(listener, state).await {
+                    log::warn!("Admin listener stopped: {e}");
+                }
+            });
+        }
+        if let Some(listener) = tap_listener {
+            log::info!("Serving taps on {}", listener.local_addr()?);
+            let state = state.clone();
+            background.spawn(async move {
+                if let Err(e) = tap::serve(listener, state).await {
+                    log::warn!("Tap listener stopped: {e}");
+                }
+            });
+        }
+        if state.pcap.is_some() {
+            let state = state.clone();
+            background.spawn(report_drops("pcap records", move || {
+                state.pcap.as_ref().unwrap().dropped()
+            }));
+        }
+        if state.netlog.is_some() {
+            let state = state.clone();
+            background.spawn(report_drops("NetLog events", move || {
+                state.netlog.as_ref().unwrap().dropped()
+            }));
+        }
+        if state.webhooks.is_some() {
+            let state = state.clone();
+            background.spawn(report_drops("webhook events", move || {
+                state.webhooks.as_ref().unwrap().dropped()
+            }));
+        }
+        let mut acceptors = JoinSet::new();
+        for (index, listener) in listeners.into_iter().enumerate() {
+            acceptors.spawn(accept_loop(index, listener, state.clone(), stats.clone()));
+        }
+        let notifier = sd_notify::Notifier::from_env()?.map(Arc::new);
+        if let Some(notifier) = &notifier {
+            notifier.notify("READY=1");
+            background.spawn(report_systemd_status(notifier.clone(), state.clone()));
+            if let Some(interval) = sd_notify::watchdog_interval_from_env() {
+                background.spawn(ping_watchdog(notifier.clone(), stats.clone(), interval));
+            }
+        }
+        let mut aborted = 0;
+        tokio::select! {
+            result = async {
+                while let Some(result) = acceptors.join_next().await {
+                    result.map_err(io::Error::other)??;
+                }
+                Ok::<_, io::Error>(())
+            } => result?,
+            Some(()) = requests.recv() => {
+                // Closes the listeners, so new connections are refused.
+                acceptors.abort_all();
+                log::info!(
+                    "Shutting down: draining {} connection(s) for up to {:?}, \
+                     signal again to abort",
+                    state.metrics.active.load(Ordering::Relaxed),
+                    state.config.shutdown_grace
+                );
+                if let Some(notifier) = &notifier {
+                    notifier.notify("STOPPING=1");
+                }
+                aborted = drain(&state, state.config.shutdown_grace, &mut requests).await;
+            }
+        }
+        if let Some(netlog) = &state.netlog {
+            netlog.finish().await?;
+        }
+        if let Some(har) = &state.har {
+            har.finish().await?;
+        }
+        if let Some(access_log) = &state.access_log {
+            access_log.finish().await?;
+        }
+        if aborted > 0 {
+            return Err(io::Error::other(format!(
+                "aborted {aborted} connection(s) on shutdown"
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Waits for the open connections to finish, for `grace` at most or until the
+// next shutdown signal, and aborts the rest; those idle between requests
+// close straight away. Returns how many were aborted.
+async fn drain(state: &ProxyState, grace: Duration, signals: &mut mpsc::Receiver<()>) -> usize {
+    state.draining.send_replace(true);
+    let mut connections = std::mem::take(&mut *state.connections.lock().unwrap());
+    tokio::select! {
+        _ = async { while connections.join_next().await.is_some() {} } => {}
+        _ = tokio::time::sleep(grace) => log::info!("Shutdown grace period expired"),
+        _ = signals.recv() => log::info!("Second shutdown signal"),
+    }
+    let aborted = connections.len();
+    connections.shutdown().await;
+    aborted
+}
+
+async fn accept_loop(
+    index: usize,
+    listener: listener::Listener,
+    state: Arc<ProxyState>,
+    stats: Arc<listener::AcceptStats>,
+) -> io::Result<()> {
+    loop {
+        stats.beat(index);
+        let mut permit = None;
+        if let Some(limit) = state.connection_limit.clone()
+            && !state.config.reject_over_limit
+        {
+            permit = match limit.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    log::info!("At the connection limit, acceptor {index} waits for one to close");
+                    Some(wait_for_permit(index, limit, &stats).await)
+                }
+            };
+        }
+        let (socket, addr) = match tokio::time::timeout(ACCEPT_HEARTBEAT, listener.accept()).await {
+            Ok(Ok(accepted)) => accepted,
+            // The connection stays queued and is accepted once there are
+            // descriptors to spare.
+            Ok(Err(e)) => {
+                log::warn!("Acceptor {index} failed to accept, retrying: {e}");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+            Err(_) => continue,
+        };
+        stats.record(index);
+        state.metrics.connections.fetch_add(1, Ordering::Relaxed);
+        if let Some(limit) = &state.connection_limit
+            && permit.is_none()
+        {
+            match limit.clone().try_acquire_owned() {
+                Ok(acquired) => permit = Some(acquired),
+                Err(_) => {
+                    log::debug!("Rejecting {addr}: at the connection limit");
+                    state.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                    tokio::spawn(reject(socket));
+                    continue;
+                }
+            }
+        }
+        let mut connections = state.connections.lock().unwrap();
+        // Reaps the finished tasks, which the set otherwise keeps until joined.
+        while connections.try_join_next().is_some() {}
+        let state = state.clone();
+        let connection = log::Connection::next(addr);
+        connections.spawn(connection.scope(async move {
+            let _permit = permit;
+            let _active = state.metrics.active();
+            log::debug!(
+                "Accepted on acceptor {index}, active connections: {}",
+                state.metrics.active.load(Ordering::Relaxed)
+            );
+            // Failures are reported with their stage and context by
+            // handle_client itself.
+            if let Err(e) = handle_client(socket, addr, state.clone()).await {
+                if let Some(error) = connection_error::proxy_error_of(&e) {
+                    log::debug!(
+                        "Connection from {addr} ended in {}: {error}",
+                        error.as_str()
+                    );
+                }
+                state.metrics.error(&e);
+                if let Some(webhooks) = &state.webhooks {
+                    webhooks.connection_failed();
+                }
+            }
+        }));
+    }
+}
+
+// Answers 503 and closes. The client's request is read and discarded for a
+// moment first: closing with it unread would reset the connection, and the
+// client might never see the answer.
+async fn reject<S: AsyncRead + AsyncWrite + Unpin>(mut socket: S) {
+    let answer = async {
+        socket.write_all(OVER_LIMIT_RESPONSE).await?;
+        socket.shutdown().await?;
+        io::copy(&mut socket, &mut io::sink()).await
+    };
+    let _ = tokio::time::timeout(REJECT_LINGER, answer).await;
+}
+
+// Waits until a connection closes, keeping up the acceptor's heartbeat.
+async fn wait_for_permit(
+    index: usize,
+    limit: Arc<Semaphore>,
+    stats: &listener::AcceptStats,
+) -> OwnedSemaphorePermit {
+    loop {
+        stats.beat(index);
+        if let Ok(permit) =
+            tokio::time::timeout(ACCEPT_HEARTBEAT, limit.clone().acquire_owned()).await
+        {
+            log::info!("Below the connection limit, acceptor {index} accepts again");
+            // The semaphore is never closed.
+            return permit.unwrap();
+        }
+    }
+}
+
+async fn report_accept_stats(stats: Arc<listener::AcceptStats>) {
+    let mut last = stats.counts();
+    loop {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let counts = stats.counts();
+        if counts != last {
+            log::debug!("Accept counts per acceptor: {:?}", counts);
+            last = counts;
+        }
+    }
+}
+
+async fn report_rule_stats(state: Arc<ProxyState>) {
+    let mut last = state.policy().rule_stats();
+    loop {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let policy = state.policy();
+        let stats = policy.rule_stats();
+        if stats != last {
+            for rule in &stats {
+                log::info!(
+                    "Rule {}: evaluations={} matches={} denials={} would_deny={} rewrites={}",
+                    policy.describe(Some(rule.index)),
+                    rule.evaluations,
+                    rule.matches,
+                    rule.denials,
+                    rule.would_deny,
+                    rule.rewrites
+                );
+            }
+            last = stats;
+        }
+    }
+}
+
+async fn report_systemd_status(notifier: Arc<sd_notify::Notifier>, state: Arc<ProxyState>) {
+    let mut last = None;
+    loop {
+        let active = state.metrics.active.load(Ordering::Relaxed);
+        if last != Some(active) {
+            notifier.notify(&sd_notify::status(active));
+            last = Some(active);
+        }
+        tokio::time::sleep(Duration::from_secs(10)).await;
+    }
+}
+
+// Pings only while every acceptor keeps running, so systemd restarts a proxy
+// whose accept loops are stuck.
+async fn ping_watchdog(
+    notifier: Arc<sd_notify::Notifier>,
+    stats: Arc<listener::AcceptStats>,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if stats.alive(ACCEPT_HEARTBEAT * 3) {
+            notifier.notify("WATCHDOG=1");
+        } else {
+            log::warn!("Accept loop unresponsive, withholding the watchdog ping");
+        }
+    }
+}
+
+async fn report_drops(what: &'static str, dropped: impl Fn() -> u64) {
+    let mut last = 0;
+    loop {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let dropped = dropped();
+        if dropped != last {
+            log::info!("{} dropped: {}", what, dropped);
+            last = dropped;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    // Runs an acceptor the way `Proxy::run` does, with its connections in `state`.
+    async fn accept_with_state(state: Arc<ProxyState>) -> (SocketAddr, JoinSet<io::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = Arc::new(listener::AcceptStats::new(1));
+        let mut acceptors = JoinSet::new();
+        let listener = listener::Listener::Tcp(Arc::new(listener));
+        acceptors.spawn(accept_loop(0, listener, state, stats));
+        (addr, acceptors)
+    }
+
+    async fn open_tunnel(proxy_addr: SocketAddr, target: &TcpListener) -> (TcpStream, TcpStream) {
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let (upstream, _) = target.accept().await.unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        (client, upstream)
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_open_tunnels() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let state = Arc::new(ProxyState::new(config::Config::default()).unwrap());
+        let (proxy_addr, mut acceptors) = accept_with_state(state.clone()).await;
+        let (mut client, mut upstream) = open_tunnel(proxy_addr, &target).await;
+
+        acceptors.abort_all();
+        while acceptors.join_next().await.is_some() {}
+        assert!(TcpStream::connect(proxy_addr).await.is_err());
+        let (_signal, mut signals) = mpsc::channel(1);
+        let drained =
+            tokio::spawn(async move { drain(&state, Duration::from_secs(30), &mut signals).await });
+        // The tunnel keeps working while draining, until its peers close it.
+        client.write_all(b"in flight").await.unwrap();
+        let mut received = [0; 9];
+        upstream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"in flight");
+        upstream.write_all(b"reply").await.unwrap();
+        drop(upstream);
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"reply");
+        drop(client);
+        assert_eq!(drained.await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_idle_kept_connections() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        tokio::spawn(async move {
+            let (mut socket, _) = origin.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            socket.write_all(response).await.unwrap();
+        });
+        let state = Arc::new(ProxyState::new(config::Config::default()).unwrap());
+        let (proxy_addr, acceptors) = accept_with_state(state.clone()).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let request = format!("GET http://{origin_addr}/ HTTP/1.1\r\nHost: x\r\n\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut received = vec![0; response.len()];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, response);
+        drop(acceptors);
+        // Kept and waiting for its next request: closed straight away rather
+        // than aborted once the grace period is over.
+        let (_signal, mut signals) = mpsc::channel(1);
+        let drained = drain(&state, Duration::from_secs(30), &mut signals);
+        let drained = tokio::time::timeout(Duration::from_secs(5), drained).await;
+        assert_eq!(drained.unwrap(), 0);
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        for reject in [true, false] {
+            let config = config::Config {
+                max_connections: Some(2),
+                reject_over_limit: reject,
+                ..Default::default()
+            };
+            let state = Arc::new(ProxyState::new(config).unwrap());
+            let (proxy_addr, _acceptors) = accept_with_state(state.clone()).await;
+            let first = open_tunnel(proxy_addr, &target).await;
+            let _second = open_tunnel(proxy_addr, &target).await;
+            let mut over = TcpStream::connect(proxy_addr).await.unwrap();
+            let target_addr = target.local_addr().unwrap();
+            over.write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            if reject {
+                let mut response = vec![];
+                over.read_to_end(&mut response).await.unwrap();
+                assert!(response.starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+                assert_eq!(state.metrics.rejected.load(Ordering::Relaxed), 1);
+                // Once a tunnel closes, a new one gets in.
+                drop(first);
+                let mut third = TcpStream::connect(proxy_addr).await.unwrap();
+                let mut answered = vec![];
+                for _ in 0..50 {
+                    third
+                        .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+                        .await
+                        .unwrap();
+                    let mut response = [0; 12];
+                    third.read_exact(&mut response).await.unwrap();
+                    if &response == b"HTTP/1.1 200" {
+                        answered.push(target.accept().await.unwrap());
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    third = TcpStream::connect(proxy_addr).await.unwrap();
+                }
+                assert_eq!(answered.len(), 1);
+            } else {
+                // Queued rather than answered, until a tunnel closes.
+                let mut response = [0; 39];
+                let queued = tokio::time::timeout(
+                    Duration::from_millis(200),
+                    over.read_exact(&mut response),
+                )
+                .await;
+                assert!(queued.is_err());
+                drop(first);
+                let (_upstream, _) = target.accept().await.unwrap();
+                over.read_exact(&mut response).await.unwrap();
+                assert_eq!(&response, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+                assert_eq!(state.metrics.rejected.load(Ordering::Relaxed), 0);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_second_signal_aborts_draining() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let state = Arc::new(ProxyState::new(config::Config::default()).unwrap());
+        let (proxy_addr, acceptors) = accept_with_state(state.clone()).await;
+        let (mut client, _upstream) = open_tunnel(proxy_addr, &target).await;
+        drop(acceptors);
+        let (signal, mut signals) = mpsc::channel(1);
+        signal.send(()).await.unwrap();
+        assert_eq!(
+            drain(&state, Duration::from_secs(30), &mut signals).await,
+            1
+        );
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+        // Grace expiry does the same.
+        let (proxy_addr, _acceptors) = accept_with_state(state.clone()).await;
+        let (_client, _upstream) = open_tunnel(proxy_addr, &target).await;
+        let (_signal, mut signals) = mpsc::channel(1);
+        assert_eq!(
+            drain(&state, Duration::from_millis(50), &mut signals).await,
+            1
+        );
+    }
+
+    #[test]
+    fn test_empty_record_buffer_is_rejected() {
+        let config = config::Config {
+            record_buffer: 0,
+            ..Default::default()
+        };
+        let error = Proxy::with_config(config).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("--record-buffer"));
+    }
+}
@@ -0,0 +1,149 @@
+// Basic proxy authentication (RFC 9110 section 11.7.1, RFC 7617): the
+// client sends `Proxy-Authorization: Basic base64(user:password)`.
+
+pub const HEADER: &str = "proxy-authorization";
+pub const CHALLENGE: &str = "Proxy-Authenticate: Basic realm=\"proxy\"";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    user: String,
+    // `user:password`, as the client encodes it.
+    expected: Vec<u8>,
+}
+
+impl Credentials {
+    /// Parses `user:password`. The user cannot contain a colon.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (user, _) = s.split_once(':')?;
+        if user.is_empty() {
+            return None;
+        }
+        Some(Self {
+            user: user.to_string(),
+            expected: s.as_bytes().to_vec(),
+        })
+    }
+
+    /// The authenticated user, if the header carries these credentials.
+    pub fn check(&self, header: Option<&str>) -> Option<&str> {
+        let (scheme, token) = header?.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = decode_base64(token.trim())?;
+        constant_time_eq(&decoded, &self.expected).then_some(self.user.as_str())
+    }
+}
+
+// Takes as long for a wrong first byte as for a wrong last one; only the
+// length is given away.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= usize::from(x ^ y);
+    }
+    diff == 0
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode_base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(bits >> (18 - 6 * i) & 63) as usize].into());
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+    let padding = s.iter().rev().take_while(|&&b| b == b'=').count();
+    if padding > 2 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    let mut bits = 0u32;
+    for (i, &b) in s[..s.len() - padding].iter().enumerate() {
+        let value = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | u32::from(value);
+        if i % 4 == 3 {
+            out.extend_from_slice(&bits.to_be_bytes()[1..]);
+            bits = 0;
+        }
+    }
+    match padding {
+        1 => out.extend_from_slice(&(bits << 6).to_be_bytes()[1..3]),
+        2 => out.push((bits << 12).to_be_bytes()[1]),
+        _ => {}
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert_eq!(decode_base64("Zg==").unwrap(), b"f");
+        assert_eq!(decode_base64("Zm8=").unwrap(), b"fo");
+        assert_eq!(decode_base64("Zm9v").unwrap(), b"foo");
+        assert_eq!(decode_base64("Zm9vYmFy").unwrap(), b"foobar");
+        for bad in ["Zm9", "Zm9v!A==", "Z===", "Zg=a"] {
+            assert_eq!(decode_base64(bad), None, "{bad}");
+        }
+    }
+    #[test]
+    fn test_encode_base64() {
+        for decoded in [&b""[..], b"f", b"fo", b"foo", b"foob", b"alice:open:sesame"] {
+            let encoded = encode_base64(decoded);
+            assert_eq!(decode_base64(&encoded).unwrap(), decoded, "{encoded}");
+        }
+        assert_eq!(encode_base64(b"fo"), "Zm8=");
+    }
+    #[test]
+    fn test_check() {
+        let credentials = Credentials::parse("alice:open:sesame").unwrap();
+        // alice:open:sesame
+        let good = "Basic YWxpY2U6b3BlbjpzZXNhbWU=";
+        assert_eq!(credentials.check(Some(good)), Some("alice"));
+        assert_eq!(
+            credentials.check(Some("basic  YWxpY2U6b3BlbjpzZXNhbWU= ")),
+            Some("alice")
+        );
+        // alice:wrong
+        assert_eq!(credentials.check(Some("Basic YWxpY2U6d3Jvbmc=")), None);
+        assert_eq!(credentials.check(Some("Basic !!not base64!!")), None);
+        assert_eq!(
+            credentials.check(Some("Bearer YWxpY2U6b3BlbjpzZXNhbWU=")),
+            None
+        );
+        assert_eq!(credentials.check(None), None);
+        assert_eq!(Credentials::parse("alice"), None);
+        assert_eq!(Credentials::parse(":password"), None);
+    }
+}
@@ -0,0 +1,248 @@
+// The PROXY protocol (v1 and v2) header a load balancer sends ahead of the
+// client's own bytes, for `--accept-proxy-protocol`. It carries the address
+// the balancer accepted the client from, which stands in for the socket's
+// peer address.
+
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// The longest v1 line the spec allows, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+
+fn malformed(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("PROXY header: {msg}"))
+}
+
+/// Reads the header and nothing after it. Returns the client's address, or
+/// None when the header conveys none (v1 UNKNOWN, v2 LOCAL or an address
+/// family without one) and the socket's address stands.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // The shortest header of either version is longer than the signature.
+    let mut start = [0; V2_SIGNATURE.len()];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        let mut fixed = [0; 4];
+        stream.read_exact(&mut fixed).await?;
+        let mut body = vec![0; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        stream.read_exact(&mut body).await?;
+        return parse_v2(fixed[0], fixed[1], &body);
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(malformed("missing"));
+    }
+    // Byte by byte, so that nothing past the line is consumed.
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(malformed("line too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(&line)
+}
+
+// "PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n"
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .ok_or_else(|| malformed("invalid line"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        [
+            "PROXY",
+            protocol,
+            source,
+            destination,
+            source_port,
+            destination_port,
+        ] => {
+            let ip = |text: &str| -> io::Result<IpAddr> {
+                let ip = match protocol {
+                    "TCP4" => text.parse::<Ipv4Addr>().map(IpAddr::from),
+                    "TCP6" => text.parse::<Ipv6Addr>().map(IpAddr::from),
+                    _ => return Err(malformed(&format!("unknown protocol {protocol}"))),
+                };
+                ip.map_err(|_| malformed(&format!("invalid address {text}")))
+            };
+            let port = |text: &str| -> io::Result<u16> {
+                match text.parse() {
+                    Ok(port) if !text.starts_with('0') || text == "0" => Ok(port),
+                    _ => Err(malformed(&format!("invalid port {text}"))),
+                }
+            };
+            let source = SocketAddr::new(ip(source)?, port(source_port)?);
+            ip(destination)?;
+            port(destination_port)?;
+            Ok(Some(source))
+        }
+        _ => Err(malformed("wrong number of fields")),
+    }
+}
+
+// The part of a v2 header after the signature: the version and command, the
+// address family and protocol, and the addresses followed by any TLVs, which
+// are skipped.
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> io::Result<Option<SocketAddr>> {
+    match version_command {
+        V2_LOCAL => {}
+        V2_PROXY => {}
+        _ if version_command >> 4 != 2 => return Err(malformed("unsupported version")),
+        _ => return Err(malformed("unknown command")),
+    }
+    // Addresses, then ports, source first.
+    let (source, addresses_len) = match family >> 4 {
+        0x0 => (None, 0),
+        0x1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            (Some(SocketAddr::new(ip.into(), port)), 12)
+        }
+        0x2 if body.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).unwrap());
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            (Some(SocketAddr::new(ip.into(), port)), 36)
+        }
+        // Unix sockets: nothing that stands in for a peer address.
+        0x3 if body.len() >= 216 => (None, 216),
+        0x1..=0x3 => return Err(malformed("addresses truncated")),
+        _ => return Err(malformed("unknown address family")),
+    };
+    let mut tlvs = &body[addresses_len..];
+    while !tlvs.is_empty() {
+        let [_, high, low, ..] = *tlvs else {
+            return Err(malformed("TLV truncated"));
+        };
+        let len = 3 + u16::from_be_bytes([high, low]) as usize;
+        tlvs = tlvs.get(len..).ok_or_else(|| malformed("TLV truncated"))?;
+    }
+    match version_command {
+        V2_LOCAL => Ok(None),
+        _ => Ok(source),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    async fn read(bytes: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = bytes;
+        let result = read_header(&mut stream).await;
+        (result, stream.to_vec())
+    }
+    fn addr(text: &str) -> Option<SocketAddr> {
+        Some(text.parse().unwrap())
+    }
+    #[tokio::test]
+    async fn test_v1() {
+        for (header, expected) in [
+            (
+                "PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n",
+                addr("192.168.0.1:56324"),
+            ),
+            (
+                "PROXY TCP4 255.255.255.255 255.255.255.255 65535 65535\r\n",
+                addr("255.255.255.255:65535"),
+            ),
+            (
+                "PROXY TCP6 ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff \
+                 ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff 65535 65535\r\n",
+                addr("[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff]:65535"),
+            ),
+            ("PROXY UNKNOWN\r\n", None),
+            (
+                "PROXY UNKNOWN ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff \
+                 ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff 65535 65535\r\n",
+                None,
+            ),
+        ] {
+            let (result, rest) = read(format!("{header}CONNECT").as_bytes()).await;
+            assert_eq!(result.unwrap(), expected, "{header}");
+            assert_eq!(rest, b"CONNECT");
+        }
+        for header in [
+            &b"CONNECT example.com:443 HTTP/1.1\r\n"[..],
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n",
+            b"PROXY TCP4 ::1 192.168.0.11 56324 443\r\n",
+            b"PROXY TCP6 192.168.0.1 192.168.0.11 56324 443\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 65536 443\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 056324 443\r\n",
+            b"PROXY TCP4  192.168.0.1 192.168.0.11 56324 443\r\n",
+            b"PROXY UDP4 192.168.0.1 192.168.0.11 56324 443\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\n",
+        ] {
+            let (result, _) = read(header).await;
+            let e = result.unwrap_err();
+            assert!(
+                matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof),
+                "{}",
+                String::from_utf8_lossy(header)
+            );
+        }
+        let long = format!("PROXY {}\r\n", "x".repeat(200));
+        let e = read(long.as_bytes()).await.0.unwrap_err();
+        assert_eq!(e.to_string(), "PROXY header: line too long");
+    }
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([command, family]);
+        header.extend((body.len() as u16).to_be_bytes());
+        header.extend(body);
+        header
+    }
+    #[tokio::test]
+    async fn test_v2() {
+        // TCP over IPv4, 192.168.0.1:56324 to 192.168.0.11:443.
+        let tcp4 = [192, 168, 0, 1, 192, 168, 0, 11, 0xdc, 0x04, 0x01, 0xbb];
+        let (result, rest) = read(&[v2(0x21, 0x11, &tcp4), b"CONNECT".to_vec()].concat()).await;
+        assert_eq!(result.unwrap(), addr("192.168.0.1:56324"));
+        assert_eq!(rest, b"CONNECT");
+        // With TLVs after the addresses: ALPN "h2", an authority and a NOOP
+        // padding the header out.
+        let mut with_tlvs = tcp4.to_vec();
+        with_tlvs.extend([0x01, 0x00, 0x02, b'h', b'2']);
+        with_tlvs.extend([0x02, 0x00, 0x0b]);
+        with_tlvs.extend(b"example.com");
+        with_tlvs.extend([0x04, 0x00, 0x03, 0, 0, 0]);
+        let (result, rest) =
+            read(&[v2(0x21, 0x11, &with_tlvs), b"CONNECT".to_vec()].concat()).await;
+        assert_eq!(result.unwrap(), addr("192.168.0.1:56324"));
+        assert_eq!(rest, b"CONNECT");
+        // TCP over IPv6.
+        let mut tcp6 = vec![0; 36];
+        tcp6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        tcp6[16..32].copy_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        tcp6[32..34].copy_from_slice(&8443u16.to_be_bytes());
+        tcp6[34..].copy_from_slice(&443u16.to_be_bytes());
+        let (result, _) = read(&v2(0x21, 0x21, &tcp6)).await;
+        assert_eq!(result.unwrap(), addr("[2001:db8::1]:8443"));
+        // LOCAL, as from the balancer's health checks, conveys no address
+        // even with one present.
+        assert_eq!(read(&v2(0x20, 0x00, &[])).await.0.unwrap(), None);
+        assert_eq!(read(&v2(0x20, 0x11, &tcp4)).await.0.unwrap(), None);
+        assert_eq!(read(&v2(0x21, 0x00, &[])).await.0.unwrap(), None);
+        for header in [
+            v2(0x11, 0x11, &tcp4),
+            v2(0x22, 0x11, &tcp4),
+            v2(0x21, 0x11, &tcp4[..8]),
+            v2(0x21, 0x41, &tcp4),
+            v2(0x21, 0x11, &[&tcp4[..], &[0x01, 0x00, 0x05, b'h']].concat()),
+            v2(0x21, 0x11, &[&tcp4[..], &[0x01, 0x00]].concat()),
+            // The length claims more than is sent.
+            v2(0x21, 0x11, &tcp4)[..20].to_vec(),
+        ] {
+            let e = read(&header).await.0.unwrap_err();
+            assert!(
+                matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof),
+                "{header:?}"
+            );
+        }
+    }
+}
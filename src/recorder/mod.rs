@@ -1,70 +1,754 @@
+use bytes::Bytes;
 use std::cmp::min;
 use std::collections::VecDeque;
+use std::fmt;
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, TryLockError};
 use std::task::{Context, Poll};
-use tokio::io::{self, ReadBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncBufRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::task::JoinHandle;
+
+use crate::log;
+
+// Consumed segments are only released once this many bytes have been written
+// since the last drain, so the drain cost is amortized over many writes.
+const DRAIN_INTERVAL: usize = 64 * 1024;
+// The most recent bytes kept by default for `snapshot_tail`.
+pub const TAIL_SIZE: usize = 4 * 1024;
+
+/// A cap on the bytes charged to the recorders sharing it, together.
+#[derive(Debug)]
+pub struct Budget {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl Budget {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    // Whether `n` more bytes take what was charged over the limit, however
+    // much of it the readers have drained since.
+    fn charge(&self, n: usize) -> bool {
+        self.used.fetch_add(n as u64, Ordering::Relaxed) + n as u64 > self.limit
+    }
+}
+
+/// How far an auxiliary reader may fall behind before it is evicted. Past
+/// either limit that is set, it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxLag {
+    pub bytes: Option<usize>,
+    // Measured from when the reader was last caught up.
+    pub duration: Option<Duration>,
+}
 
 pub struct RecorderState {
     reader_length: usize,
     waker: Option<std::task::Waker>,
+    // None for the authoritative reader, which is never evicted.
+    max_lag: Option<MaxLag>,
+    behind_since: Option<Instant>,
+    // How far behind the reader was when it was evicted.
+    evicted: Option<usize>,
+}
+
+impl RecorderState {
+    fn new(reader_length: usize, max_lag: Option<MaxLag>) -> Self {
+        Self {
+            reader_length,
+            waker: None,
+            max_lag,
+            behind_since: None,
+            evicted: None,
+        }
+    }
+}
+
+// Wake-ups are never lost because every waker is stored and taken under the
+// lock, together with the state it waits on. A task that sees nothing to do
+// registers before the lock is released. Whatever changes that state takes
+// the registered wakers in the same critical section and wakes them once the
+// lock is released. A task woken that way may find the change already used
+// up by another, which is a spurious wake-up and nothing worse, but any
+// change made after its check finds its waker registered.
+struct RecorderInner {
+    segments: VecDeque<Bytes>,
+    len: usize,
+    // Bytes released from the front of the retained stream. Readers' lengths
+    // are relative to it, so a reader is `drained + reader_length` bytes in.
+    drained: u64,
+    written_since_drain: usize,
+    // One slot per attached reader, addressed by the reader's index. A
+    // dropped reader empties its slot, which the next reader attached reuses.
+    states: Vec<Option<RecorderState>>,
+    aborted: bool,
+    // No more bytes will be appended; readers that caught up see EOF.
+    closed: bool,
+    // The writers waiting in `reserve` for readers to make room, one entry
+    // per task. All of them are woken when room is made; those that find
+    // none left register again.
+    writer_wakers: Vec<std::task::Waker>,
+}
+
+impl RecorderInner {
+    fn readers(&self) -> impl Iterator<Item = &RecorderState> {
+        self.states.iter().flatten()
+    }
+
+    fn readers_mut(&mut self) -> impl Iterator<Item = &mut RecorderState> {
+        self.states.iter_mut().flatten()
+    }
+
+    fn state(&self, index: usize) -> &RecorderState {
+        self.states[index].as_ref().expect("reader detached")
+    }
+
+    fn state_mut(&mut self, index: usize) -> &mut RecorderState {
+        self.states[index].as_mut().expect("reader detached")
+    }
+
+    fn claim(&self, begin: usize, size: usize) -> Vec<Bytes> {
+        let mut chunks = vec![];
+        let mut segment_offset = 0;
+        for segment in &self.segments {
+            if segment_offset >= begin + size {
+                break;
+            }
+            let overlap = get_overlap(segment, segment_offset, begin, size);
+            if !overlap.is_empty() {
+                chunks.push(segment.slice_ref(overlap));
+            }
+            segment_offset += segment.len();
+        }
+        chunks
+    }
+
+    // Evicted readers no longer hold anything back.
+    fn drain(&mut self) {
+        let mut consumed = self
+            .readers()
+            .filter(|state| state.evicted.is_none())
+            .map(|state| state.reader_length)
+            .min()
+            .unwrap_or(self.len);
+        for state in self.readers_mut() {
+            if state.evicted.is_none() {
+                state.reader_length -= consumed;
+            }
+        }
+        self.len -= consumed;
+        self.drained += consumed as u64;
+        while consumed > 0 {
+            let front = self.segments.front_mut().unwrap();
+            if front.len() > consumed {
+                let _ = front.split_to(consumed);
+                break;
+            }
+            consumed -= front.len();
+            self.segments.pop_front();
+        }
+    }
+
+    // Evicts the auxiliary readers over their lag budget, returning how far
+    // behind each was.
+    fn evict_lagging(&mut self, appended: usize) -> Vec<usize> {
+        let len = self.len;
+        let mut evicted = vec![];
+        for state in self.readers_mut() {
+            let Some(max_lag) = state.max_lag else {
+                continue;
+            };
+            if state.evicted.is_some() {
+                continue;
+            }
+            if state.reader_length + appended == len {
+                state.behind_since = Some(Instant::now());
+            }
+            let lag = len - state.reader_length;
+            let over_bytes = max_lag.bytes.is_some_and(|bytes| lag > bytes);
+            let over_duration = max_lag.duration.is_some_and(|duration| {
+                state
+                    .behind_since
+                    .is_some_and(|since| since.elapsed() > duration)
+            });
+            if over_bytes || over_duration {
+                state.evicted = Some(lag);
+                evicted.push(lag);
+            }
+        }
+        evicted
+    }
+
+    // An aborted stream still hands out what was recorded before failing.
+    fn check(&self, index: usize) -> io::Result<()> {
+        let state = self.state(index);
+        if let Some(lag) = state.evicted {
+            return Err(io::Error::other(format!("evicted: lagged by {lag} bytes")));
+        }
+        if self.aborted && state.reader_length == self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "recording aborted",
+            ));
+        }
+        Ok(())
+    }
+
+    // Returns the waiting writers, to be woken once the lock is released.
+    fn advance(&mut self, index: usize, n: usize) -> Vec<std::task::Waker> {
+        let len = self.len;
+        let state = self.state_mut(index);
+        state.reader_length += n;
+        if state.reader_length == len {
+            state.behind_since = None;
+        }
+        std::mem::take(&mut self.writer_wakers)
+    }
+
+    fn wait_for_room(&mut self, waker: &std::task::Waker) {
+        if !self.writer_wakers.iter().any(|w| w.will_wake(waker)) {
+            self.writer_wakers.push(waker.clone());
+        }
+    }
+
+    // Bytes the slowest authoritative reader has not read yet. Auxiliary
+    // readers are bounded by their own lag instead, and evicted past it.
+    fn unread(&self) -> usize {
+        self.readers()
+            .filter(|state| state.max_lag.is_none())
+            .map(|state| self.len - state.reader_length)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Contention {
+    pub acquisitions: u64,
+    pub contended: u64,
+    pub blocked: Duration,
 }
 
 pub struct Recorder {
-    buf: VecDeque<u8>,
-    states: Vec<RecorderState>,
+    inner: Mutex<RecorderInner>,
+    subscribers: AtomicUsize,
+    // Off, appends are only counted and nothing is retained for readers.
+    recording: bool,
+    // How many unread bytes the authoritative readers may be left before
+    // writers wait.
+    capacity: usize,
+    total: AtomicU64,
+    evictions: AtomicU64,
+    first_append_at: OnceLock<Instant>,
+    // On tokio's clock, which the idle timeout sleeps on.
+    created: tokio::time::Instant,
+    // When the last chunk was appended, in nanoseconds since `created`.
+    last_append_nanos: AtomicU64,
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    blocked_nanos: AtomicU64,
+    // The last `tail_size` bytes appended, whether or not they were
+    // retained for readers, and however far those drained.
+    tail: Mutex<VecDeque<u8>>,
+    tail_size: usize,
+    // Why whatever consumes the stream gave up, which the writers then get.
+    // The first one given is kept.
+    failure: OnceLock<(io::ErrorKind, String)>,
+    budget: Option<Arc<Budget>>,
 }
 
 impl Recorder {
     pub fn new() -> Self {
         Self {
-            buf: VecDeque::new(),
-            states: vec![],
+            inner: Mutex::new(RecorderInner {
+                segments: VecDeque::new(),
+                len: 0,
+                drained: 0,
+                written_since_drain: 0,
+                states: vec![],
+                aborted: false,
+                closed: false,
+                writer_wakers: vec![],
+            }),
+            subscribers: AtomicUsize::new(0),
+            recording: true,
+            capacity: usize::MAX,
+            total: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            first_append_at: OnceLock::new(),
+            created: tokio::time::Instant::now(),
+            last_append_nanos: AtomicU64::new(0),
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            blocked_nanos: AtomicU64::new(0),
+            tail: Mutex::new(VecDeque::new()),
+            tail_size: TAIL_SIZE,
+            failure: OnceLock::new(),
+            budget: None,
+        }
+    }
+
+    /// Keeps the last `size` bytes for `snapshot_tail` instead of
+    /// TAIL_SIZE; zero keeps none.
+    pub fn with_tail(self, size: usize) -> Self {
+        Self {
+            tail_size: size,
+            ..self
+        }
+    }
+
+    /// Has `charge` count chunks against `budget`.
+    pub fn with_budget(self, budget: Arc<Budget>) -> Self {
+        Self {
+            budget: Some(budget),
+            ..self
+        }
+    }
+
+    /// A recorder whose writers wait in `reserve` rather
+    /// than leave more than `capacity` bytes the slowest authoritative reader
+    /// has not read.
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        assert!(capacity > 0, "recorder capacity must be positive");
+        Self { capacity, ..self }
+    }
+
+    /// Counts the appended bytes without keeping any of them.
+    pub fn counting() -> Self {
+        Self {
+            recording: false,
+            ..Self::new()
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RecorderInner> {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        match self.inner.try_lock() {
+            Ok(inner) => inner,
+            Err(TryLockError::WouldBlock) => {
+                let start = Instant::now();
+                let inner = self.inner.lock().unwrap();
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                self.blocked_nanos
+                    .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                inner
+            }
+            Err(TryLockError::Poisoned(e)) => panic!("recorder lock poisoned: {e}"),
+        }
+    }
+
+    /// Appends a chunk to the recorded stream and wakes the subscribed readers.
+    /// Never waits on readers, so a capacity only holds for callers that
+    /// `reserve` first; without subscribers nothing is retained.
+    pub fn append(&self, buf: &[u8]) {
+        if buf.is_empty() {
+            return;
+        }
+        self.first_append_at.get_or_init(Instant::now);
+        let since_created = self.created.elapsed().as_nanos() as u64;
+        self.last_append_nanos
+            .fetch_max(since_created, Ordering::Relaxed);
+        self.total.fetch_add(buf.len() as u64, Ordering::Relaxed);
+        if self.tail_size > 0 {
+            let kept = &buf[buf.len().saturating_sub(self.tail_size)..];
+            let mut tail = self.tail.lock().unwrap();
+            let over = (tail.len() + kept.len()).saturating_sub(self.tail_size);
+            tail.drain(..over);
+            tail.extend(kept);
+        }
+        if !self.recording || self.subscribers.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        let segment = Bytes::copy_from_slice(buf);
+        let mut recorder = self.lock();
+        recorder.segments.push_back(segment);
+        recorder.len += buf.len();
+        recorder.written_since_drain += buf.len();
+        let evicted = recorder.evict_lagging(buf.len());
+        if !evicted.is_empty() {
+            self.subscribers.fetch_sub(evicted.len(), Ordering::Release);
+            self.evictions
+                .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+        }
+        if recorder.written_since_drain >= DRAIN_INTERVAL {
+            recorder.drain();
+            recorder.written_since_drain = 0;
+        }
+        let wakers = recorder
+            .readers_mut()
+            .map(|state| state.waker.take())
+            .collect::<Vec<_>>();
+        drop(recorder);
+        for lag in evicted {
+            log::warn!("Recorder reader evicted: lagged by {lag} bytes");
+        }
+        for waker in wakers.into_iter().flatten() {
+            waker.wake();
+        }
+    }
+
+    // Ready once the authoritative readers have less than the capacity to
+    // read. What every reader has read is released first, so
+    // the retained segments stay near the capacity too. Fails once the
+    // recorder has.
+    fn poll_room(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(e) = self.failure() {
+            return Poll::Ready(Err(e));
+        }
+        if self.capacity == usize::MAX || self.subscribers.load(Ordering::Acquire) == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let mut inner = self.lock();
+        // Checked again under the lock `fail` takes to wake the writers.
+        if let Some(e) = self.failure() {
+            return Poll::Ready(Err(e));
+        }
+        if inner.unread() >= self.capacity {
+            inner.wait_for_room(cx.waker());
+            return Poll::Pending;
+        }
+        if inner.len > self.capacity {
+            inner.drain();
+            inner.written_since_drain = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Waits until the authoritative readers, such as a capture file's sink,
+    /// are less than the capacity behind, so that a slow one holds back the
+    /// stream rather than have everything appended meanwhile retained for it.
+    pub async fn reserve(&self) -> io::Result<()> {
+        std::future::poll_fn(|cx| self.poll_room(cx)).await
+    }
+
+    /// Fails the subscribed readers once they have read what was recorded:
+    /// the stream will never be complete.
+    pub fn abort(&self) {
+        self.end(|recorder| recorder.aborted = true);
+    }
+
+    /// Ends the recorded stream: readers see EOF once they have read it all.
+    pub fn close(&self) {
+        self.end(|recorder| recorder.closed = true);
+    }
+
+    /// Records that the stream's destination failed with `e`: the readers are
+    /// aborted, and `reserve` returns the error from now on, so that whatever
+    /// writes into the recorder stops.
+    pub fn fail(&self, e: &io::Error) {
+        let _ = self.failure.set((e.kind(), e.to_string()));
+        self.end(|recorder| recorder.aborted = true);
+    }
+
+    /// The error `fail` was given, if it was called.
+    pub fn failure(&self) -> Option<io::Error> {
+        let (kind, message) = self.failure.get()?;
+        Some(io::Error::new(*kind, message.clone()))
+    }
+
+    // Wakes the readers, and the writers waiting for room, to see the end.
+    fn end(&self, mark: impl FnOnce(&mut RecorderInner)) {
+        let mut recorder = self.lock();
+        mark(&mut recorder);
+        let wakers = recorder
+            .readers_mut()
+            .map(|state| state.waker.take())
+            .collect::<Vec<_>>();
+        let writers = std::mem::take(&mut recorder.writer_wakers);
+        drop(recorder);
+        for waker in wakers.into_iter().flatten().chain(writers) {
+            waker.wake();
+        }
+    }
+
+    /// Up to the last `n` bytes appended, as far as the tail reaches back.
+    pub fn snapshot_tail(&self, n: usize) -> Vec<u8> {
+        let tail = self.tail.lock().unwrap();
+        tail.range(tail.len().saturating_sub(n)..)
+            .copied()
+            .collect()
+    }
+
+    /// Charges a chunk of `n` bytes to the budget ahead of appending it,
+    /// returning the budget's limit once this recorder and those sharing
+    /// the budget were charged more than it: the chunk that goes over is
+    /// meant to be dropped rather than appended.
+    pub fn charge(&self, n: usize) -> Option<u64> {
+        self.budget
+            .as_ref()
+            .filter(|budget| budget.charge(n))
+            .map(|budget| budget.limit())
+    }
+
+    /// Total number of bytes ever appended, independent of draining.
+    pub fn bytes_total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Copies the stream from now on into `sink` until it ends, then shuts
+    /// the sink down, whether the stream was closed or aborted.
+    pub fn attach_sink<W>(self: &Arc<Self>, mut sink: W) -> JoinHandle<io::Result<u64>>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut reader = RecorderReader::new(self.clone());
+        tokio::spawn(async move {
+            let copied = io::copy_buf(&mut reader, &mut sink).await;
+            let shutdown = sink.shutdown().await;
+            let copied = copied?;
+            shutdown?;
+            Ok(copied)
+        })
+    }
+
+    /// Auxiliary readers evicted for lagging.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    pub fn first_append_at(&self) -> Option<Instant> {
+        self.first_append_at.get().copied()
+    }
+
+    /// When the last chunk was appended, or when the recorder was created if
+    /// nothing has been.
+    pub fn last_activity(&self) -> tokio::time::Instant {
+        self.created + Duration::from_nanos(self.last_append_nanos.load(Ordering::Relaxed))
+    }
+
+    pub fn contention(&self) -> Contention {
+        Contention {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            blocked: Duration::from_nanos(self.blocked_nanos.load(Ordering::Relaxed)),
         }
     }
 }
 
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("total", &self.bytes_total())
+            .field("subscribers", &self.subscribers.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct RecorderReader {
     index: usize,
-    recorder: Arc<Mutex<Recorder>>,
+    recorder: Arc<Recorder>,
+    // The part of a segment handed out by `poll_fill_buf` and not consumed
+    // yet. Holding the `Bytes` handle keeps the slice valid without holding
+    // the recorder lock, whatever draining does to the segment meanwhile.
+    buffered: Bytes,
 }
 impl RecorderReader {
-    pub fn new(recorder: Arc<Mutex<Recorder>>) -> Self {
-        let mut recorder_locked = recorder.lock().unwrap();
-        let index = recorder_locked.buf.len();
-        recorder_locked.states.push({
-            RecorderState {
-                reader_length: 0,
-                waker: None,
+    /// The authoritative reader: the recorder retains everything it has not
+    /// read yet. Like every reader, it starts at the end of the stream so
+    /// far and sees only what is appended after it attached.
+    pub fn new(recorder: Arc<Recorder>) -> Self {
+        Self::attach(recorder, None)
+    }
+
+    /// A reader, such as a live tail, that is detached once it falls more
+    /// than `max_lag` behind; its reads then fail.
+    pub fn auxiliary(recorder: Arc<Recorder>, max_lag: MaxLag) -> Self {
+        Self::attach(recorder, Some(max_lag))
+    }
+
+    fn attach(recorder: Arc<Recorder>, max_lag: Option<MaxLag>) -> Self {
+        let mut recorder_locked = recorder.lock();
+        let state = Some(RecorderState::new(recorder_locked.len, max_lag));
+        let index = match recorder_locked.states.iter().position(Option::is_none) {
+            Some(index) => {
+                recorder_locked.states[index] = state;
+                index
             }
-        });
+            None => {
+                recorder_locked.states.push(state);
+                recorder_locked.states.len() - 1
+            }
+        };
+        recorder.subscribers.fetch_add(1, Ordering::Release);
+        drop(recorder_locked);
         Self {
             index,
-            recorder: recorder.clone(),
+            recorder,
+            buffered: Bytes::new(),
+        }
+    }
+}
+
+impl RecorderReader {
+    /// How far into the retained stream the reader is: bytes appended while
+    /// no reader was attached are not counted.
+    #[cfg(test)]
+    pub fn position(&self) -> u64 {
+        let recorder = self.recorder.lock();
+        let consumed = recorder.state(self.index).reader_length - self.buffered.len();
+        recorder.drained + consumed as u64
+    }
+}
+
+/// `bytes` as `xxd` shows them: sixteen to a line, after their offset, in
+/// groups of two, with the printable ones beside.
+pub fn hexdump(bytes: &[u8]) -> String {
+    hexdump_at(0, bytes)
+}
+
+/// Like `hexdump`, for bytes `offset` into a stream.
+pub fn hexdump_at(offset: u64, bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for (j, byte) in line.iter().enumerate() {
+            if j > 0 && j % 2 == 0 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{byte:02x}"));
         }
+        let text: String = line
+            .iter()
+            .map(|&b| match b {
+                0x20..=0x7e => b as char,
+                _ => '.',
+            })
+            .collect();
+        let at = offset + i as u64 * 16;
+        dump.push_str(&format!("{at:08x}: {hex:<39}  {text}\n"));
     }
+    dump
 }
 
 fn get_overlap(buf: &[u8], buf_offset: usize, begin: usize, size: usize) -> &[u8] {
     let end = begin + size;
-    let begin = if begin > buf_offset {
-        begin - buf_offset
-    } else {
-        0
-    };
-    let end = if end > buf_offset {
-        end - buf_offset
-    } else {
-        0
-    };
+    let begin = begin.saturating_sub(buf_offset);
+    let end = end.saturating_sub(buf_offset);
     let begin = if begin > buf.len() { buf.len() } else { begin };
     let end = if end > buf.len() { buf.len() } else { end };
-    return &buf[begin..end];
+    &buf[begin..end]
+}
+
+impl io::AsyncRead for RecorderReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.buffered.is_empty() {
+            let n = min(self.buffered.len(), buf.remaining());
+            buf.put_slice(&self.buffered[..n]);
+            self.consume(n);
+            return Poll::Ready(Ok(()));
+        }
+        // Only the range is claimed under the lock; the segments are immutable,
+        // so the copy into `buf` happens after it is released.
+        let chunks = {
+            let mut recorder = self.recorder.lock();
+            recorder.check(self.index)?;
+            let begin = recorder.state(self.index).reader_length;
+            let n = recorder.len - begin;
+            if n == 0 && recorder.closed {
+                return Poll::Ready(Ok(()));
+            }
+            if n == 0 {
+                recorder.state_mut(self.index).waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let n = min(n, buf.remaining());
+            let chunks = recorder.claim(begin, n);
+            (chunks, recorder.advance(self.index, n))
+        };
+        let (chunks, writers) = chunks;
+        for chunk in &chunks {
+            buf.put_slice(chunk);
+        }
+        for writer in writers {
+            writer.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncBufRead for RecorderReader {
+    // Hands out the unread part of the front segment: a `Bytes` clone of it
+    // is taken under the lock, and the returned slice borrows that clone
+    // from the reader rather than anything behind the lock.
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.buffered.is_empty() {
+            let mut recorder = this.recorder.lock();
+            recorder.check(this.index)?;
+            let begin = recorder.state(this.index).reader_length;
+            let n = recorder.len - begin;
+            if n == 0 && !recorder.closed {
+                recorder.state_mut(this.index).waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            if let Some(front) = recorder.claim(begin, n).into_iter().next() {
+                this.buffered = front;
+            }
+        }
+        Poll::Ready(Ok(&this.buffered))
+    }
+
+    // Advances the reader like poll_read does, waking a writer waiting for
+    // room.
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        let _ = this.buffered.split_to(amt);
+        let writers = this.recorder.lock().advance(this.index, amt);
+        for writer in writers {
+            writer.wake();
+        }
+    }
+}
+
+// Frees the reader's slot, so what only it held back can be released and a
+// writer waiting on it for room can go on.
+impl Drop for RecorderReader {
+    fn drop(&mut self) {
+        let mut recorder = self.recorder.lock();
+        let state = recorder.states[self.index].take();
+        if state.is_some_and(|state| state.evicted.is_none()) {
+            self.recorder.subscribers.fetch_sub(1, Ordering::Release);
+        }
+        recorder.drain();
+        recorder.written_since_drain = 0;
+        let writers = std::mem::take(&mut recorder.writer_wakers);
+        drop(recorder);
+        for writer in writers {
+            writer.wake();
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    // Appends `buf` as a tunnel direction does, once the readers leave room.
+    async fn record(recorder: &Recorder, buf: &[u8]) -> io::Result<()> {
+        recorder.reserve().await?;
+        recorder.append(buf);
+        Ok(())
+    }
     #[test]
     fn test_get_overlap_basic() {
         let buf = [1, 2, 3, 4, 5];
@@ -90,92 +774,535 @@ mod tests {
         let buf = [1, 2, 3, 4, 5];
         assert_eq!(get_overlap(&buf, 0, 2, 0), &[]);
     }
-}
-
-impl io::AsyncRead for RecorderReader {
-    fn poll_read(
-        self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &mut ReadBuf<'_>,
-    ) -> Poll<io::Result<()>> {
-        let mut recorder = self.recorder.lock().unwrap();
-        let state = &recorder.states[self.index];
-        let recorder_buf = &recorder.buf;
-        let n = recorder_buf.len() - state.reader_length;
-        if n == 0 {
-            recorder.states[self.index].waker = Some(cx.waker().clone());
-            return Poll::Pending;
-        }
-        let n = min(n, buf.remaining());
-        let (front, back) = recorder_buf.as_slices();
-        buf.put_slice(get_overlap(front, 0, state.reader_length, n));
-        buf.put_slice(get_overlap(back, front.len(), state.reader_length, n));
-        recorder.states[self.index].reader_length += n;
-
-        println!(
-            "poll_read: {}",
-            recorder
-                .buf
-                .iter()
-                .map(|&b| format!("{:02x}", b))
-                .collect::<String>()
+    #[tokio::test]
+    async fn test_tail_outlives_draining() {
+        use tokio::io::AsyncReadExt;
+        let recorder = Arc::new(Recorder::new().with_tail(8));
+        let mut reader = RecorderReader::new(recorder.clone());
+        recorder.append(b"0123");
+        recorder.append(b"456789ab");
+        assert_eq!(recorder.snapshot_tail(100), b"456789ab");
+        assert_eq!(recorder.snapshot_tail(3), b"9ab");
+        // Read and drained, and still in the tail.
+        let mut buf = [0; 12];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"0123456789ab");
+        recorder.lock().drain();
+        assert_eq!(recorder.lock().len, 0);
+        recorder.append(b"cd");
+        assert_eq!(recorder.snapshot_tail(8), b"6789abcd");
+        // Kept when nothing is retained for readers, too.
+        let counting = Recorder::counting().with_tail(4);
+        counting.append(b"abcdefgh");
+        assert_eq!(counting.snapshot_tail(4), b"efgh");
+        let off = Recorder::counting().with_tail(0);
+        off.append(b"abc");
+        assert!(off.snapshot_tail(4).is_empty());
+    }
+    #[test]
+    fn test_hexdump() {
+        assert_eq!(hexdump(b""), "");
+        assert_eq!(
+            hexdump(b"HTTP/1.1 200 OK\r\nServer: x\x00\xff"),
+            "00000000: 4854 5450 2f31 2e31 2032 3030 204f 4b0d  HTTP/1.1 200 OK.\n\
+             00000010: 0a53 6572 7665 723a 2078 00ff            .Server: x..\n"
         );
-        Poll::Ready(Ok(()))
     }
-}
-
-pub struct RecorderWriter {
-    pub recorder: Arc<Mutex<Recorder>>,
-}
-impl io::AsyncWrite for RecorderWriter {
-    fn poll_write(
-        self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
-        buf: &[u8],
-    ) -> Poll<Result<usize, io::Error>> {
-        let mut recorder = self.recorder.lock().unwrap();
-        println!(
-            "poll_write: {}",
-            buf.iter()
-                .map(|&b| format!("{:02x}", b))
-                .collect::<String>()
+    #[test]
+    fn test_claim_across_segments() {
+        let recorder = Recorder::new();
+        let mut inner = recorder.lock();
+        inner.segments.push_back(Bytes::from_static(&[1, 2, 3]));
+        inner.segments.push_back(Bytes::from_static(&[4, 5]));
+        inner.len = 5;
+        let chunks = inner.claim(2, 2);
+        assert_eq!(
+            chunks,
+            vec![Bytes::from_static(&[3]), Bytes::from_static(&[4])]
         );
-        if buf.len() == 0 {
-            panic!("buf is empty");
+    }
+    #[test]
+    fn test_drain_keeps_unconsumed_bytes() {
+        let recorder = Recorder::new();
+        let mut inner = recorder.lock();
+        inner.segments.push_back(Bytes::from_static(&[1, 2, 3]));
+        inner.segments.push_back(Bytes::from_static(&[4, 5]));
+        inner.len = 5;
+        for reader_length in [2, 4] {
+            inner
+                .states
+                .push(Some(RecorderState::new(reader_length, None)));
         }
-        recorder.buf.extend(buf);
-        dbg!(recorder.buf.len());
-        let wakers = recorder
-            .states
-            .iter_mut()
-            .map(|state| state.waker.take())
-            .collect::<Vec<_>>();
-        let min = recorder
-            .states
-            .iter()
-            .min_by_key(|state| state.reader_length)
-            .map(|state| state.reader_length);
-        if let Some(min) = min {
-            recorder.buf.drain(0..min);
-            for state in &mut recorder.states {
-                state.reader_length -= min;
+        inner.drain();
+        assert_eq!(inner.len, 3);
+        assert_eq!(inner.state(0).reader_length, 0);
+        assert_eq!(inner.state(1).reader_length, 2);
+        assert_eq!(inner.claim(0, 3).concat(), vec![3, 4, 5]);
+    }
+    #[tokio::test]
+    async fn test_two_readers_see_all_bytes() {
+        use tokio::io::AsyncReadExt;
+        let recorder = Arc::new(Recorder::new());
+        let mut readers = [
+            RecorderReader::new(recorder.clone()),
+            RecorderReader::new(recorder.clone()),
+        ];
+        let payload: Vec<u8> = (0..DRAIN_INTERVAL * 3).map(|i| i as u8).collect();
+        for chunk in payload.chunks(1000) {
+            record(&recorder, chunk).await.unwrap();
+        }
+        for reader in &mut readers {
+            let mut received = vec![0; payload.len()];
+            reader.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, payload);
+        }
+        assert!(recorder.contention().acquisitions > 0);
+    }
+    #[tokio::test]
+    async fn test_dropped_reader_no_longer_holds_back_drain() {
+        use tokio::io::AsyncReadExt;
+        let recorder = Arc::new(Recorder::new());
+        let mut kept = RecorderReader::new(recorder.clone());
+        let mut dropped = RecorderReader::new(recorder.clone());
+        recorder.append(&[1; 100]);
+        let mut buf = vec![0; DRAIN_INTERVAL];
+        dropped.read_exact(&mut buf[..10]).await.unwrap();
+        kept.read_exact(&mut buf[..50]).await.unwrap();
+        drop(dropped);
+        assert_eq!(recorder.lock().len, 50);
+        for _ in 0..4 {
+            recorder.append(&vec![2; DRAIN_INTERVAL]);
+            kept.read_exact(&mut buf).await.unwrap();
+        }
+        assert!(recorder.lock().len <= DRAIN_INTERVAL + 50);
+        // The slot is reused.
+        let reader = RecorderReader::new(recorder.clone());
+        assert_eq!(reader.index, 1);
+        assert_eq!(recorder.lock().states.len(), 2);
+    }
+    #[tokio::test]
+    async fn test_reader_starts_at_the_current_position() {
+        use tokio::io::AsyncReadExt;
+        let recorder = Arc::new(Recorder::new());
+        let mut forwarding = RecorderReader::new(recorder.clone());
+        let payload: Vec<u8> = (0..DRAIN_INTERVAL * 2).map(|i| (i % 251) as u8).collect();
+        // With the forwarding reader partway through what was appended.
+        recorder.append(&payload[..1024]);
+        let mut buf = vec![0; DRAIN_INTERVAL * 2];
+        forwarding.read_exact(&mut buf[..512]).await.unwrap();
+        let mut late = RecorderReader::new(recorder.clone());
+        assert_eq!(late.position(), 1024);
+        for chunk in payload[1024..].chunks(1000) {
+            recorder.append(chunk);
+        }
+        recorder.close();
+        let mut late_read = vec![];
+        late.read_to_end(&mut late_read).await.unwrap();
+        assert_eq!(late_read, payload[1024..]);
+        assert_eq!(late.position(), payload.len() as u64);
+        let mut forwarded = vec![];
+        forwarding.read_to_end(&mut forwarded).await.unwrap();
+        assert_eq!(forwarded, payload[512..]);
+    }
+    #[tokio::test]
+    async fn test_drain_waits_for_the_slowest_reader() {
+        use tokio::io::AsyncReadExt;
+        let recorder = Arc::new(Recorder::new());
+        recorder.append(&[0; 100]);
+        let mut fast = RecorderReader::new(recorder.clone());
+        let mut slow = RecorderReader::new(recorder.clone());
+        let payload: Vec<u8> = (0..DRAIN_INTERVAL * 4).map(|i| (i % 253) as u8).collect();
+        let mut fast_read = vec![0; payload.len()];
+        let mut slow_read = vec![];
+        let mut buf = vec![0; 1000];
+        for (i, chunk) in payload.chunks(1000).enumerate() {
+            recorder.append(chunk);
+            let start = i * 1000;
+            fast.read_exact(&mut fast_read[start..start + chunk.len()])
+                .await
+                .unwrap();
+            if i % 4 == 0 {
+                let n = slow.read(&mut buf).await.unwrap();
+                slow_read.extend_from_slice(&buf[..n]);
             }
+            // Nothing the slow reader has yet to read is released.
+            assert_eq!(slow.position(), slow_read.len() as u64);
+            assert!(recorder.lock().drained <= slow_read.len() as u64);
         }
-        drop(recorder);
-        for waker in wakers {
-            match waker {
-                Some(waker) => waker.wake(),
-                None => (),
-            };
+        assert!(recorder.lock().drained > 0);
+        assert_eq!(fast_read, payload);
+        recorder.close();
+        slow.read_to_end(&mut slow_read).await.unwrap();
+        assert_eq!(slow_read, payload);
+    }
+    #[tokio::test]
+    async fn test_dropped_reader_wakes_blocked_writer() {
+        let recorder = Arc::new(Recorder::new().with_capacity(16));
+        let reader = RecorderReader::new(recorder.clone());
+        recorder.append(&[3; 64]);
+        let writer = recorder.clone();
+        let write = tokio::spawn(async move { record(&writer, &[4; 64]).await });
+        tokio::task::yield_now().await;
+        assert!(!write.is_finished());
+        drop(reader);
+        write.await.unwrap().unwrap();
+    }
+    #[tokio::test]
+    async fn test_budget_counts_drained_bytes() {
+        use tokio::io::AsyncReadExt;
+        let budget = Arc::new(Budget::new(DRAIN_INTERVAL as u64 * 2));
+        let up = Recorder::new().with_budget(budget.clone());
+        let down = Arc::new(Recorder::new().with_budget(budget.clone()));
+        let mut reader = RecorderReader::new(down.clone());
+        let mut buf = vec![0; DRAIN_INTERVAL];
+        for _ in 0..2 {
+            assert_eq!(down.charge(buf.len()), None);
+            down.append(&buf);
+            reader.read_exact(&mut buf).await.unwrap();
         }
-        Poll::Ready(Ok(buf.len()))
+        // Drained, and still charged.
+        down.lock().drain();
+        assert_eq!(down.lock().len, 0);
+        assert_eq!(up.charge(1), Some(DRAIN_INTERVAL as u64 * 2));
+        assert_eq!(down.charge(1), Some(DRAIN_INTERVAL as u64 * 2));
+        assert_eq!(Recorder::new().charge(1), None);
     }
-
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        Poll::Ready(Ok(()))
+    #[tokio::test]
+    async fn test_fail_stops_the_writer() {
+        use tokio::io::AsyncReadExt;
+        let recorder = Arc::new(Recorder::new().with_capacity(16));
+        let mut reader = RecorderReader::new(recorder.clone());
+        recorder.append(&[3; 16]);
+        let writer = recorder.clone();
+        let write = tokio::spawn(async move { record(&writer, &[4; 16]).await });
+        tokio::task::yield_now().await;
+        assert!(!write.is_finished());
+        recorder.fail(&io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            "target went away",
+        ));
+        let error = write.await.unwrap().unwrap_err();
+        assert_eq!(
+            (error.kind(), error.to_string()),
+            (io::ErrorKind::BrokenPipe, "target went away".to_string())
+        );
+        assert!(recorder.reserve().await.is_err());
+        // The readers get what was recorded, and then the failure.
+        let mut recorded = [0; 16];
+        reader.read_exact(&mut recorded).await.unwrap();
+        assert_eq!(recorded, [3; 16]);
+        let error = reader.read(&mut recorded).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+        // The first failure is the one kept.
+        recorder.fail(&io::ErrorKind::TimedOut.into());
+        assert_eq!(
+            recorder.failure().unwrap().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+    #[tokio::test]
+    async fn test_abort_fails_waiting_readers() {
+        use tokio::io::AsyncReadExt;
+        let recorder = Arc::new(Recorder::new());
+        let mut reader = RecorderReader::new(recorder.clone());
+        let read = tokio::spawn(async move {
+            let mut buf = [0; 10];
+            reader.read(&mut buf).await
+        });
+        tokio::task::yield_now().await;
+        recorder.abort();
+        let error = read.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+    }
+    #[tokio::test]
+    async fn test_capacity_applies_backpressure() {
+        use tokio::io::AsyncReadExt;
+        let recorder = Arc::new(Recorder::new().with_capacity(64));
+        let mut reader = RecorderReader::new(recorder.clone());
+        let payload: Vec<u8> = (0..8192).map(|i| (i * 7) as u8).collect();
+        let expected = payload.clone();
+        let writer = recorder.clone();
+        let write = tokio::spawn(async move {
+            for chunk in payload.chunks(16) {
+                record(&writer, chunk).await.unwrap();
+            }
+            writer.close();
+        });
+        // A slow reader: small reads with pauses, never more than the
+        // capacity and a chunk waiting for it.
+        let mut received = vec![];
+        let mut buf = [0; 10];
+        for reads in 0.. {
+            if reads % 64 == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            tokio::task::yield_now().await;
+            assert!(recorder.lock().unread() <= 64 + 16);
+            let n = reader.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+        write.await.unwrap();
+        assert_eq!(received, expected);
+        assert!(recorder.lock().len <= 64 * 2);
+    }
+    #[tokio::test]
+    async fn test_capacity_alternating_reader_and_writer() {
+        use tokio::io::AsyncReadExt;
+        let recorder = Arc::new(Recorder::new().with_capacity(64));
+        let mut reader = RecorderReader::new(recorder.clone());
+        let payload: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        // Both on one task: each side only makes progress when the other
+        // lets it, so a lost wake-up would hang here.
+        let write = async {
+            for chunk in payload.chunks(100) {
+                record(&recorder, chunk).await.unwrap();
+            }
+            recorder.close();
+        };
+        let read = async {
+            let mut received = vec![];
+            reader.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let (_, received) =
+            tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(write, read) })
+                .await
+                .unwrap();
+        assert_eq!(received, payload);
+    }
+    #[tokio::test]
+    async fn test_every_blocked_writer_is_woken() {
+        use tokio::io::AsyncReadExt;
+        let recorder = Arc::new(Recorder::new().with_capacity(4));
+        let mut reader = RecorderReader::new(recorder.clone());
+        // Two writer tasks fill the capacity between them and both wait for
+        // room; one whose wake-up is lost never finishes.
+        let writers = [1, 2].map(|byte| {
+            let writer = recorder.clone();
+            tokio::spawn(async move {
+                for _ in 0..1000 {
+                    record(&writer, &[byte; 3]).await.unwrap();
+                }
+            })
+        });
+        let mut received = vec![0; 6000];
+        tokio::time::timeout(Duration::from_secs(5), reader.read_exact(&mut received))
+            .await
+            .expect("a writer was never woken")
+            .unwrap();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        assert_eq!(received.iter().filter(|&&b| b == 1).count(), 3000);
+        assert_eq!(received.iter().filter(|&&b| b == 2).count(), 3000);
+    }
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_small_writes_and_reads_across_threads() {
+        use tokio::io::AsyncReadExt;
+        const TOTAL: usize = 64 * 1024;
+        for capacity in [1, 7, 4096] {
+            let recorder = Arc::new(Recorder::new().with_capacity(capacity));
+            let mut reader = RecorderReader::new(recorder.clone());
+            let writer = recorder.clone();
+            let write = tokio::spawn(async move {
+                for i in 0..TOTAL / 16 {
+                    record(&writer, &[i as u8; 16]).await.unwrap();
+                }
+                writer.close();
+            });
+            let read = tokio::spawn(async move {
+                let mut received = vec![];
+                let mut buf = [0; 5];
+                loop {
+                    let n = reader.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        return received;
+                    }
+                    received.extend_from_slice(&buf[..n]);
+                }
+            });
+            let received = tokio::time::timeout(Duration::from_secs(20), read)
+                .await
+                .expect("reader hung")
+                .unwrap();
+            write.await.unwrap();
+            assert_eq!(received.len(), TOTAL);
+            assert!(
+                received
+                    .chunks(16)
+                    .enumerate()
+                    .all(|(i, c)| c == [i as u8; 16])
+            );
+        }
     }
+    #[tokio::test]
+    async fn test_lagging_auxiliary_reader_is_evicted() {
+        use tokio::io::AsyncReadExt;
+        let recorder = Arc::new(Recorder::new());
+        let mut forwarding = RecorderReader::new(recorder.clone());
+        let max_lag = MaxLag {
+            bytes: Some(DRAIN_INTERVAL),
+            duration: None,
+        };
+        let mut tail = RecorderReader::auxiliary(recorder.clone(), max_lag);
+        let payload: Vec<u8> = (0..DRAIN_INTERVAL * 8).map(|i| i as u8).collect();
+        let mut received = vec![0; payload.len()];
+        let mut read = 0;
+        for chunk in payload.chunks(4096) {
+            recorder.append(chunk);
+            forwarding
+                .read_exact(&mut received[read..read + chunk.len()])
+                .await
+                .unwrap();
+            read += chunk.len();
+            // The abandoned tail does not keep the stream alive.
+            assert!(recorder.lock().len <= DRAIN_INTERVAL * 2);
+        }
+        assert_eq!(received, payload);
+        assert_eq!(recorder.evictions(), 1);
+        let error = tail.read(&mut [0; 10]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+        assert!(error.to_string().starts_with("evicted: lagged by "));
 
-    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        Poll::Ready(Ok(()))
+        let recorder = Arc::new(Recorder::new());
+        let _forwarding = RecorderReader::new(recorder.clone());
+        let max_lag = MaxLag {
+            bytes: None,
+            duration: Some(Duration::ZERO),
+        };
+        let mut tail = RecorderReader::auxiliary(recorder.clone(), max_lag);
+        recorder.append(&[1]);
+        std::thread::sleep(Duration::from_millis(1));
+        recorder.append(&[2]);
+        assert_eq!(recorder.evictions(), 1);
+        assert!(tail.read(&mut [0; 10]).await.is_err());
+    }
+    #[tokio::test]
+    async fn test_close_ends_the_stream() {
+        use tokio::io::AsyncReadExt;
+        let recorder = Arc::new(Recorder::new());
+        let mut reader = RecorderReader::new(recorder.clone());
+        let read = tokio::spawn(async move {
+            let mut received = vec![];
+            reader.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        for chunk in [&b"one "[..], b"two ", b"three"] {
+            record(&recorder, chunk).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        recorder.close();
+        assert_eq!(read.await.unwrap(), b"one two three");
+    }
+    #[tokio::test]
+    async fn test_sink_gets_the_stream_even_when_aborted() {
+        use tokio::io::AsyncReadExt;
+        for abort in [false, true] {
+            let recorder = Arc::new(Recorder::new());
+            let (sink_end, mut captured) = tokio::io::duplex(1024);
+            let sink = recorder.attach_sink(sink_end);
+            recorder.append(b"recorded ");
+            recorder.append(b"bytes");
+            if abort {
+                recorder.abort();
+                let error = sink.await.unwrap().unwrap_err();
+                assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+            } else {
+                recorder.close();
+                assert_eq!(sink.await.unwrap().unwrap(), 14);
+            }
+            let mut contents = vec![];
+            captured.read_to_end(&mut contents).await.unwrap();
+            assert_eq!(contents, b"recorded bytes");
+        }
+    }
+    #[tokio::test]
+    async fn test_slow_sink_holds_back_the_writer() {
+        use tokio::io::AsyncReadExt;
+        const CAPACITY: usize = 4096;
+        const CHUNK: usize = 1000;
+        let recorder = Arc::new(Recorder::new().with_capacity(CAPACITY));
+        // A disk slower than the stream: a small pipe drained a little at a
+        // time, with pauses.
+        let (sink_end, mut disk) = tokio::io::duplex(256);
+        let sink = recorder.attach_sink(sink_end);
+        let written = tokio::spawn(async move {
+            let mut written = vec![];
+            let mut buf = [0; 100];
+            for reads in 0.. {
+                if reads % 64 == 0 {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+                let n = disk.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    return written;
+                }
+                written.extend_from_slice(&buf[..n]);
+            }
+            unreachable!()
+        });
+        let payload: Vec<u8> = (0..DRAIN_INTERVAL * 4).map(|i| (i % 249) as u8).collect();
+        for chunk in payload.chunks(CHUNK) {
+            recorder.reserve().await.unwrap();
+            recorder.append(chunk);
+            assert!(recorder.lock().len <= CAPACITY + CHUNK);
+        }
+        recorder.close();
+        assert_eq!(sink.await.unwrap().unwrap(), payload.len() as u64);
+        assert_eq!(written.await.unwrap(), payload);
+        // A tap further behind than the capacity is held to its own lag.
+        let recorder = Arc::new(Recorder::new().with_capacity(16));
+        let max_lag = MaxLag {
+            bytes: Some(1024),
+            duration: None,
+        };
+        let _tap = RecorderReader::auxiliary(recorder.clone(), max_lag);
+        recorder.append(&[0; 64]);
+        tokio::time::timeout(Duration::from_secs(1), recorder.reserve())
+            .await
+            .expect("held back by a tap")
+            .unwrap();
+    }
+    #[tokio::test]
+    async fn test_fill_buf_across_segments_and_eof() {
+        use tokio::io::AsyncBufReadExt;
+        let recorder = Arc::new(Recorder::new());
+        let mut reader = RecorderReader::new(recorder.clone());
+        recorder.append(&[1, 2, 3]);
+        recorder.append(&[4, 5]);
+        // One segment at a time, resuming mid-segment after a partial consume.
+        assert_eq!(reader.fill_buf().await.unwrap(), &[1, 2, 3]);
+        reader.consume(2);
+        assert_eq!(reader.fill_buf().await.unwrap(), &[3]);
+        reader.consume(1);
+        assert_eq!(reader.fill_buf().await.unwrap(), &[4, 5]);
+        // poll_read picks up what fill_buf buffered.
+        let mut buf = [0; 1];
+        tokio::io::AsyncReadExt::read_exact(&mut reader, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, [4]);
+        assert_eq!(reader.fill_buf().await.unwrap(), &[5]);
+        reader.consume(1);
+        recorder.close();
+        assert!(reader.fill_buf().await.unwrap().is_empty());
+        // Appends and closes after the reader is waiting wake it up.
+        let recorder = Arc::new(Recorder::new());
+        let mut reader = RecorderReader::new(recorder.clone());
+        let copy = tokio::spawn(async move {
+            let mut copied = vec![];
+            tokio::io::copy_buf(&mut reader, &mut copied).await.unwrap();
+            copied
+        });
+        tokio::task::yield_now().await;
+        let payload: Vec<u8> = (0..DRAIN_INTERVAL * 2).map(|i| i as u8).collect();
+        for chunk in payload.chunks(1000) {
+            recorder.append(chunk);
+        }
+        recorder.close();
+        assert_eq!(copy.await.unwrap(), payload);
     }
 }
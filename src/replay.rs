@@ -0,0 +1,252 @@
+// `--replay`: tunnels are served from the captures in a `--record-dir`
+// instead of the target. A CONNECT to a recorded host:port gets the
+// server-to-client bytes of one of its captures, chunk by chunk, and what the
+// client sends is discarded. Several captures of one destination are played
+// in the order they were recorded, starting over after the last.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::capture;
+use crate::log;
+
+struct Session {
+    s2c: PathBuf,
+    // When each server-to-client chunk arrived and how long it was.
+    chunks: Vec<(Duration, usize)>,
+}
+
+pub struct Library {
+    // By lowercased host and port, in recording order.
+    sessions: HashMap<(String, u16), Vec<Session>>,
+    // The next session to play, per destination.
+    next: Mutex<HashMap<(String, u16), usize>>,
+    // Reproduce the recorded pacing rather than sending at once.
+    realtime: bool,
+}
+
+impl Library {
+    /// Reads the capture indexes in `dir`. An index that cannot be read is
+    /// skipped with a warning, so a capture cut short does not stop replay.
+    pub fn load(dir: &Path, realtime: bool) -> io::Result<Self> {
+        let read_dir = std::fs::read_dir(dir).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("cannot read replay directory {}: {e}", dir.display()),
+            )
+        })?;
+        let mut paths = vec![];
+        for entry in read_dir {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|ext| ext == capture::INDEX_EXTENSION)
+            {
+                paths.push(path);
+            }
+        }
+        // File names start with the capture's sequence number.
+        paths.sort();
+        let mut sessions: HashMap<_, Vec<Session>> = HashMap::new();
+        for path in paths {
+            let recorded =
+                match std::fs::read_to_string(&path).and_then(|text| capture::parse_index(&text)) {
+                    Ok(recorded) => recorded,
+                    Err(e) => {
+                        log::warn!("Skipping capture index {}: {e}", path.display());
+                        continue;
+                    }
+                };
+            let chunks = recorded.chunks.iter().filter(|chunk| !chunk.up);
+            let session = Session {
+                s2c: capture::s2c_path(&path),
+                chunks: chunks.map(|chunk| (chunk.at, chunk.len)).collect(),
+            };
+            let key = (recorded.host.to_ascii_lowercase(), recorded.port);
+            sessions.entry(key).or_default().push(session);
+        }
+        log::info!(
+            "Replaying {} captures of {} destinations from {}",
+            sessions.values().map(Vec::len).sum::<usize>(),
+            sessions.len(),
+            dir.display()
+        );
+        Ok(Self {
+            sessions,
+            next: Mutex::new(HashMap::new()),
+            realtime,
+        })
+    }
+
+    /// The next recording of `host:port`, or None without one.
+    pub async fn open(&self, host: &str, port: u16) -> io::Result<Option<ReplayStream>> {
+        let key = (host.to_ascii_lowercase(), port);
+        let Some(sessions) = self.sessions.get(&key) else {
+            return Ok(None);
+        };
+        let session = {
+            let mut next = self.next.lock().unwrap();
+            let next = next.entry(key).or_default();
+            let session = &sessions[*next % sessions.len()];
+            *next += 1;
+            session
+        };
+        let data = tokio::fs::read(&session.s2c).await.map_err(|e| {
+            let path = session.s2c.display();
+            io::Error::new(e.kind(), format!("cannot read capture {path}: {e}"))
+        })?;
+        Ok(Some(ReplayStream::new(
+            data,
+            &session.chunks,
+            self.realtime,
+        )))
+    }
+}
+
+/// Stands in for the target: reads give the recorded bytes and then EOF,
+/// writes are accepted and dropped.
+pub struct ReplayStream {
+    data: Vec<u8>,
+    // The start of each chunk in `data`, with when to send it. The index
+    // and the data file only disagree when the capture was cut short.
+    chunks: Vec<(Duration, usize)>,
+    next: usize,
+    position: usize,
+    // None sends each chunk as soon as it is read.
+    started: Option<Instant>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl ReplayStream {
+    fn new(data: Vec<u8>, recorded: &[(Duration, usize)], realtime: bool) -> Self {
+        let mut chunks = vec![];
+        let mut start = 0;
+        for &(at, len) in recorded {
+            if start >= data.len() {
+                break;
+            }
+            chunks.push((at, start));
+            start += len;
+        }
+        // Bytes the index does not cover go out after the last chunk.
+        if start < data.len() {
+            let at = recorded.last().map_or(Duration::ZERO, |&(at, _)| at);
+            chunks.push((at, start));
+        }
+        Self {
+            data,
+            chunks,
+            next: 0,
+            position: 0,
+            started: realtime.then(Instant::now),
+            sleep: None,
+        }
+    }
+
+    fn chunk_end(&self) -> usize {
+        match self.chunks.get(self.next) {
+            Some(&(_, start)) => start,
+            None => self.data.len(),
+        }
+    }
+}
+
+impl AsyncRead for ReplayStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.position == this.chunk_end() {
+            let Some(&(at, _)) = this.chunks.get(this.next) else {
+                return Poll::Ready(Ok(()));
+            };
+            if let Some(started) = this.started {
+                let timer = this
+                    .sleep
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(started + at)));
+                ready!(timer.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            this.next += 1;
+        }
+        let end = this.chunk_end();
+        let n = (end - this.position).min(buf.remaining());
+        buf.put_slice(&this.data[this.position..this.position + n]);
+        this.position += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ReplayStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    async fn chunks(mut stream: ReplayStream) -> Vec<Vec<u8>> {
+        let mut chunks = vec![];
+        loop {
+            let mut buf = vec![0; 64];
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                return chunks;
+            }
+            chunks.push(buf[..n].to_vec());
+        }
+    }
+    #[tokio::test]
+    async fn test_replay_stream_keeps_chunks() {
+        let recorded = [
+            (Duration::ZERO, 3),
+            (Duration::from_millis(1), 2),
+            (Duration::from_millis(2), 4),
+        ];
+        let mut stream = ReplayStream::new(b"abcdefghijkl".to_vec(), &recorded, false);
+        stream.write_all(b"discarded").await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert_eq!(chunks(stream).await, [&b"abc"[..], b"de", b"fghi", b"jkl"]);
+        // A capture cut short ends where its data does.
+        let stream = ReplayStream::new(b"abcd".to_vec(), &recorded, false);
+        assert_eq!(chunks(stream).await, [&b"abc"[..], b"d"]);
+        let stream = ReplayStream::new(vec![], &recorded, false);
+        assert!(chunks(stream).await.is_empty());
+    }
+    #[tokio::test]
+    async fn test_realtime_replay_keeps_pacing() {
+        let recorded = [(Duration::ZERO, 1), (Duration::from_millis(200), 1)];
+        let start = std::time::Instant::now();
+        let stream = ReplayStream::new(b"ab".to_vec(), &recorded, true);
+        assert_eq!(chunks(stream).await, [b"a", b"b"]);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        let start = std::time::Instant::now();
+        let stream = ReplayStream::new(b"ab".to_vec(), &recorded, false);
+        assert_eq!(chunks(stream).await, [b"a", b"b"]);
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const HEADER: &str = "x-request-id";
+const MAX_LEN: usize = 128;
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+static KEYS: OnceLock<RandomState> = OnceLock::new();
+
+// SipHash keyed with the process-random RandomState keys, over a counter that
+// never repeats: a cheap keyed PRF, so ids cannot collide within a process
+// and are unpredictable across processes.
+fn random_u64(counter: u64, lane: u64) -> u64 {
+    let mut hasher = KEYS.get_or_init(RandomState::new).build_hasher();
+    hasher.write_u64(counter);
+    hasher.write_u64(lane);
+    hasher.finish()
+}
+
+/// Generates a random (version 4) UUID.
+pub fn generate() -> String {
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let value = (random_u64(counter, 0) as u128) << 64 | random_u64(counter, 1) as u128;
+    let value = value & !(0xf << 76) | 0x4 << 76;
+    let value = value & !(0x3 << 62) | 0x2 << 62;
+    let hex = format!("{value:032x}");
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+// Client-provided ids end up in log lines, so only accept short, printable,
+// whitespace-free values.
+fn is_acceptable(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Picks the request id for a connection: the client's one when it is trusted
+/// and well-formed, a freshly generated one otherwise.
+pub fn resolve(client_id: Option<&str>, trust_client: bool) -> String {
+    match client_id {
+        Some(id) if trust_client && is_acceptable(id) => id.to_string(),
+        _ => generate(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_generate_is_uuid_v4() {
+        let id = generate();
+        assert_eq!(id.len(), 36);
+        let groups: Vec<&str> = id.split('-').collect();
+        assert_eq!(
+            groups.iter().map(|g| g.len()).collect::<Vec<_>>(),
+            vec![8, 4, 4, 4, 12]
+        );
+        assert!(groups[2].starts_with('4'));
+        assert!(matches!(groups[3].as_bytes()[0], b'8' | b'9' | b'a' | b'b'));
+    }
+    #[test]
+    fn test_generate_is_unique() {
+        let ids: std::collections::HashSet<String> = (0..10_000).map(|_| generate()).collect();
+        assert_eq!(ids.len(), 10_000);
+    }
+    #[test]
+    fn test_resolve_trusted_client_id() {
+        assert_eq!(resolve(Some("abc-123"), true), "abc-123");
+    }
+    #[test]
+    fn test_resolve_replaces_untrusted_or_invalid_id() {
+        assert_ne!(resolve(Some("abc-123"), false), "abc-123");
+        assert_ne!(resolve(Some("two words"), true), "two words");
+        assert_eq!(resolve(Some(&"x".repeat(MAX_LEN + 1)), true).len(), 36);
+        assert_eq!(resolve(None, true).len(), 36);
+    }
+}
@@ -0,0 +1,212 @@
+// The HTTP/1.1 request line (RFC 9112 section 3): method SP target SP
+// version, with exactly one space between the tokens unless lenient.
+
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RequestLine<'a> {
+    pub method: &'a str,
+    pub target: &'a str,
+    pub version: &'a str,
+}
+
+// What was wrong with a request line. The descriptions are sent back to the
+// client, so they never include any of the line itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Malformed {
+    Empty,
+    LeadingWhitespace,
+    TrailingWhitespace,
+    OtherWhitespace,
+    SpacesAfterMethod,
+    SpacesAfterTarget,
+    InvalidMethod,
+    MissingTarget,
+    MissingVersion,
+    InvalidVersion,
+    TooManyFields,
+    // An authority with `user@` before the host, which RFC 9110 section
+    // 4.2.4 has recipients treat as an error.
+    Userinfo,
+}
+
+impl fmt::Display for Malformed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Malformed::Empty => "empty request line",
+            Malformed::LeadingWhitespace => "leading whitespace before method",
+            Malformed::TrailingWhitespace => "trailing whitespace after HTTP version",
+            Malformed::OtherWhitespace => "whitespace other than a single space between tokens",
+            Malformed::SpacesAfterMethod => "multiple spaces between method and target",
+            Malformed::SpacesAfterTarget => "multiple spaces between target and HTTP version",
+            Malformed::InvalidMethod => "invalid character in method",
+            Malformed::MissingTarget => "missing request target",
+            Malformed::MissingVersion => "missing HTTP version",
+            Malformed::InvalidVersion => "malformed HTTP version",
+            Malformed::TooManyFields => "too many fields in request line",
+            Malformed::Userinfo => "userinfo in request target",
+        })
+    }
+}
+
+// The whitespace RFC 9112 lets a lenient parser treat as a separator.
+fn is_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\x0b' | '\x0c' | '\r')
+}
+
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn is_version(version: &str) -> bool {
+    match version.strip_prefix("HTTP/").map(str::as_bytes) {
+        Some([major, b'.', minor]) => major.is_ascii_digit() && minor.is_ascii_digit(),
+        _ => false,
+    }
+}
+
+// The authority of an authority-form (CONNECT) or absolute-form target.
+fn authority<'a>(method: &str, target: &'a str) -> Option<&'a str> {
+    if method == "CONNECT" {
+        return Some(target);
+    }
+    let (_, rest) = target.split_once("://")?;
+    Some(&rest[..rest.find(['/', '?', '#']).unwrap_or(rest.len())])
+}
+
+/// Lenient parsing accepts any run of whitespace between the tokens; leading
+/// and trailing whitespace are rejected either way.
+pub fn parse(line: &str, lenient: bool) -> Result<RequestLine<'_>, Malformed> {
+    let (first, last) = match (line.chars().next(), line.chars().next_back()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Err(Malformed::Empty),
+    };
+    if is_whitespace(first) {
+        return Err(Malformed::LeadingWhitespace);
+    }
+    if is_whitespace(last) {
+        return Err(Malformed::TrailingWhitespace);
+    }
+    let mut fields = vec![];
+    let mut rest = line;
+    loop {
+        let end = rest.find(is_whitespace).unwrap_or(rest.len());
+        fields.push(&rest[..end]);
+        let after = &rest[end..];
+        let separator = &after[..after.find(|c| !is_whitespace(c)).unwrap_or(after.len())];
+        if separator.is_empty() {
+            break;
+        }
+        if fields.len() == 3 {
+            return Err(Malformed::TooManyFields);
+        }
+        if !lenient && separator != " " {
+            return Err(match (separator.chars().all(|c| c == ' '), fields.len()) {
+                (false, _) => Malformed::OtherWhitespace,
+                (true, 1) => Malformed::SpacesAfterMethod,
+                (true, _) => Malformed::SpacesAfterTarget,
+            });
+        }
+        rest = &after[separator.len()..];
+    }
+    let (method, target, version) = match fields[..] {
+        [method, target, version] => (method, target, version),
+        [_, _] => return Err(Malformed::MissingVersion),
+        _ => return Err(Malformed::MissingTarget),
+    };
+    if !method.bytes().all(is_tchar) {
+        return Err(Malformed::InvalidMethod);
+    }
+    if !is_version(version) {
+        return Err(Malformed::InvalidVersion);
+    }
+    if authority(method, target).is_some_and(|authority| authority.contains('@')) {
+        return Err(Malformed::Userinfo);
+    }
+    Ok(RequestLine {
+        method,
+        target,
+        version,
+    })
+}
+
+/// The line with everything but printable ASCII hex-escaped, safe to log.
+pub fn escape(line: &str) -> String {
+    let mut escaped = String::with_capacity(line.len());
+    for b in line.bytes() {
+        if (b.is_ascii_graphic() || b == b' ') && b != b'\\' {
+            escaped.push(b as char);
+        } else {
+            escaped.push_str(&format!("\\x{b:02x}"));
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_valid_lines() {
+        let expected = RequestLine {
+            method: "CONNECT",
+            target: "example.com:443",
+            version: "HTTP/1.1",
+        };
+        assert_eq!(
+            parse("CONNECT example.com:443 HTTP/1.1", false),
+            Ok(expected)
+        );
+        assert!(parse("GET / HTTP/1.0", false).is_ok());
+        assert!(parse("CONNECT example.com:443 HTTP/1.0", false).is_ok());
+        assert!(parse("CONNECT  example.com:443\tHTTP/1.1", true).is_ok());
+        assert!(parse("CONNECT   example.com:443 \t HTTP/1.1", true).is_ok());
+        // Versions the proxy does not speak are well-formed all the same.
+        assert!(parse("CONNECT example.com:443 HTTP/2.0", false).is_ok());
+        assert!(parse("GET http://example.com/@home HTTP/1.1", false).is_ok());
+    }
+    #[test]
+    fn test_malformed_lines() {
+        let cases = [
+            ("", Malformed::Empty),
+            (" CONNECT h:443 HTTP/1.1", Malformed::LeadingWhitespace),
+            ("\tCONNECT h:443 HTTP/1.1", Malformed::LeadingWhitespace),
+            ("CONNECT h:443 HTTP/1.1 ", Malformed::TrailingWhitespace),
+            ("CONNECT\th:443 HTTP/1.1", Malformed::OtherWhitespace),
+            ("CONNECT h:443\x0bHTTP/1.1", Malformed::OtherWhitespace),
+            ("CONNECT  h:443 HTTP/1.1", Malformed::SpacesAfterMethod),
+            ("CONNECT h:443  HTTP/1.1", Malformed::SpacesAfterTarget),
+            ("CON(NECT h:443 HTTP/1.1", Malformed::InvalidMethod),
+            ("CONNECT", Malformed::MissingTarget),
+            ("CONNECT h:443", Malformed::MissingVersion),
+            ("CONNECT h:443 HTTP/1", Malformed::InvalidVersion),
+            ("CONNECT h:443 http/1.1", Malformed::InvalidVersion),
+            ("CONNECT h:443 HTTP/11.1", Malformed::InvalidVersion),
+            ("CONNECT h:443 HTTP/1.1 x", Malformed::TooManyFields),
+            ("CONNECT  HTTP/1.1", Malformed::SpacesAfterMethod),
+            ("CONNECT user@h:443 HTTP/1.1", Malformed::Userinfo),
+            ("GET http://user:pw@h/ HTTP/1.1", Malformed::Userinfo),
+        ];
+        for (line, malformed) in cases {
+            assert_eq!(parse(line, false), Err(malformed), "{line:?}");
+        }
+        // Lenient parsing only relaxes the separators.
+        assert_eq!(
+            parse(" CONNECT h:443 HTTP/1.1", true),
+            Err(Malformed::LeadingWhitespace)
+        );
+        assert_eq!(
+            parse("CONNECT  h:443", true),
+            Err(Malformed::MissingVersion)
+        );
+        // An empty target leaves two fields.
+        assert_eq!(
+            parse("CONNECT  HTTP/1.1", true),
+            Err(Malformed::MissingVersion)
+        );
+    }
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("GET /\x1b[2J é\\"), "GET /\\x1b[2J \\xc3\\xa9\\x5c");
+    }
+}
@@ -0,0 +1,651 @@
+// Resolving targets ahead of connecting to them. Answers are cached for a
+// TTL, ordered by the preferred address family, and tried happy-eyeballs
+// style (after RFC 8305): the first address gets a head start, and if it has
+// not connected within FALLBACK_DELAY the other family races it.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpSocket, TcpStream};
+
+pub const FALLBACK_DELAY: Duration = Duration::from_millis(250);
+// Hosts kept in the cache; the least recently used one makes room.
+pub const CACHE_CAPACITY: usize = 1024;
+
+pub type LookupFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send + 'a>>;
+
+/// Where host names are looked up.
+pub trait Lookup: Send + Sync {
+    fn lookup<'a>(&'a self, host: &'a str) -> LookupFuture<'a>;
+}
+
+/// The system resolver, by way of tokio's `lookup_host`.
+pub struct SystemLookup;
+
+impl Lookup for SystemLookup {
+    fn lookup<'a>(&'a self, host: &'a str) -> LookupFuture<'a> {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host, 0)).await?;
+            Ok(addrs.map(|addr| addr.ip()).collect())
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Preference {
+    // In the resolver's order, alternating families.
+    #[default]
+    None,
+    Ipv4,
+    Ipv6,
+}
+
+struct Cached {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Cache {
+    hosts: HashMap<String, Cached>,
+    // Counts uses, to tell the least recently used host.
+    clock: u64,
+}
+
+pub struct Resolver {
+    lookup: Arc<dyn Lookup>,
+    ttl: Duration,
+    capacity: usize,
+    preference: Preference,
+    cache: Mutex<Cache>,
+}
+
+impl Resolver {
+    /// A zero `ttl` turns caching off.
+    pub fn new(
+        lookup: Arc<dyn Lookup>,
+        ttl: Duration,
+        capacity: usize,
+        preference: Preference,
+    ) -> Self {
+        Self {
+            lookup,
+            ttl,
+            capacity,
+            preference,
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+
+    /// The addresses to try for `host`, in order. IP literals are not looked
+    /// up, and a lookup without answers is an error.
+    pub async fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        let host = host.to_ascii_lowercase();
+        if let Some(addrs) = self.cached(&host) {
+            return Ok(addrs);
+        }
+        let addrs = order(self.lookup.lookup(&host).await?, self.preference);
+        if addrs.is_empty() {
+            return Err(io::Error::new(ErrorKind::NotFound, "no addresses resolved"));
+        }
+        self.store(host, &addrs);
+        Ok(addrs)
+    }
+
+    fn cached(&self, host: &str) -> Option<Vec<IpAddr>> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.clock += 1;
+        let clock = cache.clock;
+        let cached = cache.hosts.get_mut(host)?;
+        if cached.expires <= Instant::now() {
+            cache.hosts.remove(host);
+            return None;
+        }
+        cached.last_used = clock;
+        Some(cached.addrs.clone())
+    }
+
+    fn store(&self, host: String, addrs: &[IpAddr]) {
+        if self.ttl.is_zero() || self.capacity == 0 {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        if cache.hosts.len() >= self.capacity && !cache.hosts.contains_key(&host) {
+            let now = Instant::now();
+            cache.hosts.retain(|_, cached| cached.expires > now);
+            if cache.hosts.len() >= self.capacity {
+                let oldest = cache
+                    .hosts
+                    .iter()
+                    .min_by_key(|(_, cached)| cached.last_used)
+                    .map(|(host, _)| host.clone());
+                cache.hosts.remove(&oldest.unwrap());
+            }
+        }
+        cache.clock += 1;
+        let cached = Cached {
+            addrs: addrs.to_vec(),
+            expires: Instant::now() + self.ttl,
+            last_used: cache.clock,
+        };
+        cache.hosts.insert(host, cached);
+    }
+}
+
+// The preferred family first, or without a preference the families
+// interleaved, starting with whichever the resolver listed first.
+fn order(addrs: Vec<IpAddr>, preference: Preference) -> Vec<IpAddr> {
+    let (v4, v6): (Vec<IpAddr>, Vec<IpAddr>) = addrs.iter().partition(|ip| ip.is_ipv4());
+    let (first, second) = match preference {
+        Preference::Ipv4 => (v4, v6),
+        Preference::Ipv6 => (v6, v4),
+        Preference::None if addrs.first().is_some_and(IpAddr::is_ipv6) => (v6, v4),
+        Preference::None => (v4, v6),
+    };
+    if preference != Preference::None {
+        return first.into_iter().chain(second).collect();
+    }
+    let mut ordered = Vec::with_capacity(addrs.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Trying the addresses again after attempts were refused or timed out: up
+/// to `retries` more rounds, the first `delay` after the last one and each
+/// following round twice as long after the one before.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retry {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+/// Where outgoing connections come from: a local address for each family,
+/// and on Linux an interface. A family without an address is left to the
+/// system to pick one for.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Source {
+    pub v4: Option<Ipv4Addr>,
+    pub v6: Option<Ipv6Addr>,
+    pub interface: Option<String>,
+}
+
+impl Source {
+    fn local_addr(&self, target: SocketAddr) -> Option<IpAddr> {
+        match target {
+            SocketAddr::V4(_) => self.v4.map(IpAddr::V4),
+            SocketAddr::V6(_) => self.v6.map(IpAddr::V6),
+        }
+    }
+
+    /// The configured addresses that cannot be bound to, as they are not
+    /// assigned to this host.
+    pub fn unassigned(&self) -> Vec<IpAddr> {
+        let v4 = self.v4.map(IpAddr::V4);
+        let v6 = self.v6.map(IpAddr::V6);
+        [v4, v6]
+            .into_iter()
+            .flatten()
+            .filter(|&ip| std::net::UdpSocket::bind(SocketAddr::new(ip, 0)).is_err())
+            .collect()
+    }
+}
+
+// A connection to `addr` from `source`, bound before it connects. Binding
+// fails when the address is not this host's.
+async fn connect_from(addr: SocketAddr, source: &Source) -> io::Result<TcpStream> {
+    let local = source.local_addr(addr);
+    if local.is_none() && source.interface.is_none() {
+        return TcpStream::connect(addr).await;
+    }
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(interface) = &source.interface {
+        socket
+            .bind_device(Some(interface.as_bytes()))
+            .map_err(|e| io::Error::new(e.kind(), format!("binding to {interface}: {e}")))?;
+    }
+    if let Some(local) = local {
+        socket
+            .bind(&SocketAddr::new(local, 0).into())
+            .map_err(|e| io::Error::new(e.kind(), format!("binding to {local}: {e}")))?;
+    }
+    socket.set_nonblocking(true)?;
+    TcpSocket::from_std_stream(socket.into())
+        .connect(addr)
+        .await
+}
+
+// An address that did not connect.
+struct Failure {
+    addr: SocketAddr,
+    error: io::Error,
+}
+
+// How much an error tells the client: a refusal shows the host is up, a
+// timeout that it may be, anything else (unreachable, say) hardly anything.
+fn interest(kind: ErrorKind) -> u8 {
+    match kind {
+        ErrorKind::ConnectionRefused => 3,
+        ErrorKind::PermissionDenied => 2,
+        ErrorKind::TimedOut => 1,
+        _ => 0,
+    }
+}
+
+fn retryable(kind: ErrorKind) -> bool {
+    matches!(kind, ErrorKind::ConnectionRefused | ErrorKind::TimedOut)
+}
+
+// The most telling failure, with every attempt listed.
+fn summarize(failures: &[Failure]) -> io::Error {
+    let Some(best) = failures
+        .iter()
+        .rev()
+        .max_by_key(|f| interest(f.error.kind()))
+    else {
+        return io::Error::new(ErrorKind::NotFound, "no addresses to connect to");
+    };
+    if failures.len() == 1 {
+        return io::Error::new(best.error.kind(), best.error.to_string());
+    }
+    let attempts: Vec<String> = failures
+        .iter()
+        .map(|f| format!("{}: {}", f.addr, f.error))
+        .collect();
+    io::Error::new(
+        best.error.kind(),
+        format!("{} (attempts: {})", best.error, attempts.join(", ")),
+    )
+}
+
+// Each address in turn. Every attempt gets an even share of the time left
+// until `deadline`, so one that hangs cannot leave the rest untried.
+async fn connect_each(
+    addrs: &[SocketAddr],
+    deadline: Instant,
+    source: &Source,
+) -> Result<TcpStream, Vec<Failure>> {
+    let mut failures = vec![];
+    for (i, &addr) in addrs.iter().enumerate() {
+        let share = deadline.saturating_duration_since(Instant::now()) / (addrs.len() - i) as u32;
+        let error = match tokio::time::timeout(share, connect_from(addr, source)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => e,
+            Err(_) => io::Error::new(ErrorKind::TimedOut, format!("no answer within {share:?}")),
+        };
+        failures.push(Failure { addr, error });
+    }
+    Err(failures)
+}
+
+// One round over the addresses: the family of `addrs[0]` is tried first, and
+// the other one joins in after `fallback_delay`, or at once when the first
+// family fails sooner.
+async fn connect_round(
+    addrs: &[SocketAddr],
+    fallback_delay: Duration,
+    deadline: Instant,
+    source: &Source,
+) -> Result<TcpStream, Vec<Failure>> {
+    let Some(first) = addrs.first() else {
+        return Err(vec![]);
+    };
+    let (primary, secondary): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .iter()
+        .partition(|addr| addr.is_ipv4() == first.is_ipv4());
+    if secondary.is_empty() {
+        return connect_each(&primary, deadline, source).await;
+    }
+    let both_failed = |mut first: Vec<Failure>, second: Vec<Failure>| {
+        first.extend(second);
+        first
+    };
+    let primary_attempt = connect_each(&primary, deadline, source);
+    tokio::pin!(primary_attempt);
+    tokio::select! {
+        result = &mut primary_attempt => {
+            return match result {
+                Ok(stream) => Ok(stream),
+                Err(failures) => connect_each(&secondary, deadline, source)
+                    .await
+                    .map_err(|more| both_failed(failures, more)),
+            };
+        }
+        _ = tokio::time::sleep(fallback_delay) => {}
+    }
+    let secondary_attempt = connect_each(&secondary, deadline, source);
+    tokio::pin!(secondary_attempt);
+    tokio::select! {
+        result = &mut primary_attempt => match result {
+            Ok(stream) => Ok(stream),
+            Err(failures) => secondary_attempt
+                .await
+                .map_err(|more| both_failed(failures, more)),
+        },
+        result = &mut secondary_attempt => match result {
+            Ok(stream) => Ok(stream),
+            Err(more) => primary_attempt
+                .await
+                .map_err(|failures| both_failed(failures, more)),
+        },
+    }
+}
+
+/// Connects to the first address that answers, happy-eyeballs style, by
+/// `deadline`. Rounds that were refused or timed out are retried as `retry`
+/// says, as long as the deadline leaves room. The error is the most telling
+/// of the attempts', and lists all of them.
+pub async fn connect(
+    addrs: &[SocketAddr],
+    fallback_delay: Duration,
+    deadline: Instant,
+    retry: Retry,
+    source: &Source,
+) -> io::Result<TcpStream> {
+    let mut failures = vec![];
+    let mut delay = retry.delay;
+    for round in 0..=retry.retries {
+        if round > 0 {
+            if Instant::now() + delay >= deadline {
+                break;
+            }
+            tokio::time::sleep(delay).await;
+            delay = delay.saturating_mul(2);
+        }
+        match connect_round(addrs, fallback_delay, deadline, source).await {
+            Ok(stream) => return Ok(stream),
+            Err(round_failures) => {
+                let again = round_failures.iter().any(|f| retryable(f.error.kind()));
+                failures.extend(round_failures);
+                if !again {
+                    break;
+                }
+            }
+        }
+    }
+    Err(summarize(&failures))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    // Answers from a fixed table, counting lookups.
+    struct FakeLookup {
+        answers: Vec<(&'static str, Vec<IpAddr>)>,
+        lookups: AtomicUsize,
+    }
+
+    impl Lookup for FakeLookup {
+        fn lookup<'a>(&'a self, host: &'a str) -> LookupFuture<'a> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            let answer = self.answers.iter().find(|(name, _)| *name == host);
+            let answer = answer.map(|(_, addrs)| addrs.clone());
+            Box::pin(async move { answer.ok_or_else(|| io::Error::other("no such host")) })
+        }
+    }
+
+    fn ips(ips: &[&str]) -> Vec<IpAddr> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    fn fake() -> Arc<FakeLookup> {
+        Arc::new(FakeLookup {
+            answers: vec![
+                ("a.test", ips(&["10.0.0.1"])),
+                ("b.test", ips(&["10.0.0.2"])),
+                ("c.test", ips(&["10.0.0.3"])),
+                ("none.test", vec![]),
+            ],
+            lookups: AtomicUsize::new(0),
+        })
+    }
+
+    #[test]
+    fn test_order() {
+        let addrs = ips(&["::1", "::2", "10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+        assert_eq!(
+            order(addrs.clone(), Preference::None),
+            ips(&["::1", "10.0.0.1", "::2", "10.0.0.2", "10.0.0.3"])
+        );
+        assert_eq!(
+            order(addrs.clone(), Preference::Ipv4),
+            ips(&["10.0.0.1", "10.0.0.2", "10.0.0.3", "::1", "::2"])
+        );
+        assert_eq!(
+            order(addrs, Preference::Ipv6),
+            ips(&["::1", "::2", "10.0.0.1", "10.0.0.2", "10.0.0.3"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let lookup = fake();
+        let resolver = Resolver::new(lookup.clone(), Duration::from_secs(60), 2, Preference::None);
+        let lookups = || lookup.lookups.load(Ordering::Relaxed);
+        assert_eq!(
+            resolver.resolve("a.test").await.unwrap(),
+            ips(&["10.0.0.1"])
+        );
+        assert_eq!(
+            resolver.resolve("A.test").await.unwrap(),
+            ips(&["10.0.0.1"])
+        );
+        assert_eq!(lookups(), 1);
+        resolver.resolve("b.test").await.unwrap();
+        // Using a.test makes b.test the least recently used.
+        resolver.resolve("a.test").await.unwrap();
+        resolver.resolve("c.test").await.unwrap();
+        assert_eq!(lookups(), 3);
+        resolver.resolve("a.test").await.unwrap();
+        assert_eq!(lookups(), 3);
+        resolver.resolve("b.test").await.unwrap();
+        assert_eq!(lookups(), 4);
+        // Literals, failures and empty answers.
+        assert_eq!(resolver.resolve("::1").await.unwrap(), ips(&["::1"]));
+        assert_eq!(lookups(), 4);
+        assert!(resolver.resolve("missing.test").await.is_err());
+        let e = resolver.resolve("none.test").await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+
+        let resolver = Resolver::new(lookup.clone(), Duration::ZERO, 2, Preference::None);
+        resolver.resolve("a.test").await.unwrap();
+        resolver.resolve("a.test").await.unwrap();
+        assert_eq!(lookups(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_connect_falls_back_to_the_other_family() {
+        use socket2::{Domain, Socket, Type};
+        // An IPv4 listener that never answers: its backlog is kept full.
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        socket
+            .bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into())
+            .unwrap();
+        socket.listen(0).unwrap();
+        let unanswered: std::net::TcpListener = socket.into();
+        let unanswered_addr = unanswered.local_addr().unwrap();
+        let mut queued = vec![];
+        while let Ok(Ok(stream)) = tokio::time::timeout(
+            Duration::from_millis(100),
+            TcpStream::connect(unanswered_addr),
+        )
+        .await
+        {
+            queued.push(stream);
+        }
+        let v6 = TcpListener::bind("[::1]:0").await.unwrap();
+        let v6_addr = v6.local_addr().unwrap();
+
+        let start = Instant::now();
+        let delay = Duration::from_millis(100);
+        let deadline = start + Duration::from_secs(10);
+        let (retry, source) = (Retry::default(), Source::default());
+        let stream = connect(&[unanswered_addr, v6_addr], delay, deadline, retry, &source)
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), v6_addr);
+        assert!(start.elapsed() >= delay);
+        // A failing first family hands over without waiting.
+        let refused = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused_addr = refused.local_addr().unwrap();
+        drop(refused);
+        let start = Instant::now();
+        let stream = connect(
+            &[refused_addr, v6_addr],
+            Duration::from_secs(10),
+            deadline,
+            retry,
+            &source,
+        )
+        .await
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), v6_addr);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let e = connect(&[refused_addr], delay, deadline, retry, &source)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+        // An address that does not answer only gets its share of the time.
+        let v4 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let v4_addr = v4.local_addr().unwrap();
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(600);
+        let stream = connect(&[unanswered_addr, v4_addr], delay, deadline, retry, &source)
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), v4_addr);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        let deadline = Instant::now() + Duration::from_millis(200);
+        let e = connect(
+            &[unanswered_addr, refused_addr],
+            delay,
+            deadline,
+            retry,
+            &source,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+        assert!(e.to_string().contains(&unanswered_addr.to_string()), "{e}");
+    }
+
+    #[tokio::test]
+    async fn test_connect_retries_refusals() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        // Refused, and then up by the time of the second retry.
+        let retry = Retry {
+            retries: 2,
+            delay: Duration::from_millis(100),
+        };
+        let source = Source::default();
+        let up = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            TcpListener::bind(addr).await.unwrap()
+        });
+        let start = Instant::now();
+        let deadline = start + Duration::from_secs(10);
+        let stream = connect(&[addr], FALLBACK_DELAY, deadline, retry, &source).await;
+        let _listener = up.await.unwrap();
+        assert_eq!(stream.unwrap().peer_addr().unwrap(), addr);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        // The deadline leaves no room for the retries.
+        drop(_listener);
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(50);
+        let e = connect(&[addr], FALLBACK_DELAY, deadline, retry, &source)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_connect_from_a_source_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let retry = Retry::default();
+        let source = Source {
+            v4: Some("127.0.0.2".parse().unwrap()),
+            ..Source::default()
+        };
+        let stream = connect(&[addr], FALLBACK_DELAY, deadline, retry, &source)
+            .await
+            .unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(stream.local_addr().unwrap(), peer);
+        // The other family is left to the system.
+        let v6 = TcpListener::bind("[::1]:0").await.unwrap();
+        let v6_addr = v6.local_addr().unwrap();
+        let stream = connect(&[v6_addr], FALLBACK_DELAY, deadline, retry, &source)
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), v6_addr);
+        // An address that is not this host's.
+        let source = Source {
+            v4: Some("192.0.2.10".parse().unwrap()),
+            ..Source::default()
+        };
+        assert_eq!(
+            source.unassigned(),
+            ["192.0.2.10".parse::<IpAddr>().unwrap()]
+        );
+        let e = connect(&[addr], FALLBACK_DELAY, deadline, retry, &source)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::AddrNotAvailable);
+        assert!(e.to_string().contains("binding to 192.0.2.10"), "{e}");
+    }
+
+    #[test]
+    fn test_summarize_prefers_refusals() {
+        let failure = |addr: &str, kind: ErrorKind, msg: &str| Failure {
+            addr: addr.parse().unwrap(),
+            error: io::Error::new(kind, msg),
+        };
+        let e = summarize(&[
+            failure("10.0.0.1:443", ErrorKind::HostUnreachable, "unreachable"),
+            failure("10.0.0.2:443", ErrorKind::ConnectionRefused, "refused"),
+            failure(
+                "10.0.0.3:443",
+                ErrorKind::ConnectionRefused,
+                "refused again",
+            ),
+        ]);
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(
+            e.to_string(),
+            "refused (attempts: 10.0.0.1:443: unreachable, 10.0.0.2:443: refused, \
+             10.0.0.3:443: refused again)"
+        );
+        let e = summarize(&[failure("10.0.0.1:443", ErrorKind::TimedOut, "slow")]);
+        assert_eq!(
+            (e.kind(), e.to_string()),
+            (ErrorKind::TimedOut, "slow".to_string())
+        );
+        assert_eq!(summarize(&[]).kind(), ErrorKind::NotFound);
+    }
+}
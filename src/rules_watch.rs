@@ -0,0 +1,112 @@
+// Polls the rules files for changes. There is no filesystem notification
+// backend available to this build, so polling is the only mode; it also
+// keeps working across deleted, replaced or unreadable files.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::log;
+
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+// Editors often write a file more than once when saving it.
+pub const DEBOUNCE: Duration = Duration::from_millis(500);
+
+async fn fingerprint(paths: &[PathBuf]) -> io::Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    for path in paths {
+        tokio::fs::read(path).await?.hash(&mut hasher);
+    }
+    Ok(hasher.finish())
+}
+
+/// Calls `changed` whenever the contents of `paths` change and then stay the
+/// same for `debounce`. Never returns.
+pub async fn watch<F: FnMut()>(
+    paths: Vec<PathBuf>,
+    poll: Duration,
+    debounce: Duration,
+    mut changed: F,
+) {
+    let mut applied = fingerprint(&paths).await.ok();
+    let mut pending: Option<(u64, Instant)> = None;
+    let mut failing = false;
+    loop {
+        tokio::time::sleep(poll).await;
+        let current = match fingerprint(&paths).await {
+            Ok(current) => current,
+            Err(e) => {
+                if !failing {
+                    log::warn!("cannot read rules files, will keep polling: {e}");
+                    failing = true;
+                }
+                pending = None;
+                continue;
+            }
+        };
+        if failing {
+            log::info!("rules files are readable again");
+            failing = false;
+        }
+        if Some(current) == applied {
+            pending = None;
+            continue;
+        }
+        match pending {
+            Some((hash, since)) if hash == current => {
+                if since.elapsed() >= debounce {
+                    // Not retried until the files change again, even if the
+                    // new contents turn out to be invalid.
+                    applied = Some(current);
+                    pending = None;
+                    changed();
+                }
+            }
+            _ => pending = Some((current, Instant::now())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    #[tokio::test]
+    async fn test_debounces_and_survives_missing_files() {
+        let poll = Duration::from_millis(10);
+        let debounce = Duration::from_millis(300);
+        let path = std::env::temp_dir().join(format!("proxy-watch-{}.txt", std::process::id()));
+        std::fs::write(&path, "one").unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let watcher = tokio::spawn({
+            let (path, calls) = (path.clone(), calls.clone());
+            watch(vec![path], poll, debounce, move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+            })
+        });
+        let settle = || tokio::time::sleep(debounce * 3);
+        settle().await;
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // Several writes in quick succession are one change.
+        for contents in ["two", "three", "four"] {
+            std::fs::write(&path, contents).unwrap();
+            tokio::time::sleep(poll).await;
+        }
+        settle().await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        std::fs::remove_file(&path).unwrap();
+        settle().await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        std::fs::write(&path, "five").unwrap();
+        settle().await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        watcher.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
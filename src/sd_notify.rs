@@ -0,0 +1,137 @@
+// The systemd notification protocol (sd_notify(3)): datagrams of
+// `KEY=value` lines sent to the socket named by $NOTIFY_SOCKET.
+
+use std::io;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::log;
+
+pub struct Notifier {
+    socket: UnixDatagram,
+    // Logs the first failure of a run of failures only.
+    failing: AtomicBool,
+}
+
+fn socket_addr(path: &str) -> io::Result<SocketAddr> {
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "abstract notify sockets need Linux",
+        )),
+        None => SocketAddr::from_pathname(path),
+    }
+}
+
+impl Notifier {
+    /// None when not started by systemd with `Type=notify`.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        match std::env::var("NOTIFY_SOCKET") {
+            Ok(path) if !path.is_empty() => Self::connect(&path).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn connect(path: &str) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect_addr(&socket_addr(path)?)?;
+        // A full socket buffer drops the message instead of stalling.
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            failing: AtomicBool::new(false),
+        })
+    }
+
+    pub fn notify(&self, message: &str) {
+        match self.socket.send(message.as_bytes()) {
+            Ok(_) => self.failing.store(false, Ordering::Relaxed),
+            Err(e) => {
+                if !self.failing.swap(true, Ordering::Relaxed) {
+                    log::warn!("sd_notify `{message}` failed: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// How often to send WATCHDOG=1, given $WATCHDOG_USEC and $WATCHDOG_PID:
+/// half the watchdog timeout, if the watchdog is meant for this process.
+pub fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = pid
+        && pid.parse::<u32>().ok() != Some(std::process::id())
+    {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+pub fn watchdog_interval_from_env() -> Option<Duration> {
+    let var = |name| std::env::var(name).ok();
+    watchdog_interval(
+        var("WATCHDOG_USEC").as_deref(),
+        var("WATCHDOG_PID").as_deref(),
+    )
+}
+
+pub fn status(active: u64) -> String {
+    format!("STATUS={active} active connections")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_message_sequence() {
+        let path = std::env::temp_dir().join(format!("proxy-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier::connect(path.to_str().unwrap()).unwrap();
+        notifier.notify("READY=1");
+        notifier.notify(&status(3));
+        notifier.notify("WATCHDOG=1");
+        notifier.notify("STOPPING=1");
+        let mut received = vec![];
+        let mut buf = [0; 256];
+        for _ in 0..4 {
+            let n = systemd.recv(&mut buf).unwrap();
+            received.push(String::from_utf8(buf[..n].to_vec()).unwrap());
+        }
+        assert_eq!(
+            received,
+            [
+                "READY=1",
+                "STATUS=3 active connections",
+                "WATCHDOG=1",
+                "STOPPING=1"
+            ]
+        );
+        drop(systemd);
+        std::fs::remove_file(&path).unwrap();
+        // Nobody listening any more: logged, not fatal.
+        notifier.notify("STATUS=gone");
+    }
+    #[test]
+    fn test_watchdog_interval() {
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some(&pid)),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(watchdog_interval(Some("30000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(None, None), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+    }
+}
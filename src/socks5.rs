@@ -0,0 +1,249 @@
+// SOCKS5 (RFC 1928) CONNECT, without authentication. A SOCKS5 client's first
+// byte is the version, 0x05, which no HTTP request line starts with; once the
+// destination is known the connection is handled like a CONNECT.
+
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::connection_error::{self, Stage};
+
+pub const VERSION: u8 = 0x05;
+
+const NO_AUTH: u8 = 0x00;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+impl Reply {
+    /// The reply for a destination that could not be connected to.
+    pub fn for_error(error: &io::Error) -> Self {
+        if connection_error::stage_of(error) == Some(Stage::Resolve) {
+            return Reply::HostUnreachable;
+        }
+        match error.kind() {
+            ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+            ErrorKind::HostUnreachable | ErrorKind::TimedOut => Reply::HostUnreachable,
+            ErrorKind::NetworkUnreachable => Reply::NetworkUnreachable,
+            _ => Reply::GeneralFailure,
+        }
+    }
+
+    /// The reply standing in for an HTTP error status.
+    pub fn for_status(status: u32) -> Self {
+        match status {
+            504 => Reply::HostUnreachable,
+            400..=499 => Reply::NotAllowed,
+            _ => Reply::GeneralFailure,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Request {
+    // host:port, with an IPv6 host in brackets.
+    Connect(String),
+    // A request that is answered with this reply and not served.
+    Refused(Reply),
+}
+
+/// Reads the client's version and methods and answers them. Only "no
+/// authentication" is offered, and not even that when `accept` is false;
+/// returns whether the client may go on to send its request.
+pub async fn negotiate<S>(stream: &mut S, accept: bool) -> io::Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported SOCKS version {}", header[0]),
+        ));
+    }
+    let mut methods = vec![0; header[1].into()];
+    stream.read_exact(&mut methods).await?;
+    let accepted = accept && methods.contains(&NO_AUTH);
+    let method = if accepted {
+        NO_AUTH
+    } else {
+        NO_ACCEPTABLE_METHODS
+    };
+    stream.write_all(&[VERSION, method]).await?;
+    Ok(accepted)
+}
+
+/// Reads the request that follows a successful negotiation.
+pub async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Request> {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [version, command, _, address_type] = header;
+    if version != VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported SOCKS version {version}"),
+        ));
+    }
+    let host = match address_type {
+        ATYP_IPV4 => {
+            let mut octets = [0; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0; 16];
+            stream.read_exact(&mut octets).await?;
+            format!("[{}]", Ipv6Addr::from(octets))
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut name = vec![0; len.into()];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name)
+                .map_err(|_| io::Error::new(ErrorKind::InvalidData, "domain name is not UTF-8"))?
+        }
+        // The rest of the request cannot be told apart from what follows it.
+        _ => return Ok(Request::Refused(Reply::AddressTypeNotSupported)),
+    };
+    let port = stream.read_u16().await?;
+    if command != CMD_CONNECT {
+        return Ok(Request::Refused(Reply::CommandNotSupported));
+    }
+    Ok(Request::Connect(format!("{host}:{port}")))
+}
+
+/// Sends `reply`, with the address the proxy connected from once connected.
+pub async fn reply<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    reply: Reply,
+    bound: Option<SocketAddr>,
+) -> io::Result<()> {
+    let mut message = vec![VERSION, reply as u8, 0];
+    match bound {
+        Some(SocketAddr::V4(addr)) => {
+            message.push(ATYP_IPV4);
+            message.extend_from_slice(&addr.ip().octets());
+        }
+        Some(SocketAddr::V6(addr)) => {
+            message.push(ATYP_IPV6);
+            message.extend_from_slice(&addr.ip().octets());
+        }
+        None => message.extend_from_slice(&[ATYP_IPV4, 0, 0, 0, 0]),
+    }
+    message.extend_from_slice(&bound.map_or(0, |addr| addr.port()).to_be_bytes());
+    stream.write_all(&message).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[tokio::test]
+    async fn test_negotiate() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[5, 2, 2, 0]).await.unwrap();
+        assert!(negotiate(&mut server, true).await.unwrap());
+        let mut answer = [0; 2];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer, [5, 0]);
+
+        // Only username/password offered, or authentication required.
+        for (message, accept) in [(&[5, 1, 2][..], true), (&[5, 1, 0][..], false)] {
+            client.write_all(message).await.unwrap();
+            assert!(!negotiate(&mut server, accept).await.unwrap());
+            client.read_exact(&mut answer).await.unwrap();
+            assert_eq!(answer, [5, 0xff]);
+        }
+
+        client.write_all(&[4, 1, 0]).await.unwrap();
+        let e = negotiate(&mut server, true).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+    #[tokio::test]
+    async fn test_read_request() {
+        let requests: [(&[u8], Request); 5] = [
+            (
+                &[5, 1, 0, 1, 127, 0, 0, 1, 0x01, 0xbb],
+                Request::Connect("127.0.0.1:443".to_string()),
+            ),
+            (
+                &[
+                    5, 1, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 80,
+                ],
+                Request::Connect("[::1]:80".to_string()),
+            ),
+            (
+                b"\x05\x01\x00\x03\x0bexample.com\x00\x50",
+                Request::Connect("example.com:80".to_string()),
+            ),
+            (
+                &[5, 2, 0, 1, 0, 0, 0, 0, 0, 0],
+                Request::Refused(Reply::CommandNotSupported),
+            ),
+            (
+                &[5, 1, 0, 9, 0, 0],
+                Request::Refused(Reply::AddressTypeNotSupported),
+            ),
+        ];
+        for (message, expected) in requests {
+            let mut message = message;
+            assert_eq!(read_request(&mut message).await.unwrap(), expected);
+        }
+        let mut truncated: &[u8] = &[5, 1, 0, 1, 127, 0];
+        let e = read_request(&mut truncated).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+    #[tokio::test]
+    async fn test_reply() {
+        let mut message = vec![];
+        let bound = "192.0.2.1:8080".parse().unwrap();
+        reply(&mut message, Reply::Succeeded, Some(bound))
+            .await
+            .unwrap();
+        assert_eq!(message, [5, 0, 0, 1, 192, 0, 2, 1, 0x1f, 0x90]);
+        let mut message = vec![];
+        let bound = "[2001:db8::1]:1".parse().unwrap();
+        reply(&mut message, Reply::Succeeded, Some(bound))
+            .await
+            .unwrap();
+        assert_eq!(message[..4], [5, 0, 0, 4]);
+        assert_eq!(
+            message[4..20],
+            "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets()
+        );
+        assert_eq!(message[20..], [0, 1]);
+        let mut message = vec![];
+        reply(&mut message, Reply::ConnectionRefused, None)
+            .await
+            .unwrap();
+        assert_eq!(message, [5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+    #[test]
+    fn test_reply_for_error() {
+        let refused = io::Error::from(ErrorKind::ConnectionRefused);
+        assert_eq!(Reply::for_error(&refused), Reply::ConnectionRefused);
+        let timed_out = io::Error::from(ErrorKind::TimedOut);
+        assert_eq!(Reply::for_error(&timed_out), Reply::HostUnreachable);
+        let other = io::Error::other("boom");
+        assert_eq!(Reply::for_error(&other), Reply::GeneralFailure);
+        assert_eq!(Reply::for_status(403), Reply::NotAllowed);
+        assert_eq!(Reply::for_status(504), Reply::HostUnreachable);
+        assert_eq!(Reply::for_status(500), Reply::GeneralFailure);
+    }
+}
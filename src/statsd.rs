@@ -0,0 +1,177 @@
+// Sends the metrics to a statsd server in DogStatsD format: counters as
+// deltas since the last flush, gauges as they are now, and every timing
+// sample.
+
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use crate::log;
+use crate::metrics::Metrics;
+
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+// Fits an Ethernet MTU after IP and UDP headers.
+const MAX_DATAGRAM: usize = 1432;
+const PREFIX: &str = "proxy";
+
+/// `udp://host:port` → `host:port`.
+pub fn parse_url(url: &str) -> io::Result<String> {
+    match url.strip_prefix("udp://") {
+        Some(addr)
+            if addr
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) =>
+        {
+            Ok(addr.to_string())
+        }
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid --statsd value: {url} (expected udp://host:port)"),
+        )),
+    }
+}
+
+// Joins lines into datagrams of at most MAX_DATAGRAM bytes.
+fn batch(lines: &[String]) -> Vec<String> {
+    let mut datagrams = vec![];
+    let mut current = String::new();
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+    datagrams
+}
+
+struct Emitter {
+    metrics: Arc<Metrics>,
+    // Joined with commas, added to every line.
+    tags: String,
+    last: [u64; 3],
+    last_errors: Vec<((&'static str, &'static str), u64)>,
+}
+
+impl Emitter {
+    fn line(
+        &self,
+        name: &str,
+        value: impl std::fmt::Display,
+        kind: &str,
+        tags: &[String],
+    ) -> String {
+        let mut line = format!("{PREFIX}.{name}:{value}|{kind}");
+        let extra = tags.join(",");
+        match (extra.is_empty(), self.tags.is_empty()) {
+            (true, true) => {}
+            (true, false) => line.push_str(&format!("|#{}", self.tags)),
+            (false, true) => line.push_str(&format!("|#{extra}")),
+            (false, false) => line.push_str(&format!("|#{extra},{}", self.tags)),
+        }
+        line
+    }
+
+    fn lines(&mut self) -> Vec<String> {
+        let metrics = &self.metrics;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let counters = [
+            ("connections", load(&metrics.connections)),
+            ("bytes_up", load(&metrics.bytes_up)),
+            ("bytes_down", load(&metrics.bytes_down)),
+        ];
+        let mut lines = vec![];
+        for (i, (name, value)) in counters.iter().enumerate() {
+            lines.push(self.line(name, value - self.last[i], "c", &[]));
+            self.last[i] = *value;
+        }
+        let errors = metrics.errors();
+        for ((stage, class), count) in &errors {
+            let before = self
+                .last_errors
+                .iter()
+                .find(|(key, _)| key == &(*stage, *class))
+                .map_or(0, |(_, count)| *count);
+            if *count > before {
+                let tags = [format!("stage:{stage}"), format!("class:{class}")];
+                lines.push(self.line("errors", count - before, "c", &tags));
+            }
+        }
+        self.last_errors = errors;
+        lines.push(self.line("active_connections", load(&metrics.active), "g", &[]));
+        lines.push(self.line("buffered_bytes", load(&metrics.buffered), "g", &[]));
+        for (timer, duration) in metrics.take_timings() {
+            let millis = duration.as_secs_f64() * 1000.0;
+            lines.push(self.line(timer.as_str(), format!("{millis:.3}"), "ms", &[]));
+        }
+        lines
+    }
+}
+
+/// Flushes every `interval` until the process exits. Send failures are logged
+/// once per run of failures and otherwise ignored.
+pub async fn run(
+    metrics: Arc<Metrics>,
+    addr: String,
+    tags: Vec<String>,
+    interval: Duration,
+) -> io::Result<()> {
+    let bind = if addr.starts_with('[') {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    let mut emitter = Emitter {
+        metrics,
+        tags: tags.join(","),
+        last: [0; 3],
+        last_errors: vec![],
+    };
+    let mut failing = false;
+    loop {
+        tokio::time::sleep(interval).await;
+        for datagram in batch(&emitter.lines()) {
+            match socket.send_to(datagram.as_bytes(), addr.as_str()).await {
+                Ok(_) => failing = false,
+                Err(e) => {
+                    if !failing {
+                        log::warn!("statsd send to {addr} failed: {e}");
+                        failing = true;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(parse_url("udp://127.0.0.1:8125").unwrap(), "127.0.0.1:8125");
+        assert_eq!(parse_url("udp://[::1]:8125").unwrap(), "[::1]:8125");
+        for url in ["127.0.0.1:8125", "udp://host", "udp://:8125", "tcp://h:1"] {
+            assert!(parse_url(url).is_err(), "{url}");
+        }
+    }
+    #[test]
+    fn test_batches_into_datagrams() {
+        let lines: Vec<String> = (0..100)
+            .map(|i| format!("proxy.metric_{i:03}:1|c"))
+            .collect();
+        let datagrams = batch(&lines);
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|d| d.len() <= MAX_DATAGRAM));
+        assert_eq!(datagrams.join("\n"), lines.join("\n"));
+    }
+}
@@ -0,0 +1,342 @@
+// What a running proxy is doing, served as JSON on the `--admin-addr`
+// listener: `/status` has the totals and the open tunnels by destination
+// host, `/connections/<id>` one open tunnel, which a DELETE closes, and a
+// POST to `/reload` reads the `--config` file again. One request per
+// connection, answered and closed, as on `--metrics-addr`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+
+use crate::http_reader::HttpReader;
+use crate::json;
+use crate::log;
+use crate::proxy::ProxyState;
+use crate::recorder::Recorder;
+
+// A client that takes longer than this to send its request is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An open tunnel, updated by its pipes as bytes go through.
+#[derive(Debug)]
+pub struct Tunnel {
+    pub id: u64,
+    client: SocketAddr,
+    target: String,
+    host: String,
+    opened: Instant,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    // Milliseconds after `opened`.
+    last_activity: AtomicU64,
+    cancel: Notify,
+    // Client to target and back, once the tunnel is up, for `--tap-addr`.
+    recorders: OnceLock<[Arc<Recorder>; 2]>,
+}
+
+impl Tunnel {
+    pub fn transferred(&self, up: bool, n: usize) {
+        let bytes = if up { &self.bytes_up } else { &self.bytes_down };
+        bytes.fetch_add(n as u64, Ordering::Relaxed);
+        let at = self.opened.elapsed().as_millis() as u64;
+        self.last_activity.fetch_max(at, Ordering::Relaxed);
+    }
+
+    /// Resolves once the tunnel is to be closed; a close asked for before
+    /// this is waited on still counts.
+    pub async fn cancelled(&self) {
+        self.cancel.notified().await
+    }
+
+    pub fn set_recorders(&self, up: &Arc<Recorder>, down: &Arc<Recorder>) {
+        let _ = self.recorders.set([up.clone(), down.clone()]);
+    }
+
+    pub fn recorders(&self) -> Option<&[Arc<Recorder>; 2]> {
+        self.recorders.get()
+    }
+
+    fn last_activity(&self) -> Instant {
+        self.opened + Duration::from_millis(self.last_activity.load(Ordering::Relaxed))
+    }
+
+    fn render(&self, now: Instant) -> String {
+        format!(
+            "{{\"id\":{},\"client\":{},\"target\":{},\"open_ms\":{},\"bytes_up\":{},\
+             \"bytes_down\":{},\"idle_ms\":{}}}",
+            self.id,
+            json::quote(&self.client.to_string()),
+            json::quote(&self.target),
+            now.duration_since(self.opened).as_millis(),
+            self.bytes_up.load(Ordering::Relaxed),
+            self.bytes_down.load(Ordering::Relaxed),
+            now.saturating_duration_since(self.last_activity())
+                .as_millis()
+        )
+    }
+}
+
+// The tunnels to one host that have closed.
+#[derive(Debug, Clone, Copy)]
+struct Closed {
+    bytes_up: u64,
+    bytes_down: u64,
+    last_activity: Instant,
+}
+
+#[derive(Debug)]
+pub struct Registry {
+    started: Instant,
+    tunnels: Mutex<HashMap<u64, Arc<Tunnel>>>,
+    // By lowercased host.
+    closed: Mutex<HashMap<String, Closed>>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            tunnels: Mutex::new(HashMap::new()),
+            closed: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Keeps a tunnel listed until dropped.
+pub struct Registration<'a> {
+    registry: &'a Registry,
+    pub tunnel: Arc<Tunnel>,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.registry
+            .tunnels
+            .lock()
+            .unwrap()
+            .remove(&self.tunnel.id);
+        let mut closed = self.registry.closed.lock().unwrap();
+        let host = closed.entry(self.tunnel.host.clone()).or_insert(Closed {
+            bytes_up: 0,
+            bytes_down: 0,
+            last_activity: self.tunnel.opened,
+        });
+        host.bytes_up += self.tunnel.bytes_up.load(Ordering::Relaxed);
+        host.bytes_down += self.tunnel.bytes_down.load(Ordering::Relaxed);
+        host.last_activity = host.last_activity.max(self.tunnel.last_activity());
+    }
+}
+
+impl Registry {
+    /// Lists the tunnel connection `id` opened to `target`, `host:port`.
+    pub fn register(&self, id: u64, client: SocketAddr, target: &str) -> Registration<'_> {
+        let host = crate::policy::split_authority(target).map_or(target, |(host, _)| host);
+        let tunnel = Arc::new(Tunnel {
+            id,
+            client,
+            target: target.to_string(),
+            host: host.to_ascii_lowercase(),
+            opened: Instant::now(),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            last_activity: AtomicU64::new(0),
+            cancel: Notify::new(),
+            recorders: OnceLock::new(),
+        });
+        self.tunnels.lock().unwrap().insert(id, tunnel.clone());
+        Registration {
+            registry: self,
+            tunnel,
+        }
+    }
+
+    pub fn tunnel(&self, id: u64) -> Option<Arc<Tunnel>> {
+        self.tunnels.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Closes an open tunnel. False when there is none with that id.
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.tunnel(id) {
+            Some(tunnel) => {
+                tunnel.cancel.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The `/status` document.
+    pub fn render(&self, connections: u64, active: u64) -> String {
+        let now = Instant::now();
+        #[derive(Default)]
+        struct Host {
+            tunnels: Vec<u64>,
+            bytes_up: u64,
+            bytes_down: u64,
+            last_activity: Option<Instant>,
+        }
+        let mut hosts: BTreeMap<String, Host> = BTreeMap::new();
+        for (name, closed) in self.closed.lock().unwrap().iter() {
+            let entry = hosts.entry(name.clone()).or_default();
+            entry.bytes_up += closed.bytes_up;
+            entry.bytes_down += closed.bytes_down;
+            entry.last_activity = entry.last_activity.max(Some(closed.last_activity));
+        }
+        for tunnel in self.tunnels.lock().unwrap().values() {
+            let entry = hosts.entry(tunnel.host.clone()).or_default();
+            entry.tunnels.push(tunnel.id);
+            entry.bytes_up += tunnel.bytes_up.load(Ordering::Relaxed);
+            entry.bytes_down += tunnel.bytes_down.load(Ordering::Relaxed);
+            entry.last_activity = entry.last_activity.max(Some(tunnel.last_activity()));
+        }
+        let hosts: Vec<String> = hosts
+            .into_iter()
+            .map(|(name, mut host)| {
+                host.tunnels.sort_unstable();
+                let tunnels: Vec<String> = host.tunnels.iter().map(u64::to_string).collect();
+                let idle = host
+                    .last_activity
+                    .map_or(0, |at| now.saturating_duration_since(at).as_millis() as u64);
+                format!(
+                    "{{\"host\":{},\"open_tunnels\":[{}],\"bytes_up\":{},\"bytes_down\":{},\
+                     \"idle_ms\":{idle}}}",
+                    json::quote(&name),
+                    tunnels.join(","),
+                    host.bytes_up,
+                    host.bytes_down
+                )
+            })
+            .collect();
+        format!(
+            "{{\"uptime_secs\":{},\"connections_total\":{connections},\
+             \"active_connections\":{active},\"hosts\":[{}]}}\n",
+            now.duration_since(self.started).as_secs(),
+            hosts.join(",")
+        )
+    }
+}
+
+async fn respond(mut stream: TcpStream, state: &ProxyState) -> io::Result<()> {
+    let mut reader = HttpReader::new(1024);
+    let request_line = reader.read_line(&mut stream).await?;
+    while !reader.read_line(&mut stream).await?.is_empty() {}
+    let registry = state
+        .status
+        .as_ref()
+        .expect("--admin-addr without a registry");
+    let not_found = || ("404 Not Found", "{\"error\":\"not found\"}\n".to_string());
+    let mut parts = request_line.split(' ');
+    let (method, path) = (parts.next(), parts.next().unwrap_or_default());
+    let id = path
+        .strip_prefix("/connections/")
+        .and_then(|id| id.parse::<u64>().ok());
+    let (status, body) = match (method, path, id) {
+        (Some("GET"), "/status", _) => {
+            let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+            let metrics = &state.metrics;
+            let body = registry.render(load(&metrics.connections), load(&metrics.active));
+            ("200 OK", body)
+        }
+        (Some("GET"), _, Some(id)) => match registry.tunnel(id) {
+            Some(tunnel) => ("200 OK", tunnel.render(Instant::now()) + "\n"),
+            None => not_found(),
+        },
+        (Some("DELETE"), _, Some(id)) if registry.cancel(id) => {
+            log::info!("Closing connection {id} for the admin endpoint");
+            ("200 OK", format!("{{\"closed\":{id}}}\n"))
+        }
+        (Some("POST"), "/reload", _) => match state.reload_config() {
+            Ok(()) => ("200 OK", "{\"reloaded\":true}\n".to_string()),
+            Err(e) => {
+                log::error!("Config reload failed, keeping the current config: {e}");
+                let status = match e.kind() {
+                    io::ErrorKind::InvalidInput => "400 Bad Request",
+                    _ => "500 Internal Server Error",
+                };
+                (
+                    status,
+                    format!("{{\"error\":{}}}\n", json::quote(&e.to_string())),
+                )
+            }
+        },
+        (Some("DELETE"), _, Some(_)) | (Some("GET"), _, None) => not_found(),
+        _ => (
+            "405 Method Not Allowed",
+            "{\"error\":\"method not allowed\"}\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Answers admin requests until the task is dropped.
+pub async fn serve(listener: Arc<TcpListener>, state: Arc<ProxyState>) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, respond(stream, &state)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log::debug!("Admin request from {peer} failed: {e}"),
+                Err(_) => log::debug!("Admin request from {peer} timed out"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[tokio::test]
+    async fn test_registry() {
+        let registry = Registry::default();
+        let client = "192.0.2.1:5000".parse().unwrap();
+        let first = registry.register(7, client, "Example.com:443");
+        first.tunnel.transferred(true, 10);
+        first.tunnel.transferred(false, 200);
+        let second = registry.register(9, client, "example.com:80");
+        second.tunnel.transferred(true, 1);
+        let other = registry.register(8, client, "[::1]:22");
+        let status = registry.render(3, 3);
+        assert!(status.starts_with(
+            "{\"uptime_secs\":0,\"connections_total\":3,\"active_connections\":3,\"hosts\":["
+        ));
+        assert!(
+            status.contains(
+                "{\"host\":\"::1\",\"open_tunnels\":[8],\"bytes_up\":0,\"bytes_down\":0,"
+            )
+        );
+        assert!(status.contains(
+            "{\"host\":\"example.com\",\"open_tunnels\":[7,9],\"bytes_up\":11,\
+             \"bytes_down\":200,"
+        ));
+        let details = registry.tunnel(7).unwrap().render(Instant::now());
+        assert!(details.starts_with(
+            "{\"id\":7,\"client\":\"192.0.2.1:5000\",\"target\":\"Example.com:443\","
+        ));
+        // Closed tunnels leave their bytes behind.
+        drop(first);
+        drop(other);
+        let status = registry.render(3, 1);
+        assert!(status.contains("{\"host\":\"example.com\",\"open_tunnels\":[9],\"bytes_up\":11,"));
+        assert!(status.contains("{\"host\":\"::1\",\"open_tunnels\":[],"));
+        assert!(registry.tunnel(7).is_none());
+        assert!(!registry.cancel(7));
+        // A close asked for before it is waited on is not lost.
+        assert!(registry.cancel(9));
+        tokio::time::timeout(Duration::from_secs(1), second.tunnel.cancelled())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+// A live dump of one open tunnel, served on the `--tap-addr` listener to a
+// debugging client such as netcat. The client sends a connection's id, as
+// `/status` and the log lines number it, on a line of its own, and is sent
+// what the tunnel carries from then on, chunk by chunk, as hex and text:
+// `>>>` for what the client sent and `<<<` for what the target did, each
+// after when it went through. The dump ends with the tunnel, or when the
+// tap's client goes away, which detaches it from the tunnel's recorders.
+// A tap that falls too far behind is cut off rather than hold their memory.
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::har::iso8601;
+use crate::http_reader::HttpReader;
+use crate::log;
+use crate::proxy::ProxyState;
+use crate::recorder::{self, MaxLag, Recorder, RecorderReader};
+
+// A client that takes longer than this to name a connection is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// How far behind the tunnel a tap may fall, and for how long, before it is
+// taken to be abandoned.
+const MAX_LAG: MaxLag = MaxLag {
+    bytes: Some(4 * 1024 * 1024),
+    duration: Some(Duration::from_secs(60)),
+};
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// A tunnel's two directions, to be read from where they were attached.
+pub struct Tap {
+    up: RecorderReader,
+    down: RecorderReader,
+    // Into each direction, counted from the tunnel's start.
+    offsets: [u64; 2],
+}
+
+impl Tap {
+    pub fn attach(up: &Arc<Recorder>, down: &Arc<Recorder>) -> Self {
+        Self {
+            offsets: [up.bytes_total(), down.bytes_total()],
+            up: RecorderReader::auxiliary(up.clone(), MAX_LAG),
+            down: RecorderReader::auxiliary(down.clone(), MAX_LAG),
+        }
+    }
+
+    /// Writes what the tunnel carries to `client` until both directions
+    /// end, the tap is cut off, or `client` closes its side.
+    pub async fn dump<S>(self, client: &mut S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let Self {
+            up: mut up_reader,
+            down: mut down_reader,
+            mut offsets,
+        } = self;
+        let (mut up_buf, mut down_buf) = (vec![0; CHUNK_SIZE], vec![0; CHUNK_SIZE]);
+        let mut open = [true, true];
+        let mut ignored = [0; 64];
+        loop {
+            if open == [false, false] {
+                return client.write_all(b"--- tunnel closed\n").await;
+            }
+            let (up, read) = tokio::select! {
+                read = up_reader.read(&mut up_buf), if open[0] => (true, read),
+                read = down_reader.read(&mut down_buf), if open[1] => (false, read),
+                read = client.read(&mut ignored) => match read {
+                    Ok(n) if n > 0 => continue,
+                    _ => return Ok(()),
+                },
+            };
+            let direction = usize::from(!up);
+            let n = match read {
+                Ok(0) => {
+                    open[direction] = false;
+                    continue;
+                }
+                Ok(n) => n,
+                Err(e) => return client.write_all(format!("--- {e}\n").as_bytes()).await,
+            };
+            let (marker, chunk) = match up {
+                true => (">>>", &up_buf[..n]),
+                false => ("<<<", &down_buf[..n]),
+            };
+            let text = format!(
+                "{marker} {} {n} bytes\n{}",
+                iso8601(SystemTime::now()),
+                recorder::hexdump_at(offsets[direction], chunk)
+            );
+            offsets[direction] += n as u64;
+            client.write_all(text.as_bytes()).await?;
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, state: &ProxyState) -> io::Result<()> {
+    let mut reader = HttpReader::new(1024);
+    let line = tokio::time::timeout(REQUEST_TIMEOUT, reader.read_line(&mut stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no connection id"))??;
+    let registry = state
+        .status
+        .as_ref()
+        .expect("--tap-addr without a registry");
+    let Ok(id) = line.trim().parse::<u64>() else {
+        let reply = format!("--- expected a connection id, got {}\n", line.trim());
+        return stream.write_all(reply.as_bytes()).await;
+    };
+    let tunnel = registry.tunnel(id);
+    let Some([up, down]) = tunnel.as_ref().and_then(|tunnel| tunnel.recorders()) else {
+        let reply = format!("--- no open tunnel for connection {id}\n");
+        return stream.write_all(reply.as_bytes()).await;
+    };
+    log::info!("Tapping connection {id} for {}", stream.peer_addr()?);
+    stream
+        .write_all(format!("--- tapping connection {id}\n").as_bytes())
+        .await?;
+    Tap::attach(up, down).dump(&mut stream).await?;
+    stream.shutdown().await
+}
+
+/// Serves taps until the task is dropped.
+pub async fn serve(listener: Arc<TcpListener>, state: Arc<ProxyState>) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &state).await {
+                log::debug!("Tap from {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // The chunk after `marker`'s header line, which ends in `bytes`.
+    fn chunk<'a>(text: &'a str, marker: &str, bytes: &str) -> Vec<&'a str> {
+        let mut lines = text.lines().skip_while(|line| !line.starts_with(marker));
+        let header = lines.next().unwrap();
+        assert!(header.ends_with(bytes), "{header}");
+        lines
+            .take_while(|line| !line.starts_with("---") && !line.contains(" bytes"))
+            .collect()
+    }
+    #[tokio::test]
+    async fn test_tap_sees_only_what_follows_it() {
+        let up = Arc::new(Recorder::new());
+        let down = Arc::new(Recorder::new());
+        up.append(b"before the tap");
+        let tap = Tap::attach(&up, &down);
+        up.append(b"GET / HTTP/1.1\r\n");
+        down.append(b"HTTP/1.1 200 OK\r\n");
+        up.close();
+        down.close();
+        let (mut tap_client, mut client) = tokio::io::duplex(64 * 1024);
+        tap.dump(&mut client).await.unwrap();
+        drop(client);
+        let mut text = String::new();
+        tap_client.read_to_string(&mut text).await.unwrap();
+        assert!(!text.contains("before"), "{text}");
+        assert_eq!(
+            chunk(&text, ">>> ", " 16 bytes"),
+            ["0000000e: 4745 5420 2f20 4854 5450 2f31 2e31 0d0a  GET / HTTP/1.1.."]
+        );
+        assert_eq!(
+            chunk(&text, "<<< ", " 17 bytes"),
+            [
+                "00000000: 4854 5450 2f31 2e31 2032 3030 204f 4b0d  HTTP/1.1 200 OK.",
+                "00000010: 0a                                       .",
+            ]
+        );
+        assert!(text.ends_with("--- tunnel closed\n"), "{text}");
+    }
+    #[tokio::test]
+    async fn test_tap_ends_when_its_client_goes_away() {
+        let up = Arc::new(Recorder::new());
+        let down = Arc::new(Recorder::new());
+        let tap = Tap::attach(&up, &down);
+        let (tap_client, mut client) = tokio::io::duplex(1024);
+        drop(tap_client);
+        tokio::time::timeout(Duration::from_secs(1), tap.dump(&mut client))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
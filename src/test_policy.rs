@@ -0,0 +1,277 @@
+// `proxy test-policy`: runs the rules engine against a request described on
+// the command line, without listening or connecting anywhere.
+
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::connection_error::ConnectionContext;
+use crate::json;
+use crate::policy::{self, Policy, PolicyDecision};
+
+pub struct Options {
+    pub client: IpAddr,
+    // None reads one target per line from stdin.
+    pub target: Option<String>,
+    pub user: Option<String>,
+    pub json: bool,
+}
+
+impl Options {
+    /// Takes the test-policy flags out of `args`, returning the rest for
+    /// `Config::from_args`.
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> io::Result<(Self, Vec<String>)> {
+        let invalid = |msg: String| io::Error::new(ErrorKind::InvalidInput, msg);
+        let mut options = Options {
+            client: IpAddr::from([127, 0, 0, 1]),
+            target: None,
+            user: None,
+            json: false,
+        };
+        let mut rest = vec![];
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| invalid(format!("{arg} requires a value")))
+            };
+            match arg.as_str() {
+                "--client" => {
+                    let value = value()?;
+                    options.client = value
+                        .parse()
+                        .map_err(|_| invalid(format!("invalid --client value: {value}")))?;
+                }
+                "--target" => options.target = Some(value()?),
+                "--user" => options.user = Some(value()?),
+                "--json" => options.json = true,
+                _ => rest.push(arg),
+            }
+        }
+        Ok((options, rest))
+    }
+}
+
+pub struct Verdict {
+    pub target: String,
+    // Every rule that matched, in order; the deciding one is in `decision`.
+    pub matched: Vec<usize>,
+    pub decision: PolicyDecision,
+}
+
+pub async fn check(policy: &Policy, options: &Options, target: &str) -> io::Result<Verdict> {
+    let (host, port) = policy::split_authority(target).ok_or_else(|| {
+        io::Error::new(ErrorKind::InvalidInput, format!("invalid target: {target}"))
+    })?;
+    let request = policy::ConnectRequest {
+        host,
+        port,
+        client: options.client,
+        user: options.user.as_deref(),
+    };
+    let mut ctx = ConnectionContext::new(SocketAddr::new(options.client, 0));
+    ctx.target = Some(target.to_string());
+    Ok(Verdict {
+        target: target.to_string(),
+        matched: policy.matching_rules(&request),
+        decision: policy.decide(&request, &ctx).await,
+    })
+}
+
+fn to_text(policy: &Policy, options: &Options, verdict: &Verdict) -> String {
+    let matched: Vec<String> = verdict
+        .matched
+        .iter()
+        .map(|index| policy.describe(Some(*index)))
+        .collect();
+    let decision = &verdict.decision;
+    let mut access = format!(
+        "{} by rule {}",
+        decision.access,
+        policy.describe(decision.access_rule)
+    );
+    if let Some(plugin) = &decision.plugin {
+        access.push_str(&format!(" via plugin {plugin}"));
+    }
+    if !decision.enforced {
+        access.push_str(" (dry run, not enforced)");
+    }
+    let route = match &decision.rewrite {
+        Some((host, port)) => format!(
+            "direct to {host}:{port} (rewritten by rule {})",
+            policy.describe(decision.rewrite_rule)
+        ),
+        None => "direct".to_string(),
+    };
+    format!(
+        "target: {}\nclient: {}\nuser: {}\nmatched rules: {}\naccess: {}\nroute: {}\n",
+        verdict.target,
+        options.client,
+        options.user.as_deref().unwrap_or("-"),
+        if matched.is_empty() {
+            "none".to_string()
+        } else {
+            matched.join(", ")
+        },
+        access,
+        route
+    )
+}
+
+fn to_json(policy: &Policy, options: &Options, verdict: &Verdict) -> String {
+    let matched: Vec<String> = verdict
+        .matched
+        .iter()
+        .map(|index| json::quote(&policy.describe(Some(*index))))
+        .collect();
+    let decision = &verdict.decision;
+    format!(
+        "{{\"target\":{},\"client\":{},\"user\":{},\"matched\":[{}],\"access\":{},\
+         \"access_rule\":{},\"plugin\":{},\"enforced\":{},\"route\":\"direct\",\
+         \"effective_target\":{}}}\n",
+        json::quote(&verdict.target),
+        json::quote(&options.client.to_string()),
+        options
+            .user
+            .as_deref()
+            .map_or("null".to_string(), json::quote),
+        matched.join(","),
+        json::quote(&decision.access.to_string()),
+        json::quote(&policy.describe(decision.access_rule)),
+        decision
+            .plugin
+            .as_deref()
+            .map_or("null".to_string(), json::quote),
+        decision.enforced,
+        json::quote(
+            &decision
+                .rewrite
+                .as_ref()
+                .map_or(verdict.target.clone(), |(host, port)| format!(
+                    "{host}:{port}"
+                ))
+        )
+    )
+}
+
+pub async fn run<R, W>(
+    policy: &Policy,
+    options: &Options,
+    input: R,
+    mut output: W,
+) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let targets = match &options.target {
+        Some(target) => vec![target.clone()],
+        None => {
+            let mut lines = input.lines();
+            let mut targets = vec![];
+            while let Some(line) = lines.next_line().await? {
+                let line = line.trim();
+                if !line.is_empty() && !line.starts_with('#') {
+                    targets.push(line.to_string());
+                }
+            }
+            targets
+        }
+    };
+    for (i, target) in targets.iter().enumerate() {
+        let verdict = check(policy, options, target).await?;
+        let report = if options.json {
+            to_json(policy, options, &verdict)
+        } else if i > 0 {
+            format!("\n{}", to_text(policy, options, &verdict))
+        } else {
+            to_text(policy, options, &verdict)
+        };
+        output.write_all(report.as_bytes()).await?;
+    }
+    output.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{Plugins, RuleSource};
+    fn policy() -> Policy {
+        let sources: Vec<RuleSource> = [
+            "ssh: port=22 client=10.0.0.0/8 => allow",
+            "no-ssh: port=22 => deny",
+            "port=1-1024 => allow",
+        ]
+        .iter()
+        .map(|text| RuleSource {
+            origin: "test".to_string(),
+            text: text.to_string(),
+        })
+        .collect();
+        Policy::parse(&sources, Plugins::default()).unwrap()
+    }
+    fn options(args: &[&str]) -> Options {
+        let (options, rest) = Options::from_args(args.iter().map(|s| s.to_string())).unwrap();
+        assert!(rest.is_empty());
+        options
+    }
+    #[tokio::test]
+    async fn test_text_report() {
+        let options = options(&[
+            "--client",
+            "10.1.2.3",
+            "--target",
+            "example.com:22",
+            "--user",
+            "alice",
+        ]);
+        let mut output = vec![];
+        run(&policy(), &options, &b""[..], &mut output)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "target: example.com:22\nclient: 10.1.2.3\nuser: alice\n\
+             matched rules: #1 (ssh), #2 (no-ssh), #3\naccess: allow by rule #1 (ssh)\n\
+             route: direct\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_bulk_json_report() {
+        let options = options(&["--client", "192.168.0.1", "--json"]);
+        let input = &b"example.com:22\n\n# comment\nexample.com\nexample.com:8443\n"[..];
+        let mut output = vec![];
+        run(&policy(), &options, input, &mut output).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<json::Value> = output.lines().map(|l| json::parse(l).unwrap()).collect();
+        let summary: Vec<_> = lines
+            .iter()
+            .map(|line| {
+                let field = |name| line.get(name).and_then(json::Value::as_str).unwrap();
+                (field("target"), field("access"), field("access_rule"))
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("example.com:22", "deny(403)", "#2 (no-ssh)"),
+                ("example.com", "allow", "#3"),
+                ("example.com:8443", "allow", "default"),
+            ]
+        );
+        assert_eq!(lines[0].get("user"), Some(&json::Value::Null));
+    }
+    #[test]
+    fn test_options_pass_other_flags_through() {
+        let (options, rest) = Options::from_args(
+            ["--rule", "=> deny", "--json", "--client", "::1"]
+                .iter()
+                .map(|s| s.to_string()),
+        )
+        .unwrap();
+        assert_eq!(rest, ["--rule", "=> deny"]);
+        assert!(options.json);
+        assert_eq!(options.client, "::1".parse::<IpAddr>().unwrap());
+        assert!(Options::from_args(["--client", "nope"].iter().map(|s| s.to_string())).is_err());
+    }
+}
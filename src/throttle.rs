@@ -0,0 +1,159 @@
+// Bandwidth shaping for `--limit-up` and `--limit-down`: a token bucket in
+// front of the socket each direction writes to. A write is accepted only as
+// far as the bucket allows and the rest waits for it to refill, so a large
+// write is split up and delayed rather than buffered.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{self, AsyncWrite};
+use tokio::time::{Instant, Sleep};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    // Bytes per second.
+    pub rate: u64,
+    // How many bytes may go out at once after an idle spell.
+    pub burst: u64,
+}
+
+struct Bucket {
+    limit: Limit,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(limit: Limit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            refilled: now,
+        }
+    }
+
+    // How many of `want` bytes may be written now, or how long until they
+    // may. Waits for as many tokens as the write can use, up to a full
+    // bucket, rather than trickling out a few bytes at a time.
+    fn available(&mut self, now: Instant, want: usize) -> Result<usize, Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate as f64).min(self.limit.burst as f64);
+        self.refilled = now;
+        let needed = (want as f64).min(self.limit.burst as f64);
+        if self.tokens >= needed {
+            return Ok((self.tokens as usize).min(want));
+        }
+        let wait = (needed - self.tokens) / self.limit.rate as f64;
+        Err(Duration::from_secs_f64(wait))
+    }
+
+    fn take(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+}
+
+pub struct ThrottledWriter<W> {
+    inner: W,
+    // None writes straight through.
+    bucket: Option<Bucket>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> ThrottledWriter<W> {
+    pub fn new(inner: W, limit: Option<Limit>) -> Self {
+        Self {
+            inner,
+            bucket: limit.map(|limit| Bucket::new(limit, Instant::now())),
+            sleep: None,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ThrottledWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let Self {
+            inner,
+            bucket,
+            sleep,
+        } = self.get_mut();
+        let Some(bucket) = bucket.as_mut().filter(|_| !buf.is_empty()) else {
+            return Pin::new(inner).poll_write(cx, buf);
+        };
+        loop {
+            if let Some(timer) = sleep {
+                ready!(timer.as_mut().poll(cx));
+                *sleep = None;
+            }
+            match bucket.available(Instant::now(), buf.len()) {
+                Ok(n) => {
+                    let written = ready!(Pin::new(&mut *inner).poll_write(cx, &buf[..n]))?;
+                    bucket.take(written);
+                    return Poll::Ready(Ok(written));
+                }
+                Err(wait) => *sleep = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let limit = Limit {
+            rate: 1000,
+            burst: 500,
+        };
+        let mut bucket = Bucket::new(limit, start);
+        // A full bucket lets a burst through at once.
+        assert_eq!(bucket.available(start, 800), Ok(500));
+        bucket.take(500);
+        assert_eq!(
+            bucket.available(start, 100),
+            Err(Duration::from_millis(100))
+        );
+        let later = start + Duration::from_millis(50);
+        assert_eq!(bucket.available(later, 100), Err(Duration::from_millis(50)));
+        let later = start + Duration::from_millis(100);
+        assert_eq!(bucket.available(later, 100), Ok(100));
+        // An idle spell refills no more than the burst.
+        let idle = start + Duration::from_secs(60);
+        assert_eq!(bucket.available(idle, 10_000), Ok(500));
+    }
+    #[tokio::test]
+    async fn test_writes_are_delayed_not_changed() {
+        let data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        let limit = Limit {
+            rate: 256 * 1024,
+            burst: 16 * 1024,
+        };
+        let mut writer = ThrottledWriter::new(vec![], Some(limit));
+        let start = std::time::Instant::now();
+        writer.write_all(&data).await.unwrap();
+        let elapsed = start.elapsed();
+        // 16 KiB at once, then 48 KiB at 256 KiB/s.
+        assert!(elapsed >= Duration::from_millis(180), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+        assert_eq!(writer.inner, data);
+
+        let mut unlimited = ThrottledWriter::new(vec![], None);
+        unlimited.write_all(&data).await.unwrap();
+        assert_eq!(unlimited.inner, data);
+    }
+}
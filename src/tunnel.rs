@@ -0,0 +1,1128 @@
+// Both directions of an established tunnel, copied until both sides are
+// done, with every chunk handed to the recorders and the other taps.
+
+use crate::connection_error::{ConnectionContext, Peer, Stage, Tails, millis};
+use crate::throttle::{self, ThrottledWriter};
+use crate::{
+    capture, debug_dump, fault, har, intercept, log, metrics, netlog, pcap, recorder, status,
+    websocket,
+};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const PIPE_BUFFER_SIZE: usize = 8 * 1024;
+// When the recorders only count, nothing holds on to the chunks and a larger
+// buffer takes fewer reads and writes per byte.
+const COUNTING_BUFFER_SIZE: usize = 64 * 1024;
+
+// Where a tunnel reports its bytes, besides the recorders.
+pub struct Taps<'a> {
+    pub netlog: &'a netlog::Source<'a>,
+    pub pcap: Option<&'a pcap::Flow<'a>>,
+    pub metrics: &'a metrics::Metrics,
+    pub dump: &'a debug_dump::Dump,
+    // The start of what the target sends, for the HAR entry.
+    pub response_head: Option<&'a har::HeadCapture>,
+    pub websocket: Option<&'a websocket::Decoder>,
+    // The tunnel's entry on the admin endpoint.
+    pub status: Option<&'a status::Tunnel>,
+    pub interceptors: Option<&'a intercept::Chain>,
+    pub faults: Option<&'a fault::Injector>,
+    // A forwarded request's tunnel, whose client is answered 413 for going
+    // over the byte limit while the target has sent nothing.
+    pub forwarded: bool,
+}
+
+// `--max-tunnel-bytes`: how much a tunnel may carry, in both directions
+// together or in each on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteLimit {
+    pub bytes: u64,
+    pub each_direction: bool,
+}
+
+// What a tunnel is held to, from the configuration.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub idle: Option<Duration>,
+    // Bytes per second towards the target and towards the client.
+    pub up: Option<throttle::Limit>,
+    pub down: Option<throttle::Limit>,
+    // The last bytes of each direction kept for a failure's log.
+    pub error_tail: usize,
+    pub max_bytes: Option<ByteLimit>,
+    // How far a capture file may fall behind its direction before the
+    // tunnel waits for the disk to catch up.
+    pub record_buffer: Option<usize>,
+}
+
+// Forwards one direction of the tunnel. Each chunk is offered to the
+// interceptors and the fault injector, then, once the recorder's capture
+// sink has room for it, handed to the recorder and written straight on to
+// the destination; when the source reaches EOF the destination's write half
+// is shut down. Errors name the peer whose socket failed. A destination that
+// cannot be written to fails the recorder, so its readers stop as well. A
+// chunk that takes the recorder over its byte budget is neither recorded nor
+// forwarded, and ends the tunnel.
+async fn pipe<R, W>(
+    mut source: R,
+    mut destination: W,
+    recorder: &recorder::Recorder,
+    taps: &Taps<'_>,
+    index: Option<&capture::Index>,
+    stage: Stage,
+    buffer_size: usize,
+) -> Result<(), (Stage, Peer, io::Error)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let up = stage == Stage::TunnelC2s;
+    let (from, to) = if up {
+        (Peer::Client, Peer::Target)
+    } else {
+        (Peer::Target, Peer::Client)
+    };
+    let bytes = if up {
+        &taps.metrics.bytes_up
+    } else {
+        &taps.metrics.bytes_down
+    };
+    let direction = if up { "c2s" } else { "s2c" };
+    let mut buf = vec![0; buffer_size];
+    let mut intercepted;
+    loop {
+        let n = source.read(&mut buf).await.map_err(|e| (stage, from, e))?;
+        if n == 0 {
+            taps.dump.event(|| format!("{direction} eof"));
+            recorder.close();
+            if let Some(flow) = taps.pcap {
+                flow.fin(up);
+            }
+            if let Err(e) = destination.shutdown().await {
+                recorder.fail(&e);
+                return Err((Stage::Shutdown, to, e));
+            }
+            return Ok(());
+        }
+        let chunk = match taps.interceptors {
+            None => &mut buf[..n],
+            Some(chain) => {
+                intercepted = buf[..n].to_vec();
+                match chain
+                    .offer(up, &mut intercepted)
+                    .map_err(|e| (stage, from, e))?
+                {
+                    intercept::Action::Forward if intercepted.is_empty() => continue,
+                    intercept::Action::Forward => &mut intercepted[..],
+                    intercept::Action::Drop => continue,
+                    intercept::Action::Abort(reason) => {
+                        taps.dump.event(|| format!("{direction} aborted: {reason}"));
+                        let e = format!("aborted by an interceptor: {reason}");
+                        return Err((stage, from, io::Error::new(ErrorKind::PermissionDenied, e)));
+                    }
+                }
+            }
+        };
+        // What is left of a chunk a fault closes the tunnel in is still sent.
+        let (chunk, closed_at) = match taps.faults {
+            None => (chunk, None),
+            Some(injector) => {
+                let effect = injector.apply(up, chunk);
+                if !effect.delay.is_zero() {
+                    tokio::time::sleep(effect.delay).await;
+                }
+                match effect.close {
+                    Some((keep, at)) => (&mut chunk[..keep], Some(at)),
+                    None => (chunk, None),
+                }
+            }
+        };
+        let closed = |at| {
+            taps.dump
+                .event(|| format!("{direction} closed by a fault at byte {at}"));
+            let e = format!("closed by fault injection at byte {at}");
+            Err((stage, from, io::Error::new(ErrorKind::ConnectionAborted, e)))
+        };
+        if let Some(at) = closed_at.filter(|_| chunk.is_empty()) {
+            return closed(at);
+        }
+        let chunk = &*chunk;
+        let n = chunk.len();
+        if let Some(limit) = recorder.charge(n) {
+            taps.dump
+                .event(|| format!("{direction} over the byte limit"));
+            let e = format!("tunnel byte limit of {limit} exceeded");
+            return Err((stage, from, io::Error::new(ErrorKind::QuotaExceeded, e)));
+        }
+        recorder.reserve().await.map_err(|e| (stage, to, e))?;
+        recorder.append(chunk);
+        if let Some(index) = index {
+            index.chunk(up, n);
+        }
+        taps.dump.event(|| {
+            format!(
+                "{direction} chunk: {n} bytes, {} recorded",
+                recorder.bytes_total()
+            )
+        });
+        log::trace!("{direction} chunk: {n} bytes");
+        taps.netlog.bytes(up, chunk);
+        if let Some(flow) = taps.pcap {
+            flow.data(up, chunk);
+        }
+        if let Some(head) = taps.response_head.filter(|_| !up) {
+            head.observe(chunk);
+        }
+        if let Some(decoder) = taps.websocket {
+            decoder.observe(up, chunk);
+        }
+        if let Some(tunnel) = taps.status {
+            tunnel.transferred(up, n);
+        }
+        taps.metrics.buffered.fetch_add(n as u64, Ordering::Relaxed);
+        let written = destination.write_all(chunk).await;
+        taps.metrics.buffered.fetch_sub(n as u64, Ordering::Relaxed);
+        if let Err(e) = written {
+            recorder.fail(&e);
+            return Err((stage, to, e));
+        }
+        bytes.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(at) = closed_at {
+            return closed(at);
+        }
+    }
+}
+
+// What the tunnel recorders keep.
+pub enum Recording {
+    Counting,
+    Memory,
+    // Client-to-server and server-to-client capture files, and their index.
+    Files(Box<capture::Capture>),
+}
+
+// Waits for the capture files to be written out, then writes the index. An
+// aborted tunnel's files end where the tunnel failed, which is not an error
+// of its own.
+// A tunnel's recorders, and the capture files written from them, seen to
+// an end however the tunnel ends. A tunnel dropped before it is done, with
+// its connection, aborts them, and its files are finished by a task of
+// their own.
+struct Captures {
+    recorders: [Arc<recorder::Recorder>; 2],
+    sinks: Vec<tokio::task::JoinHandle<io::Result<u64>>>,
+    index: Option<Arc<capture::Index>>,
+    finished: bool,
+}
+
+impl Captures {
+    async fn finish(mut self) {
+        self.finished = true;
+        let sinks = std::mem::take(&mut self.sinks);
+        finish_captures(sinks, self.index.as_deref()).await;
+    }
+}
+
+impl Drop for Captures {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        for recorder in &self.recorders {
+            recorder.abort();
+        }
+        let (sinks, index) = (std::mem::take(&mut self.sinks), self.index.take());
+        if (!sinks.is_empty() || index.is_some())
+            && let Ok(runtime) = tokio::runtime::Handle::try_current()
+        {
+            runtime.spawn(async move { finish_captures(sinks, index.as_deref()).await });
+        }
+    }
+}
+
+// Resolves once the tunnel is closed from the admin endpoint.
+async fn cancelled(status: Option<&status::Tunnel>) {
+    match status {
+        Some(tunnel) => tunnel.cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn finish_captures(
+    sinks: Vec<tokio::task::JoinHandle<io::Result<u64>>>,
+    index: Option<&capture::Index>,
+) {
+    for sink in sinks {
+        match sink.await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) if e.kind() == ErrorKind::ConnectionAborted => {}
+            Ok(Err(e)) => log::warn!("Capture write failed: {e}"),
+            Err(e) => log::warn!("Capture task failed: {e}"),
+        }
+    }
+    if let Some(index) = index
+        && let Err(e) = index.write().await
+    {
+        log::warn!("Capture index write failed: {e}");
+    }
+}
+
+// Resolves once neither recorder has seen a chunk for `timeout`.
+async fn idle_timeout(timeout: Option<Duration>, recorders: [&recorder::Recorder; 2]) -> io::Error {
+    let Some(timeout) = timeout else {
+        return std::future::pending().await;
+    };
+    loop {
+        let last = recorders.map(|recorder| recorder.last_activity());
+        let deadline = last[0].max(last[1]) + timeout;
+        if tokio::time::Instant::now() >= deadline {
+            let e = format!("no data in either direction for {timeout:?}");
+            return io::Error::new(ErrorKind::TimedOut, e);
+        }
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+/// What a finished tunnel carried, and how it ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunnelStats {
+    pub duration: Duration,
+    // When the first byte from the target arrived, if it sent any.
+    pub first_byte_at: Option<Instant>,
+    // From the start of the tunnel, when the client had been told it was
+    // up, to `first_byte_at`.
+    pub ttfb: Option<Duration>,
+    // The connection's, if it got that far before the tunnel.
+    pub request_read: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    // Whose side ended first, by closing or failing; None for a tunnel torn
+    // down as idle.
+    pub closed_first: Option<Peer>,
+}
+
+impl TunnelStats {
+    // "up=12.3KiB down=1.2MiB duration=8.4s closed_first=client
+    // request_read=0.4ms connect=12.1ms ttfb=80.2ms", with `none` for a
+    // time not taken.
+    fn summary(&self) -> String {
+        let closed_first = match self.closed_first {
+            Some(Peer::Client) => "client",
+            Some(Peer::Target) => "target",
+            None => "none",
+        };
+        let took = |duration: Option<Duration>| duration.map_or("none".to_string(), millis);
+        format!(
+            "up={} down={} duration={:.1}s closed_first={closed_first} request_read={} \
+             connect={} ttfb={}",
+            human_bytes(self.bytes_up),
+            human_bytes(self.bytes_down),
+            self.duration.as_secs_f64(),
+            took(self.request_read),
+            took(self.connect),
+            took(self.ttfb)
+        )
+    }
+}
+
+// Bytes in the largest binary unit that keeps the number at least 1.
+fn human_bytes(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{bytes}B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    for unit in ["KiB", "MiB", "GiB"] {
+        if value < 1024.0 {
+            return format!("{value:.1}{unit}");
+        }
+        value /= 1024.0;
+    }
+    format!("{value:.1}TiB")
+}
+
+/// Forwards between `client` and `target`, as the proxy does an established
+/// tunnel, until both have closed. `peer` is the client's address for the
+/// error a failed tunnel returns. Nothing is recorded beyond the byte counts
+/// and the last bytes of each direction.
+pub async fn forward<C, T>(client: C, target: T, peer: SocketAddr) -> io::Result<TunnelStats>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let netlog = netlog::Source::disabled();
+    let taps = Taps {
+        netlog: &netlog,
+        pcap: None,
+        metrics: &metrics::Metrics::default(),
+        dump: &debug_dump::Dump::disabled(),
+        response_head: None,
+        websocket: None,
+        status: None,
+        interceptors: None,
+        faults: None,
+        forwarded: false,
+    };
+    let limits = Limits {
+        error_tail: recorder::TAIL_SIZE,
+        ..Limits::default()
+    };
+    let ctx = ConnectionContext::new(peer);
+    forward_streams(
+        client,
+        target,
+        b"",
+        &ctx,
+        &taps,
+        Recording::Counting,
+        limits,
+    )
+    .await
+}
+
+// `first` is sent to the target ahead of whatever the client sends next.
+pub async fn forward_streams<C, T>(
+    mut client_stream: C,
+    mut target_stream: T,
+    first: &[u8],
+    ctx: &ConnectionContext,
+    taps: &Taps<'_>,
+    recording: Recording,
+    limits: Limits,
+) -> io::Result<TunnelStats>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let start = Instant::now();
+    let (client_reader, client_writer) = io::split(&mut client_stream);
+    let (target_reader, target_writer) = io::split(&mut target_stream);
+
+    let buffer_size = match recording {
+        Recording::Counting => COUNTING_BUFFER_SIZE,
+        _ => PIPE_BUFFER_SIZE,
+    };
+    let budget = |bytes| Some(Arc::new(recorder::Budget::new(bytes)));
+    let (up_budget, down_budget) = match limits.max_bytes {
+        Some(limit) if limit.each_direction => (budget(limit.bytes), budget(limit.bytes)),
+        Some(limit) => {
+            let shared = budget(limit.bytes);
+            (shared.clone(), shared)
+        }
+        None => (None, None),
+    };
+    let new_recorder = |budget: Option<Arc<recorder::Budget>>| {
+        let recorder = match recording {
+            Recording::Counting => recorder::Recorder::counting(),
+            _ => recorder::Recorder::new(),
+        };
+        let recorder = recorder.with_tail(limits.error_tail);
+        let recorder = match limits.record_buffer {
+            Some(capacity) => recorder.with_capacity(capacity),
+            None => recorder,
+        };
+        Arc::new(match budget {
+            Some(budget) => recorder.with_budget(budget),
+            None => recorder,
+        })
+    };
+    let client_to_server_recorder = new_recorder(up_budget);
+    let server_to_client_recorder = new_recorder(down_budget);
+    if let Some(tunnel) = taps.status {
+        tunnel.set_recorders(&client_to_server_recorder, &server_to_client_recorder);
+    }
+    let (sinks, index) = match recording {
+        Recording::Files(capture) => {
+            let capture::Capture { c2s, s2c, index } = *capture;
+            let sinks = vec![
+                client_to_server_recorder.attach_sink(c2s),
+                server_to_client_recorder.attach_sink(s2c),
+            ];
+            (sinks, Some(Arc::new(index)))
+        }
+        _ => (vec![], None),
+    };
+    let captures = Captures {
+        recorders: [
+            client_to_server_recorder.clone(),
+            server_to_client_recorder.clone(),
+        ],
+        sinks,
+        index,
+        finished: false,
+    };
+    let index = captures.index.clone();
+
+    // Each direction runs until its source is done and then shuts down its
+    // destination's write half, so a peer that half-closes still gets all of
+    // the other side's answer. A failure ends the failed direction and the
+    // one writing to the peer whose socket failed, which has nowhere left to
+    // deliver to, while the other one carries on; the first failure is the
+    // tunnel's. The idle timeout ends both; a tunnel torn down by it has no
+    // peer to blame. So does a close from the admin endpoint, which ends the
+    // recorded streams where they are, as though both peers had closed.
+    // Nothing is left running once this returns.
+    let mut closed_first = None;
+    let tunnel = async {
+        let c2s = pipe(
+            first.chain(client_reader),
+            ThrottledWriter::new(target_writer, limits.up),
+            &client_to_server_recorder,
+            taps,
+            index.as_deref(),
+            Stage::TunnelC2s,
+            buffer_size,
+        );
+        let s2c = pipe(
+            target_reader,
+            ThrottledWriter::new(client_writer, limits.down),
+            &server_to_client_recorder,
+            taps,
+            index.as_deref(),
+            Stage::TunnelS2c,
+            buffer_size,
+        );
+        tokio::pin!(c2s, s2c);
+        let (mut c2s_done, mut s2c_done) = (false, false);
+        let mut failure = None;
+        while !(c2s_done && s2c_done) {
+            let (source, result) = tokio::select! {
+                result = &mut c2s, if !c2s_done => {
+                    c2s_done = true;
+                    (Peer::Client, result)
+                }
+                result = &mut s2c, if !s2c_done => {
+                    s2c_done = true;
+                    (Peer::Target, result)
+                }
+            };
+            match result {
+                Ok(()) => {
+                    closed_first.get_or_insert(source);
+                }
+                Err((stage, peer, e)) => {
+                    match peer {
+                        Peer::Client => s2c_done = true,
+                        Peer::Target => c2s_done = true,
+                    }
+                    closed_first.get_or_insert(peer);
+                    match failure {
+                        None => failure = Some((stage, Some(peer), e)),
+                        Some(_) => log::debug!("{stage:?} failed as well: {e}"),
+                    }
+                }
+            }
+        }
+        failure.map_or(Ok(()), Err)
+    };
+    let recorders = [&*client_to_server_recorder, &*server_to_client_recorder];
+    let mut closed_by_admin = false;
+    let result = tokio::select! {
+        result = tunnel => result,
+        e = idle_timeout(limits.idle, recorders) => Err((Stage::Idle, None, e)),
+        () = cancelled(taps.status) => {
+            closed_by_admin = true;
+            client_to_server_recorder.close();
+            server_to_client_recorder.close();
+            Ok(())
+        }
+    };
+    let target = ctx.rewritten_to.as_ref().or(ctx.target.as_ref());
+    let target = target.map_or("-", String::as_str);
+    let stats = TunnelStats {
+        duration: start.elapsed(),
+        first_byte_at: server_to_client_recorder.first_append_at(),
+        ttfb: server_to_client_recorder
+            .first_append_at()
+            .map(|at| at.saturating_duration_since(start)),
+        request_read: ctx.request_read,
+        connect: ctx.connect,
+        bytes_up: client_to_server_recorder.bytes_total(),
+        bytes_down: server_to_client_recorder.bytes_total(),
+        closed_first: match &result {
+            Err((_, None, _)) => None,
+            _ if closed_by_admin => None,
+            _ => closed_first,
+        },
+    };
+    let evictions = client_to_server_recorder.evictions() + server_to_client_recorder.evictions();
+    taps.metrics
+        .evictions
+        .fetch_add(evictions, Ordering::Relaxed);
+    if let Err((stage, peer, e)) = result {
+        log::info!("Tunnel failed: {target} {} error={e}", stats.summary());
+        client_to_server_recorder.abort();
+        server_to_client_recorder.abort();
+        if e.kind() == ErrorKind::QuotaExceeded
+            && taps.forwarded
+            && server_to_client_recorder.bytes_total() == 0
+        {
+            let _ = crate::client::send_error(&mut client_stream, 413, "Payload Too Large\n").await;
+        }
+        // Best effort: the failed socket usually cannot be shut down, and the
+        // healthy peer learns of the close either way.
+        let _ = client_stream.shutdown().await;
+        let _ = target_stream.shutdown().await;
+        captures.finish().await;
+        let (up, down) = (stats.bytes_up, stats.bytes_down);
+        let tails = Tails {
+            up: client_to_server_recorder.snapshot_tail(limits.error_tail),
+            down: server_to_client_recorder.snapshot_tail(limits.error_tail),
+        };
+        log::debug!(
+            "Last bytes of the failed tunnel to {target}, from the client:\n{}from the target:\n{}",
+            recorder::hexdump(&tails.up),
+            recorder::hexdump(&tails.down)
+        );
+        return Err(match peer {
+            Some(peer) => ctx.fail_tunnel(stage, peer, e, up, down, tails),
+            None => ctx.fail_idle(e, up, down, tails),
+        });
+    }
+    captures.finish().await;
+    if closed_by_admin {
+        log::info!(
+            "Tunnel closed from the admin endpoint: {target} {}",
+            stats.summary()
+        );
+        taps.dump
+            .event(|| "closed from the admin endpoint".to_string());
+    } else {
+        log::info!("Tunnel closed: {target} {}", stats.summary());
+    }
+    taps.dump.event(|| {
+        format!(
+            "recorder contention: c2s {:?}, s2c {:?}",
+            client_to_server_recorder.contention(),
+            server_to_client_recorder.contention()
+        )
+    });
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_error;
+    #[tokio::test]
+    async fn test_pipe_records_forwarded_bytes() {
+        let (mut client, source) = io::duplex(64);
+        let (destination, mut target) = io::duplex(64);
+        let recorder = Arc::new(recorder::Recorder::new());
+        let mut subscriber = recorder::RecorderReader::new(recorder.clone());
+        let payload: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+
+        let forward = tokio::spawn({
+            let recorder = recorder.clone();
+            async move {
+                let netlog = netlog::Source::disabled();
+                let taps = Taps {
+                    netlog: &netlog,
+                    pcap: None,
+                    metrics: &metrics::Metrics::default(),
+                    dump: &debug_dump::Dump::disabled(),
+                    response_head: None,
+                    websocket: None,
+                    status: None,
+                    interceptors: None,
+                    faults: None,
+                    forwarded: false,
+                };
+                pipe(
+                    source,
+                    destination,
+                    &recorder,
+                    &taps,
+                    None,
+                    Stage::TunnelC2s,
+                    PIPE_BUFFER_SIZE,
+                )
+                .await
+            }
+        });
+        let sent = payload.clone();
+        let write = tokio::spawn(async move {
+            client.write_all(&sent).await.unwrap();
+            client.shutdown().await.unwrap();
+        });
+        let mut forwarded = vec![];
+        target.read_to_end(&mut forwarded).await.unwrap();
+        write.await.unwrap();
+        forward.await.unwrap().unwrap();
+        assert_eq!(forwarded, payload);
+
+        let mut recorded = vec![0; payload.len()];
+        subscriber.read_exact(&mut recorded).await.unwrap();
+        assert_eq!(recorded, payload);
+    }
+
+    #[test]
+    fn test_human_bytes() {
+        assert_eq!(human_bytes(0), "0B");
+        assert_eq!(human_bytes(1023), "1023B");
+        assert_eq!(human_bytes(12_595), "12.3KiB");
+        assert_eq!(human_bytes(1_258_291), "1.2MiB");
+        assert_eq!(human_bytes(5 << 30), "5.0GiB");
+        assert_eq!(human_bytes(3 << 40), "3.0TiB");
+    }
+
+    async fn forward<C, T>(client: C, target: T, first: &[u8]) -> io::Result<TunnelStats>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        forward_intercepted(client, target, first, None, None).await
+    }
+
+    async fn forward_intercepted<C, T>(
+        client: C,
+        target: T,
+        first: &[u8],
+        interceptors: Option<&intercept::Chain>,
+        faults: Option<&fault::Injector>,
+    ) -> io::Result<TunnelStats>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let netlog = netlog::Source::disabled();
+        let taps = Taps {
+            netlog: &netlog,
+            pcap: None,
+            metrics: &metrics::Metrics::default(),
+            dump: &debug_dump::Dump::disabled(),
+            response_head: None,
+            websocket: None,
+            status: None,
+            interceptors,
+            faults,
+            forwarded: false,
+        };
+        let ctx = ConnectionContext::new("127.0.0.1:1".parse().unwrap());
+        let recording = Recording::Counting;
+        forward_streams(
+            client,
+            target,
+            first,
+            &ctx,
+            &taps,
+            recording,
+            Limits::default(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_forward_streams_returns_stats() {
+        let (mut client, client_side) = io::duplex(1024);
+        let (target_side, mut target) = io::duplex(1024);
+        let request: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let reply: Vec<u8> = (0..70_000).map(|i| (i * 7) as u8).collect();
+        let forward = tokio::spawn(forward(client_side, target_side, b"hi"));
+        let sent = request.clone();
+        let client_task = tokio::spawn(async move {
+            client.write_all(&sent).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut received = vec![];
+            client.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let mut forwarded = vec![];
+        target.read_to_end(&mut forwarded).await.unwrap();
+        assert_eq!(forwarded, [&b"hi"[..], &request].concat());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        target.write_all(&reply).await.unwrap();
+        target.shutdown().await.unwrap();
+        assert_eq!(client_task.await.unwrap(), reply);
+        let stats = forward.await.unwrap().unwrap();
+        assert_eq!(stats.bytes_up, 3002);
+        assert_eq!(stats.bytes_down, 70_000);
+        assert_eq!(stats.closed_first, Some(Peer::Client));
+        assert!(stats.duration >= Duration::from_millis(50));
+        assert!(stats.first_byte_at.is_some());
+        assert!(stats.ttfb.unwrap() >= Duration::from_millis(50));
+        assert_eq!(
+            stats.summary(),
+            format!(
+                "up=2.9KiB down=68.4KiB duration={:.1}s closed_first=client request_read=none \
+                 connect=none ttfb={}",
+                stats.duration.as_secs_f64(),
+                millis(stats.ttfb.unwrap())
+            )
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout_follows_tokio_time() {
+        let (mut client, client_side) = io::duplex(1024);
+        let (target_side, mut target) = io::duplex(1024);
+        let start = tokio::time::Instant::now();
+        let forwarding = tokio::spawn(async move {
+            let netlog = netlog::Source::disabled();
+            let taps = Taps {
+                netlog: &netlog,
+                pcap: None,
+                metrics: &metrics::Metrics::default(),
+                dump: &debug_dump::Dump::disabled(),
+                response_head: None,
+                websocket: None,
+                status: None,
+                interceptors: None,
+                faults: None,
+                forwarded: false,
+            };
+            let ctx = ConnectionContext::new("127.0.0.1:1".parse().unwrap());
+            let limits = Limits {
+                idle: Some(Duration::from_secs(30)),
+                ..Limits::default()
+            };
+            forward_streams(
+                client_side,
+                target_side,
+                b"",
+                &ctx,
+                &taps,
+                Recording::Counting,
+                limits,
+            )
+            .await
+        });
+        // Traffic keeps the tunnel up past the timeout.
+        tokio::time::advance(Duration::from_secs(20)).await;
+        client.write_all(b"tick").await.unwrap();
+        let mut tick = [0; 4];
+        target.read_exact(&mut tick).await.unwrap();
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert!(!forwarding.is_finished());
+        // Then nothing for the timeout, which the paused clock skips over
+        // rather than the tunnel waiting on the wall clock.
+        let error = tokio::time::timeout(Duration::from_secs(60), forwarding)
+            .await
+            .expect("not timed out on tokio's clock")
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(50) && elapsed < Duration::from_secs(51));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_tunnel_finishes_its_capture() {
+        let dir = std::env::temp_dir().join(format!("proxy-dropped-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let capture = capture::create(&dir, "example.com", 80).await.unwrap();
+        let (mut client, client_side) = io::duplex(1024);
+        let (target_side, mut target) = io::duplex(1024);
+        let forwarding = tokio::spawn(async move {
+            let netlog = netlog::Source::disabled();
+            let taps = Taps {
+                netlog: &netlog,
+                pcap: None,
+                metrics: &metrics::Metrics::default(),
+                dump: &debug_dump::Dump::disabled(),
+                response_head: None,
+                websocket: None,
+                status: None,
+                interceptors: None,
+                faults: None,
+                forwarded: false,
+            };
+            let ctx = ConnectionContext::new("127.0.0.1:1".parse().unwrap());
+            let recording = Recording::Files(Box::new(capture));
+            let limits = Limits::default();
+            forward_streams(
+                client_side,
+                target_side,
+                b"",
+                &ctx,
+                &taps,
+                recording,
+                limits,
+            )
+            .await
+        });
+        client.write_all(b"ping").await.unwrap();
+        let mut ping = [0; 4];
+        target.read_exact(&mut ping).await.unwrap();
+        // As when the proxy drains and aborts its connections.
+        forwarding.abort();
+        let written = async {
+            loop {
+                let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+                let index = files
+                    .iter()
+                    .map(|entry| entry.as_ref().unwrap().path())
+                    .find(|path| path.extension() == Some(capture::INDEX_EXTENSION.as_ref()));
+                if let Some(index) = index {
+                    return index;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let index = tokio::time::timeout(Duration::from_secs(5), written)
+            .await
+            .unwrap();
+        let recorded = capture::parse_index(&std::fs::read_to_string(&index).unwrap()).unwrap();
+        assert_eq!(recorded.chunks.len(), 1);
+        let stem = index.file_stem().unwrap().to_string_lossy();
+        let c2s = std::fs::read(index.with_file_name(format!("{stem}-c2s.bin"))).unwrap();
+        assert_eq!(c2s, b"ping");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ttfb_counts_a_slow_target() {
+        let (mut client, client_side) = io::duplex(1024);
+        let (target_side, mut target) = io::duplex(1024);
+        let forwarding = tokio::spawn(forward(client_side, target_side, b"ping"));
+        let mut request = [0; 4];
+        target.read_exact(&mut request).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        target.write_all(b"pong").await.unwrap();
+        target.shutdown().await.unwrap();
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await.unwrap();
+        client.shutdown().await.unwrap();
+        let stats = forwarding.await.unwrap().unwrap();
+        assert!(stats.ttfb.unwrap() >= Duration::from_millis(200));
+        // A target that never answers has no time to first byte.
+        let (mut client, client_side) = io::duplex(1024);
+        let (target_side, mut target) = io::duplex(1024);
+        let forwarding = tokio::spawn(forward(client_side, target_side, b""));
+        target.shutdown().await.unwrap();
+        client.shutdown().await.unwrap();
+        let stats = forwarding.await.unwrap().unwrap();
+        assert_eq!(stats.ttfb, None);
+        assert!(stats.summary().ends_with(" ttfb=none"));
+    }
+
+    // Uppercases what the target sends.
+    struct Shouting;
+    impl intercept::Interceptor for Shouting {
+        fn on_client_data(&mut self, _: &mut Vec<u8>) -> io::Result<intercept::Action> {
+            Ok(intercept::Action::Forward)
+        }
+        fn on_server_data(&mut self, chunk: &mut Vec<u8>) -> io::Result<intercept::Action> {
+            chunk.make_ascii_uppercase();
+            chunk.extend_from_slice(b"!");
+            Ok(intercept::Action::Forward)
+        }
+    }
+    struct Shout;
+    impl intercept::Factory for Shout {
+        fn intercept(&self, _: &str) -> Option<Box<dyn intercept::Interceptor>> {
+            Some(Box::new(Shouting))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interceptors_change_and_abort_tunnels() {
+        let (mut client, client_side) = io::duplex(1024);
+        let (target_side, mut target) = io::duplex(1024);
+        let factories: Vec<Arc<dyn intercept::Factory>> = vec![
+            Arc::new(Shout),
+            Arc::new(intercept::BlockPattern(b"EVIL".to_vec())),
+        ];
+        let chain = intercept::Chain::open(&factories, "example.com:443").unwrap();
+        let tunnel = tokio::spawn(async move {
+            forward_intercepted(client_side, target_side, b"", Some(&chain), None).await
+        });
+        target.write_all(b"hello").await.unwrap();
+        let mut changed = [0; 6];
+        client.read_exact(&mut changed).await.unwrap();
+        assert_eq!(&changed, b"HELLO!");
+        // The pattern is split across two writes.
+        client.write_all(b"an EV").await.unwrap();
+        let mut forwarded = [0; 5];
+        target.read_exact(&mut forwarded).await.unwrap();
+        assert_eq!(&forwarded, b"an EV");
+        client.write_all(b"IL plan").await.unwrap();
+        let e = tunnel.await.unwrap().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        assert!(
+            e.to_string().contains("blocked pattern hex:4556494c"),
+            "{e}"
+        );
+        // Neither side gets anything more.
+        let mut rest = vec![];
+        target.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fault_closes_at_the_same_byte_every_run() {
+        let faults = fault::Faults {
+            seed: Some(3),
+            close_prob: 1.0,
+            ..fault::Faults::default()
+        };
+        let total = 2 * fault::CLOSE_WITHIN as usize;
+        let mut runs = vec![];
+        for _ in 0..3 {
+            let (mut client, client_side) = io::duplex(2 * total);
+            let (target_side, mut target) = io::duplex(2 * total);
+            let data: Vec<u8> = (0..total).map(|i| i as u8).collect();
+            client.write_all(&data).await.unwrap();
+            target.write_all(&data).await.unwrap();
+            let injector = faults.injector(1, 0);
+            let tunnel = forward_intercepted(client_side, target_side, b"", None, Some(&injector));
+            let e = tunnel.await.unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::ConnectionAborted);
+            let (mut up, mut down) = (vec![], vec![]);
+            target.read_to_end(&mut up).await.unwrap();
+            client.read_to_end(&mut down).await.unwrap();
+            // The closed direction got what came before the fault, whatever
+            // the other had carried by then.
+            let stage = connection_error::stage_of(&e).unwrap();
+            let closed = if stage == Stage::TunnelC2s { up } else { down };
+            assert!(closed.len() < fault::CLOSE_WITHIN as usize);
+            assert_eq!(closed, data[..closed.len()]);
+            let at = format!("closed by fault injection at byte {}", closed.len());
+            assert!(e.to_string().contains(&at), "{e}");
+            runs.push((stage, closed.len()));
+        }
+        assert!(runs.iter().all(|run| *run == runs[0]), "{runs:?}");
+    }
+
+    #[tokio::test]
+    async fn test_half_closed_client_gets_the_whole_answer() {
+        let (mut client, client_side) = io::duplex(64 * 1024);
+        let (target_side, mut target) = io::duplex(64 * 1024);
+        let tunnel = tokio::spawn(async move { forward(client_side, target_side, b"").await });
+        let reply: Vec<u8> = (0..1024 * 1024).map(|i| (i % 253) as u8).collect();
+        let expected = reply.clone();
+        // The target answers only once the request is complete, as an HTTP
+        // server reading a request to EOF would.
+        let server = tokio::spawn(async move {
+            let mut request = vec![];
+            target.read_to_end(&mut request).await.unwrap();
+            target.write_all(&reply).await.unwrap();
+            target.shutdown().await.unwrap();
+            request
+        });
+        client.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), expected.len());
+        assert!(received == expected);
+        assert_eq!(server.await.unwrap(), b"GET / HTTP/1.0\r\n\r\n");
+        let stats = tunnel.await.unwrap().unwrap();
+        assert_eq!(stats.closed_first, Some(Peer::Client));
+    }
+
+    // Reads come from the data; writes fail as to a peer that has gone away.
+    struct Unwritable(&'static [u8]);
+
+    impl AsyncRead for Unwritable {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::pin::Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Unwritable {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &[u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            std::task::Poll::Ready(Err(ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Err(ErrorKind::NotConnected.into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_direction_leaves_the_other_running() {
+        static REPLY: [u8; 1024 * 1024] = [9; 1024 * 1024];
+        let (mut client, client_side) = io::duplex(64 * 1024);
+        let tunnel =
+            tokio::spawn(async move { forward(client_side, Unwritable(&REPLY), b"request").await });
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        assert!(received == REPLY);
+        let error = tunnel.await.unwrap().unwrap_err();
+        assert_eq!(connection_error::stage_of(&error), Some(Stage::TunnelC2s));
+        assert_eq!(error.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn test_upload_to_a_closed_target_stops() {
+        let (mut client, client_side) = io::duplex(64 * 1024);
+        let (target_side, mut target) = io::duplex(1024);
+        let tunnel = tokio::spawn(async move { forward(client_side, target_side, b"").await });
+        let upload = tokio::spawn(async move {
+            let written = client.write_all(&vec![5; 16 * 1024 * 1024]).await;
+            (client, written)
+        });
+        // The target takes the first KiB without reading it, and closes.
+        let mut taken = [0; 1024];
+        target.read_exact(&mut taken).await.unwrap();
+        drop(target);
+        let error = tokio::time::timeout(Duration::from_secs(5), tunnel)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(connection_error::stage_of(&error), Some(Stage::TunnelC2s));
+        assert_eq!(error.kind(), ErrorKind::BrokenPipe);
+        let (_, written) = tokio::time::timeout(Duration::from_secs(5), upload)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(written.unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn test_failed_destination_fails_the_recorder() {
+        let recorder = recorder::Recorder::new();
+        let netlog = netlog::Source::disabled();
+        let taps = Taps {
+            netlog: &netlog,
+            pcap: None,
+            metrics: &metrics::Metrics::default(),
+            dump: &debug_dump::Dump::disabled(),
+            response_head: None,
+            websocket: None,
+            status: None,
+            interceptors: None,
+            faults: None,
+            forwarded: false,
+        };
+        let piped = pipe(
+            &b"request"[..],
+            Unwritable(b""),
+            &recorder,
+            &taps,
+            None,
+            Stage::TunnelC2s,
+            PIPE_BUFFER_SIZE,
+        )
+        .await;
+        assert_eq!(piped.unwrap_err().2.kind(), ErrorKind::BrokenPipe);
+        assert_eq!(recorder.failure().unwrap().kind(), ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,560 @@
+// Posts notable events to webhooks as JSON. Events are batched and each hook
+// has a rate limit, so a scanning client cannot turn into thousands of
+// requests; events beyond the bounded queues are dropped and counted.
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::{json, log};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Subscription {
+    // Any enforced denial, by a rule or the default.
+    AnyDenial,
+    // Denials by the rule with this name.
+    Denial(String),
+    ErrorRate,
+}
+
+impl Subscription {
+    fn parse(s: &str) -> Option<Self> {
+        match s.split_once(':') {
+            None if s == "deny" => Some(Subscription::AnyDenial),
+            None if s == "errors" => Some(Subscription::ErrorRate),
+            Some(("deny", name)) if !name.is_empty() => {
+                Some(Subscription::Denial(name.to_string()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// `http://host[:port][/path]`, split up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    fn parse(s: &str) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(ErrorKind::InvalidInput, msg);
+        if s.starts_with("https://") {
+            return Err(invalid(format!(
+                "{s}: https webhooks are not supported, this build has no TLS"
+            )));
+        }
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or_else(|| invalid(format!("{s}: expected an http:// URL")))?;
+        let (authority, path) = match rest.find('/') {
+            Some(at) => (&rest[..at], &rest[at..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| invalid(format!("{s}: invalid port")))?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid(format!("{s}: missing host")));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// A `--webhook EVENTS=URL` value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hook {
+    pub url: Url,
+    pub subscriptions: Vec<Subscription>,
+}
+
+impl Hook {
+    pub fn parse(s: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid --webhook value: {s} (expected EVENTS=URL)"),
+            )
+        };
+        let (events, url) = s.split_once('=').ok_or_else(invalid)?;
+        let subscriptions = events
+            .split(',')
+            .map(Subscription::parse)
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        Ok(Self {
+            url: Url::parse(url)?,
+            subscriptions,
+        })
+    }
+
+    fn wants(&self, event: &Event) -> bool {
+        self.subscriptions
+            .iter()
+            .any(|subscription| match (subscription, event) {
+                (Subscription::AnyDenial, Event::Denial { .. }) => true,
+                (Subscription::Denial(name), Event::Denial { rule_name, .. }) => {
+                    rule_name.as_deref() == Some(name.as_str())
+                }
+                (Subscription::ErrorRate, Event::ErrorRate { .. }) => true,
+                _ => false,
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Denial {
+        rule: String,
+        rule_name: Option<String>,
+        target: String,
+        client: String,
+        status: u16,
+    },
+    ErrorRate {
+        errors: usize,
+        window: Duration,
+    },
+}
+
+impl Event {
+    fn to_json(&self) -> String {
+        match self {
+            Event::Denial {
+                rule,
+                target,
+                client,
+                status,
+                ..
+            } => format!(
+                "{{\"event\":\"rule_denial\",\"rule\":{},\"target\":{},\"client\":{},\
+                 \"status\":{status}}}",
+                json::quote(rule),
+                json::quote(target),
+                json::quote(client)
+            ),
+            Event::ErrorRate { errors, window } => format!(
+                "{{\"event\":\"error_rate\",\"errors\":{errors},\"window_secs\":{}}}",
+                window.as_secs()
+            ),
+        }
+    }
+}
+
+/// How hard the hooks may be hit.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    // Events arriving within this of the first one go in the same post.
+    pub batch_window: Duration,
+    pub max_batch: usize,
+    // At most this many posts per hook per `rate_window`.
+    pub max_posts: usize,
+    pub rate_window: Duration,
+    pub retries: u32,
+    pub backoff: Duration,
+    pub timeout: Duration,
+    // Alert when this many connections fail within `error_window`.
+    pub error_threshold: usize,
+    pub error_window: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            batch_window: Duration::from_secs(1),
+            max_batch: 100,
+            max_posts: 10,
+            rate_window: Duration::from_secs(60),
+            retries: 3,
+            backoff: Duration::from_millis(500),
+            timeout: Duration::from_secs(5),
+            error_threshold: 50,
+            error_window: Duration::from_secs(60),
+        }
+    }
+}
+
+// Events per hook waiting for its task.
+const QUEUE_EVENTS: usize = 1000;
+
+pub struct Webhooks {
+    hooks: Vec<(Hook, mpsc::Sender<Event>)>,
+    limits: Limits,
+    // Failures within the error window, and whether it was already alerted.
+    errors: Mutex<(VecDeque<Instant>, bool)>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Webhooks {
+    /// Starts one task per hook.
+    pub fn start(hooks: Vec<Hook>, limits: Limits) -> Self {
+        let dropped = Arc::new(AtomicU64::new(0));
+        let hooks = hooks
+            .into_iter()
+            .map(|hook| {
+                let (sender, receiver) = mpsc::channel(QUEUE_EVENTS);
+                tokio::spawn(deliver(
+                    hook.url.clone(),
+                    receiver,
+                    limits.clone(),
+                    dropped.clone(),
+                ));
+                (hook, sender)
+            })
+            .collect();
+        Self {
+            hooks,
+            limits,
+            errors: Mutex::new((VecDeque::new(), false)),
+            dropped,
+        }
+    }
+
+    pub fn notify(&self, event: Event) {
+        for (hook, sender) in &self.hooks {
+            if hook.wants(&event) && sender.try_send(event.clone()).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Counts a failed connection, notifying when the failures within the
+    /// error window reach the threshold; not again until a whole window
+    /// passes without failures.
+    pub fn connection_failed(&self) {
+        let now = Instant::now();
+        let (window, threshold) = (self.limits.error_window, self.limits.error_threshold);
+        let alert = {
+            let mut errors = self.errors.lock().unwrap();
+            let (times, alerted) = &mut *errors;
+            while times
+                .front()
+                .is_some_and(|at| now.duration_since(*at) > window)
+            {
+                times.pop_front();
+            }
+            if times.is_empty() {
+                *alerted = false;
+            }
+            times.push_back(now);
+            let alert = times.len() >= threshold && !*alerted;
+            if alert {
+                *alerted = true;
+            }
+            alert.then_some(times.len())
+        };
+        if let Some(errors) = alert {
+            self.notify(Event::ErrorRate { errors, window });
+        }
+    }
+
+    /// Events dropped by full queues, full batches or failed posts.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+async fn deliver(
+    url: Url,
+    mut events: mpsc::Receiver<Event>,
+    limits: Limits,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut posts: VecDeque<Instant> = VecDeque::new();
+    let mut batch: Vec<Event> = vec![];
+    let mut dropped_in_batch = 0;
+    loop {
+        if batch.is_empty() {
+            match events.recv().await {
+                Some(event) => batch.push(event),
+                None => return,
+            }
+        }
+        let deadline = Instant::now() + limits.batch_window;
+        // Waits out the batch window, and the rate limit if it is reached,
+        // while collecting more events.
+        loop {
+            while posts
+                .front()
+                .is_some_and(|at| at.elapsed() >= limits.rate_window)
+            {
+                posts.pop_front();
+            }
+            let ready_at = match posts.front() {
+                Some(oldest) if posts.len() >= limits.max_posts => {
+                    deadline.max(*oldest + limits.rate_window)
+                }
+                _ => deadline,
+            };
+            if Instant::now() >= ready_at {
+                break;
+            }
+            match tokio::time::timeout_at(ready_at, events.recv()).await {
+                Ok(Some(event)) if batch.len() < limits.max_batch => batch.push(event),
+                Ok(Some(_)) => dropped_in_batch += 1,
+                Ok(None) => break,
+                Err(_) => {}
+            }
+        }
+        let body = format!(
+            "{{\"events\":[{}],\"dropped\":{dropped_in_batch}}}",
+            batch
+                .iter()
+                .map(Event::to_json)
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+        posts.push_back(Instant::now());
+        let mut backoff = limits.backoff;
+        let mut attempt = 0;
+        loop {
+            match tokio::time::timeout(limits.timeout, post(&url, &body)).await {
+                Ok(Ok(status)) if status < 500 => {
+                    if status >= 300 {
+                        log::warn!("webhook {}:{} answered {status}", url.host, url.port);
+                    }
+                    break;
+                }
+                result => {
+                    let error = match result {
+                        Ok(Ok(status)) => format!("status {status}"),
+                        Ok(Err(e)) => e.to_string(),
+                        Err(_) => "timed out".to_string(),
+                    };
+                    attempt += 1;
+                    if attempt > limits.retries {
+                        log::warn!(
+                            "webhook {}:{} failed, dropping {} events: {error}",
+                            url.host,
+                            url.port,
+                            batch.len()
+                        );
+                        dropped.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                        break;
+                    }
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        }
+        dropped.fetch_add(dropped_in_batch, Ordering::Relaxed);
+        batch.clear();
+        dropped_in_batch = 0;
+    }
+}
+
+// A minimal HTTP/1.1 POST; returns the response status.
+async fn post(url: &Url, body: &str) -> io::Result<u16> {
+    let mut stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        url.path,
+        url.host,
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = vec![];
+    let mut buf = [0; 1024];
+    // Only the status line is needed.
+    while !response.windows(2).any(|w| w == b"\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..n]);
+    }
+    let line = String::from_utf8_lossy(&response);
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed HTTP response"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // Answers with `statuses` in turn, then 200, sending each body it got.
+    async fn stub(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (bodies, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut statuses = statuses.into_iter();
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 4096];
+                let body = loop {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length: usize = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("Content-Length: "))
+                            .unwrap()
+                            .parse()
+                            .unwrap();
+                        if body.len() >= length {
+                            break body.to_string();
+                        }
+                    }
+                };
+                let status = statuses.next().unwrap_or(200);
+                let _ = socket
+                    .write_all(format!("HTTP/1.1 {status} X\r\n\r\n").as_bytes())
+                    .await;
+                bodies.send(body.to_string()).unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    fn denial(n: usize) -> Event {
+        Event::Denial {
+            rule: "#1 (ssh)".to_string(),
+            rule_name: Some("ssh".to_string()),
+            target: format!("host{n}:22"),
+            client: "10.0.0.1:5000".to_string(),
+            status: 403,
+        }
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            batch_window: Duration::from_millis(100),
+            backoff: Duration::from_millis(10),
+            ..Limits::default()
+        }
+    }
+
+    fn events(body: &str) -> usize {
+        match json::parse(body).unwrap().get("events") {
+            Some(json::Value::Array(events)) => events.len(),
+            _ => panic!("no events in {body}"),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let hook = Hook::parse("deny:ssh,errors=http://hooks.test:8080/notify?x=1").unwrap();
+        assert_eq!(
+            hook.url,
+            Url {
+                host: "hooks.test".to_string(),
+                port: 8080,
+                path: "/notify?x=1".to_string()
+            }
+        );
+        assert_eq!(
+            hook.subscriptions,
+            [
+                Subscription::Denial("ssh".to_string()),
+                Subscription::ErrorRate
+            ]
+        );
+        assert!(hook.wants(&denial(0)));
+        assert!(
+            !Hook::parse("deny:other=http://h/")
+                .unwrap()
+                .wants(&denial(0))
+        );
+        for s in [
+            "http://h/",
+            "bogus=http://h/",
+            "deny=https://h/",
+            "deny=h:80",
+        ] {
+            assert!(Hook::parse(s).is_err(), "{s}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batches_and_retries() {
+        let (url, mut bodies) = stub(vec![500]).await;
+        let webhooks =
+            Webhooks::start(vec![Hook::parse(&format!("deny={url}")).unwrap()], limits());
+        for n in 0..5 {
+            webhooks.notify(denial(n));
+        }
+        let failed = bodies.recv().await.unwrap();
+        let retried = bodies.recv().await.unwrap();
+        assert_eq!(failed, retried);
+        assert_eq!(events(&retried), 5);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), bodies.recv())
+                .await
+                .is_err()
+        );
+        assert_eq!(webhooks.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let (url, mut bodies) = stub(vec![]).await;
+        let limits = Limits {
+            max_posts: 2,
+            rate_window: Duration::from_millis(800),
+            ..limits()
+        };
+        let webhooks = Webhooks::start(vec![Hook::parse(&format!("deny={url}")).unwrap()], limits);
+        let start = Instant::now();
+        let mut posted = vec![];
+        for n in 0..20 {
+            webhooks.notify(denial(n));
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            while let Ok(body) = bodies.try_recv() {
+                posted.push((start.elapsed(), events(&body)));
+            }
+        }
+        while posted.iter().map(|(_, n)| n).sum::<usize>() < 20 {
+            let body = bodies.recv().await.unwrap();
+            posted.push((start.elapsed(), events(&body)));
+        }
+        // The third post waits for the first to leave the rate window.
+        assert_eq!(posted.len(), 3, "{posted:?}");
+        assert!(posted[2].0 >= Duration::from_millis(800), "{posted:?}");
+    }
+
+    #[tokio::test]
+    async fn test_error_rate_alerts_once_per_window() {
+        let (url, mut bodies) = stub(vec![]).await;
+        let limits = Limits {
+            error_threshold: 3,
+            ..limits()
+        };
+        let webhooks =
+            Webhooks::start(vec![Hook::parse(&format!("errors={url}")).unwrap()], limits);
+        for _ in 0..10 {
+            webhooks.connection_failed();
+        }
+        webhooks.notify(denial(0));
+        let body = bodies.recv().await.unwrap();
+        assert!(
+            body.contains("\"event\":\"error_rate\",\"errors\":3"),
+            "{body}"
+        );
+        assert_eq!(events(&body), 1);
+    }
+}
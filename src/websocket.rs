@@ -0,0 +1,480 @@
+// WebSocket frames (RFC 6455 section 5), for `--decode-websocket`. A tunnel
+// that turns out to carry an upgraded HTTP connection gets its messages
+// logged: text in full, client frames unmasked, other messages by length
+// and close frames with their code and reason. Anything else is left alone.
+
+use std::io::{self, ErrorKind};
+use std::sync::Mutex;
+
+use crate::log;
+use crate::request_line;
+
+// Frames longer than this are counted, not buffered.
+const MAX_PAYLOAD: usize = 1024 * 1024;
+// Handshake heads longer than this are not WebSocket upgrades.
+const MAX_HEAD: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_bits(bits: u8) -> io::Result<Self> {
+        Ok(match bits {
+            0x0 => Opcode::Continuation,
+            0x1 => Opcode::Text,
+            0x2 => Opcode::Binary,
+            0x8 => Opcode::Close,
+            0x9 => Opcode::Ping,
+            0xa => Opcode::Pong,
+            _ => return Err(malformed(&format!("reserved opcode {bits:#x}"))),
+        })
+    }
+
+    fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+fn malformed(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("WebSocket frame: {msg}"))
+}
+
+/// The fixed part of a frame, ahead of its payload.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Header {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub mask: Option<[u8; 4]>,
+    pub len: u64,
+}
+
+/// Parses the header at the start of `buf`, with its length in bytes, or
+/// None until all of it has arrived.
+pub fn parse_header(buf: &[u8]) -> io::Result<Option<(Header, usize)>> {
+    let [first, second, ..] = *buf else {
+        return Ok(None);
+    };
+    if first & 0x70 != 0 {
+        return Err(malformed("reserved bits set"));
+    }
+    let opcode = Opcode::from_bits(first & 0x0f)?;
+    let fin = first & 0x80 != 0;
+    let (len, mut used) = match second & 0x7f {
+        126 => match buf.get(2..4) {
+            Some(len) => (u16::from_be_bytes([len[0], len[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match buf.get(2..10) {
+            Some(len) => (u64::from_be_bytes(len.try_into().unwrap()), 10),
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+    if opcode.is_control() && (!fin || len > 125) {
+        return Err(malformed("fragmented or long control frame"));
+    }
+    let mask = match second & 0x80 {
+        0 => None,
+        _ => {
+            let Some(mask) = buf.get(used..used + 4) else {
+                return Ok(None);
+            };
+            used += 4;
+            Some(mask.try_into().unwrap())
+        }
+    };
+    let header = Header {
+        fin,
+        opcode,
+        mask,
+        len,
+    };
+    Ok(Some((header, used)))
+}
+
+fn unmask(payload: &mut [u8], mask: Option<[u8; 4]>) {
+    if let Some(mask) = mask {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+}
+
+/// A frame with its payload unmasked.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+/// Parses the frame at the start of `buf`, with its length in bytes, or
+/// None until all of it has arrived.
+pub fn parse_frame(buf: &[u8]) -> io::Result<Option<(Frame, usize)>> {
+    let Some((header, used)) = parse_header(buf)? else {
+        return Ok(None);
+    };
+    let Some(payload) = usize::try_from(header.len)
+        .ok()
+        .and_then(|len| buf.get(used..used.checked_add(len)?))
+    else {
+        return Ok(None);
+    };
+    let mut payload = payload.to_vec();
+    unmask(&mut payload, header.mask);
+    let frame = Frame {
+        fin: header.fin,
+        opcode: header.opcode,
+        payload,
+    };
+    let len = used + frame.payload.len();
+    Ok(Some((frame, len)))
+}
+
+/// What gets logged of a connection's messages.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    // Binary messages, and text too long to buffer, by length.
+    Data(Opcode, u64),
+    Close(Option<(u16, String)>),
+    Ping(usize),
+    Pong(usize),
+}
+
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Message::Text(text) => write!(f, "text: {}", request_line::escape(text)),
+            Message::Data(Opcode::Text, len) => write!(f, "text: {len} bytes"),
+            Message::Data(_, len) => write!(f, "binary: {len} bytes"),
+            Message::Close(None) => write!(f, "close"),
+            Message::Close(Some((code, reason))) => {
+                write!(f, "close: {code} {}", request_line::escape(reason))
+            }
+            Message::Ping(len) => write!(f, "ping: {len} bytes"),
+            Message::Pong(len) => write!(f, "pong: {len} bytes"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Direction {
+    // Past the handshake head that starts the direction.
+    head_done: bool,
+    buf: Vec<u8>,
+    // A data message that started with a non-final frame.
+    message: Option<(Opcode, Vec<u8>, u64)>,
+    // Payload bytes still to come of a frame too long to buffer.
+    skipping: u64,
+}
+
+impl Direction {
+    // Frames complete in `buf`, each unmasked and joined into messages.
+    fn messages(&mut self) -> io::Result<Vec<Message>> {
+        let mut messages = vec![];
+        let mut start = 0;
+        loop {
+            let buf = &self.buf[start..];
+            if self.skipping > 0 {
+                let skipped = self.skipping.min(buf.len() as u64);
+                self.skipping -= skipped;
+                start += skipped as usize;
+                if self.skipping > 0 {
+                    break;
+                }
+                continue;
+            }
+            let Some((header, used)) = parse_header(buf)? else {
+                break;
+            };
+            if header.len > MAX_PAYLOAD as u64 {
+                start += used;
+                self.skipping = header.len;
+                self.data(header.opcode, header.fin, None, header.len, &mut messages)?;
+                continue;
+            }
+            let Some((frame, used)) = parse_frame(buf)? else {
+                break;
+            };
+            start += used;
+            let len = frame.payload.len();
+            match frame.opcode {
+                Opcode::Close => messages.push(Message::Close(close(&frame.payload)?)),
+                Opcode::Ping => messages.push(Message::Ping(len)),
+                Opcode::Pong => messages.push(Message::Pong(len)),
+                opcode => {
+                    let payload = Some(frame.payload);
+                    self.data(opcode, frame.fin, payload, len as u64, &mut messages)?
+                }
+            }
+        }
+        self.buf.drain(..start);
+        Ok(messages)
+    }
+
+    // A data frame, with its payload unless it was too long to buffer.
+    fn data(
+        &mut self,
+        opcode: Opcode,
+        fin: bool,
+        payload: Option<Vec<u8>>,
+        len: u64,
+        messages: &mut Vec<Message>,
+    ) -> io::Result<()> {
+        let (opcode, mut collected, total) = match (opcode, self.message.take()) {
+            (Opcode::Continuation, Some((opcode, collected, total))) => (opcode, collected, total),
+            (Opcode::Continuation, None) => return Err(malformed("continuation of nothing")),
+            (_, Some(_)) => return Err(malformed("message interrupted")),
+            (opcode, None) => (opcode, vec![], 0),
+        };
+        let total = total + len;
+        match payload {
+            Some(payload) if collected.len() + payload.len() <= MAX_PAYLOAD => {
+                collected.extend(payload)
+            }
+            // Only the length is kept of a message that outgrew the buffer.
+            _ => collected = vec![],
+        }
+        if !fin {
+            self.message = Some((opcode, collected, total));
+            return Ok(());
+        }
+        messages.push(match opcode {
+            Opcode::Text if collected.len() as u64 == total => {
+                let text = String::from_utf8(collected).map_err(|_| malformed("invalid UTF-8"))?;
+                Message::Text(text)
+            }
+            opcode => Message::Data(opcode, total),
+        });
+        Ok(())
+    }
+}
+
+fn close(payload: &[u8]) -> io::Result<Option<(u16, String)>> {
+    match payload {
+        [] => Ok(None),
+        [high, low, reason @ ..] => {
+            let reason = std::str::from_utf8(reason).map_err(|_| malformed("invalid UTF-8"))?;
+            Ok(Some((
+                u16::from_be_bytes([*high, *low]),
+                reason.to_string(),
+            )))
+        }
+        _ => Err(malformed("close code truncated")),
+    }
+}
+
+#[derive(Default)]
+struct State {
+    // Client to server, server to client.
+    directions: [Direction; 2],
+    // Whether the target answered the handshake with 101, once it did.
+    upgraded: Option<bool>,
+}
+
+/// Decodes the frames of both directions of one tunnel. Each direction
+/// starts with its handshake head, and frames are only decoded once the
+/// target has switched protocols; a tunnel that does not, or that sends
+/// something that is not a frame, is left alone from then on.
+#[derive(Default)]
+pub struct Decoder(Mutex<State>);
+
+impl Decoder {
+    pub fn observe(&self, up: bool, chunk: &[u8]) {
+        let direction = if up { "c2s" } else { "s2c" };
+        match self.messages(up, chunk) {
+            Ok(messages) => {
+                for message in messages {
+                    match message {
+                        Message::Ping(_) | Message::Pong(_) => {
+                            log::debug!("WebSocket {direction} {message}")
+                        }
+                        _ => log::info!("WebSocket {direction} {message}"),
+                    }
+                }
+            }
+            Err(e) => log::debug!("No longer decoding WebSocket {direction}: {e}"),
+        }
+    }
+
+    fn messages(&self, up: bool, chunk: &[u8]) -> io::Result<Vec<Message>> {
+        let mut state = self.0.lock().unwrap();
+        if state.upgraded == Some(false) {
+            return Ok(vec![]);
+        }
+        let result = state.decode(up, chunk);
+        if result.is_err() {
+            state.upgraded = Some(false);
+            state.directions = Default::default();
+        }
+        result
+    }
+}
+
+impl State {
+    fn decode(&mut self, up: bool, chunk: &[u8]) -> io::Result<Vec<Message>> {
+        let direction = &mut self.directions[usize::from(!up)];
+        direction.buf.extend_from_slice(chunk);
+        if !direction.head_done {
+            let Some(end) = direction.buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+                if direction.buf.len() > MAX_HEAD {
+                    return Err(malformed("no handshake"));
+                }
+                return Ok(vec![]);
+            };
+            let head = direction.buf.drain(..end + 4).collect::<Vec<_>>();
+            direction.head_done = true;
+            if !up {
+                let switching = head.starts_with(b"HTTP/1.1 101 ");
+                self.upgraded = Some(switching);
+                if !switching {
+                    return Err(malformed("the target did not switch protocols"));
+                }
+            }
+        }
+        if self.upgraded != Some(true) {
+            return Ok(vec![]);
+        }
+        // Client frames that came in ahead of the 101 are decoded with it.
+        let mut messages = vec![];
+        if !up && self.directions[0].head_done {
+            messages.extend(self.directions[0].messages()?);
+        }
+        messages.extend(self.directions[usize::from(!up)].messages()?);
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // The examples of RFC 6455 section 5.7.
+    const HELLO: [u8; 7] = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+    const MASKED_HELLO: [u8; 11] = [
+        0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+    ];
+    const HEL: [u8; 5] = [0x01, 0x03, 0x48, 0x65, 0x6c];
+    const LO: [u8; 4] = [0x80, 0x02, 0x6c, 0x6f];
+    const PING: [u8; 7] = [0x89, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+    const MASKED_PONG: [u8; 11] = [
+        0x8a, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+    ];
+    fn frame(bytes: &[u8]) -> Frame {
+        let (frame, used) = parse_frame(bytes).unwrap().unwrap();
+        assert_eq!(used, bytes.len());
+        frame
+    }
+    #[test]
+    fn test_rfc_examples() {
+        for (bytes, fin, opcode, payload) in [
+            (&HELLO[..], true, Opcode::Text, &b"Hello"[..]),
+            (&MASKED_HELLO, true, Opcode::Text, b"Hello"),
+            (&HEL, false, Opcode::Text, b"Hel"),
+            (&LO, true, Opcode::Continuation, b"lo"),
+            (&PING, true, Opcode::Ping, b"Hello"),
+            (&MASKED_PONG, true, Opcode::Pong, b"Hello"),
+        ] {
+            let expected = Frame {
+                fin,
+                opcode,
+                payload: payload.to_vec(),
+            };
+            assert_eq!(frame(bytes), expected);
+            for end in 0..bytes.len() {
+                assert_eq!(parse_frame(&bytes[..end]).unwrap(), None);
+            }
+        }
+        // 256 bytes and 64 KiB of binary data in a single unmasked frame.
+        let mut medium = vec![0x82, 0x7e, 0x01, 0x00];
+        medium.extend([7; 256]);
+        assert_eq!(frame(&medium).payload, [7; 256]);
+        let mut long = vec![0x82, 0x7f, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00];
+        long.extend(vec![7; 65536]);
+        assert_eq!(frame(&long).payload.len(), 65536);
+        for bad in [
+            &[0xc1, 0x00][..],
+            &[0x83, 0x00],
+            &[0x09, 0x00],
+            &[0x89, 0x7e, 0x00, 0x7e],
+        ] {
+            assert_eq!(
+                parse_frame(bad).unwrap_err().kind(),
+                ErrorKind::InvalidData,
+                "{bad:?}"
+            );
+        }
+    }
+    const REQUEST: &[u8] = b"GET /chat HTTP/1.1\r\nUpgrade: websocket\r\n\r\n";
+    const RESPONSE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n";
+    #[test]
+    fn test_decoder_joins_messages() {
+        let decoder = Decoder::default();
+        assert_eq!(decoder.messages(true, REQUEST).unwrap(), []);
+        // Split mid-frame, and a ping between the fragments of a message.
+        let mut up = MASKED_HELLO.to_vec();
+        up.extend(HEL);
+        up.extend(MASKED_PONG);
+        up.extend(LO);
+        up.extend([0x88, 0x80, 1, 2, 3, 4]);
+        assert_eq!(decoder.messages(true, &up[..13]).unwrap(), []);
+        // The 101 lets the client's frames through.
+        let text = || Message::Text("Hello".to_string());
+        assert_eq!(decoder.messages(false, RESPONSE).unwrap(), [text()]);
+        assert_eq!(
+            decoder.messages(true, &up[13..]).unwrap(),
+            [Message::Pong(5), text(), Message::Close(None)]
+        );
+        let mut down = PING.to_vec();
+        down.extend([0x82, 0x03, 1, 2, 3]);
+        down.extend([0x88, 0x04, 0x03, 0xe8, b'o', b'k']);
+        assert_eq!(
+            decoder.messages(false, &down).unwrap(),
+            [
+                Message::Ping(5),
+                Message::Data(Opcode::Binary, 3),
+                Message::Close(Some((1000, "ok".to_string())))
+            ]
+        );
+    }
+    #[test]
+    fn test_decoder_counts_long_frames() {
+        let decoder = Decoder::default();
+        decoder.messages(true, REQUEST).unwrap();
+        decoder.messages(false, RESPONSE).unwrap();
+        let len = MAX_PAYLOAD as u64 + 1;
+        let mut header = vec![0x82, 0x7f];
+        header.extend(len.to_be_bytes());
+        assert_eq!(
+            decoder.messages(false, &header).unwrap(),
+            [Message::Data(Opcode::Binary, len)]
+        );
+        assert_eq!(decoder.messages(false, &vec![0; MAX_PAYLOAD]).unwrap(), []);
+        assert_eq!(
+            decoder
+                .messages(false, &[0, 0x81, 0x02, b'h', b'i'])
+                .unwrap(),
+            [Message::Text("hi".to_string())]
+        );
+    }
+    #[test]
+    fn test_decoder_leaves_other_tunnels_alone() {
+        let decoder = Decoder::default();
+        decoder.messages(true, REQUEST).unwrap();
+        let response = b"HTTP/1.1 200 OK\r\n\r\n";
+        assert!(decoder.messages(false, response).is_err());
+        assert_eq!(decoder.messages(false, &HELLO).unwrap(), []);
+        // A TLS tunnel has no head to end.
+        let decoder = Decoder::default();
+        assert_eq!(decoder.messages(true, &[0x16; 1024]).unwrap(), []);
+        assert!(decoder.messages(true, &vec![0x16; MAX_HEAD]).is_err());
+        assert_eq!(decoder.messages(false, RESPONSE).unwrap(), []);
+    }
+}
@@ -0,0 +1,63 @@
+// Runs the proxy out of file descriptors. The limit is the process's, so this
+// is a test binary of its own rather than one of the tests in proxy.rs.
+
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use proxy::Proxy;
+
+fn set_nofile(limit: libc::rlim_t) -> libc::rlim_t {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `rlimit` is a valid rlimit to write to and read from.
+    assert_eq!(
+        unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) },
+        0
+    );
+    let previous = rlimit.rlim_cur;
+    rlimit.rlim_cur = limit;
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit) }, 0);
+    previous
+}
+
+#[tokio::test]
+async fn test_proxy_keeps_accepting_after_running_out_of_descriptors() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy = Proxy::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let running = tokio::spawn(proxy.run());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let previous = set_nofile(256);
+    let mut spare = vec![];
+    while let Ok(file) = std::fs::File::open("/dev/null") {
+        spare.push(file);
+    }
+    // One descriptor for the client; the proxy has none left to accept it.
+    spare.pop();
+    let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!running.is_finished(), "the proxy stopped at EMFILE");
+
+    drop(spare);
+    set_nofile(previous);
+    client
+        .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let (mut upstream, _) = tokio::time::timeout(Duration::from_secs(5), target.accept())
+        .await
+        .expect("the queued connection was never accepted")
+        .unwrap();
+    let mut response = [0; 39];
+    client.read_exact(&mut response).await.unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200"));
+    client.write_all(b"ping").await.unwrap();
+    let mut ping = [0; 4];
+    upstream.read_exact(&mut ping).await.unwrap();
+    assert_eq!(&ping, b"ping");
+}
@@ -0,0 +1,573 @@
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use proxy::Proxy;
+
+#[tokio::test]
+async fn test_connect_tunnel_through_embedded_proxy() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy = Proxy::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    assert_ne!(proxy_addr.port(), 0);
+    let shutdown = proxy.shutdown();
+    let running = tokio::spawn(proxy.run());
+
+    let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+    client
+        .write_all(
+            format!("CONNECT {target_addr} HTTP/1.1\r\nHost: {target_addr}\r\n\r\n").as_bytes(),
+        )
+        .await
+        .unwrap();
+    let (mut upstream, _) = target.accept().await.unwrap();
+    let mut response = [0; 39];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+
+    client.write_all(b"ping").await.unwrap();
+    let mut ping = [0; 4];
+    upstream.read_exact(&mut ping).await.unwrap();
+    assert_eq!(&ping, b"ping");
+    upstream.write_all(b"pong").await.unwrap();
+    drop(upstream);
+    let mut pong = vec![];
+    client.read_to_end(&mut pong).await.unwrap();
+    assert_eq!(pong, b"pong");
+    drop(client);
+
+    shutdown.request();
+    let stopped = tokio::time::timeout(Duration::from_secs(5), running).await;
+    stopped.unwrap().unwrap().unwrap();
+    assert!(TcpStream::connect(proxy_addr).await.is_err());
+}
+
+#[tokio::test]
+async fn test_refused_target_gets_bad_gateway() {
+    // Bound and dropped, so nothing listens on the port.
+    let target_addr = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let proxy = Proxy::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let shutdown = proxy.shutdown();
+    let running = tokio::spawn(proxy.run());
+
+    let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+    client
+        .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    assert_eq!(
+        response,
+        "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 12\r\nConnection: close\r\n\r\n\
+         Bad Gateway\n"
+    );
+
+    shutdown.request();
+    let stopped = tokio::time::timeout(Duration::from_secs(5), running).await;
+    stopped.unwrap().unwrap().unwrap();
+}
+
+async fn scrape(addr: std::net::SocketAddr) -> String {
+    let mut scraper = TcpStream::connect(addr).await.unwrap();
+    scraper
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: proxy\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    scraper.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    response
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint_counts_tunnelled_bytes() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let config = proxy::config::Config {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        metrics_addr: Some("127.0.0.1:0".parse().unwrap()),
+        ..Default::default()
+    };
+    let proxy = Proxy::with_config(config).unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let metrics_addr = proxy.metrics_addr().unwrap();
+    let shutdown = proxy.shutdown();
+    let running = tokio::spawn(proxy.run());
+
+    let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+    client
+        .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let (mut upstream, _) = target.accept().await.unwrap();
+    let mut response = [0; 39];
+    client.read_exact(&mut response).await.unwrap();
+    client.write_all(&[1; 1000]).await.unwrap();
+    let mut up = [0; 1000];
+    upstream.read_exact(&mut up).await.unwrap();
+    upstream.write_all(&[2; 2500]).await.unwrap();
+    let mut down = [0; 2500];
+    client.read_exact(&mut down).await.unwrap();
+
+    let metrics = scrape(metrics_addr).await;
+    for line in [
+        "proxy_connections_total 1",
+        "proxy_active_connections 1",
+        "proxy_bytes_total{direction=\"up\"} 1000",
+        "proxy_bytes_total{direction=\"down\"} 2500",
+    ] {
+        assert!(metrics.lines().any(|l| l == line), "{line}:\n{metrics}");
+    }
+
+    // A refused target shows up as a connect failure.
+    drop(target);
+    let mut refused = TcpStream::connect(proxy_addr).await.unwrap();
+    refused
+        .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    refused.read_to_end(&mut vec![]).await.unwrap();
+    // The failure is counted just after the client sees the close.
+    let line = "proxy_connect_failures_total{class=\"refused\"} 1";
+    let mut metrics = scrape(metrics_addr).await;
+    for _ in 0..50 {
+        if metrics.lines().any(|l| l == line) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        metrics = scrape(metrics_addr).await;
+    }
+    assert!(metrics.lines().any(|l| l == line), "{line}:\n{metrics}");
+
+    drop(upstream);
+    drop(client);
+    shutdown.request();
+    let stopped = tokio::time::timeout(Duration::from_secs(5), running).await;
+    stopped.unwrap().unwrap().unwrap();
+}
+
+async fn admin(addr: std::net::SocketAddr, method: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(format!("{method} {path} HTTP/1.1\r\nHost: proxy\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_admin_endpoint_lists_and_closes_tunnels() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let config = proxy::config::Config {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        admin_addr: Some("127.0.0.1:0".parse().unwrap()),
+        ..Default::default()
+    };
+    let proxy = Proxy::with_config(config).unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let admin_addr = proxy.admin_addr().unwrap();
+    let shutdown = proxy.shutdown();
+    let running = tokio::spawn(proxy.run());
+
+    let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+    client
+        .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let (mut upstream, _) = target.accept().await.unwrap();
+    let mut response = [0; 39];
+    client.read_exact(&mut response).await.unwrap();
+    client.write_all(b"ping").await.unwrap();
+    let mut ping = [0; 4];
+    upstream.read_exact(&mut ping).await.unwrap();
+
+    let status = admin(admin_addr, "GET", "/status").await;
+    assert!(status.starts_with("HTTP/1.1 200 OK\r\n"), "{status}");
+    assert!(
+        status.contains("\"connections_total\":1,\"active_connections\":1"),
+        "{status}"
+    );
+    let host = "{\"host\":\"127.0.0.1\",\"open_tunnels\":[";
+    let listed = &status[status.find(host).expect(&status) + host.len()..];
+    let id: u64 = listed[..listed.find(']').unwrap()].parse().unwrap();
+    assert!(
+        listed.contains("\"bytes_up\":4,\"bytes_down\":0"),
+        "{status}"
+    );
+
+    let details = admin(admin_addr, "GET", &format!("/connections/{id}")).await;
+    assert!(
+        details.contains(&format!("\"target\":\"{target_addr}\"")),
+        "{details}"
+    );
+    let closed = admin(admin_addr, "DELETE", &format!("/connections/{id}")).await;
+    assert!(closed.starts_with("HTTP/1.1 200 OK\r\n"), "{closed}");
+    let mut rest = vec![];
+    let read = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest));
+    read.await.unwrap().unwrap();
+    assert!(rest.is_empty());
+    let gone = admin(admin_addr, "GET", &format!("/connections/{id}")).await;
+    assert!(gone.starts_with("HTTP/1.1 404 Not Found\r\n"), "{gone}");
+    let status = admin(admin_addr, "GET", "/status").await;
+    assert!(
+        status.contains(&format!("{host}],\"bytes_up\":4")),
+        "{status}"
+    );
+
+    shutdown.request();
+    let stopped = tokio::time::timeout(Duration::from_secs(5), running).await;
+    stopped.unwrap().unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_closing_a_recorded_tunnel_from_the_admin_endpoint_completes_its_capture() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let dir = std::env::temp_dir().join(format!("proxy-admin-close-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = proxy::config::Config {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        admin_addr: Some("127.0.0.1:0".parse().unwrap()),
+        record_dir: Some(dir.clone()),
+        ..Default::default()
+    };
+    let proxy = Proxy::with_config(config).unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let admin_addr = proxy.admin_addr().unwrap();
+    let shutdown = proxy.shutdown();
+    let running = tokio::spawn(proxy.run());
+
+    let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+    client
+        .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let (mut upstream, _) = target.accept().await.unwrap();
+    let mut response = [0; 39];
+    client.read_exact(&mut response).await.unwrap();
+    client.write_all(b"ping").await.unwrap();
+    let mut ping = [0; 4];
+    upstream.read_exact(&mut ping).await.unwrap();
+    upstream.write_all(b"pong!").await.unwrap();
+    let mut pong = [0; 5];
+    client.read_exact(&mut pong).await.unwrap();
+
+    let status = admin(admin_addr, "GET", "/status").await;
+    let host = "{\"host\":\"127.0.0.1\",\"open_tunnels\":[";
+    let listed = &status[status.find(host).expect(&status) + host.len()..];
+    let id: u64 = listed[..listed.find(']').unwrap()].parse().unwrap();
+    let closed = admin(admin_addr, "DELETE", &format!("/connections/{id}")).await;
+    assert!(closed.starts_with("HTTP/1.1 200 OK\r\n"), "{closed}");
+    let mut rest = vec![];
+    let read = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest));
+    read.await.unwrap().unwrap();
+
+    // Both directions' files and the index are written before the tunnel's
+    // sockets are closed.
+    let files: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    let file = |suffix: &str| {
+        let path = files
+            .iter()
+            .find(|path| path.to_string_lossy().ends_with(suffix));
+        std::fs::read(path.expect(suffix)).unwrap()
+    };
+    assert_eq!(file("-c2s.bin"), b"ping");
+    assert_eq!(file("-s2c.bin"), b"pong!");
+    let index = String::from_utf8(file(".idx")).unwrap();
+    assert!(index.contains("\nc2s "), "{index}");
+    assert!(index.contains("\ns2c "), "{index}");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    shutdown.request();
+    let stopped = tokio::time::timeout(Duration::from_secs(5), running).await;
+    stopped.unwrap().unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_reloading_the_config_file_denies_new_tunnels_only() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let path = std::env::temp_dir().join(format!("proxy-config-{}.toml", std::process::id()));
+    std::fs::write(&path, "deny = [\"192.0.2.1\"]\n").unwrap();
+    let args = [
+        "--listen",
+        "127.0.0.1:0",
+        "--admin-addr",
+        "127.0.0.1:0",
+        "--config",
+        path.to_str().unwrap(),
+    ];
+    let config = proxy::config::Config::from_args(args.iter().map(|arg| arg.to_string())).unwrap();
+    let proxy = Proxy::with_config(config).unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let admin_addr = proxy.admin_addr().unwrap();
+    let shutdown = proxy.shutdown();
+    let running = tokio::spawn(proxy.run());
+    let connect = || async {
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut status = [0; 12];
+        client.read_exact(&mut status).await.unwrap();
+        (client, String::from_utf8(status.to_vec()).unwrap())
+    };
+
+    let (mut open, status) = connect().await;
+    assert_eq!(status, "HTTP/1.1 200");
+    let (mut upstream, _) = target.accept().await.unwrap();
+    let mut rest = [0; 27];
+    open.read_exact(&mut rest).await.unwrap();
+
+    std::fs::write(&path, "deny = [\"127.0.0.1\"]\n").unwrap();
+    let reloaded = admin(admin_addr, "POST", "/reload").await;
+    assert!(reloaded.ends_with("{\"reloaded\":true}\n"), "{reloaded}");
+    assert_eq!(connect().await.1, "HTTP/1.1 403");
+    // A file that does not parse leaves the last good config in place.
+    std::fs::write(&path, "deny = [\"127.0.0.1\"\n").unwrap();
+    let rejected = admin(admin_addr, "POST", "/reload").await;
+    assert!(rejected.starts_with("HTTP/1.1 400 "), "{rejected}");
+    assert!(rejected.contains("unclosed array"), "{rejected}");
+    assert_eq!(connect().await.1, "HTTP/1.1 403");
+
+    // The tunnel opened before the reload still flows.
+    open.write_all(b"ping").await.unwrap();
+    let mut ping = [0; 4];
+    upstream.read_exact(&mut ping).await.unwrap();
+    assert_eq!(&ping, b"ping");
+    upstream.write_all(b"pong").await.unwrap();
+    let mut pong = [0; 4];
+    open.read_exact(&mut pong).await.unwrap();
+    assert_eq!(&pong, b"pong");
+
+    drop((open, upstream));
+    std::fs::remove_file(&path).unwrap();
+    shutdown.request();
+    let stopped = tokio::time::timeout(Duration::from_secs(5), running).await;
+    stopped.unwrap().unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_tunnel_is_cut_at_the_byte_limit() {
+    let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = echo.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = socket.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    let limit = 64 * 1024;
+    let dir = std::env::temp_dir().join(format!("proxy-byte-limit-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = proxy::config::Config {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        max_tunnel_bytes: Some(limit as u64),
+        max_tunnel_bytes_each: true,
+        metrics_addr: Some("127.0.0.1:0".parse().unwrap()),
+        record_dir: Some(dir.clone()),
+        ..Default::default()
+    };
+    let proxy = Proxy::with_config(config).unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let metrics_addr = proxy.metrics_addr().unwrap();
+    let shutdown = proxy.shutdown();
+    let running = tokio::spawn(proxy.run());
+    let connect = || async {
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("CONNECT {echo_addr} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = [0; 39];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+        client
+    };
+
+    let (mut reader, mut writer) = connect().await.into_split();
+    let upload = tokio::spawn(async move { writer.write_all(&vec![7; 1024 * 1024]).await });
+    let mut buf = [0; 8192];
+    let read = async {
+        // Cut with a reset as often as a close.
+        while let Ok(1..) = reader.read(&mut buf).await {}
+    };
+    tokio::time::timeout(Duration::from_secs(5), read)
+        .await
+        .unwrap();
+    // Most of the upload may have fit in the socket buffers before the cut.
+    let uploaded = tokio::time::timeout(Duration::from_secs(5), upload).await;
+    let _ = uploaded.unwrap().unwrap();
+    // Short of the limit by no more than the chunk that went over it.
+    let metrics = scrape(metrics_addr).await;
+    let up = "proxy_bytes_total{direction=\"up\"} ";
+    let line = metrics.lines().find(|l| l.starts_with(up)).expect(&metrics);
+    let forwarded: usize = line[up.len()..].parse().unwrap();
+    assert!((limit - 8192..=limit).contains(&forwarded), "{forwarded}");
+
+    // The proxy goes on serving.
+    let mut client = connect().await;
+    client.write_all(b"ping").await.unwrap();
+    let mut ping = [0; 4];
+    client.read_exact(&mut ping).await.unwrap();
+    assert_eq!(&ping, b"ping");
+    drop(client);
+
+    shutdown.request();
+    let stopped = tokio::time::timeout(Duration::from_secs(5), running).await;
+    stopped.unwrap().unwrap().unwrap();
+    // The capture holds what was forwarded, and not the chunk that was cut.
+    let mut uploads: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_string_lossy().ends_with("-c2s.bin"))
+        .collect();
+    uploads.sort();
+    let captured = std::fs::read(&uploads[0]).unwrap();
+    assert_eq!(captured.len(), forwarded);
+    assert!(captured.iter().all(|&b| b == 7));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_hosts_override_sends_a_name_to_a_local_server() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let args = [
+        "--listen".to_string(),
+        "127.0.0.1:0".to_string(),
+        "--hosts-override".to_string(),
+        format!("www.example.invalid=1.2.3.4,*.example.invalid={target_addr}"),
+    ];
+    let config = proxy::config::Config::from_args(args.into_iter()).unwrap();
+    let proxy = Proxy::with_config(config).unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let shutdown = proxy.shutdown();
+    let running = tokio::spawn(proxy.run());
+
+    let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+    client
+        .write_all(b"CONNECT staging.example.invalid:443 HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let (mut upstream, _) = target.accept().await.unwrap();
+    let mut response = [0; 39];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+    client.write_all(b"ping").await.unwrap();
+    let mut ping = [0; 4];
+    upstream.read_exact(&mut ping).await.unwrap();
+    assert_eq!(&ping, b"ping");
+    drop((client, upstream));
+
+    shutdown.request();
+    let stopped = tokio::time::timeout(Duration::from_secs(5), running).await;
+    stopped.unwrap().unwrap().unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_proxy_listens_on_a_unix_socket() {
+    use std::os::unix::fs::PermissionsExt;
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let path = std::env::temp_dir().join(format!("proxy-test-{}.sock", std::process::id()));
+    let args = [
+        "--listen".to_string(),
+        format!("unix:{}", path.display()),
+        "--listen-mode".to_string(),
+        "0600".to_string(),
+    ];
+    let config = proxy::config::Config::from_args(args.into_iter()).unwrap();
+    let proxy = Proxy::with_config(config).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let shutdown = proxy.shutdown();
+    let running = tokio::spawn(proxy.run());
+
+    let mut client = tokio::net::UnixStream::connect(&path).await.unwrap();
+    client
+        .write_all(format!("CONNECT {target_addr} HTTP/1.1\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let (mut upstream, _) = target.accept().await.unwrap();
+    let mut response = [0; 39];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+    client.write_all(b"ping").await.unwrap();
+    let mut ping = [0; 4];
+    upstream.read_exact(&mut ping).await.unwrap();
+    assert_eq!(&ping, b"ping");
+    upstream.write_all(b"pong").await.unwrap();
+    drop(upstream);
+    let mut pong = vec![];
+    client.read_to_end(&mut pong).await.unwrap();
+    assert_eq!(pong, b"pong");
+    drop(client);
+
+    shutdown.request();
+    let stopped = tokio::time::timeout(Duration::from_secs(5), running).await;
+    stopped.unwrap().unwrap().unwrap();
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn test_forward_returns_a_tunnels_stats() {
+    let (mut client, client_side) = tokio::io::duplex(1024);
+    let (target_side, mut target) = tokio::io::duplex(1024);
+    let peer = "127.0.0.1:1".parse().unwrap();
+    let forwarding = tokio::spawn(proxy::forward(client_side, target_side, peer));
+    client.write_all(&[1; 1000]).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut up = vec![];
+    target.read_to_end(&mut up).await.unwrap();
+    target.write_all(&[2; 3000]).await.unwrap();
+    target.shutdown().await.unwrap();
+    let mut down = vec![];
+    client.read_to_end(&mut down).await.unwrap();
+    let stats = forwarding.await.unwrap().unwrap();
+    assert_eq!((up.len(), down.len()), (1000, 3000));
+    assert_eq!((stats.bytes_up, stats.bytes_down), (1000, 3000));
+    assert_eq!(stats.closed_first, Some(proxy::Peer::Client));
+    assert!(stats.ttfb.is_some());
+}
+
+#[tokio::test]
+async fn test_a_failed_tunnels_error_carries_its_tails() {
+    let (mut client, client_side) = tokio::io::duplex(1024);
+    let (target_side, mut target) = tokio::io::duplex(1024);
+    let peer = "127.0.0.1:1".parse().unwrap();
+    let forwarding = tokio::spawn(proxy::forward(client_side, target_side, peer));
+    client.write_all(b"hello").await.unwrap();
+    let mut hello = [0; 5];
+    target.read_exact(&mut hello).await.unwrap();
+    target.write_all(b"partial").await.unwrap();
+    let mut partial = [0; 7];
+    client.read_exact(&mut partial).await.unwrap();
+    // The target goes away mid-response, and the client's next bytes have
+    // nowhere to go.
+    drop(target);
+    client.write_all(b" again").await.unwrap();
+    let error = forwarding.await.unwrap().unwrap_err();
+    let tails = proxy::tails_of(&error).unwrap();
+    assert_eq!(tails.up, b"hello again");
+    assert_eq!(tails.down, b"partial");
+    let connection = proxy::ConnectionError::of(&error).unwrap();
+    assert_eq!(connection.stage, proxy::Stage::TunnelC2s);
+}